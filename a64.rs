@@ -0,0 +1,881 @@
+// $t@$h
+use crate::aarch64::Cpu;
+use crate::crypto::random_bytes;
+use crate::mitigations::{branch_type, compute_pac, is_landing_pad, logical_tag, strip_pac, untagged, with_tag, TAG_GRANULE};
+use crate::tzasc::PHYS_MEMORY;
+
+// PSTATE.NZCV bits as they sit in Pstate::nzcv
+const N: u8 = 0b1000;
+const Z: u8 = 0b0100;
+const C: u8 = 0b0010;
+const V: u8 = 0b0001;
+
+// The general purpose and SIMD&FP registers. SP is banked per EL; which
+// one is in use follows PSTATE.SP.
+#[derive(Clone, Default)]
+pub struct RegisterFile {
+    pub x: [u64; 31],
+    pub sp: [u64; 4],
+    pub v: [u128; 32],
+}
+
+impl RegisterFile {
+    pub fn new() -> Self {
+        RegisterFile::default()
+    }
+}
+
+// A general purpose register operand. Number 31 is XZR, or SP where the
+// instruction allows it.
+#[derive(Clone, Copy)]
+struct Gpr {
+    index: usize,
+    wide: bool,
+    sp: bool,
+}
+
+fn parse_gpr(s: &str) -> Option<Gpr> {
+    let s = s.to_ascii_uppercase();
+    let (index, wide, sp) = match s.as_str() {
+        "SP" => (31, true, true),
+        "WSP" => (31, false, true),
+        "XZR" => (31, true, false),
+        "WZR" => (31, false, false),
+        "LR" => (30, true, false),
+        "FP" => (29, true, false),
+        _ => {
+            let wide = s.starts_with('X');
+            if !wide && !s.starts_with('W') {
+                return None;
+            }
+            let index: usize = s[1..].parse().ok()?;
+            if index > 30 {
+                return None;
+            }
+            (index, wide, false)
+        },
+    };
+    Some(Gpr { index, wide, sp })
+}
+
+// "#5", "#0x10", "#-8"
+fn parse_imm(s: &str) -> Option<u64> {
+    let s = s.strip_prefix('#')?;
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+fn parse_fimm(s: &str) -> Option<f64> {
+    s.strip_prefix('#')?.parse().ok()
+}
+
+// A SIMD&FP register with an arrangement: V0.4S is four 32-bit lanes
+#[derive(Clone, Copy)]
+struct VecReg {
+    index: usize,
+    lanes: usize,
+    bits: usize,
+}
+
+fn parse_vreg(s: &str) -> Option<VecReg> {
+    let s = s.to_ascii_uppercase();
+    let (reg, arrangement) = s.strip_prefix('V')?.split_once('.')?;
+    let index: usize = reg.parse().ok()?;
+    let (lanes, bits) = match arrangement {
+        "16B" => (16, 8),
+        "8B" => (8, 8),
+        "8H" => (8, 16),
+        "4H" => (4, 16),
+        "4S" => (4, 32),
+        "2S" => (2, 32),
+        "2D" => (2, 64),
+        "1D" => (1, 64),
+        _ => return None,
+    };
+    if index > 31 {
+        return None;
+    }
+    Some(VecReg { index, lanes, bits })
+}
+
+// A scalar FP register: D0 (double) or S0 (single)
+fn parse_fpreg(s: &str) -> Option<(usize, usize)> {
+    let s = s.to_ascii_uppercase();
+    let bits = match s.chars().next()? {
+        'D' => 64,
+        'S' => 32,
+        _ => return None,
+    };
+    let index: usize = s[1..].parse().ok()?;
+    if index > 31 {
+        return None;
+    }
+    Some((index, bits))
+}
+
+fn mask(bits: usize) -> u64 {
+    if bits == 64 { u64::MAX } else { (1u64 << bits) - 1 }
+}
+
+fn lanes_of(value: u128, reg: VecReg) -> Vec<u64> {
+    (0..reg.lanes).map(|i| ((value >> (i * reg.bits)) as u64) & mask(reg.bits)).collect()
+}
+
+// Writing a 64-bit arrangement clears the upper half of the register
+fn from_lanes(lanes: &[u64], bits: usize) -> u128 {
+    lanes.iter().enumerate().fold(0u128, |acc, (i, &lane)| acc | ((lane & mask(bits)) as u128) << (i * bits))
+}
+
+fn sign_extend(value: u64, bits: usize) -> i64 {
+    ((value << (64 - bits)) as i64) >> (64 - bits)
+}
+
+fn to_float(lane: u64, bits: usize) -> f64 {
+    if bits == 32 { f32::from_bits(lane as u32) as f64 } else { f64::from_bits(lane) }
+}
+
+// Rounds once to the lane's format, so single precision results are
+// exactly what an FPU would produce
+fn from_float(value: f64, bits: usize) -> u64 {
+    if bits == 32 { (value as f32).to_bits() as u64 } else { value.to_bits() }
+}
+
+fn float_op(mnemonic: &str, a: f64, b: f64, bits: usize) -> u64 {
+    if bits == 32 {
+        let (a, b) = (a as f32, b as f32);
+        let r = match mnemonic {
+            "FADD" => a + b,
+            "FSUB" => a - b,
+            _ => a * b,
+        };
+        r.to_bits() as u64
+    } else {
+        let r = match mnemonic {
+            "FADD" => a + b,
+            "FSUB" => a - b,
+            _ => a * b,
+        };
+        r.to_bits()
+    }
+}
+
+// AddWithCarry() from the Arm ARM: the result and the NZCV it would set
+fn add_with_carry(x: u64, y: u64, carry: bool, bits: usize) -> (u64, u8) {
+    let m = mask(bits);
+    let (x, y) = (x & m, y & m);
+    let unsigned_sum = x as u128 + y as u128 + carry as u128;
+    let signed_sum = sign_extend(x, bits) as i128 + sign_extend(y, bits) as i128 + carry as i128;
+    let result = (unsigned_sum as u64) & m;
+    let mut nzcv = 0;
+    if (result >> (bits - 1)) & 1 == 1 {
+        nzcv |= N;
+    }
+    if result == 0 {
+        nzcv |= Z;
+    }
+    if result as u128 != unsigned_sum {
+        nzcv |= C;
+    }
+    if sign_extend(result, bits) as i128 != signed_sum {
+        nzcv |= V;
+    }
+    (result, nzcv)
+}
+
+// Operands are comma separated, but not inside [] or {}
+fn split_operands(args: &[&str]) -> Vec<String> {
+    let joined = args.join(" ");
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    for ch in joined.chars() {
+        match ch {
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue;
+            },
+            _ => {},
+        }
+        current.push(ch);
+    }
+    if !current.trim().is_empty() {
+        operands.push(current.trim().to_string());
+    }
+    operands
+}
+
+// [Xn], [Xn, #imm] or [Xn, #imm]! (pre-index)
+fn parse_address(s: &str) -> Option<(Gpr, u64, bool)> {
+    let (inner, writeback) = match s.strip_suffix('!') {
+        Some(rest) => (rest, true),
+        None => (s, false),
+    };
+    let inner = inner.strip_prefix('[')?.strip_suffix(']')?;
+    let mut parts = inner.split(',').map(str::trim);
+    let base = parse_gpr(parts.next()?)?;
+    let offset = match parts.next() {
+        Some(imm) => parse_imm(imm)?,
+        None => 0,
+    };
+    // The base of an address can be SP but never XZR
+    let base = if base.index == 31 { Gpr { sp: true, ..base } } else { base };
+    Some((base, offset, writeback))
+}
+
+impl Cpu {
+    pub fn sp(&self) -> u64 {
+        self.regs.sp[self.sp_index()]
+    }
+
+    fn sp_index(&self) -> usize {
+        if self.pstate.sp_elx { self.el() as usize } else { 0 }
+    }
+
+    fn read_gpr(&self, r: Gpr) -> u64 {
+        let value = match (r.index, r.sp) {
+            (31, true) => self.sp(),
+            (31, false) => 0,
+            (i, _) => self.regs.x[i],
+        };
+        value & mask(if r.wide { 64 } else { 32 })
+    }
+
+    // W writes zero the upper half; writes to XZR vanish
+    fn write_gpr(&mut self, r: Gpr, value: u64) {
+        let value = value & mask(if r.wide { 64 } else { 32 });
+        match (r.index, r.sp) {
+            (31, true) => {
+                let i = self.sp_index();
+                self.regs.sp[i] = value;
+            },
+            (31, false) => {},
+            (i, _) => self.regs.x[i] = value,
+        }
+    }
+
+    fn gpr_name(r: Gpr) -> String {
+        match (r.index, r.sp, r.wide) {
+            (31, true, _) => "SP".to_string(),
+            (31, false, true) => "XZR".to_string(),
+            (31, false, false) => "WZR".to_string(),
+            (i, _, true) => format!("X{}", i),
+            (i, _, false) => format!("W{}", i),
+        }
+    }
+
+    // Register or immediate second source
+    fn operand2(&self, s: &str, bits: usize) -> Result<u64, String> {
+        match (parse_imm(s), parse_gpr(s)) {
+            (Some(imm), _) => Ok(imm & mask(bits)),
+            (None, Some(r)) => Ok(self.read_gpr(r)),
+            _ => Err(format!("Invalid operand '{}'", s)),
+        }
+    }
+
+    // With MTE on, every granule an access touches must carry the
+    // pointer's tag. Returns the address with the tag stripped.
+    fn check_tags(&mut self, addr: u64, len: usize) -> Result<u64, String> {
+        let pa = untagged(addr);
+        if !self.mitigations.mte {
+            return Ok(pa);
+        }
+        let tag = logical_tag(addr);
+        let first = pa - pa % TAG_GRANULE;
+        let mut granule = first;
+        while granule < pa + len as u64 {
+            // A granule the TZASC hides fails the access itself, not the tag check
+            let allocation_tag = PHYS_MEMORY.lock().unwrap().allocation_tag(granule, self.is_secure()).unwrap_or(tag);
+            if allocation_tag != tag {
+                println!(
+                    "MTE: pointer tag {:#x} does not match allocation tag {:#x} of granule {:#x}",
+                    tag, allocation_tag, granule
+                );
+                return self.tag_check_fault(addr).and(Err("Tag check fault".to_string()));
+            }
+            granule += TAG_GRANULE;
+        }
+        Ok(pa)
+    }
+
+    fn load(&mut self, addr: u64, len: usize) -> Result<Vec<u8>, String> {
+        let pa = self.check_tags(addr, len)?;
+        let result = PHYS_MEMORY.lock().unwrap().read(pa, len, self.is_secure());
+        result.or_else(|msg| {
+            println!("{}", msg);
+            self.data_abort(addr).and(Err("Load aborted".to_string()))
+        })
+    }
+
+    fn store(&mut self, addr: u64, data: &[u8]) -> Result<(), String> {
+        let pa = self.check_tags(addr, data.len())?;
+        let result = PHYS_MEMORY.lock().unwrap().write(pa, data, self.is_secure());
+        result.or_else(|msg| {
+            println!("{}", msg);
+            self.data_abort(addr).and(Err("Store aborted".to_string()))
+        })
+    }
+
+    // The effective address of a load/store, and the base update to make
+    // once the access has succeeded
+    fn address(&self, operands: &[String]) -> Result<(u64, Option<(Gpr, u64)>), String> {
+        let (base, offset, pre) = operands
+            .first()
+            .and_then(|s| parse_address(s))
+            .ok_or_else(|| "Expected an address: [Xn], [Xn, #imm], [Xn, #imm]! or [Xn], #imm".to_string())?;
+        let base_value = self.read_gpr(base);
+        match operands.get(1) {
+            Some(post) => {
+                let post = parse_imm(post).ok_or_else(|| format!("Invalid post-index '{}'", post))?;
+                Ok((base_value, Some((base, base_value.wrapping_add(post)))))
+            },
+            None if pre => {
+                let addr = base_value.wrapping_add(offset);
+                Ok((addr, Some((base, addr))))
+            },
+            None => Ok((base_value.wrapping_add(offset), None)),
+        }
+    }
+
+    // Execute one user-level instruction and return what changed. Branches
+    // set the PC; anything else that completes moves on to the next
+    // instruction, and a fault leaves the PC at the vector.
+    pub fn execute(&mut self, mnemonic: &str, args: &[&str]) -> Result<String, String> {
+        let ops = split_operands(args);
+        let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+        let btype = std::mem::take(&mut self.pstate.btype);
+        if btype != 0 && self.mitigations.bti && !is_landing_pad(mnemonic, &ops, btype) {
+            println!("BTI: '{}' is not a landing pad for BTYPE={:02b}", mnemonic, btype);
+            // SPSR keeps the BTYPE, so returning to the same instruction faults again
+            self.pstate.btype = btype;
+            return self.branch_target_exception(btype).and(Err("Branch target exception".to_string()));
+        }
+        let result = match mnemonic {
+            "ADD" | "SUB" | "AND" | "ORR" | "EOR" => self.exec_alu(mnemonic, &ops),
+            "CMP" | "CMN" => self.exec_compare(mnemonic, &ops),
+            "MOV" | "MVN" => self.exec_move(mnemonic, &ops),
+            "LDR" | "STR" => self.exec_load_store(mnemonic, &ops),
+            "B" | "BL" | "RET" | "BR" | "BLR" => self.exec_branch(mnemonic, &ops),
+            // Outside a landing pad check, BTI is a NOP
+            "BTI" => Ok("landing pad".to_string()),
+            "PACIA" | "AUTIA" | "XPACI" => self.exec_pac(mnemonic, &ops),
+            "IRG" | "STG" | "LDG" => self.exec_mte(mnemonic, &ops),
+            "VADD" | "VSUB" | "SADD" | "SSUB" => self.exec_vector_int(mnemonic, &ops),
+            "FADD" | "FSUB" | "FMUL" => self.exec_float(mnemonic, &ops),
+            "VMOV" => self.exec_vmov(&ops),
+            "LD1" | "ST1" => self.exec_ld1_st1(mnemonic, &ops),
+            "MATMUL" | "MMV" => self.exec_matrix(mnemonic, &ops),
+            _ => Err(format!("No semantics for '{}'", mnemonic)),
+        };
+        if result.is_ok() && !matches!(mnemonic, "B" | "BL" | "RET" | "BR" | "BLR") {
+            self.step();
+        }
+        result
+    }
+
+    fn exec_alu(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let [d, n, m] = ops else {
+            return Err(format!("Usage: {} Xd, Xn, Xm|#imm", mnemonic));
+        };
+        let (d, n) = match (parse_gpr(d), parse_gpr(n)) {
+            (Some(d), Some(n)) => (d, n),
+            _ => return Err(format!("Invalid register in '{}'", ops.join(", "))),
+        };
+        let bits = if d.wide { 64 } else { 32 };
+        let (a, b) = (self.read_gpr(n), self.operand2(m, bits)?);
+        let result = match mnemonic {
+            "ADD" => add_with_carry(a, b, false, bits).0,
+            "SUB" => add_with_carry(a, !b, true, bits).0,
+            "AND" => a & b,
+            "ORR" => a | b,
+            _ => a ^ b,
+        };
+        self.write_gpr(d, result);
+        Ok(format!("{}={:#x}", Self::gpr_name(d), self.read_gpr(d)))
+    }
+
+    fn exec_compare(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let [n, m] = ops else {
+            return Err(format!("Usage: {} Xn, Xm|#imm", mnemonic));
+        };
+        let n = parse_gpr(n).ok_or_else(|| format!("Invalid register '{}'", n))?;
+        let bits = if n.wide { 64 } else { 32 };
+        let (a, b) = (self.read_gpr(n), self.operand2(m, bits)?);
+        let (_, nzcv) = match mnemonic {
+            "CMP" => add_with_carry(a, !b, true, bits),
+            _ => add_with_carry(a, b, false, bits),
+        };
+        self.pstate.nzcv = nzcv;
+        Ok(format!("NZCV={:04b}", nzcv))
+    }
+
+    fn exec_move(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let [d, m] = ops else {
+            return Err(format!("Usage: {} Xd, Xm|#imm", mnemonic));
+        };
+        let d = parse_gpr(d).ok_or_else(|| format!("Invalid register '{}'", d))?;
+        // MOV to or from SP is really ADD Xd, Xn, #0
+        let d = if d.index == 31 && parse_gpr(m).is_some_and(|m| m.sp) { Gpr { sp: true, ..d } } else { d };
+        let value = self.operand2(m, if d.wide { 64 } else { 32 })?;
+        self.write_gpr(d, if mnemonic == "MVN" { !value } else { value });
+        Ok(format!("{}={:#x}", Self::gpr_name(d), self.read_gpr(d)))
+    }
+
+    fn exec_load_store(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let t = ops.first().and_then(|t| parse_gpr(t)).ok_or_else(|| format!("Usage: {} Xt|Wt, [Xn{{, #imm}}]{{!}} | [Xn], #imm", mnemonic))?;
+        let ops: Vec<String> = ops[1..].iter().map(|s| s.to_string()).collect();
+        let (addr, writeback) = self.address(&ops)?;
+        let len = if t.wide { 8 } else { 4 };
+        let effect = if mnemonic == "LDR" {
+            let bytes = self.load(addr, len)?;
+            let mut buf = [0u8; 8];
+            buf[..len].copy_from_slice(&bytes);
+            self.write_gpr(t, u64::from_le_bytes(buf));
+            format!("{}={:#x} from [{:#x}]", Self::gpr_name(t), self.read_gpr(t), addr)
+        } else {
+            let value = self.read_gpr(t);
+            self.store(addr, &value.to_le_bytes()[..len])?;
+            format!("[{:#x}]={:#x}", addr, value)
+        };
+        if let Some((base, value)) = writeback {
+            self.write_gpr(base, value);
+        }
+        Ok(effect)
+    }
+
+    // PACIA with the key of the current EL. With EnIA clear the pointer
+    // passes through unchanged.
+    fn sign(&self, ptr: u64, modifier: u64) -> u64 {
+        if !self.mitigations.pac {
+            return ptr;
+        }
+        strip_pac(ptr) | compute_pac(self.apia_key(), ptr, modifier)
+    }
+
+    fn authenticate(&mut self, ptr: u64, modifier: u64) -> Result<u64, String> {
+        if !self.mitigations.pac {
+            return Ok(ptr);
+        }
+        let expected = self.sign(ptr, modifier);
+        if ptr == expected {
+            return Ok(strip_pac(ptr));
+        }
+        println!("PAC: {:#x} does not authenticate with modifier {:#x}", ptr, modifier);
+        self.pac_fail().and(Err("Pointer authentication failure".to_string()))
+    }
+
+    // B/BL take a PC-relative #offset or an absolute address; BR/BLR/RET a
+    // register. With pointer authentication on, every call signs its
+    // return address against SP (the PACIASP a function starts with) and
+    // RET authenticates it (AUTIASP).
+    fn exec_branch(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let register = match (mnemonic, ops) {
+            ("RET", []) => Some(30),
+            ("RET" | "BR" | "BLR", [n]) => Some(parse_gpr(n).filter(|r| r.wide && !r.sp).ok_or_else(|| format!("Invalid register '{}'", n))?.index),
+            _ => None,
+        };
+        let target = match (mnemonic, ops, register) {
+            ("RET", _, Some(n)) => {
+                let sp = self.sp();
+                self.authenticate(self.regs.x[n], sp)?
+            },
+            (_, _, Some(n)) => self.regs.x[n],
+            ("B" | "BL", [t], _) if t.starts_with('#') => self.pc.wrapping_add(parse_imm(t).ok_or_else(|| format!("Invalid offset '{}'", t))?),
+            ("B" | "BL", [t], _) => t
+                .strip_prefix("0x")
+                .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("Invalid target '{}'", t))?,
+            _ => {
+                return Err(format!(
+                    "Usage: {}",
+                    match mnemonic {
+                        "RET" => "RET [Xn]",
+                        "BR" | "BLR" => "BR|BLR Xn",
+                        _ => "B|BL #offset|<addr>",
+                    }
+                ))
+            },
+        };
+        if let ("BR" | "BLR", Some(n)) = (mnemonic, register) {
+            self.pstate.btype = branch_type(mnemonic, n);
+        }
+        if mnemonic == "BL" || mnemonic == "BLR" {
+            self.regs.x[30] = self.sign(self.pc.wrapping_add(4), self.sp());
+        }
+        let from = self.pc;
+        self.pc = target;
+        Ok(match mnemonic {
+            "BL" | "BLR" => format!("PC {:#x} -> {:#x}, X30={:#x}", from, target, self.regs.x[30]),
+            _ => format!("PC {:#x} -> {:#x}", from, target),
+        })
+    }
+
+    // PACIA Xd, Xn|SP and AUTIA Xd, Xn|SP sign and authenticate any pointer,
+    // a function pointer say, with Xn as the modifier; XPACI Xd strips it
+    fn exec_pac(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let (d, modifier) = match (mnemonic, ops) {
+            ("XPACI", [d]) => (parse_gpr(d), Some(0)),
+            ("PACIA" | "AUTIA", [d, n]) => (parse_gpr(d), parse_gpr(n).map(|n| self.read_gpr(n))),
+            _ => return Err(format!("Usage: {}", if mnemonic == "XPACI" { "XPACI Xd" } else { "PACIA|AUTIA Xd, Xn|SP" })),
+        };
+        let (d, modifier) = match (d, modifier) {
+            (Some(d), Some(modifier)) if d.wide && !d.sp => (d, modifier),
+            _ => return Err(format!("Invalid register in '{}'", ops.join(", "))),
+        };
+        let ptr = self.read_gpr(d);
+        let value = match mnemonic {
+            "PACIA" => self.sign(ptr, modifier),
+            "AUTIA" => self.authenticate(ptr, modifier)?,
+            _ => strip_pac(ptr),
+        };
+        self.write_gpr(d, value);
+        Ok(format!("{}={:#x}", Self::gpr_name(d), value))
+    }
+
+    // IRG Xd, Xn|SP picks a random tag for a pointer; STG Xt, [Xn] gives a
+    // 16-byte granule Xt's tag; LDG Xt, [Xn] reads a granule's tag into Xt
+    fn exec_mte(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        if mnemonic == "IRG" {
+            let [d, n] = ops else {
+                return Err("Usage: IRG Xd, Xn|SP".to_string());
+            };
+            let (d, n) = match (parse_gpr(d), parse_gpr(n)) {
+                (Some(d), Some(n)) if d.wide && n.wide => (d, n),
+                _ => return Err(format!("Invalid register in '{}'", ops.join(", "))),
+            };
+            // GCR_EL1.Exclude keeps tag 0 for untagged memory
+            let tag = random_bytes()[0] % 15 + 1;
+            self.write_gpr(d, with_tag(self.read_gpr(n), tag));
+            return Ok(format!("{}={:#x} (tag {:#x})", Self::gpr_name(d), self.read_gpr(d), tag));
+        }
+        let t = ops
+            .first()
+            .and_then(|t| parse_gpr(t))
+            .filter(|t| t.wide)
+            .ok_or_else(|| format!("Usage: {} Xt, [Xn{{, #imm}}]{{!}} | [Xn], #imm", mnemonic))?;
+        let rest: Vec<String> = ops[1..].iter().map(|s| s.to_string()).collect();
+        let (addr, writeback) = self.address(&rest)?;
+        if addr % TAG_GRANULE != 0 {
+            return Err(format!("{} needs a 16-byte aligned address, not {:#x}", mnemonic, addr));
+        }
+        let secure = self.is_secure();
+        let result = if mnemonic == "STG" {
+            let tag = logical_tag(self.read_gpr(t));
+            PHYS_MEMORY.lock().unwrap().set_allocation_tag(untagged(addr), tag, secure).map(|_| format!("granule {:#x} tag={:#x}", untagged(addr), tag))
+        } else {
+            PHYS_MEMORY.lock().unwrap().allocation_tag(untagged(addr), secure).map(|tag| {
+                self.write_gpr(t, with_tag(self.read_gpr(t), tag));
+                format!("{}={:#x} (tag {:#x})", Self::gpr_name(t), self.read_gpr(t), tag)
+            })
+        };
+        let effect = result.or_else(|msg| {
+            println!("{}", msg);
+            self.data_abort(addr).and(Err("Tag access aborted".to_string()))
+        })?;
+        if let Some((base, value)) = writeback {
+            self.write_gpr(base, value);
+        }
+        Ok(effect)
+    }
+
+    fn vector_operands(&self, mnemonic: &str, ops: &[&str]) -> Result<(VecReg, VecReg, VecReg), String> {
+        match ops {
+            [d, n, m] => match (parse_vreg(d), parse_vreg(n), parse_vreg(m)) {
+                (Some(d), Some(n), Some(m)) if d.lanes == n.lanes && n.lanes == m.lanes && d.bits == n.bits && n.bits == m.bits => {
+                    Ok((d, n, m))
+                },
+                _ => Err(format!("{} needs three vector registers with the same arrangement", mnemonic)),
+            },
+            _ => Err(format!("Usage: {} Vd.<T>, Vn.<T>, Vm.<T>  (T = 16B|8B|8H|4H|4S|2S|2D)", mnemonic)),
+        }
+    }
+
+    fn describe_vreg(&self, reg: VecReg) -> String {
+        let lanes = lanes_of(self.regs.v[reg.index], reg);
+        let lanes: Vec<String> = lanes.iter().map(|l| format!("{:#x}", l)).collect();
+        format!("V{}=[{}]", reg.index, lanes.join(", "))
+    }
+
+    // VADD/VSUB wrap per lane; SADD/SSUB saturate to the signed lane range
+    fn exec_vector_int(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let (d, n, m) = self.vector_operands(mnemonic, ops)?;
+        let (a, b) = (lanes_of(self.regs.v[n.index], n), lanes_of(self.regs.v[m.index], m));
+        let max = (1i128 << (d.bits - 1)) - 1;
+        let min = -(1i128 << (d.bits - 1));
+        let result: Vec<u64> = a
+            .iter()
+            .zip(&b)
+            .map(|(&x, &y)| match mnemonic {
+                "VADD" => x.wrapping_add(y),
+                "VSUB" => x.wrapping_sub(y),
+                _ => {
+                    let (x, y) = (sign_extend(x, d.bits) as i128, sign_extend(y, d.bits) as i128);
+                    let r = if mnemonic == "SADD" { x + y } else { x - y };
+                    r.clamp(min, max) as u64
+                },
+            })
+            .collect();
+        self.regs.v[d.index] = from_lanes(&result, d.bits);
+        Ok(self.describe_vreg(d))
+    }
+
+    fn exec_float(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        // Scalar form: Dd, Dn, Dm or Sd, Sn, Sm
+        if let [d, n, m] = ops {
+            if let (Some((d, db)), Some((n, nb)), Some((m, mb))) = (parse_fpreg(d), parse_fpreg(n), parse_fpreg(m)) {
+                if db != nb || nb != mb {
+                    return Err(format!("{} operands must all be D or all be S registers", mnemonic));
+                }
+                let a = to_float(self.regs.v[n] as u64 & mask(db), db);
+                let b = to_float(self.regs.v[m] as u64 & mask(db), db);
+                let r = float_op(mnemonic, a, b, db);
+                self.regs.v[d] = r as u128;
+                return Ok(format!("{}{}={} ({:#x})", if db == 64 { "D" } else { "S" }, d, to_float(r, db), r));
+            }
+        }
+        let (d, n, m) = self.vector_operands(mnemonic, ops)?;
+        if d.bits < 32 {
+            return Err(format!("{} works on 4S, 2S or 2D", mnemonic));
+        }
+        let (a, b) = (lanes_of(self.regs.v[n.index], n), lanes_of(self.regs.v[m.index], m));
+        let result: Vec<u64> = a.iter().zip(&b).map(|(&x, &y)| float_op(mnemonic, to_float(x, d.bits), to_float(y, d.bits), d.bits)).collect();
+        self.regs.v[d.index] = from_lanes(&result, d.bits);
+        let shown: Vec<String> = result.iter().map(|&l| format!("{}", to_float(l, d.bits))).collect();
+        Ok(format!("V{}=[{}]", d.index, shown.join(", ")))
+    }
+
+    // VMOV Vd.<T>, Vn.<T> | Vd.<T>, #imm (a #1.5 style immediate fills FP
+    // lanes) | Dd|Sd, #fimm | Dd, Xn | Xd, Dn
+    fn exec_vmov(&mut self, ops: &[&str]) -> Result<String, String> {
+        let usage = "Usage: VMOV Vd.<T>, Vn.<T>|#imm | Dd|Sd, #fimm | Dd, Xn | Xd, Dn";
+        let [d, s] = ops else {
+            return Err(usage.to_string());
+        };
+        if let Some(d) = parse_vreg(d) {
+            if let Some(n) = parse_vreg(s) {
+                let lanes = lanes_of(self.regs.v[n.index], d);
+                self.regs.v[d.index] = from_lanes(&lanes, d.bits);
+            } else if s.contains('.') && d.bits >= 32 {
+                let value = parse_fimm(s).ok_or_else(|| format!("Invalid immediate '{}'", s))?;
+                self.regs.v[d.index] = from_lanes(&vec![from_float(value, d.bits); d.lanes], d.bits);
+            } else {
+                let value = parse_imm(s).ok_or_else(|| format!("Invalid immediate '{}'", s))?;
+                self.regs.v[d.index] = from_lanes(&vec![value; d.lanes], d.bits);
+            }
+            return Ok(self.describe_vreg(d));
+        }
+        match (parse_fpreg(d), parse_gpr(d)) {
+            (Some((d, bits)), _) => {
+                let value = match (parse_fimm(s), parse_gpr(s)) {
+                    (_, Some(n)) => self.read_gpr(n) & mask(bits),
+                    (Some(f), None) => from_float(f, bits),
+                    _ => return Err(usage.to_string()),
+                };
+                self.regs.v[d] = value as u128;
+                Ok(format!("{}{}={} ({:#x})", if bits == 64 { "D" } else { "S" }, d, to_float(value, bits), value))
+            },
+            (None, Some(g)) => {
+                let (n, bits) = parse_fpreg(s).ok_or_else(|| usage.to_string())?;
+                self.write_gpr(g, self.regs.v[n] as u64 & mask(bits));
+                Ok(format!("{}={:#x}", Self::gpr_name(g), self.read_gpr(g)))
+            },
+            _ => Err(usage.to_string()),
+        }
+    }
+
+    // LD1/ST1 {Vt.<T>[, ...]}: whole 128-bit registers, lane 0 at the lowest
+    // address, consecutive registers at consecutive addresses
+    fn exec_ld1_st1(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let usage = format!("Usage: {} {{Vt.<T>[, Vt2.<T> ...]}}, [Xn] [, #imm]", mnemonic);
+        let list = ops.first().and_then(|l| l.strip_prefix('{')).and_then(|l| l.strip_suffix('}')).ok_or_else(|| usage.clone())?;
+        let regs: Option<Vec<VecReg>> = list.split(',').map(|r| parse_vreg(r.trim())).collect();
+        let regs = regs.filter(|r| !r.is_empty() && r.len() <= 4).ok_or_else(|| usage.clone())?;
+        let rest: Vec<String> = ops[1..].iter().map(|s| s.to_string()).collect();
+        if rest.first().is_some_and(|a| a.contains('#')) {
+            return Err("LD1/ST1 take no offset inside the brackets".to_string());
+        }
+        let (addr, writeback) = self.address(&rest)?;
+        let bytes_per_reg = regs[0].lanes * regs[0].bits / 8;
+        let mut effects = Vec::new();
+        for (i, reg) in regs.iter().enumerate() {
+            let at = addr + (i * bytes_per_reg) as u64;
+            if mnemonic == "LD1" {
+                let bytes = self.load(at, bytes_per_reg)?;
+                let mut buf = [0u8; 16];
+                buf[..bytes_per_reg].copy_from_slice(&bytes);
+                self.regs.v[reg.index] = u128::from_le_bytes(buf);
+                effects.push(self.describe_vreg(*reg));
+            } else {
+                let bytes = self.regs.v[reg.index].to_le_bytes();
+                self.store(at, &bytes[..bytes_per_reg])?;
+                effects.push(format!("[{:#x}]=V{}", at, reg.index));
+            }
+        }
+        if let Some((base, value)) = writeback {
+            self.write_gpr(base, value);
+        }
+        Ok(effects.join(" "))
+    }
+
+    // MATMUL Vd.4S, Vn.4S, Vm.4S: 2x2 single precision matrices, row major.
+    // MMV Vd.2S, Vn.4S, Vm.2S: 2x2 matrix times a 2-lane vector.
+    fn exec_matrix(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let usage = if mnemonic == "MATMUL" { "Usage: MATMUL Vd.4S, Vn.4S, Vm.4S" } else { "Usage: MMV Vd.2S, Vn.4S, Vm.2S" };
+        let [d, n, m] = ops else {
+            return Err(usage.to_string());
+        };
+        let (d, n, m) = match (parse_vreg(d), parse_vreg(n), parse_vreg(m)) {
+            (Some(d), Some(n), Some(m)) if d.bits == 32 && n.bits == 32 && m.bits == 32 && n.lanes == 4 => (d, n, m),
+            _ => return Err(usage.to_string()),
+        };
+        let a: Vec<f32> = lanes_of(self.regs.v[n.index], n).iter().map(|&l| f32::from_bits(l as u32)).collect();
+        let b: Vec<f32> = lanes_of(self.regs.v[m.index], m).iter().map(|&l| f32::from_bits(l as u32)).collect();
+        let result: Vec<f32> = match (mnemonic, d.lanes, m.lanes) {
+            ("MATMUL", 4, 4) => vec![
+                a[0] * b[0] + a[1] * b[2],
+                a[0] * b[1] + a[1] * b[3],
+                a[2] * b[0] + a[3] * b[2],
+                a[2] * b[1] + a[3] * b[3],
+            ],
+            ("MMV", 2, 2) => vec![a[0] * b[0] + a[1] * b[1], a[2] * b[0] + a[3] * b[1]],
+            _ => return Err(usage.to_string()),
+        };
+        let lanes: Vec<u64> = result.iter().map(|f| f.to_bits() as u64).collect();
+        self.regs.v[d.index] = from_lanes(&lanes, 32);
+        let shown: Vec<String> = result.iter().map(|f| f.to_string()).collect();
+        Ok(format!("V{}=[{}]", d.index, shown.join(", ")))
+    }
+
+    pub fn print_registers(&self) {
+        for row in 0..8 {
+            let cells: Vec<String> = (row * 4..row * 4 + 4)
+                .filter(|&i| i < 31)
+                .map(|i| format!("X{:<2}={:016x}", i, self.regs.x[i]))
+                .collect();
+            println!(" {}", cells.join(" "));
+        }
+        println!(
+            " SP={:016x} (SP_{})  PC={:016x}  NZCV={:04b}",
+            self.sp(),
+            if self.pstate.sp_elx { format!("{:?}", self.el()) } else { "EL0".to_string() },
+            self.pc,
+            self.pstate.nzcv
+        );
+    }
+
+    // Only the registers that hold something, to keep the dump readable
+    pub fn print_vector_registers(&self) {
+        let mut any = false;
+        for (i, v) in self.regs.v.iter().enumerate() {
+            if *v != 0 {
+                println!(" V{:<2}={:032x}", i, v);
+                any = true;
+            }
+        }
+        if !any {
+            println!(" V0-V31 are all zero");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(cpu: &mut Cpu, line: &str) -> Result<String, String> {
+        let mut parts = line.split_whitespace();
+        let mnemonic = parts.next().unwrap();
+        cpu.execute(mnemonic, &parts.collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn vadd_and_vsub_wrap_within_each_lane() {
+        let mut cpu = Cpu::new();
+        run(&mut cpu, "VMOV V1.16B, #0xff").unwrap();
+        run(&mut cpu, "VMOV V2.16B, #1").unwrap();
+        run(&mut cpu, "VADD V0.16B, V1.16B, V2.16B").unwrap();
+        assert_eq!(cpu.regs.v[0], 0);
+        // The same bits as halfwords: no carry crosses a lane either
+        run(&mut cpu, "VMOV V2.8H, #1").unwrap();
+        run(&mut cpu, "VADD V0.8H, V1.8H, V2.8H").unwrap();
+        assert_eq!(cpu.regs.v[0], 0);
+        run(&mut cpu, "VMOV V2.4S, #1").unwrap();
+        run(&mut cpu, "VSUB V0.4S, V2.4S, V1.4S").unwrap();
+        assert_eq!(cpu.regs.v[0], 0x00000002_00000002_00000002_00000002);
+        // SADD saturates where VADD wraps
+        run(&mut cpu, "VMOV V3.4S, #0x7fffffff").unwrap();
+        run(&mut cpu, "VMOV V4.4S, #1").unwrap();
+        run(&mut cpu, "VADD V0.4S, V3.4S, V4.4S").unwrap();
+        assert_eq!(lanes_of(cpu.regs.v[0], parse_vreg("V0.4S").unwrap()), [0x8000_0000; 4]);
+        run(&mut cpu, "SADD V0.4S, V3.4S, V4.4S").unwrap();
+        assert_eq!(lanes_of(cpu.regs.v[0], parse_vreg("V0.4S").unwrap()), [0x7fff_ffff; 4]);
+        // A 64-bit arrangement clears the upper half of the destination
+        run(&mut cpu, "VADD V3.2S, V3.2S, V4.2S").unwrap();
+        assert_eq!(cpu.regs.v[3], 0x80000000_80000000);
+    }
+
+    #[test]
+    fn st1_and_ld1_keep_lane_order() {
+        let mut cpu = Cpu::new();
+        let base = 0x4010_0000;
+        cpu.regs.x[0] = base;
+        cpu.regs.x[1] = base;
+        cpu.regs.v[0] = 0x0f0e0d0c_0b0a0908_07060504_03020100;
+        cpu.regs.v[1] = 0x1f1e1d1c_1b1a1918_17161514_13121110;
+        run(&mut cpu, "ST1 {V0.16B, V1.16B}, [X0], #32").unwrap();
+        assert_eq!(cpu.regs.x[0], base + 32);
+        let stored = PHYS_MEMORY.lock().unwrap().read(base, 32, true).unwrap();
+        assert_eq!(stored, (0..32).collect::<Vec<u8>>());
+        run(&mut cpu, "LD1 {V2.4S, V3.4S}, [X1]").unwrap();
+        assert_eq!((cpu.regs.v[2], cpu.regs.v[3]), (cpu.regs.v[0], cpu.regs.v[1]));
+        // An 8-byte arrangement loads one doubleword and clears the rest
+        cpu.regs.v[4] = u128::MAX;
+        run(&mut cpu, "LD1 {V4.8B}, [X1]").unwrap();
+        assert_eq!(cpu.regs.v[4], 0x07060504_03020100);
+    }
+
+    fn fp(cpu: &mut Cpu, op: &str, a: &str, b: &str) -> f64 {
+        run(cpu, &format!("VMOV D1, #{}", a)).unwrap();
+        run(cpu, &format!("VMOV D2, #{}", b)).unwrap();
+        run(cpu, &format!("{} D0, D1, D2", op)).unwrap();
+        f64::from_bits(cpu.regs.v[0] as u64)
+    }
+
+    #[test]
+    fn fp_special_values() {
+        let mut cpu = Cpu::new();
+        assert!(fp(&mut cpu, "FADD", "NaN", "1.0").is_nan());
+        assert!(fp(&mut cpu, "FADD", "inf", "-inf").is_nan());
+        assert!(fp(&mut cpu, "FMUL", "inf", "0.0").is_nan());
+        assert_eq!(fp(&mut cpu, "FADD", "inf", "1e308"), f64::INFINITY);
+        assert_eq!(fp(&mut cpu, "FMUL", "1e308", "10.0"), f64::INFINITY);
+        assert_eq!(fp(&mut cpu, "FMUL", "-1e308", "10.0"), f64::NEG_INFINITY);
+        assert!(fp(&mut cpu, "FADD", "-0.0", "-0.0").is_sign_negative());
+        assert!(fp(&mut cpu, "FADD", "0.0", "-0.0").is_sign_positive());
+        assert!(fp(&mut cpu, "FMUL", "-0.0", "5.0").is_sign_negative());
+        assert_eq!(fp(&mut cpu, "FSUB", "0.0", "0.0").to_bits(), 0);
+    }
+
+    #[test]
+    fn single_precision_rounds_to_single() {
+        let mut cpu = Cpu::new();
+        run(&mut cpu, "VMOV V1.4S, #3.0e38").unwrap();
+        run(&mut cpu, "VMOV V2.4S, #10.0").unwrap();
+        run(&mut cpu, "FMUL V0.4S, V1.4S, V2.4S").unwrap();
+        assert_eq!(cpu.regs.v[0] as u32, f32::INFINITY.to_bits());
+        run(&mut cpu, "VMOV S1, #NaN").unwrap();
+        run(&mut cpu, "VMOV S2, #1.0").unwrap();
+        run(&mut cpu, "FADD S0, S1, S2").unwrap();
+        assert!(f32::from_bits(cpu.regs.v[0] as u32).is_nan());
+        assert_eq!(cpu.regs.v[0] >> 32, 0);
+    }
+}
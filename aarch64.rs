@@ -0,0 +1,635 @@
+// $t@$h
+use crate::a64::RegisterFile;
+use crate::crypto::random_bytes;
+use crate::mitigations::Mitigations;
+use crate::psci::NUM_CORES;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum ExceptionLevel {
+    EL0,
+    EL1,
+    EL2,
+    EL3,
+}
+
+impl ExceptionLevel {
+    fn from_index(i: u64) -> ExceptionLevel {
+        match i & 3 {
+            0 => ExceptionLevel::EL0,
+            1 => ExceptionLevel::EL1,
+            2 => ExceptionLevel::EL2,
+            _ => ExceptionLevel::EL3,
+        }
+    }
+
+    fn parse(s: &str) -> Option<ExceptionLevel> {
+        match s {
+            "EL1" => Some(ExceptionLevel::EL1),
+            "EL2" => Some(ExceptionLevel::EL2),
+            "EL3" => Some(ExceptionLevel::EL3),
+            _ => None,
+        }
+    }
+}
+
+// Where each stage of the boot flow runs. BL1 is the boot ROM at the reset
+// vector; BL2, BL31 and BL32 run from trusted SRAM, BL33 from DRAM.
+pub const BL1_BASE: u64 = 0x0000_0000;
+pub const BL2_BASE: u64 = 0x0406_0000;
+pub const BL31_BASE: u64 = 0x0400_0000;
+pub const BL32_BASE: u64 = 0x0410_0000;
+pub const BL33_BASE: u64 = 0x8800_0000;
+pub const KERNEL_BASE: u64 = 0x4008_0000;
+pub const KERNEL_SIZE: u64 = 0x0200_0000;
+pub const USER_BASE: u64 = 0x0040_0000;
+pub const USER_STACK: u64 = 0x0050_0000;
+// Each stage puts its vector table 2KB aligned, just past its entry point
+pub const VECTOR_OFFSET: u64 = 0x800;
+
+// ESR_ELx.EC: why an exception was taken
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExceptionClass {
+    Unknown = 0x00,
+    BranchTarget = 0x0d,
+    IllegalState = 0x0e,
+    Svc = 0x15,
+    Hvc = 0x16,
+    Smc = 0x17,
+    PacFail = 0x1c,
+    DataAbortLower = 0x24,
+    DataAbortSame = 0x25,
+}
+
+// Synchronous entries of a vector table; IRQ, FIQ and SError follow each at 0x80 steps
+const VECTOR_CURRENT_EL_SPX: u64 = 0x200;
+const VECTOR_LOWER_EL_AARCH64: u64 = 0x400;
+
+// The parts of PSTATE an exception saves to SPSR_ELx and ERET restores
+#[derive(Clone, Copy)]
+pub struct Pstate {
+    pub el: ExceptionLevel,
+    // ELxh (SP_ELx) rather than ELxt (SP_EL0)
+    pub sp_elx: bool,
+    pub nzcv: u8,
+    pub daif: u8,
+    // Illegal execution state, set by a bad ERET
+    pub il: bool,
+    // Set by BR/BLR, checked by the instruction at the target
+    pub btype: u8,
+}
+
+impl Pstate {
+    fn to_spsr(self) -> u64 {
+        ((self.nzcv as u64) << 28)
+            | ((self.il as u64) << 20)
+            | ((self.btype as u64) << 10)
+            | ((self.daif as u64) << 6)
+            | ((self.el as u64) << 2)
+            | self.sp_elx as u64
+    }
+
+    fn from_spsr(spsr: u64) -> Pstate {
+        Pstate {
+            el: ExceptionLevel::from_index(spsr >> 2),
+            sp_elx: spsr & 1 == 1,
+            nzcv: ((spsr >> 28) & 0xf) as u8,
+            daif: ((spsr >> 6) & 0xf) as u8,
+            il: (spsr >> 20) & 1 == 1,
+            btype: ((spsr >> 10) & 3) as u8,
+        }
+    }
+
+    fn mode_name(self) -> String {
+        match self.el {
+            ExceptionLevel::EL0 => "EL0t".to_string(),
+            el => format!("{:?}{}", el, if self.sp_elx { "h" } else { "t" }),
+        }
+    }
+}
+
+// System registers reachable with MRS/MSR; banked ones carry their EL
+enum SysReg {
+    CurrentEl,
+    Scr,
+    Spsr(usize),
+    Elr(usize),
+    Esr(usize),
+    Far(usize),
+    Vbar(usize),
+    ApiaKeyLo(usize),
+    ApiaKeyHi(usize),
+}
+
+impl SysReg {
+    // The register and the lowest EL that may access it
+    fn parse(name: &str) -> Option<(SysReg, ExceptionLevel)> {
+        let name = name.to_ascii_uppercase();
+        match name.as_str() {
+            "CURRENTEL" => return Some((SysReg::CurrentEl, ExceptionLevel::EL1)),
+            "SCR_EL3" => return Some((SysReg::Scr, ExceptionLevel::EL3)),
+            _ => {},
+        }
+        let (reg, el) = name.split_once('_')?;
+        let el = ExceptionLevel::parse(el)?;
+        let i = el as usize;
+        let reg = match reg {
+            "SPSR" => SysReg::Spsr(i),
+            "ELR" => SysReg::Elr(i),
+            "ESR" => SysReg::Esr(i),
+            "FAR" => SysReg::Far(i),
+            "VBAR" => SysReg::Vbar(i),
+            "APIAKEYLO" => SysReg::ApiaKeyLo(i),
+            "APIAKEYHI" => SysReg::ApiaKeyHi(i),
+            _ => return None,
+        };
+        Some((reg, el))
+    }
+}
+
+// The banked EL1/EL2 state and EL3 return state of one security world,
+// which the secure monitor swaps when it changes SCR_EL3.NS
+#[derive(Clone, Copy, Default)]
+struct WorldContext {
+    spsr: [u64; 4],
+    elr: [u64; 4],
+    esr: [u64; 4],
+    far: [u64; 4],
+    vbar: [Option<u64>; 4],
+    handler: [&'static str; 4],
+    // Instruction key A of each EL; EL0 signs with EL1's
+    apia_key: [u128; 4],
+}
+
+impl WorldContext {
+    fn with_random_keys() -> Self {
+        let mut world = WorldContext::default();
+        for el in 1..4 {
+            world.apia_key[el] = u128::from_le_bytes(random_bytes()[..16].try_into().unwrap());
+        }
+        world
+    }
+}
+
+// One AArch64 core: its registers and exception state
+pub struct Cpu {
+    pub regs: RegisterFile,
+    pub pstate: Pstate,
+    pub pc: u64,
+    // SCR_EL3.NS: the security state of EL0-EL2
+    pub ns: bool,
+    pub mitigations: Mitigations,
+    world: WorldContext,
+    // The context of the world that is not running
+    saved_world: WorldContext,
+}
+
+impl Cpu {
+    // Cold reset: EL3h with everything masked, at the reset vector. The
+    // boot ROM's vectors are in ROM, so they are there from the start.
+    pub fn new() -> Self {
+        let mut world = WorldContext::with_random_keys();
+        world.vbar[3] = Some(BL1_BASE + VECTOR_OFFSET);
+        world.handler[3] = "BL1 ROM";
+        Cpu {
+            regs: RegisterFile::new(),
+            pstate: Pstate {
+                el: ExceptionLevel::EL3,
+                sp_elx: true,
+                nzcv: 0,
+                daif: 0xf,
+                il: false,
+                btype: 0,
+            },
+            pc: BL1_BASE,
+            ns: false,
+            mitigations: Mitigations::new(),
+            world,
+            saved_world: WorldContext::with_random_keys(),
+        }
+    }
+
+    // Power back on after a suspend: the core restarts at EL3 in BL31's warm
+    // boot entry with its general purpose registers gone. The system
+    // registers of each world are what BL31 saved before powering down.
+    pub fn warm_boot(&mut self) {
+        self.regs = RegisterFile::new();
+        self.pstate = Pstate {
+            el: ExceptionLevel::EL3,
+            sp_elx: true,
+            nzcv: 0,
+            daif: 0xf,
+            il: false,
+            btype: 0,
+        };
+        self.pc = BL31_BASE;
+    }
+
+    // A core released by CPU_ON comes out of reset the same way, and BL31
+    // and the kernel program it with the vectors they gave the boot core
+    pub fn secondary(boot: &Cpu) -> Self {
+        let mut cpu = Cpu::new();
+        cpu.warm_boot();
+        cpu.ns = boot.ns;
+        for (world, from) in [(&mut cpu.world, &boot.world), (&mut cpu.saved_world, &boot.saved_world)] {
+            world.vbar = from.vbar;
+            world.handler = from.handler;
+        }
+        cpu
+    }
+
+    pub fn el(&self) -> ExceptionLevel {
+        self.pstate.el
+    }
+
+    // EL3 is always Secure; below it, SCR_EL3.NS decides
+    pub fn is_secure(&self) -> bool {
+        self.el() == ExceptionLevel::EL3 || !self.ns
+    }
+
+    pub fn step(&mut self) {
+        self.pc = self.pc.wrapping_add(4);
+    }
+
+    // MSR VBAR_ELx at the end of each stage's early setup
+    pub fn install_vectors(&mut self, el: ExceptionLevel, base: u64, handler: &'static str) {
+        self.world.vbar[el as usize] = Some(base);
+        self.world.handler[el as usize] = handler;
+        println!("VBAR_{:?} = {:#x} ({} vectors)", el, base, handler);
+    }
+
+    // Exception entry: save PSTATE and the return address, record the
+    // syndrome, mask interrupts and branch to the target EL's vector
+    pub fn take_exception(&mut self, target: ExceptionLevel, class: ExceptionClass, iss: u32, return_addr: u64) -> Result<(), String> {
+        let t = target as usize;
+        let vbar = self.world.vbar[t].ok_or_else(|| {
+            format!("No vector table at {:?}: the core would fetch from VBAR_{:?}=0 and hang", target, target)
+        })?;
+        let from = self.el();
+        let offset = if from == target { VECTOR_CURRENT_EL_SPX } else { VECTOR_LOWER_EL_AARCH64 };
+        // IL=1: a 32-bit instruction
+        let esr = ((class as u64) << 26) | (1 << 25) | iss as u64;
+        self.world.spsr[t] = self.pstate.to_spsr();
+        self.world.elr[t] = return_addr;
+        self.world.esr[t] = esr;
+        self.pstate = Pstate {
+            el: target,
+            sp_elx: true,
+            nzcv: self.pstate.nzcv,
+            daif: 0xf,
+            il: false,
+            btype: 0,
+        };
+        self.pc = vbar + offset;
+        println!("Exception taken from {:?} to {:?} ({:?}, EC={:#04x})", from, target, class, class as u64);
+        println!(
+            "  ESR_{:?}={:#010x}  ELR_{:?}={:#x}  SPSR_{:?}={:#x}",
+            target, esr, target, return_addr, target, self.world.spsr[t]
+        );
+        println!(
+            "  vector VBAR_{:?}+{:#x} = {:#x}: [{}] synchronous exception from {}",
+            target,
+            offset,
+            self.pc,
+            self.world.handler[t],
+            if from == target { "the current EL" } else { "a lower EL" }
+        );
+        Ok(())
+    }
+
+    // Synchronous faults go to EL1 from EL0, otherwise to the current EL
+    fn fault_target(&self) -> ExceptionLevel {
+        if self.el() == ExceptionLevel::EL0 { ExceptionLevel::EL1 } else { self.el() }
+    }
+
+    pub fn undefined(&mut self, what: &str) -> Result<(), String> {
+        println!("{} is UNDEFINED at {:?}", what, self.el());
+        self.take_exception(self.fault_target(), ExceptionClass::Unknown, 0, self.pc)
+    }
+
+    fn abort(&mut self, addr: u64, dfsc: u32) -> Result<(), String> {
+        let from = self.el();
+        let target = self.fault_target();
+        let class = if target == from { ExceptionClass::DataAbortSame } else { ExceptionClass::DataAbortLower };
+        self.world.far[target as usize] = addr;
+        self.take_exception(target, class, dfsc, self.pc)?;
+        println!("  FAR_{:?}={:#x}", target, addr);
+        Ok(())
+    }
+
+    // A load or store the memory system answered with an error. ISS DFSC
+    // 0b010000: synchronous external abort, with the address in FAR_ELx.
+    pub fn data_abort(&mut self, addr: u64) -> Result<(), String> {
+        self.abort(addr, 0x10)
+    }
+
+    // DFSC 0b010001: the pointer's tag did not match the memory's
+    pub fn tag_check_fault(&mut self, addr: u64) -> Result<(), String> {
+        self.abort(addr, 0x11)
+    }
+
+    // FEAT_FPAC: a failed AUT faults on the spot instead of leaving a
+    // poisoned pointer behind. ISS 0: instruction key A.
+    pub fn pac_fail(&mut self) -> Result<(), String> {
+        self.take_exception(self.fault_target(), ExceptionClass::PacFail, 0, self.pc)
+    }
+
+    // ISS holds the BTYPE the instruction at the target did not accept
+    pub fn branch_target_exception(&mut self, btype: u8) -> Result<(), String> {
+        self.take_exception(self.fault_target(), ExceptionClass::BranchTarget, btype as u32, self.pc)
+    }
+
+    pub fn apia_key(&self) -> u128 {
+        self.world.apia_key[(self.el() as usize).max(1)]
+    }
+
+    // System calls: SVC to EL1, HVC to EL2, SMC to EL3. The preferred
+    // return address is the next instruction.
+    pub fn svc(&mut self, imm: u16) -> Result<(), String> {
+        self.take_exception(self.fault_target(), ExceptionClass::Svc, imm as u32, self.pc.wrapping_add(4))
+    }
+
+    pub fn hvc(&mut self, imm: u16) -> Result<(), String> {
+        match self.el() {
+            ExceptionLevel::EL0 => self.undefined("HVC"),
+            // This core has no Secure EL2
+            ExceptionLevel::EL1 if !self.ns => self.undefined("HVC in the Secure world"),
+            ExceptionLevel::EL3 => self.take_exception(ExceptionLevel::EL3, ExceptionClass::Hvc, imm as u32, self.pc.wrapping_add(4)),
+            _ => self.take_exception(ExceptionLevel::EL2, ExceptionClass::Hvc, imm as u32, self.pc.wrapping_add(4)),
+        }
+    }
+
+    pub fn smc(&mut self, imm: u16) -> Result<(), String> {
+        match self.el() {
+            ExceptionLevel::EL0 => self.undefined("SMC"),
+            _ => self.take_exception(ExceptionLevel::EL3, ExceptionClass::Smc, imm as u32, self.pc.wrapping_add(4)),
+        }
+    }
+
+    // Exception return: PSTATE from SPSR_ELx, PC from ELR_ELx. Returning to
+    // a higher EL is an illegal return, which leaves the core where it was
+    // with PSTATE.IL set so the next instruction faults.
+    pub fn eret(&mut self) -> Result<(), String> {
+        let from = self.el();
+        if from == ExceptionLevel::EL0 {
+            return self.undefined("ERET");
+        }
+        let f = from as usize;
+        let target = Pstate::from_spsr(self.world.spsr[f]);
+        if target.el > from {
+            println!(
+                "Illegal exception return: SPSR_{:?}={:#x} names {:?}, above {:?}. PSTATE.IL set.",
+                from, self.world.spsr[f], target.el, from
+            );
+            self.pstate.il = true;
+            self.pc = self.world.elr[f];
+            return self.take_exception(from, ExceptionClass::IllegalState, 0, self.pc);
+        }
+        self.pstate = target;
+        self.pc = self.world.elr[f];
+        println!(
+            "ERET from {:?} to {} ({}): PC=ELR_{:?}={:#x}, PSTATE from SPSR_{:?}={:#x}",
+            from,
+            target.mode_name(),
+            if self.is_secure() { "Secure" } else { "Non-secure" },
+            from,
+            self.pc,
+            from,
+            self.world.spsr[f]
+        );
+        Ok(())
+    }
+
+    // How each boot stage hands over to the next: point SPSR/ELR of the
+    // current EL at the next stage, then ERET. BL1 enters BL31 this way
+    // without leaving EL3.
+    pub fn enter_lower(&mut self, target: ExceptionLevel, entry: u64) -> Result<(), String> {
+        let f = self.el() as usize;
+        self.world.spsr[f] = Pstate {
+            el: target,
+            sp_elx: target != ExceptionLevel::EL0,
+            nzcv: 0,
+            daif: if target == ExceptionLevel::EL0 { 0 } else { 0xf },
+            il: false,
+            btype: 0,
+        }
+        .to_spsr();
+        self.world.elr[f] = entry;
+        self.eret()
+    }
+
+    // The secure monitor flips SCR_EL3.NS and swaps in the other world's context
+    pub fn switch_world(&mut self) -> Result<(), String> {
+        if self.el() != ExceptionLevel::EL3 {
+            return Err(format!(
+                "SCR_EL3 is only writable at EL3. From {:?}, ask the secure monitor with SMC.",
+                self.el()
+            ));
+        }
+        std::mem::swap(&mut self.world, &mut self.saved_world);
+        // VBAR_EL3 and the EL3 key belong to the monitor, not to either world
+        self.world.vbar[3] = self.saved_world.vbar[3];
+        self.world.handler[3] = self.saved_world.handler[3];
+        self.world.apia_key[3] = self.saved_world.apia_key[3];
+        self.ns = !self.ns;
+        println!("SCR_EL3.NS={}: the next ERET enters the {} world", self.ns as u8, if self.ns { "Non-secure" } else { "Secure" });
+        Ok(())
+    }
+
+    // MRS/MSR: a register of a higher EL than the current one is UNDEFINED
+    fn access(&mut self, name: &str) -> Result<SysReg, String> {
+        let (reg, el) = SysReg::parse(name).ok_or_else(|| format!("Unknown system register '{}'", name))?;
+        if self.el() < el {
+            self.undefined(&format!("MRS/MSR of {}", name))?;
+            return Err(format!("{} is not accessible below {:?}", name, el));
+        }
+        Ok(reg)
+    }
+
+    pub fn mrs(&mut self, name: &str) -> Result<u64, String> {
+        let value = match self.access(name)? {
+            SysReg::CurrentEl => (self.el() as u64) << 2,
+            SysReg::Scr => self.ns as u64,
+            SysReg::Spsr(el) => self.world.spsr[el],
+            SysReg::Elr(el) => self.world.elr[el],
+            SysReg::Esr(el) => self.world.esr[el],
+            SysReg::Far(el) => self.world.far[el],
+            SysReg::Vbar(el) => self.world.vbar[el].unwrap_or(0),
+            SysReg::ApiaKeyLo(el) => self.world.apia_key[el] as u64,
+            SysReg::ApiaKeyHi(el) => (self.world.apia_key[el] >> 64) as u64,
+        };
+        self.step();
+        Ok(value)
+    }
+
+    pub fn msr(&mut self, name: &str, value: u64) -> Result<(), String> {
+        match self.access(name)? {
+            SysReg::CurrentEl => return Err("CurrentEL is read-only".to_string()),
+            SysReg::Scr if value & 1 != self.ns as u64 => self.switch_world()?,
+            SysReg::Scr => {},
+            SysReg::Spsr(el) => self.world.spsr[el] = value,
+            SysReg::Elr(el) => self.world.elr[el] = value,
+            SysReg::Esr(el) => self.world.esr[el] = value,
+            SysReg::Far(el) => self.world.far[el] = value,
+            SysReg::Vbar(el) => self.world.vbar[el] = Some(value & !0x7ff),
+            SysReg::ApiaKeyLo(el) => self.world.apia_key[el] = (self.world.apia_key[el] & !(u64::MAX as u128)) | value as u128,
+            SysReg::ApiaKeyHi(el) => self.world.apia_key[el] = (self.world.apia_key[el] & u64::MAX as u128) | (value as u128) << 64,
+        }
+        self.step();
+        Ok(())
+    }
+
+    pub fn print_sysregs(&self) {
+        println!(
+            " PC={:#x}  PSTATE: {} {} DAIF={:04b} NZCV={:04b} BTYPE={:02b}{}",
+            self.pc,
+            self.pstate.mode_name(),
+            if self.is_secure() { "Secure" } else { "Non-secure" },
+            self.pstate.daif,
+            self.pstate.nzcv,
+            self.pstate.btype,
+            if self.pstate.il { " IL" } else { "" }
+        );
+        println!(" SCR_EL3.NS={}", self.ns as u8);
+        for el in 1..4 {
+            println!(
+                " EL{}: SPSR={:#010x} ELR={:#012x} ESR={:#010x} VBAR={}",
+                el,
+                self.world.spsr[el],
+                self.world.elr[el],
+                self.world.esr[el],
+                self.world.vbar[el].map_or("unset".to_string(), |v| format!("{:#x} ({})", v, self.world.handler[el]))
+            );
+        }
+    }
+}
+
+// The cores the REPL is not driving, kept as they were left. CPU holds the
+// one it is driving, so the slot for that core here is stale.
+pub struct Cores {
+    pub current: usize,
+    parked: Vec<Cpu>,
+}
+
+impl Cores {
+    fn new() -> Self {
+        Cores {
+            current: 0,
+            parked: (0..NUM_CORES).map(|_| Cpu::new()).collect(),
+        }
+    }
+
+    pub fn get_mut(&mut self, core: usize) -> &mut Cpu {
+        &mut self.parked[core]
+    }
+
+    // Park the driven core and take over `core`
+    pub fn switch(&mut self, cpu: &mut Cpu, core: usize) {
+        std::mem::swap(cpu, &mut self.parked[self.current]);
+        std::mem::swap(cpu, &mut self.parked[core]);
+        self.current = core;
+    }
+
+    pub fn reset(&mut self) {
+        *self = Cores::new();
+    }
+}
+
+lazy_static! {
+    pub static ref CPU: Mutex<Cpu> = Mutex::new(Cpu::new());
+    pub static ref CORES: Mutex<Cores> = Mutex::new(Cores::new());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNEL_VECTORS: u64 = KERNEL_BASE + VECTOR_OFFSET;
+    // PSTATE.N and C
+    const NC: u8 = 0b1010;
+
+    // A core running user space under a kernel with its vectors installed
+    fn at_el0() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.install_vectors(ExceptionLevel::EL1, KERNEL_VECTORS, "kernel");
+        cpu.enter_lower(ExceptionLevel::EL1, KERNEL_BASE).unwrap();
+        cpu.enter_lower(ExceptionLevel::EL0, USER_BASE).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn svc_saves_pstate_and_eret_restores_it() {
+        let mut cpu = at_el0();
+        cpu.pstate.nzcv = NC;
+        cpu.pc = USER_BASE + 8;
+        cpu.svc(7).unwrap();
+        assert_eq!(cpu.el(), ExceptionLevel::EL1);
+        assert_eq!(cpu.pc, KERNEL_VECTORS + VECTOR_LOWER_EL_AARCH64);
+        assert_eq!(cpu.world.elr[1], USER_BASE + 12);
+        assert_eq!(cpu.world.esr[1], (ExceptionClass::Svc as u64) << 26 | 1 << 25 | 7);
+        assert_eq!((cpu.pstate.daif, cpu.pstate.sp_elx, cpu.pstate.nzcv), (0xf, true, NC));
+
+        cpu.pstate.nzcv = 0;
+        cpu.eret().unwrap();
+        assert_eq!(cpu.el(), ExceptionLevel::EL0);
+        assert_eq!(cpu.pc, USER_BASE + 12);
+        assert_eq!((cpu.pstate.daif, cpu.pstate.sp_elx, cpu.pstate.nzcv), (0, false, NC));
+    }
+
+    #[test]
+    fn fault_at_the_current_el_uses_its_own_vector() {
+        let mut cpu = at_el0();
+        cpu.svc(0).unwrap();
+        cpu.pc = KERNEL_BASE + 0x40;
+        cpu.undefined("test").unwrap();
+        assert_eq!(cpu.pc, KERNEL_VECTORS + VECTOR_CURRENT_EL_SPX);
+        assert_eq!(cpu.world.elr[1], KERNEL_BASE + 0x40);
+        assert_eq!(Pstate::from_spsr(cpu.world.spsr[1]).el, ExceptionLevel::EL1);
+        assert_eq!(cpu.world.esr[1] >> 26, ExceptionClass::Unknown as u64);
+    }
+
+    #[test]
+    fn eret_to_a_higher_el_is_illegal() {
+        let mut cpu = at_el0();
+        cpu.svc(0).unwrap();
+        cpu.world.spsr[1] = Pstate { el: ExceptionLevel::EL3, ..cpu.pstate }.to_spsr();
+        cpu.world.elr[1] = USER_BASE;
+        cpu.eret().unwrap();
+        assert_eq!(cpu.el(), ExceptionLevel::EL1);
+        assert_eq!(cpu.world.esr[1] >> 26, ExceptionClass::IllegalState as u64);
+        // The fault's own SPSR records PSTATE.IL
+        assert!(Pstate::from_spsr(cpu.world.spsr[1]).il);
+        assert_eq!(cpu.world.elr[1], USER_BASE);
+    }
+
+    #[test]
+    fn eret_is_undefined_at_el0() {
+        let mut cpu = at_el0();
+        cpu.pc = USER_BASE + 4;
+        cpu.eret().unwrap();
+        assert_eq!(cpu.el(), ExceptionLevel::EL1);
+        assert_eq!(cpu.world.elr[1], USER_BASE + 4);
+        assert_eq!(cpu.world.esr[1] >> 26, ExceptionClass::Unknown as u64);
+    }
+
+    #[test]
+    fn preferred_return_address_wraps() {
+        let mut cpu = at_el0();
+        cpu.pc = 0xffff_ffff_ffff_fffc;
+        cpu.svc(0).unwrap();
+        assert_eq!(cpu.world.elr[1], 0);
+        cpu.eret().unwrap();
+        cpu.pc = 0xffff_ffff_ffff_fffc;
+        cpu.step();
+        assert_eq!(cpu.pc, 0);
+    }
+
+    #[test]
+    fn exception_without_vectors_is_refused() {
+        let mut cpu = Cpu::new();
+        cpu.enter_lower(ExceptionLevel::EL1, KERNEL_BASE).unwrap();
+        assert!(cpu.svc(0).unwrap_err().starts_with("No vector table at EL1"));
+        assert_eq!((cpu.el(), cpu.pc), (ExceptionLevel::EL1, KERNEL_BASE));
+    }
+}
@@ -0,0 +1,757 @@
+// $t@$h
+mod a64;
+mod aarch64;
+// Shared with the x8664 edition, which uses the rest of them
+#[allow(dead_code)]
+mod crypto;
+mod engine;
+#[allow(dead_code)]
+mod keys;
+#[allow(dead_code)]
+mod memory;
+mod mitigations;
+mod optee;
+mod psci;
+mod tfa;
+mod tzasc;
+
+use aarch64::{
+    Cpu, ExceptionLevel, BL1_BASE, BL2_BASE, BL31_BASE, BL32_BASE, BL33_BASE, CORES, CPU, KERNEL_BASE, USER_BASE,
+    USER_STACK, VECTOR_OFFSET,
+};
+use engine::{report, CommandResult, Engine, Instruction, InstructionHandler, Machine, Transition};
+use memory::{hexdump, parse_hex, parse_u64};
+use optee::{TrustedOs, OPTEE};
+use psci::{
+    function_name, PowerState, SystemState, AFFINITY_INFO, CPU_OFF, CPU_ON, MIGRATE_INFO_TYPE, NUM_CORES, PSCI,
+    PSCI_VERSION, SYSTEM_OFF, SYSTEM_RESET, SYSTEM_SUSPEND,
+};
+use tfa::{Fip, Image, Stage, TRUSTED_BOOT};
+use tzasc::PHYS_MEMORY;
+
+// The security state the core runs in, or the power state that stops it running at all
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Mode {
+    Secure,
+    NonSecure,
+    Suspended,
+    Off,
+}
+
+// Main Instruction Handlers, run on the core's register file
+fn run(mnemonic: &str, args: &[&str]) {
+    match CPU.lock().unwrap().execute(mnemonic, args) {
+        Ok(effect) => println!("Executed {} instruction: {}", mnemonic, effect),
+        Err(msg) => println!("{}", msg),
+    }
+}
+
+fn add_handler(args: &[&str]) { run("ADD", args); }
+fn sub_handler(args: &[&str]) { run("SUB", args); }
+fn and_handler(args: &[&str]) { run("AND", args); }
+fn orr_handler(args: &[&str]) { run("ORR", args); }
+fn eor_handler(args: &[&str]) { run("EOR", args); }
+fn b_handler(args: &[&str]) { run("B", args); }
+fn bl_handler(args: &[&str]) { run("BL", args); }
+fn cmp_handler(args: &[&str]) { run("CMP", args); }
+fn cmn_handler(args: &[&str]) { run("CMN", args); }
+fn mov_handler(args: &[&str]) { run("MOV", args); }
+fn mvn_handler(args: &[&str]) { run("MVN", args); }
+fn ldr_handler(args: &[&str]) { run("LDR", args); }
+fn str_handler(args: &[&str]) { run("STR", args); }
+fn vadd_handler(args: &[&str]) { run("VADD", args); }
+fn vsub_handler(args: &[&str]) { run("VSUB", args); }
+fn fadd_handler(args: &[&str]) { run("FADD", args); }
+fn fsub_handler(args: &[&str]) { run("FSUB", args); }
+fn fmul_handler(args: &[&str]) { run("FMUL", args); }
+fn vmov_handler(args: &[&str]) { run("VMOV", args); }
+fn sadd_handler(args: &[&str]) { run("SADD", args); }
+fn ssub_handler(args: &[&str]) { run("SSUB", args); }
+fn ld1_handler(args: &[&str]) { run("LD1", args); }
+fn st1_handler(args: &[&str]) { run("ST1", args); }
+fn matmul_handler(args: &[&str]) { run("MATMUL", args); }
+fn mmv_handler(args: &[&str]) { run("MMV", args); }
+fn ret_handler(args: &[&str]) { run("RET", args); }
+fn br_handler(args: &[&str]) { run("BR", args); }
+fn blr_handler(args: &[&str]) { run("BLR", args); }
+fn bti_handler(args: &[&str]) { run("BTI", args); }
+fn pacia_handler(args: &[&str]) { run("PACIA", args); }
+fn autia_handler(args: &[&str]) { run("AUTIA", args); }
+fn xpaci_handler(args: &[&str]) { run("XPACI", args); }
+fn irg_handler(args: &[&str]) { run("IRG", args); }
+fn stg_handler(args: &[&str]) { run("STG", args); }
+fn ldg_handler(args: &[&str]) { run("LDG", args); }
+// Secure instructions: AES-GCM with a key from the trusted OS key store.
+// The normal world reaches the same service through 'ta invoke crypto'.
+fn encrypt_handler(args: &[&str]) {
+    match args {
+        [key, text @ ..] if !text.is_empty() => match OPTEE.lock().unwrap().encrypt(key, text.join(" ").as_bytes()) {
+            Ok(hex) => {
+                println!("Executed ENCRYPT instruction: {}", hex);
+                CPU.lock().unwrap().step();
+            },
+            Err(msg) => println!("{}", msg),
+        },
+        _ => println!("Usage: encrypt <key> <text>"),
+    }
+}
+fn decrypt_handler(args: &[&str]) {
+    match args {
+        [key, hex] => match OPTEE.lock().unwrap().decrypt(key, hex) {
+            Ok(data) => {
+                println!("Executed DECRYPT instruction: '{}'", String::from_utf8_lossy(&data));
+                CPU.lock().unwrap().step();
+            },
+            Err(msg) => println!("{}", msg),
+        },
+        _ => println!("Usage: decrypt <key> <hex>"),
+    }
+}
+
+// System Instruction Handlers. Each stage installs its vector table, points
+// SPSR/ELR at the next stage and drops into it with ERET.
+fn init_trustzone(_: &[&str]) {
+    let mut boot = TRUSTED_BOOT.lock().unwrap();
+    if boot.stage != Stage::Bl31 {
+        println!("BL31 not loaded. Aborting.");
+        return;
+    }
+    let mut cpu = CPU.lock().unwrap();
+    println!("Initialized TrustZone in EL3");
+    let mut memory = PHYS_MEMORY.lock().unwrap();
+    memory.enabled = true;
+    memory.print_regions();
+    drop(memory);
+    // BL31 runs BL32's cold boot entry in S-EL1; the trusted OS reports back
+    // with an SMC before the normal world starts
+    let result = cpu
+        .enter_lower(ExceptionLevel::EL1, BL32_BASE)
+        .and_then(|_| {
+            cpu.install_vectors(ExceptionLevel::EL1, BL32_BASE + VECTOR_OFFSET, "OP-TEE");
+            OPTEE.lock().unwrap().init()
+        })
+        .and_then(|_| cpu.smc(0))
+        .and_then(|_| cpu.switch_world())
+        .and_then(|_| cpu.enter_lower(ExceptionLevel::EL2, BL33_BASE));
+    if result.is_ok() {
+        boot.stage = Stage::Bl33;
+    }
+    report(result);
+}
+fn setup_virtualization(_: &[&str]) {
+    let mut cpu = CPU.lock().unwrap();
+    println!("Set up virtualization in EL2");
+    cpu.install_vectors(ExceptionLevel::EL2, BL33_BASE + VECTOR_OFFSET, "hypervisor");
+    report(cpu.enter_lower(ExceptionLevel::EL1, KERNEL_BASE));
+}
+fn init_kernel(_: &[&str]) {
+    let mut cpu = CPU.lock().unwrap();
+    println!("Kernel initialized in EL1");
+    cpu.install_vectors(ExceptionLevel::EL1, KERNEL_BASE + VECTOR_OFFSET, "kernel");
+    cpu.regs.sp[0] = USER_STACK;
+    report(cpu.enter_lower(ExceptionLevel::EL0, USER_BASE));
+}
+fn start_user_apps(_: &[&str]) { println!("User space applications started in EL0"); }
+
+// The EL each system instruction's stage runs at
+fn system_level(instruction: &str) -> Option<ExceptionLevel> {
+    match instruction {
+        "init_trustzone" => Some(ExceptionLevel::EL3),
+        "setup_virtualization" => Some(ExceptionLevel::EL2),
+        "init_kernel" => Some(ExceptionLevel::EL1),
+        "start_user_apps" => Some(ExceptionLevel::EL0),
+        _ => None,
+    }
+}
+
+// "#0x10", "#16" or nothing (#0)
+fn parse_imm(arg: Option<&&str>) -> Option<u16> {
+    let s = arg.map_or("0", |a| a.trim_start_matches('#'));
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// Every reset puts core 0 back in BL1 at the reset vector with nothing
+// verified and the other cores off. Flash and the fuses survive, and
+// memory does too unless the power went.
+fn reset_platform(cpu: &mut Cpu, system: SystemState) {
+    *cpu = Cpu::new();
+    CORES.lock().unwrap().reset();
+    TRUSTED_BOOT.lock().unwrap().reset();
+    PHYS_MEMORY.lock().unwrap().reset(system == SystemState::Off);
+    OPTEE.lock().unwrap().reset();
+    PSCI.lock().unwrap().reset(system);
+}
+
+// BL31's runtime service: an SMC with a PSCI function ID in X0 is handled
+// at EL3 and returns to the caller with the result in X0. Anything else
+// stays at EL3 for the monitor to deal with. The caller is whichever core
+// the REPL drives.
+fn service_psci(cpu: &mut Cpu) -> Result<(), String> {
+    let (function, arg1, arg2, arg3) = (cpu.regs.x[0], cpu.regs.x[1], cpu.regs.x[2], cpu.regs.x[3]);
+    let name = match function_name(function) {
+        Some(name) if TRUSTED_BOOT.lock().unwrap().stage == Stage::Bl33 => name,
+        _ => return Ok(()),
+    };
+    let mut cores = CORES.lock().unwrap();
+    let caller = cores.current;
+    println!("BL31: {}({:#x}, {:#x}, {:#x})", name, arg1, arg2, arg3);
+    let mut psci = PSCI.lock().unwrap();
+    let result = match function {
+        PSCI_VERSION => Ok(0x1_0001),
+        // 1: a uniprocessor trusted OS that cannot migrate
+        MIGRATE_INFO_TYPE => Ok(1),
+        AFFINITY_INFO => psci.affinity_info(arg1),
+        CPU_ON => psci.cpu_on(arg1, arg2).map(|core| {
+            println!("Core {}: out of reset in BL31's warm boot entry at {:#x}", core, BL31_BASE);
+            println!("Core {}: EL3 context set up by BL31; the TZASC core 0 programmed already applies", core);
+            let secondary = cores.get_mut(core);
+            *secondary = Cpu::secondary(cpu);
+            secondary.regs.x[0] = arg3;
+            print!("Core {}: ", core);
+            report(secondary.enter_lower(ExceptionLevel::EL1, arg2));
+            println!("Core {}: running. Type 'cpu {}' to drive it.", core, core);
+            0
+        }),
+        // The core does not come back from a successful CPU_OFF, so the REPL moves to the boot core
+        CPU_OFF => match psci.cpu_off(caller) {
+            Ok(()) => {
+                println!("Core {}: powered down by BL31", caller);
+                cores.switch(cpu, 0);
+                println!("Now driving core 0");
+                return Ok(());
+            },
+            Err(err) => Err(err),
+        },
+        SYSTEM_SUSPEND => match psci.system_suspend(caller, arg1, arg2) {
+            Ok(()) => {
+                println!("BL31: EL3 and Secure context saved to trusted SRAM; DRAM in self-refresh");
+                println!("System suspended. Type 'wake' to resume.");
+                return Ok(());
+            },
+            Err(err) => Err(err),
+        },
+        _ => {
+            drop(psci);
+            drop(cores);
+            if function == SYSTEM_OFF {
+                println!("BL31: powering the SoC down");
+                reset_platform(cpu, SystemState::Off);
+                println!("System off. Type 'powerup' to cold boot.");
+            } else {
+                println!("BL31: resetting the SoC");
+                reset_platform(cpu, SystemState::Running);
+                println!("Core 0 back in BL1 at {:#x}. The chain of trust has to be verified again.", BL1_BASE);
+            }
+            return Ok(());
+        },
+    };
+    cpu.regs.x[0] = match result {
+        Ok(value) => {
+            println!("BL31: {} returns {:#x}", name, value);
+            value
+        },
+        Err(err) => {
+            println!("BL31: {} returns {:?} ({})", name, err, err as i64);
+            err as i64 as u64
+        },
+    };
+    cpu.eret()
+}
+
+// The kernel's side of PSCI: arguments in X0-X3, then SMC #0
+fn process_psci_command(args: &[&str]) -> Result<(), String> {
+    let number = |s: &str| parse_u64(s).ok_or_else(|| format!("Invalid argument '{}'", s));
+    let context = |rest: &[&str]| rest.first().map_or(Ok(0), |s| number(s));
+    let call = match args {
+        [] | ["status"] => {
+            PSCI.lock().unwrap().print();
+            return Ok(());
+        },
+        ["version"] => [PSCI_VERSION, 0, 0, 0],
+        ["migrate_info_type"] => [MIGRATE_INFO_TYPE, 0, 0, 0],
+        ["cpu_on", core, entry, rest @ ..] => [CPU_ON, number(core)?, number(entry)?, context(rest)?],
+        ["cpu_off"] => [CPU_OFF, 0, 0, 0],
+        ["affinity_info", core] => [AFFINITY_INFO, number(core)?, 0, 0],
+        ["system_off"] => [SYSTEM_OFF, 0, 0, 0],
+        ["system_reset"] => [SYSTEM_RESET, 0, 0, 0],
+        ["system_suspend", entry, rest @ ..] => [SYSTEM_SUSPEND, number(entry)?, context(rest)?, 0],
+        _ => {
+            return Err("Usage: psci [status] | psci version | psci cpu_on <core> <entry> [context] | psci cpu_off | \
+                        psci affinity_info <core> | psci migrate_info_type | psci system_off | psci system_reset | \
+                        psci system_suspend <entry> [context]"
+                .to_string())
+        },
+    };
+    let mut cpu = CPU.lock().unwrap();
+    if cpu.is_secure() || !matches!(cpu.el(), ExceptionLevel::EL1 | ExceptionLevel::EL2) {
+        return Err(format!(
+            "PSCI calls come from the Non-secure kernel or hypervisor, not {} {:?}",
+            if cpu.is_secure() { "Secure" } else { "Non-secure" },
+            cpu.el()
+        ));
+    }
+    cpu.regs.x[..4].copy_from_slice(&call);
+    cpu.smc(0)?;
+    service_psci(&mut cpu)
+}
+
+// Exception generation and return, and system register access
+fn process_exception_command(command: &str, args: &[&str]) {
+    let mut cpu = CPU.lock().unwrap();
+    let imm = parse_imm(args.first()).ok_or_else(|| format!("Invalid immediate '{}'", args[0]));
+    let result = match (command, args) {
+        ("SVC", _) => imm.and_then(|imm| cpu.svc(imm)),
+        ("HVC", _) => imm.and_then(|imm| cpu.hvc(imm)),
+        ("SMC", _) => imm.and_then(|imm| cpu.smc(imm)).and_then(|_| service_psci(&mut cpu)),
+        ("ERET", []) => cpu.eret(),
+        ("MRS", [reg]) => cpu.mrs(reg).map(|value| println!("{} = {:#x}", reg, value)),
+        ("MSR", [reg, value]) => match parse_u64(value) {
+            Some(value) => cpu.msr(reg, value),
+            None => Err(format!("Invalid value '{}'", value)),
+        },
+        _ => Err("Usage: SVC|HVC|SMC [#imm] | ERET | MRS <sysreg> | MSR <sysreg> <value>".to_string()),
+    };
+    report(result);
+}
+
+// The EL each boot stage's firmware runs at
+fn stage_level(stage: Stage) -> ExceptionLevel {
+    match stage {
+        Stage::Bl1 | Stage::Bl31 => ExceptionLevel::EL3,
+        Stage::Bl2 => ExceptionLevel::EL1,
+        Stage::Bl33 => ExceptionLevel::EL2,
+    }
+}
+
+// Boot stage commands only run in the firmware that owns them
+fn in_stage(command: &str, stage: Stage) -> Result<(), String> {
+    let current = TRUSTED_BOOT.lock().unwrap().stage;
+    if current != stage || CPU.lock().unwrap().el() != stage_level(stage) {
+        return Err(format!("'{}' runs in {:?} at {:?}", command, stage, stage_level(stage)));
+    }
+    Ok(())
+}
+
+// The normal world's way into a TA: the kernel's TEE driver issues an SMC,
+// the secure monitor switches worlds and enters OP-TEE in S-EL1, and
+// OP-TEE's return SMC comes back the same way. From EL0, libteec goes
+// through the kernel first.
+fn call_trusted_os<T>(request: impl FnOnce(&mut TrustedOs) -> Result<T, String>) -> Result<T, String> {
+    let mut cpu = CPU.lock().unwrap();
+    let from = cpu.el();
+    if cpu.is_secure() || from > ExceptionLevel::EL1 {
+        return Err(format!(
+            "The TEE driver belongs to the Non-secure kernel; '{}' has nothing to call from {:?}",
+            if cpu.is_secure() { "Secure" } else { "Non-secure" },
+            from
+        ));
+    }
+    if from == ExceptionLevel::EL0 {
+        cpu.svc(0)?;
+    }
+    // OPTEE_SMC_CALL_WITH_ARG
+    cpu.smc(0)?;
+    cpu.switch_world()?;
+    cpu.enter_lower(ExceptionLevel::EL1, BL32_BASE)?;
+    let result = request(&mut OPTEE.lock().unwrap());
+    // OPTEE_SMC_RETURN_CALL_DONE
+    cpu.smc(0)?;
+    cpu.switch_world()?;
+    cpu.eret()?;
+    if from == ExceptionLevel::EL0 {
+        cpu.eret()?;
+    }
+    result
+}
+
+fn process_ta_command(args: &[&str]) -> Result<(), String> {
+    match args {
+        [] | ["list"] => {
+            OPTEE.lock().unwrap().list();
+            Ok(())
+        },
+        ["tamper", name] => OPTEE.lock().unwrap().tamper(name),
+        ["open", name] => call_trusted_os(|tee| tee.open_session(name)).map(|_| println!("Session with '{}' opened", name)),
+        ["invoke", name, command, rest @ ..] => {
+            call_trusted_os(|tee| tee.invoke(name, command, rest)).map(|out| println!("{}: {}", name, out))
+        },
+        ["close", name] => call_trusted_os(|tee| tee.close_session(name)).map(|_| println!("Session with '{}' closed", name)),
+        _ => Err("Usage: ta [list] | ta open <ta> | ta invoke <ta> <command> [args] | ta close <ta> | ta tamper <ta>".to_string()),
+    }
+}
+
+// The most one `mem read` dumps
+const MAX_DUMP: u64 = 0x1000;
+
+// Physical memory accesses in the core's current security state. One the
+// TZASC refuses comes back as a synchronous external abort.
+fn process_mem_command(args: &[&str]) -> Result<(), String> {
+    let secure = CPU.lock().unwrap().is_secure();
+    let (addr, result) = match args {
+        ["read", addr, rest @ ..] => match (parse_u64(addr), rest.first().map_or(Some(32), |l| parse_u64(l))) {
+            (_, Some(len)) if len > MAX_DUMP => return Err(format!("mem read shows at most {:#x} bytes", MAX_DUMP)),
+            (Some(addr), Some(len)) => (addr, PHYS_MEMORY.lock().unwrap().read(addr, len as usize, secure).map(|bytes| hexdump(addr as usize, &bytes))),
+            _ => return Err("Invalid address or length".to_string()),
+        },
+        ["write", addr, hex] => match (parse_u64(addr), parse_hex(hex)) {
+            (Some(addr), Some(bytes)) => (addr, PHYS_MEMORY.lock().unwrap().write(addr, &bytes, secure)),
+            _ => return Err("Invalid address or hex bytes".to_string()),
+        },
+        _ => return Err("Usage: mem read <addr> [len] | mem write <addr> <hexbytes>".to_string()),
+    };
+    if let Err(msg) = result {
+        println!("{}", msg);
+        CPU.lock().unwrap().data_abort(addr)?;
+    }
+    Ok(())
+}
+
+// The REPL drives one core at a time. The others keep running where they
+// were left, and only a core PSCI has powered on can be driven.
+fn process_cpu_command(args: &[&str]) -> Result<(), String> {
+    let mut cpu = CPU.lock().unwrap();
+    let mut cores = CORES.lock().unwrap();
+    let power = PSCI.lock().unwrap().cores;
+    match args {
+        [] => {
+            let current = cores.current;
+            for (core, state) in power.iter().enumerate() {
+                if *state == PowerState::Off {
+                    println!(" core {}  Off", core);
+                    continue;
+                }
+                let c: &Cpu = if core == current { &cpu } else { cores.get_mut(core) };
+                println!(
+                    " core {}  {:?} {}  PC={:#x}{}",
+                    core,
+                    c.el(),
+                    if c.is_secure() { "Secure" } else { "Non-secure" },
+                    c.pc,
+                    if core == current { "  <- driven" } else { "" }
+                );
+            }
+            Ok(())
+        },
+        [core] => {
+            let core = match parse_u64(core) {
+                Some(core) if (core as usize) < NUM_CORES => core as usize,
+                _ => return Err(format!("No core '{}'; the SoC has cores 0-{}", core, NUM_CORES - 1)),
+            };
+            if power[core] == PowerState::Off {
+                return Err(format!("Core {} is off. Bring it up from the kernel with 'psci cpu_on {} <entry>'", core, core));
+            }
+            if core != cores.current {
+                cores.switch(&mut cpu, core);
+            }
+            println!("Now driving core {} at {:?}", core, cpu.el());
+            Ok(())
+        },
+        _ => Err("Usage: cpu [core]".to_string()),
+    }
+}
+
+// The SCTLR_EL1 bits behind PAC, BTI and MTE, flipped the way a kernel
+// would for its processes
+fn process_mitigations_command(args: &[&str]) -> Result<(), String> {
+    let mut cpu = CPU.lock().unwrap();
+    match args {
+        [] => {},
+        [feature, "on"] => cpu.mitigations.set(feature, true)?,
+        [feature, "off"] => cpu.mitigations.set(feature, false)?,
+        _ => return Err("Usage: mitigations [pac|bti|mte on|off]".to_string()),
+    }
+    cpu.mitigations.print();
+    Ok(())
+}
+
+// The trusted boot flow: BL1 authenticates and runs BL2, BL2 authenticates
+// BL31, BL32 and BL33 and asks BL1 to run BL31
+fn process_command(command: &str, args: &[&str]) -> CommandResult {
+    // Nothing runs while the system is off or suspended, but BL31's power state can be looked at
+    let system = PSCI.lock().unwrap().system;
+    if system != SystemState::Running && command != "psci" {
+        println!("The system is {:?}. Type '{}' first.", system, if system == SystemState::Off { "powerup" } else { "wake" });
+        return CommandResult::Failed;
+    }
+    match command {
+        "switch_mode" => report(CPU.lock().unwrap().switch_world()),
+        "sysregs" | "regs" | "vregs" => {
+            let cpu = CPU.lock().unwrap();
+            match command {
+                "sysregs" => cpu.print_sysregs(),
+                "regs" => cpu.print_registers(),
+                _ => cpu.print_vector_registers(),
+            }
+            CommandResult::Success
+        },
+        "SVC" | "HVC" | "SMC" | "ERET" | "MRS" | "MSR" => {
+            process_exception_command(command, args);
+            CommandResult::Success
+        },
+        "verify_bl2" | "verify_bl31" | "verify_bl32" | "verify_bl33" => {
+            let image = Image::parse(&command["verify_".len()..]).unwrap();
+            let stage = if image == Image::Bl2 { Stage::Bl1 } else { Stage::Bl2 };
+            match in_stage(command, stage).and_then(|_| TRUSTED_BOOT.lock().unwrap().verify(image)) {
+                Ok(()) => {
+                    println!("Verified {}", image.name());
+                    CommandResult::Success
+                },
+                Err(msg) => {
+                    println!("{}", msg);
+                    CommandResult::Failed
+                },
+            }
+        },
+        "load_bl2" => {
+            if let Err(msg) = in_stage(command, Stage::Bl1) {
+                println!("{}", msg);
+                return CommandResult::Failed;
+            }
+            let mut boot = TRUSTED_BOOT.lock().unwrap();
+            if !boot.is_verified(Image::Bl2) {
+                println!("BL2 not verified. Aborting.");
+                return CommandResult::NotVerified;
+            }
+            let mut cpu = CPU.lock().unwrap();
+            match boot.load(Image::Bl2, BL2_BASE).and_then(|_| cpu.enter_lower(ExceptionLevel::EL1, BL2_BASE)) {
+                Ok(()) => {
+                    cpu.install_vectors(ExceptionLevel::EL1, BL2_BASE + VECTOR_OFFSET, "BL2");
+                    boot.stage = Stage::Bl2;
+                    CommandResult::Success
+                },
+                Err(msg) => {
+                    println!("{}", msg);
+                    CommandResult::Failed
+                },
+            }
+        },
+        "load_bl31" => {
+            if let Err(msg) = in_stage(command, Stage::Bl2) {
+                println!("{}", msg);
+                return CommandResult::Failed;
+            }
+            let mut boot = TRUSTED_BOOT.lock().unwrap();
+            let images = [(Image::Bl31, BL31_BASE), (Image::Bl32, BL32_BASE), (Image::Bl33, BL33_BASE)];
+            if let Some((image, _)) = images.iter().find(|(image, _)| !boot.is_verified(*image)) {
+                println!("{} not verified. Aborting.", image.name());
+                return CommandResult::NotVerified;
+            }
+            let mut cpu = CPU.lock().unwrap();
+            // BL2 cannot enter EL3 itself: it hands BL31's entry point to BL1 with an SMC
+            let result = images
+                .iter()
+                .try_for_each(|&(image, base)| boot.load(image, base))
+                .and_then(|_| cpu.smc(0))
+                .and_then(|_| {
+                    println!("BL1: BL2 done, running BL31");
+                    cpu.enter_lower(ExceptionLevel::EL3, BL31_BASE)
+                });
+            match result {
+                Ok(()) => {
+                    cpu.install_vectors(ExceptionLevel::EL3, BL31_BASE + VECTOR_OFFSET, "BL31 runtime");
+                    boot.stage = Stage::Bl31;
+                    CommandResult::Success
+                },
+                Err(msg) => {
+                    println!("{}", msg);
+                    CommandResult::Failed
+                },
+            }
+        },
+        "fip" => {
+            let mut boot = TRUSTED_BOOT.lock().unwrap();
+            let result = match args {
+                [] | ["list"] => {
+                    boot.fip.list();
+                    Ok(())
+                },
+                ["tamper", entry] => boot.fip.tamper(entry),
+                ["resign", entry] => boot.fip.resign(entry),
+                ["restore"] => {
+                    boot.fip = Fip::build();
+                    println!("Flash restored from the vendor's FIP.");
+                    Ok(())
+                },
+                _ => Err("Usage: fip [list] | fip tamper <image|cert> | fip resign <image> | fip restore".to_string()),
+            };
+            report(result)
+        },
+        "ta" | "mem" | "tzasc" | "mitigations" | "psci" | "cpu" => {
+            let result = match command {
+                "ta" => process_ta_command(args),
+                "cpu" => process_cpu_command(args),
+                "psci" => process_psci_command(args),
+                "mem" => process_mem_command(args),
+                "mitigations" => process_mitigations_command(args),
+                _ => {
+                    PHYS_MEMORY.lock().unwrap().print_regions();
+                    Ok(())
+                },
+            };
+            report(result)
+        },
+        _ => CommandResult::UnknownCommand,
+    }
+}
+
+fn provide_hint(mode: Mode, current_el: ExceptionLevel) {
+    match mode {
+        Mode::Off => return println!("Hint: The system is off. Type 'powerup' to cold boot from BL1"),
+        Mode::Suspended => return println!("Hint: The system is suspended. Type 'wake' to resume it through BL31"),
+        _ => {},
+    }
+    let stage = TRUSTED_BOOT.lock().unwrap().stage;
+    match (stage, current_el) {
+        (Stage::Bl1, ExceptionLevel::EL3) => println!("Hint: BL1 ROM at EL3. Type 'verify_bl2' to authenticate BL2 from the FIP, then 'load_bl2' to run it"),
+        (Stage::Bl2, ExceptionLevel::EL1) => println!("Hint: BL2 at S-EL1. Type 'verify_bl31', 'verify_bl32' and 'verify_bl33', then 'load_bl31'"),
+        (Stage::Bl31, ExceptionLevel::EL3) => println!("Hint: BL31 at EL3. Type 'init_trustzone' to initialize TrustZone and ERET to BL33 at EL2"),
+        (_, ExceptionLevel::EL3) => println!("Hint: Type 'ERET' to return from an SMC"),
+        (_, ExceptionLevel::EL2) => println!("Hint: Type 'setup_virtualization' to set up virtualization and ERET to EL1, or 'ERET' to return from an HVC"),
+        (_, ExceptionLevel::EL1) => println!("Hint: Type 'init_kernel' to initialize the kernel and ERET to EL0, or 'ERET' to return from an SVC"),
+        (_, ExceptionLevel::EL0) => {
+            match mode {
+                Mode::Secure => println!("Hint: Perform secure operations, or 'SVC #n' to call the kernel"),
+                _ => println!("Hint: You can now execute Non-Secure instructions like 'ADD', 'SUB', etc. 'SVC #n' calls the kernel"),
+            }
+        }
+    }
+    println!("      'sysregs' shows the exception state; only EL3 can 'switch_mode'");
+    println!("      'regs' and 'vregs' show X0-X30, SP, PC, NZCV and V0-V31, e.g. after 'ADD X0, X1, #5' or 'FADD V0.4S, V1.4S, V2.4S'");
+    println!("      'fip' lists the firmware image package; 'fip tamper <entry>' corrupts it");
+    println!("      'ta' lists trusted applications; 'ta open <ta>' and 'ta invoke <ta> <command>' call them from the normal world");
+    println!("      'mem read <addr>' reads physical memory; 'tzasc' shows which ranges are Secure only");
+    println!("      'psci' shows core power states; from the kernel, 'psci cpu_on <core> <entry>', 'psci system_suspend <entry>', 'psci system_reset' and 'psci system_off' call BL31");
+    println!("      'cpu' lists the cores; 'cpu <n>' drives one that 'psci cpu_on' started, e.g. to issue its own PSCI calls");
+    println!("      'mitigations pac|bti|mte on' arms pointer authentication on BL/RET, BTI landing pads for BR/BLR and MTE tag checks");
+    println!("      In Secure state, 'encrypt <key> <text>' and 'decrypt <key> <hex>' use the trusted OS key store ('default' is provisioned)");
+}
+
+// The arm64 edition on the shared engine. Its state is read off the core
+// and BL31's power bookkeeping.
+struct Arm64;
+
+impl Machine for Arm64 {
+    type State = Mode;
+
+    fn state(&self) -> Mode {
+        match PSCI.lock().unwrap().system {
+            SystemState::Off => Mode::Off,
+            SystemState::Suspended => Mode::Suspended,
+            SystemState::Running if CPU.lock().unwrap().is_secure() => Mode::Secure,
+            SystemState::Running => Mode::NonSecure,
+        }
+    }
+
+    fn prompt(&self) -> String {
+        match self.state() {
+            mode @ (Mode::Off | Mode::Suspended) => format!("{:?}>> ", mode),
+            _ => match CORES.lock().unwrap().current {
+                0 => format!("{:?}>> ", CPU.lock().unwrap().el()),
+                core => format!("{:?}@core{}>> ", CPU.lock().unwrap().el(), core),
+            },
+        }
+    }
+
+    fn hint(&self) {
+        provide_hint(self.state(), CPU.lock().unwrap().el());
+    }
+
+    fn transition(&mut self, transition: &Transition<Mode>) -> Result<(), String> {
+        let mut psci = PSCI.lock().unwrap();
+        // A cold boot comes up in the Secure world; a wake-up goes straight back to the kernel
+        if transition.to == Mode::Secure {
+            psci.reset(SystemState::Running);
+            println!("Power on: core 0 starts in BL1 at the reset vector {:#x}", BL1_BASE);
+            return Ok(());
+        }
+        let (entry, context) = psci.wake().ok_or("Nothing to resume")?;
+        drop(psci);
+        let mut cpu = CPU.lock().unwrap();
+        cpu.warm_boot();
+        println!("Wake-up event: core {} restarts in BL31's warm boot entry at {:#x}", CORES.lock().unwrap().current, BL31_BASE);
+        println!("BL31: EL3 and Secure context restored from trusted SRAM; the TZASC kept its regions");
+        // The kernel gets back only its context ID; the rest it saved in DRAM itself
+        cpu.regs.x[0] = context;
+        cpu.enter_lower(ExceptionLevel::EL1, entry)
+    }
+
+    fn process_command(&mut self, command: &str, args: &[&str]) -> CommandResult {
+        process_command(command, args)
+    }
+
+    fn guard(&mut self, instruction: &str, required: Mode) -> Result<(), String> {
+        let mode = self.state();
+        // Secure instructions do not exist as far as the Non-secure world is concerned
+        if required == Mode::Secure && mode == Mode::NonSecure {
+            report(CPU.lock().unwrap().undefined(instruction));
+            return Err(format!("'{}' is refused in Non-secure state", instruction));
+        }
+        if required != mode {
+            return Err(format!("Cannot access '{}' in {:?} mode", instruction, mode));
+        }
+        let current_el = CPU.lock().unwrap().el();
+        match system_level(instruction) {
+            Some(el) if el != current_el => Err(format!("'{}' runs at {:?}, not {:?}", instruction, el, current_el)),
+            // Handlers move the PC themselves: on to the next instruction,
+            // to a branch target, or to a vector
+            _ => Ok(()),
+        }
+    }
+}
+
+fn main() {
+    // Arm Instructions Simplified
+    let instructions = vec![
+        Instruction { name: "ADD", handler: add_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "SUB", handler: sub_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "AND", handler: and_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "ORR", handler: orr_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "EOR", handler: eor_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "B", handler: b_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "BL", handler: bl_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "RET", handler: ret_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "BR", handler: br_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "BLR", handler: blr_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "BTI", handler: bti_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "PACIA", handler: pacia_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "AUTIA", handler: autia_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "XPACI", handler: xpaci_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "IRG", handler: irg_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "STG", handler: stg_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "LDG", handler: ldg_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "CMP", handler: cmp_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "CMN", handler: cmn_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "MOV", handler: mov_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "MVN", handler: mvn_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "LDR", handler: ldr_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "STR", handler: str_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "VADD", handler: vadd_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "VSUB", handler: vsub_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "FADD", handler: fadd_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "FSUB", handler: fsub_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "FMUL", handler: fmul_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "VMOV", handler: vmov_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "SADD", handler: sadd_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "SSUB", handler: ssub_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "LD1", handler: ld1_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "ST1", handler: st1_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "MATMUL", handler: matmul_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "MMV", handler: mmv_handler as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "encrypt", handler: encrypt_handler as InstructionHandler, state: Mode::Secure },
+        Instruction { name: "decrypt", handler: decrypt_handler as InstructionHandler, state: Mode::Secure },
+        Instruction { name: "init_trustzone", handler: init_trustzone as InstructionHandler, state: Mode::Secure },
+        Instruction { name: "setup_virtualization", handler: setup_virtualization as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "init_kernel", handler: init_kernel as InstructionHandler, state: Mode::NonSecure },
+        Instruction { name: "start_user_apps", handler: start_user_apps as InstructionHandler, state: Mode::NonSecure },
+    ];
+
+    let transitions = vec![
+        Transition { command: "powerup", from: Mode::Off, to: Mode::Secure },
+        Transition { command: "wake", from: Mode::Suspended, to: Mode::NonSecure },
+    ];
+
+    Engine::new(Arm64, transitions, instructions).run();
+}
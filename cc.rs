@@ -0,0 +1,110 @@
+// $t@$h
+use crate::crypto::{ct_eq, hmac_sha256, random_bytes, sha256, to_hex};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+// Confidential computing technologies the simulator can launch a guest under.
+// Both share the same mechanics here; only the naming differs.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CcTech {
+    SevSnp,
+    Tdx,
+}
+
+impl CcTech {
+    pub fn parse(s: &str) -> Option<CcTech> {
+        match s {
+            "snp" | "sev-snp" => Some(CcTech::SevSnp),
+            "tdx" => Some(CcTech::Tdx),
+            _ => None,
+        }
+    }
+
+    pub fn processor(&self) -> &'static str {
+        match self {
+            CcTech::SevSnp => "AMD Secure Processor",
+            CcTech::Tdx => "TDX module",
+        }
+    }
+}
+
+pub struct AttestationReport {
+    pub tech: CcTech,
+    pub measurement: [u8; 32],
+    pub report_data: [u8; 32],
+    pub chip_id: [u8; 32],
+    pub signature: [u8; 32],
+}
+
+impl AttestationReport {
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![self.tech as u8];
+        buf.extend_from_slice(&self.measurement);
+        buf.extend_from_slice(&self.report_data);
+        buf.extend_from_slice(&self.chip_id);
+        buf
+    }
+
+    pub fn print(&self) {
+        println!("{:?} attestation report:", self.tech);
+        println!("  measurement  {}", to_hex(&self.measurement));
+        println!("  report_data  {}", to_hex(&self.report_data));
+        println!("  chip_id      {}", to_hex(&self.chip_id));
+        println!("  signature    {}", to_hex(&self.signature));
+    }
+}
+
+// The security processor sits outside the hypervisor's reach. It owns the
+// per-guest memory encryption keys, takes the launch measurement and signs
+// reports with a chip-unique key (HMAC-SHA256 stands in for the signature).
+pub struct SecurityProcessor {
+    chip_secret: [u8; 32],
+}
+
+impl SecurityProcessor {
+    fn new() -> Self {
+        SecurityProcessor {
+            chip_secret: random_bytes(),
+        }
+    }
+
+    pub fn chip_id(&self) -> [u8; 32] {
+        sha256(&self.chip_secret)
+    }
+
+    // LAUNCH_START/UPDATE/FINISH collapsed: returns a fresh memory
+    // encryption key and the measurement of the initial image
+    pub fn launch(&self, image: &[u8]) -> ([u8; 16], [u8; 32]) {
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&random_bytes()[..16]);
+        (key, launch_digest(image))
+    }
+
+    pub fn report(&self, tech: CcTech, measurement: [u8; 32], report_data: [u8; 32]) -> AttestationReport {
+        let mut report = AttestationReport {
+            tech,
+            measurement,
+            report_data,
+            chip_id: self.chip_id(),
+            signature: [0u8; 32],
+        };
+        report.signature = hmac_sha256(&self.chip_secret, &report.signed_bytes());
+        report
+    }
+
+    pub fn verify_report(&self, report: &AttestationReport) -> bool {
+        ct_eq(&report.chip_id, &self.chip_id())
+            && ct_eq(&report.signature, &hmac_sha256(&self.chip_secret, &report.signed_bytes()))
+    }
+}
+
+// What a guest owner expects the launch measurement of a given image to be
+pub fn launch_digest(image: &[u8]) -> [u8; 32] {
+    let mut buf = b"LAUNCH".to_vec();
+    buf.extend_from_slice(&sha256(image));
+    sha256(&buf)
+}
+
+lazy_static! {
+    pub static ref SECURITY_PROCESSOR: Mutex<SecurityProcessor> = Mutex::new(SecurityProcessor::new());
+}
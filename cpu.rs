@@ -0,0 +1,39 @@
+// $t@$h
+
+// The general purpose registers the simulator tracks for a (v)CPU
+#[derive(Clone, Default)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rsp: u64,
+    pub rip: u64,
+}
+
+impl Registers {
+    pub fn new() -> Self {
+        Registers::default()
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut u64> {
+        match name.to_ascii_lowercase().as_str() {
+            "rax" => Some(&mut self.rax),
+            "rbx" => Some(&mut self.rbx),
+            "rcx" => Some(&mut self.rcx),
+            "rdx" => Some(&mut self.rdx),
+            "rsi" => Some(&mut self.rsi),
+            "rdi" => Some(&mut self.rdi),
+            "rsp" => Some(&mut self.rsp),
+            "rip" => Some(&mut self.rip),
+            _ => None,
+        }
+    }
+
+    pub fn print(&self) {
+        println!(" RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}", self.rax, self.rbx, self.rcx, self.rdx);
+        println!(" RSI={:016x} RDI={:016x} RSP={:016x} RIP={:016x}", self.rsi, self.rdi, self.rsp, self.rip);
+    }
+}
@@ -0,0 +1,748 @@
+// $t@$h
+// Small self-contained crypto primitives for the simulator. These favour
+// readability over speed and are not constant time; they only protect
+// simulated secrets.
+
+const K256: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+
+    let mut message = data.to_vec();
+    let bit_len = (data.len() as u64).wrapping_mul(8);
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&bit_len.to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(K256[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(message);
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+// Compares without bailing out on the first mismatching byte
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Simulation-grade randomness: the clock and a counter run through SHA-256
+#[allow(dead_code)]
+pub fn random_bytes() -> [u8; 32] {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let count = COUNTER.fetch_add(1, Ordering::SeqCst);
+    let mut seed = nanos.to_le_bytes().to_vec();
+    seed.extend_from_slice(&count.to_le_bytes());
+    sha256(&seed)
+}
+
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    p
+}
+
+// AES block cipher for 128- and 256-bit keys
+#[derive(Clone)]
+pub struct Aes {
+    round_keys: Vec<[u8; 16]>,
+}
+
+#[allow(dead_code)]
+impl Aes {
+    pub fn new(key: &[u8]) -> Self {
+        assert!(key.len() == 16 || key.len() == 32, "AES key must be 16 or 32 bytes");
+        let nk = key.len() / 4;
+        let rounds = nk + 6;
+        let mut words: Vec<[u8; 4]> = key.chunks(4).map(|c| [c[0], c[1], c[2], c[3]]).collect();
+        let mut rcon = 1u8;
+        for i in nk..4 * (rounds + 1) {
+            let mut t = words[i - 1];
+            if i % nk == 0 {
+                t = [SBOX[t[1] as usize] ^ rcon, SBOX[t[2] as usize], SBOX[t[3] as usize], SBOX[t[0] as usize]];
+                rcon = xtime(rcon);
+            } else if nk > 6 && i % nk == 4 {
+                t = [SBOX[t[0] as usize], SBOX[t[1] as usize], SBOX[t[2] as usize], SBOX[t[3] as usize]];
+            }
+            let prev = words[i - nk];
+            words.push([prev[0] ^ t[0], prev[1] ^ t[1], prev[2] ^ t[2], prev[3] ^ t[3]]);
+        }
+        let round_keys = words
+            .chunks(4)
+            .map(|w| {
+                let mut k = [0u8; 16];
+                for (i, word) in w.iter().enumerate() {
+                    k[i * 4..i * 4 + 4].copy_from_slice(word);
+                }
+                k
+            })
+            .collect();
+        Aes { round_keys }
+    }
+
+    // The expanded key as software AES keeps it in memory
+    pub fn key_schedule(&self) -> Vec<u8> {
+        self.round_keys.concat()
+    }
+
+    // The AES-128 schedule runs backwards too: any one round key gives the
+    // cipher key, which is how key-search tools repair a decayed copy
+    pub fn key_from_round_key(round: usize, round_key: &[u8; 16]) -> [u8; 16] {
+        let mut words = [[0u8; 4]; 44];
+        for (i, word) in round_key.chunks(4).enumerate() {
+            words[round * 4 + i].copy_from_slice(word);
+        }
+        for i in (4..round * 4 + 4).rev() {
+            let mut t = words[i - 1];
+            if i % 4 == 0 {
+                let rcon = (1..i / 4).fold(1u8, |r, _| xtime(r));
+                t = [SBOX[t[1] as usize] ^ rcon, SBOX[t[2] as usize], SBOX[t[3] as usize], SBOX[t[0] as usize]];
+            }
+            let next = words[i];
+            words[i - 4] = [next[0] ^ t[0], next[1] ^ t[1], next[2] ^ t[2], next[3] ^ t[3]];
+        }
+        let mut key = [0u8; 16];
+        for (i, word) in words[..4].iter().enumerate() {
+            key[i * 4..i * 4 + 4].copy_from_slice(word);
+        }
+        key
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; 16]) {
+        let rounds = self.round_keys.len() - 1;
+        xor_into(block, &self.round_keys[0]);
+        for round in 1..=rounds {
+            for b in block.iter_mut() {
+                *b = SBOX[*b as usize];
+            }
+            shift_rows(block);
+            if round != rounds {
+                mix_columns(block);
+            }
+            xor_into(block, &self.round_keys[round]);
+        }
+    }
+
+    pub fn decrypt_block(&self, block: &mut [u8; 16]) {
+        let rounds = self.round_keys.len() - 1;
+        xor_into(block, &self.round_keys[rounds]);
+        for round in (0..rounds).rev() {
+            inv_shift_rows(block);
+            for b in block.iter_mut() {
+                *b = inv_sbox(*b);
+            }
+            xor_into(block, &self.round_keys[round]);
+            if round != 0 {
+                inv_mix_columns(block);
+            }
+        }
+    }
+}
+
+fn inv_sbox(b: u8) -> u8 {
+    SBOX.iter().position(|&s| s == b).unwrap() as u8
+}
+
+#[allow(dead_code)]
+pub fn xor_into(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+fn shift_rows(s: &mut [u8; 16]) {
+    let t = *s;
+    for c in 0..4 {
+        for r in 0..4 {
+            s[c * 4 + r] = t[((c + r) % 4) * 4 + r];
+        }
+    }
+}
+
+fn inv_shift_rows(s: &mut [u8; 16]) {
+    let t = *s;
+    for c in 0..4 {
+        for r in 0..4 {
+            s[((c + r) % 4) * 4 + r] = t[c * 4 + r];
+        }
+    }
+}
+
+fn mix_columns(s: &mut [u8; 16]) {
+    for c in s.chunks_mut(4) {
+        let a = [c[0], c[1], c[2], c[3]];
+        c[0] = gmul(a[0], 2) ^ gmul(a[1], 3) ^ a[2] ^ a[3];
+        c[1] = a[0] ^ gmul(a[1], 2) ^ gmul(a[2], 3) ^ a[3];
+        c[2] = a[0] ^ a[1] ^ gmul(a[2], 2) ^ gmul(a[3], 3);
+        c[3] = gmul(a[0], 3) ^ a[1] ^ a[2] ^ gmul(a[3], 2);
+    }
+}
+
+fn inv_mix_columns(s: &mut [u8; 16]) {
+    for c in s.chunks_mut(4) {
+        let a = [c[0], c[1], c[2], c[3]];
+        c[0] = gmul(a[0], 14) ^ gmul(a[1], 11) ^ gmul(a[2], 13) ^ gmul(a[3], 9);
+        c[1] = gmul(a[0], 9) ^ gmul(a[1], 14) ^ gmul(a[2], 11) ^ gmul(a[3], 13);
+        c[2] = gmul(a[0], 13) ^ gmul(a[1], 9) ^ gmul(a[2], 14) ^ gmul(a[3], 11);
+        c[3] = gmul(a[0], 11) ^ gmul(a[1], 13) ^ gmul(a[2], 9) ^ gmul(a[3], 14);
+    }
+}
+
+// XTS-AES-128 (IEEE 1619) for sector-sized data units. The 32-byte key is
+// the data key followed by the tweak key.
+pub struct Xts {
+    data: Aes,
+    tweak: Aes,
+}
+
+#[allow(dead_code)]
+impl Xts {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Xts {
+            data: Aes::new(&key[..16]),
+            tweak: Aes::new(&key[16..]),
+        }
+    }
+
+    // Data key schedule, then tweak key schedule
+    pub fn key_schedules(&self) -> Vec<u8> {
+        let mut schedules = self.data.key_schedule();
+        schedules.extend_from_slice(&self.tweak.key_schedule());
+        schedules
+    }
+
+    // Multiply the tweak by x in GF(2^128), little-endian as XTS defines it
+    fn next_tweak(t: &mut [u8; 16]) {
+        let carry = t[15] >> 7;
+        for i in (1..16).rev() {
+            t[i] = (t[i] << 1) | (t[i - 1] >> 7);
+        }
+        t[0] = (t[0] << 1) ^ if carry == 1 { 0x87 } else { 0 };
+    }
+
+    fn crypt(&self, unit: u64, buf: &mut [u8], encrypt: bool) {
+        let mut t = [0u8; 16];
+        t[..8].copy_from_slice(&unit.to_le_bytes());
+        self.tweak.encrypt_block(&mut t);
+        for chunk in buf.chunks_exact_mut(16) {
+            let mut block = [0u8; 16];
+            block.copy_from_slice(chunk);
+            xor_into(&mut block, &t);
+            if encrypt {
+                self.data.encrypt_block(&mut block);
+            } else {
+                self.data.decrypt_block(&mut block);
+            }
+            xor_into(&mut block, &t);
+            chunk.copy_from_slice(&block);
+            Xts::next_tweak(&mut t);
+        }
+    }
+
+    // `buf` must be a whole number of AES blocks; sectors always are
+    pub fn encrypt(&self, unit: u64, buf: &mut [u8]) {
+        self.crypt(unit, buf, true);
+    }
+
+    pub fn decrypt(&self, unit: u64, buf: &mut [u8]) {
+        self.crypt(unit, buf, false);
+    }
+}
+
+// AES-256 in counter mode. The nonce must never repeat under one key.
+#[allow(dead_code)]
+pub fn aes_ctr(key: &[u8; 32], nonce: u64, data: &mut [u8]) {
+    let aes = Aes::new(key);
+    for (i, chunk) in data.chunks_mut(16).enumerate() {
+        let mut counter = [0u8; 16];
+        counter[..8].copy_from_slice(&nonce.to_be_bytes());
+        counter[8..].copy_from_slice(&(i as u64).to_be_bytes());
+        aes.encrypt_block(&mut counter);
+        xor_into(chunk, &counter);
+    }
+}
+
+// Multiplication in GF(2^128) with GCM's bit order (NIST SP 800-38D, 6.3)
+fn gf128_mul(x: u128, y: u128) -> u128 {
+    const R: u128 = 0xe1 << 120;
+    let mut z = 0u128;
+    let mut v = y;
+    for i in 0..128 {
+        if (x >> (127 - i)) & 1 == 1 {
+            z ^= v;
+        }
+        v = if v & 1 == 1 { (v >> 1) ^ R } else { v >> 1 };
+    }
+    z
+}
+
+fn ghash(h: u128, aad: &[u8], ciphertext: &[u8]) -> u128 {
+    let mut y = 0u128;
+    for data in [aad, ciphertext] {
+        for chunk in data.chunks(16) {
+            let mut block = [0u8; 16];
+            block[..chunk.len()].copy_from_slice(chunk);
+            y = gf128_mul(y ^ u128::from_be_bytes(block), h);
+        }
+    }
+    let lengths = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+    gf128_mul(y ^ lengths, h)
+}
+
+// AES-256-GCM with a 96-bit nonce: CTR encryption from counter 2, and the
+// GHASH of AAD and ciphertext masked with counter block 1 as the tag
+fn gcm_crypt(aes: &Aes, nonce: &[u8; 12], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(16).enumerate() {
+        let mut counter = [0u8; 16];
+        counter[..12].copy_from_slice(nonce);
+        counter[12..].copy_from_slice(&(i as u32 + 2).to_be_bytes());
+        aes.encrypt_block(&mut counter);
+        xor_into(chunk, &counter);
+    }
+}
+
+fn gcm_tag(aes: &Aes, nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let mut h = [0u8; 16];
+    aes.encrypt_block(&mut h);
+    let mut j0 = [0u8; 16];
+    j0[..12].copy_from_slice(nonce);
+    j0[15] = 1;
+    aes.encrypt_block(&mut j0);
+    (ghash(u128::from_be_bytes(h), aad, ciphertext) ^ u128::from_be_bytes(j0)).to_be_bytes()
+}
+
+// Returns ciphertext || 16-byte tag
+#[allow(dead_code)]
+pub fn aes_gcm_seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let aes = Aes::new(key);
+    let mut out = plaintext.to_vec();
+    gcm_crypt(&aes, nonce, &mut out);
+    let tag = gcm_tag(&aes, nonce, aad, &out);
+    out.extend_from_slice(&tag);
+    out
+}
+
+#[allow(dead_code)]
+pub fn aes_gcm_open(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+    if sealed.len() < 16 {
+        return Err("ciphertext shorter than the tag".to_string());
+    }
+    let aes = Aes::new(key);
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - 16);
+    if !ct_eq(tag, &gcm_tag(&aes, nonce, aad, ciphertext)) {
+        return Err("GCM tag mismatch".to_string());
+    }
+    let mut out = ciphertext.to_vec();
+    gcm_crypt(&aes, nonce, &mut out);
+    Ok(out)
+}
+
+// X25519 (RFC 7748) over GF(2^255 - 19), five 51-bit limbs per element
+type Fe = [u64; 5];
+const MASK51: u64 = (1 << 51) - 1;
+
+fn fe_carry(mut h: [u128; 5]) -> Fe {
+    for i in 0..4 {
+        h[i + 1] += h[i] >> 51;
+        h[i] &= MASK51 as u128;
+    }
+    h[0] += 19 * (h[4] >> 51);
+    h[4] &= MASK51 as u128;
+    h[1] += h[0] >> 51;
+    h[0] &= MASK51 as u128;
+    [h[0] as u64, h[1] as u64, h[2] as u64, h[3] as u64, h[4] as u64]
+}
+
+fn fe_add(a: &Fe, b: &Fe) -> Fe {
+    fe_carry([0, 1, 2, 3, 4].map(|i| (a[i] + b[i]) as u128))
+}
+
+// Adds 2p first so limbs never go negative
+fn fe_sub(a: &Fe, b: &Fe) -> Fe {
+    const TWO_P: Fe = [0xf_ffff_ffff_ffda, 0xf_ffff_ffff_fffe, 0xf_ffff_ffff_fffe, 0xf_ffff_ffff_fffe, 0xf_ffff_ffff_fffe];
+    fe_carry([0, 1, 2, 3, 4].map(|i| (a[i] + TWO_P[i] - b[i]) as u128))
+}
+
+fn fe_mul(a: &Fe, b: &Fe) -> Fe {
+    let m = |i: usize, j: usize| a[i] as u128 * b[j] as u128;
+    fe_carry([
+        m(0, 0) + 19 * (m(1, 4) + m(2, 3) + m(3, 2) + m(4, 1)),
+        m(0, 1) + m(1, 0) + 19 * (m(2, 4) + m(3, 3) + m(4, 2)),
+        m(0, 2) + m(1, 1) + m(2, 0) + 19 * (m(3, 4) + m(4, 3)),
+        m(0, 3) + m(1, 2) + m(2, 1) + m(3, 0) + 19 * m(4, 4),
+        m(0, 4) + m(1, 3) + m(2, 2) + m(3, 1) + m(4, 0),
+    ])
+}
+
+// a^(p-2) by square and multiply
+fn fe_invert(a: &Fe) -> Fe {
+    let mut exponent = [0xffu8; 32];
+    exponent[0] = 0xeb;
+    exponent[31] = 0x7f;
+    let mut r: Fe = [1, 0, 0, 0, 0];
+    for bit in (0..255).rev() {
+        r = fe_mul(&r, &r);
+        if (exponent[bit / 8] >> (bit % 8)) & 1 == 1 {
+            r = fe_mul(&r, a);
+        }
+    }
+    r
+}
+
+fn fe_from_bytes(b: &[u8; 32]) -> Fe {
+    let w = |i: usize| u64::from_le_bytes(b[i * 8..i * 8 + 8].try_into().unwrap());
+    [
+        w(0) & MASK51,
+        (w(0) >> 51 | w(1) << 13) & MASK51,
+        (w(1) >> 38 | w(2) << 26) & MASK51,
+        (w(2) >> 25 | w(3) << 39) & MASK51,
+        (w(3) >> 12) & MASK51,
+    ]
+}
+
+fn fe_to_bytes(a: &Fe) -> [u8; 32] {
+    // Fully reduce: subtract p once if a >= p
+    let mut h = *a;
+    let mut q = (h[0] + 19) >> 51;
+    for limb in &h[1..] {
+        q = (limb + q) >> 51;
+    }
+    h[0] += 19 * q;
+    for i in 0..4 {
+        h[i + 1] += h[i] >> 51;
+        h[i] &= MASK51;
+    }
+    h[4] &= MASK51;
+    let words = [h[0] | h[1] << 51, h[1] >> 13 | h[2] << 38, h[2] >> 26 | h[3] << 25, h[3] >> 39 | h[4] << 12];
+    let mut out = [0u8; 32];
+    for (i, w) in words.iter().enumerate() {
+        out[i * 8..i * 8 + 8].copy_from_slice(&w.to_le_bytes());
+    }
+    out
+}
+
+pub fn x25519(scalar: &[u8; 32], u: &[u8; 32]) -> [u8; 32] {
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+    let x1 = fe_from_bytes(u);
+    let (mut x2, mut z2, mut x3, mut z3): (Fe, Fe, Fe, Fe) = ([1, 0, 0, 0, 0], [0; 5], x1, [1, 0, 0, 0, 0]);
+    let a24: Fe = [121665, 0, 0, 0, 0];
+    let mut swap = 0;
+    for t in (0..255).rev() {
+        let bit = (k[t / 8] >> (t % 8)) & 1;
+        if swap ^ bit == 1 {
+            std::mem::swap(&mut x2, &mut x3);
+            std::mem::swap(&mut z2, &mut z3);
+        }
+        swap = bit;
+        let a = fe_add(&x2, &z2);
+        let aa = fe_mul(&a, &a);
+        let b = fe_sub(&x2, &z2);
+        let bb = fe_mul(&b, &b);
+        let e = fe_sub(&aa, &bb);
+        let c = fe_add(&x3, &z3);
+        let d = fe_sub(&x3, &z3);
+        let da = fe_mul(&d, &a);
+        let cb = fe_mul(&c, &b);
+        let sum = fe_add(&da, &cb);
+        let diff = fe_sub(&da, &cb);
+        x3 = fe_mul(&sum, &sum);
+        z3 = fe_mul(&x1, &fe_mul(&diff, &diff));
+        x2 = fe_mul(&aa, &bb);
+        z2 = fe_mul(&e, &fe_add(&aa, &fe_mul(&a24, &e)));
+    }
+    if swap == 1 {
+        std::mem::swap(&mut x2, &mut x3);
+        std::mem::swap(&mut z2, &mut z3);
+    }
+    fe_to_bytes(&fe_mul(&x2, &fe_invert(&z2)))
+}
+
+#[allow(dead_code)]
+pub fn x25519_public(secret: &[u8; 32]) -> [u8; 32] {
+    let mut base = [0u8; 32];
+    base[0] = 9;
+    x25519(secret, &base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn xts_key(key1: &str, key2: &str) -> [u8; 32] {
+        hex(&format!("{}{}", key1, key2)).try_into().unwrap()
+    }
+
+    fn check_xts(key: &[u8; 32], unit: u64, plaintext: &[u8], ciphertext: &[u8]) {
+        let xts = Xts::new(key);
+        let mut buf = plaintext.to_vec();
+        xts.encrypt(unit, &mut buf);
+        assert_eq!(to_hex(&buf), to_hex(ciphertext));
+        xts.decrypt(unit, &mut buf);
+        assert_eq!(buf, plaintext);
+    }
+
+    // FIPS 180-2 Appendix B
+    #[test]
+    fn sha256_fips180_vectors() {
+        assert_eq!(to_hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(to_hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        let two_blocks = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(to_hex(&sha256(two_blocks)), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    }
+
+    // RFC 4231 test case 2
+    #[test]
+    fn hmac_sha256_rfc4231_case_2() {
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(to_hex(&mac), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn aes128_fips197_vector() {
+        let aes = Aes::new(&hex("000102030405060708090a0b0c0d0e0f"));
+        let mut block: [u8; 16] = hex("00112233445566778899aabbccddeeff").try_into().unwrap();
+        aes.encrypt_block(&mut block);
+        assert_eq!(to_hex(&block), "69c4e0d86a7b0430d8cdb78070b4c55a");
+        aes.decrypt_block(&mut block);
+        assert_eq!(to_hex(&block), "00112233445566778899aabbccddeeff");
+    }
+
+    // IEEE 1619-2007 Annex B, XTS-AES-128 vectors 1-4
+    #[test]
+    fn xts_ieee1619_vector_1() {
+        let key = xts_key("00000000000000000000000000000000", "00000000000000000000000000000000");
+        check_xts(&key, 0, &[0u8; 32], &hex("917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e"));
+    }
+
+    #[test]
+    fn xts_ieee1619_vector_2() {
+        let key = xts_key("11111111111111111111111111111111", "22222222222222222222222222222222");
+        check_xts(&key, 0x3333333333, &[0x44; 32], &hex("c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0"));
+    }
+
+    #[test]
+    fn xts_ieee1619_vector_3() {
+        let key = xts_key("fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0", "22222222222222222222222222222222");
+        check_xts(&key, 0x3333333333, &[0x44; 32], &hex("af85336b597afc1a900b2eb21ec949d292df4c047e0b21532186a5971a227a89"));
+    }
+
+    #[test]
+    fn xts_ieee1619_vector_4() {
+        let key = xts_key("27182818284590452353602874713526", "31415926535897932384626433832795");
+        let plaintext: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        let ciphertext = [
+            "27a7479befa1d476489f308cd4cfa6e2a96e4bbe3208ff25287dd3819616e89c",
+            "c78cf7f5e543445f8333d8fa7f56000005279fa5d8b5e4ad40e736ddb4d35412",
+            "328063fd2aab53e5ea1e0a9f332500a5df9487d07a5c92cc512c8866c7e860ce",
+            "93fdf166a24912b422976146ae20ce846bb7dc9ba94a767aaef20c0d61ad0265",
+            "5ea92dc4c4e41a8952c651d33174be51a10c421110e6d81588ede82103a252d8",
+            "a750e8768defffed9122810aaeb99f9172af82b604dc4b8e51bcb08235a6f434",
+            "1332e4ca60482a4ba1a03b3e65008fc5da76b70bf1690db4eae29c5f1badd03c",
+            "5ccf2a55d705ddcd86d449511ceb7ec30bf12b1fa35b913f9f747a8afd1b130e",
+            "94bff94effd01a91735ca1726acd0b197c4e5b03393697e126826fb6bbde8ecc",
+            "1e08298516e2c9ed03ff3c1b7860f6de76d4cecd94c8119855ef5297ca67e9f3",
+            "e7ff72b1e99785ca0a7e7720c5b36dc6d72cac9574c8cbbc2f801e23e56fd344",
+            "b07f22154beba0f08ce8891e643ed995c94d9a69c9f1b5f499027a78572aeebd",
+            "74d20cc39881c213ee770b1010e4bea718846977ae119f7a023ab58cca0ad752",
+            "afe656bb3c17256a9f6e9bf19fdd5a38fc82bbe872c5539edb609ef4f79c203e",
+            "bb140f2e583cb2ad15b4aa5b655016a8449277dbd477ef2c8d6c017db738b18d",
+            "eb4a427d1923ce3ff262735779a418f20a282df920147beabe421ee5319d0568",
+        ]
+        .concat();
+        check_xts(&key, 0, &plaintext, &hex(&ciphertext));
+    }
+
+    #[test]
+    fn xts_sectors_differ_by_tweak() {
+        let xts = Xts::new(&[7u8; 32]);
+        let (mut a, mut b) = ([0u8; 64], [0u8; 64]);
+        xts.encrypt(1, &mut a);
+        xts.encrypt(2, &mut b);
+        assert_ne!(a, b);
+        assert_ne!(a[..16], a[16..32]);
+    }
+
+    fn check_gcm(key: &str, nonce: &str, aad: &str, plaintext: &str, sealed: &str) {
+        let key: [u8; 32] = hex(key).try_into().unwrap();
+        let nonce: [u8; 12] = hex(nonce).try_into().unwrap();
+        let out = aes_gcm_seal(&key, &nonce, &hex(aad), &hex(plaintext));
+        assert_eq!(to_hex(&out), sealed);
+        assert_eq!(aes_gcm_open(&key, &nonce, &hex(aad), &out).unwrap(), hex(plaintext));
+    }
+
+    // McGrew and Viega, "The Galois/Counter Mode of Operation", test cases 13, 14 and 16
+    #[test]
+    fn gcm_spec_test_case_13() {
+        let zero = "0000000000000000000000000000000000000000000000000000000000000000";
+        check_gcm(zero, "000000000000000000000000", "", "", "530f8afbc74536b9a963b4f1c4cb738b");
+    }
+
+    #[test]
+    fn gcm_spec_test_case_14() {
+        let zero = "0000000000000000000000000000000000000000000000000000000000000000";
+        check_gcm(zero, "000000000000000000000000", "", &zero[..32], "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919");
+    }
+
+    #[test]
+    fn gcm_spec_test_case_16() {
+        let plaintext = [
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72",
+            "1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+        ]
+        .concat();
+        let sealed = [
+            "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa",
+            "8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662",
+            "76fc6ece0f4e1768cddf8853bb2d551b",
+        ]
+        .concat();
+        let key = "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308";
+        check_gcm(key, "cafebabefacedbaddecaf888", "feedfacedeadbeeffeedfacedeadbeefabaddad2", &plaintext, &sealed);
+    }
+
+    #[test]
+    fn gcm_open_refuses_tampering() {
+        let (key, nonce) = ([9u8; 32], [3u8; 12]);
+        let sealed = aes_gcm_seal(&key, &nonce, b"header", b"attack at dawn");
+        for i in [0, sealed.len() - 1] {
+            let mut bad = sealed.clone();
+            bad[i] ^= 1;
+            assert_eq!(aes_gcm_open(&key, &nonce, b"header", &bad).unwrap_err(), "GCM tag mismatch");
+        }
+        assert!(aes_gcm_open(&key, &nonce, b"headers", &sealed).is_err());
+        assert!(aes_gcm_open(&key, &[4u8; 12], b"header", &sealed).is_err());
+        assert!(aes_gcm_open(&key, &nonce, b"header", &sealed[..15]).is_err());
+    }
+
+    fn bytes32(s: &str) -> [u8; 32] {
+        hex(s).try_into().unwrap()
+    }
+
+    // RFC 7748 section 5.2
+    #[test]
+    fn x25519_rfc7748_vectors() {
+        let scalar = bytes32("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4");
+        let u = bytes32("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c");
+        assert_eq!(to_hex(&x25519(&scalar, &u)), "c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552");
+        let scalar = bytes32("4b66e9d4d1b4673c5ad22691957d6af5c11b6421e0ea01d42ca4169e7918ba0d");
+        let u = bytes32("e5210f12786811d3f4b7959d0538ae2c31dbe7106fc03c3efc4cd549c715a493");
+        assert_eq!(to_hex(&x25519(&scalar, &u)), "95cbde9476e8907d7aade45cb4b873f88b595a68799fa152e6f8f7647aac7957");
+    }
+
+    // RFC 7748 section 6.1
+    #[test]
+    fn x25519_rfc7748_diffie_hellman() {
+        let alice = bytes32("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = bytes32("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let (alice_public, bob_public) = (x25519_public(&alice), x25519_public(&bob));
+        assert_eq!(to_hex(&alice_public), "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        assert_eq!(to_hex(&bob_public), "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+        let shared = "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742";
+        assert_eq!(to_hex(&x25519(&alice, &bob_public)), shared);
+        assert_eq!(to_hex(&x25519(&bob, &alice_public)), shared);
+    }
+}
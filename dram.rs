@@ -0,0 +1,209 @@
+// $t@$h
+use crate::crypto::{random_bytes, to_hex, Aes, Xts};
+use crate::fde::{SECTOR_SIZE, VOLUME};
+use crate::memory::{GuestMemory, GUEST_MEMORY_SIZE};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+const SCHEDULE_SIZE: usize = 176;
+// A window further than one bit in eight from a real schedule is just data
+const MAX_BIT_ERRORS: u32 = SCHEDULE_SIZE as u32;
+
+// How cold the attacker keeps the DIMMs once power is gone
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Temperature {
+    Room,
+    // Inverted can of compressed air, about -50C
+    Chilled,
+    // Liquid nitrogen, about -196C
+    Frozen,
+}
+
+impl Temperature {
+    pub fn parse(s: &str) -> Option<Temperature> {
+        match s {
+            "room" => Some(Temperature::Room),
+            "chilled" => Some(Temperature::Chilled),
+            "frozen" => Some(Temperature::Frozen),
+            _ => None,
+        }
+    }
+
+    // Seconds until half the cells have leaked to their ground state
+    fn half_life(self) -> f64 {
+        match self {
+            Temperature::Room => 1.5,
+            Temperature::Chilled => 30_000.0,
+            Temperature::Frozen => 1_500_000.0,
+        }
+    }
+}
+
+// The memory controller settings that decide what survives in DRAM, and
+// what a cold-boot attacker has managed to image so far
+pub struct Dram {
+    // Total memory encryption with a key the CPU makes up at every reset
+    pub tme: bool,
+    // Memory overwrite request: firmware wipes DRAM on the next reset
+    pub mor: bool,
+    pub temperature: Temperature,
+    // Seconds without power, or None while the platform is powered
+    unpowered: Option<f64>,
+    dump: Option<Vec<u8>>,
+}
+
+impl Dram {
+    fn new() -> Self {
+        Dram {
+            tme: false,
+            mor: false,
+            temperature: Temperature::Room,
+            unpowered: None,
+            dump: None,
+        }
+    }
+
+    fn fresh(&self) -> GuestMemory {
+        if self.tme {
+            GuestMemory::new_encrypted(&random_bytes()[..16].try_into().unwrap())
+        } else {
+            GuestMemory::new()
+        }
+    }
+
+    pub fn power_off(&mut self) {
+        self.unpowered = Some(0.0);
+    }
+
+    // Whatever was left after sitting unpowered is gone by the time firmware runs
+    pub fn power_on(&mut self, dram: &mut GuestMemory) {
+        *dram = self.fresh();
+        self.unpowered = None;
+    }
+
+    // A reset without losing power leaves the cells charged. Firmware honours
+    // MOR by overwriting them; TME makes the CPU forget the key they were
+    // encrypted under.
+    pub fn reset(&mut self, dram: &mut GuestMemory) {
+        if self.mor {
+            *dram = self.fresh();
+            println!("Firmware: MOR bit set, DRAM overwritten before boot.");
+        } else if self.tme {
+            dram.rekey(&random_bytes()[..16].try_into().unwrap());
+            println!("CPU: new TME key generated; DRAM contents from before the reset are unreadable.");
+        }
+    }
+
+    // The attacker images the DIMMs `seconds` after power was cut, or boots
+    // a memory imager straight after a reset
+    pub fn dump(&mut self, dram: &mut GuestMemory, seconds: f64) -> Result<(), String> {
+        match self.unpowered.as_mut() {
+            Some(elapsed) => {
+                let fraction = 1.0 - 0.5f64.powf(seconds / self.temperature.half_life());
+                let decayed = dram.decay(fraction);
+                *elapsed += seconds;
+                println!(
+                    "Pulled the DIMMs {:.1}s after power loss ({:?}): {} of {} bits have decayed.",
+                    *elapsed,
+                    self.temperature,
+                    decayed,
+                    GUEST_MEMORY_SIZE * 8
+                );
+            },
+            None if seconds == 0.0 => println!("Booted a memory imager from USB; DRAM never lost power."),
+            None => return Err("DRAM is powered. Image it straight after a reset, or after a shutdown or cold reset.".to_string()),
+        }
+        let image = dram.host_read(0, GUEST_MEMORY_SIZE)?;
+        println!("Imaged {} bytes of DRAM.", image.len());
+        self.dump = Some(image);
+        Ok(())
+    }
+
+    pub fn image(&self) -> Option<&[u8]> {
+        self.dump.as_deref()
+    }
+
+    pub fn print_status(&self) {
+        println!("DRAM ({} bytes):", GUEST_MEMORY_SIZE);
+        match self.unpowered {
+            Some(seconds) => println!(" power            off for {:.1}s", seconds),
+            None => println!(" power            on"),
+        }
+        println!(" TME              {} (applies from the next reset or power-on)", if self.tme { "on" } else { "off" });
+        println!(" MOR              {}", if self.mor { "on" } else { "off" });
+        println!(" decay model      {:?}, half-life {}s", self.temperature, self.temperature.half_life());
+        println!(" image            {}", if self.dump.is_some() { "captured" } else { "none" });
+    }
+}
+
+fn bit_errors(a: &[u8], b: &[u8]) -> u32 {
+    a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
+
+// Every byte already at a ground state: nothing that could be a key
+fn is_blank(window: &[u8]) -> bool {
+    window.iter().filter(|&&b| b == 0x00 || b == 0xff).count() > window.len() * 3 / 4
+}
+
+// Like aeskeyfind: a window of memory is an AES-128 key schedule if some
+// round key in it expands to something close to the whole window. Trying
+// every round key tolerates decay as long as one of them came through
+// intact. Returns (offset, key, corrected bits).
+pub fn find_keys(image: &[u8]) -> Vec<(usize, [u8; 16], u32)> {
+    let mut found: Vec<(usize, [u8; 16], u32)> = Vec::new();
+    let mut offset = 0;
+    while offset + SCHEDULE_SIZE <= image.len() {
+        let window = &image[offset..offset + SCHEDULE_SIZE];
+        let best = if is_blank(window) {
+            None
+        } else {
+            (0..SCHEDULE_SIZE / 16)
+                .map(|round| {
+                    let key = Aes::key_from_round_key(round, &window[round * 16..round * 16 + 16].try_into().unwrap());
+                    (key, bit_errors(&Aes::new(&key).key_schedule(), window))
+                })
+                .min_by_key(|&(_, errors)| errors)
+        };
+        match best {
+            Some((key, errors)) if errors < MAX_BIT_ERRORS => {
+                found.push((offset, key, errors));
+                offset += SCHEDULE_SIZE;
+            },
+            _ => offset += 4,
+        }
+    }
+    found
+}
+
+// Try recovered keys as XTS data/tweak pairs against the stolen drive
+pub fn keyfind(image: &[u8]) -> Result<(), String> {
+    let keys = find_keys(image);
+    if keys.is_empty() {
+        return Err("No AES key schedules found in the image.".to_string());
+    }
+    for (offset, key, errors) in &keys {
+        println!(" {:#06x}  AES-128 key {}  ({} bits corrected)", offset, to_hex(key), errors);
+    }
+    let sector = VOLUME.lock().unwrap().raw(0)?;
+    for (_, data, _) in &keys {
+        for (_, tweak, _) in &keys {
+            let mut xts_key = [0u8; 32];
+            xts_key[..16].copy_from_slice(data);
+            xts_key[16..].copy_from_slice(tweak);
+            let mut plain = sector;
+            Xts::new(&xts_key).decrypt(0, &mut plain);
+            let text = plain.iter().take_while(|&&b| b != 0).count();
+            if text > 0 && plain[..text].iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+                println!("Data volume key recovered: {}", to_hex(&xts_key));
+                println!("Sector 0 of the stolen drive ({} bytes) reads: {}", SECTOR_SIZE, String::from_utf8_lossy(&plain[..text]));
+                return Ok(());
+            }
+        }
+    }
+    println!("None of the recovered keys opens the data volume.");
+    Ok(())
+}
+
+lazy_static! {
+    pub static ref DRAM: Mutex<Dram> = Mutex::new(Dram::new());
+}
@@ -0,0 +1,364 @@
+// $t@$h
+use crate::crypto::{sha256, to_hex};
+use crate::keys::{find_key, SigningKey};
+use crate::kmod::BUILTIN_TRUSTED_KEYS;
+use crate::memory::PAGE_SIZE;
+use crate::process::{USER_STACK_LIMIT, USER_TEXT_BASE};
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+// Trailer of an appended signature, in the spirit of signed kernel modules:
+// [ELF][signature][key id][key id length, u32 LE][magic]
+const SIG_MAGIC: &[u8] = b"~ELF signature appended~\n";
+
+pub fn flags_str(flags: u32) -> String {
+    let bit = |mask, c| if flags & mask != 0 { c } else { '-' };
+    [bit(PF_R, 'r'), bit(PF_W, 'w'), bit(PF_X, 'x')].iter().collect()
+}
+
+// A PT_LOAD segment after relocation by the load bias
+pub struct Segment {
+    pub vaddr: usize,
+    pub memsz: usize,
+    pub flags: u32,
+    pub data: Vec<u8>,
+}
+
+pub struct ElfImage {
+    pub entry: usize,
+    pub bias: usize,
+    pub segments: Vec<Segment>,
+}
+
+fn u16_at(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], off: usize) -> usize {
+    u64::from_le_bytes(b[off..off + 8].try_into().unwrap()) as usize
+}
+
+// Split an appended signature off the file, if there is one
+pub fn split_signature(file: &[u8]) -> (&[u8], Option<(String, [u8; 32])>) {
+    let body = match file.strip_suffix(SIG_MAGIC) {
+        Some(body) if body.len() >= 4 => body,
+        _ => return (file, None),
+    };
+    let id_len = u32_at(body, body.len() - 4) as usize;
+    let sig_start = match body.len().checked_sub(4 + id_len + 32) {
+        Some(start) => start,
+        None => return (file, None),
+    };
+    let id = String::from_utf8_lossy(&body[sig_start + 32..body.len() - 4]).to_string();
+    let mut sig = [0u8; 32];
+    sig.copy_from_slice(&body[sig_start..sig_start + 32]);
+    (&body[..sig_start], Some((id, sig)))
+}
+
+pub fn append_signature(elf: &[u8], key: &SigningKey) -> Vec<u8> {
+    let mut out = elf.to_vec();
+    out.extend_from_slice(&key.sign(&sha256(elf)));
+    out.extend_from_slice(key.id.as_bytes());
+    out.extend_from_slice(&(key.id.len() as u32).to_le_bytes());
+    out.extend_from_slice(SIG_MAGIC);
+    out
+}
+
+// Detached signature file (<binary>.sig) as written by `elfsign`
+pub fn detached_signature(file: &[u8], key: &SigningKey) -> String {
+    format!("{}\n{}\n", key.id, to_hex(&key.sign(&sha256(file))))
+}
+
+fn check_signer(key_id: &str, data: &[u8], sig: &[u8; 32]) -> Result<(), String> {
+    if !BUILTIN_TRUSTED_KEYS.iter().any(|k| k.id == key_id) {
+        return Err(format!("ELF signed by '{}', which is not in the keyring", key_id));
+    }
+    match find_key(key_id) {
+        Some(key) if key.verify(&sha256(data), sig) => Ok(()),
+        _ => Err("ELF signature does not match the binary".to_string()),
+    }
+}
+
+// Check the appended signature, or the detached one over the whole file,
+// and return the bare ELF
+pub fn verify_signature<'a>(file: &'a [u8], detached: Option<(&str, [u8; 32])>) -> Result<&'a [u8], String> {
+    let (elf, appended) = split_signature(file);
+    match (appended, detached) {
+        (Some((id, sig)), _) => check_signer(&id, elf, &sig).map(|_| elf),
+        (None, Some((id, sig))) => check_signer(id, file, &sig).map(|_| elf),
+        (None, None) => Err("ELF carries no appended or detached signature".to_string()),
+    }
+}
+
+// Parse an ELF64 executable and check every segment before anything is mapped
+pub fn parse(elf: &[u8]) -> Result<ElfImage, String> {
+    if elf.len() < EHDR_SIZE || &elf[..4] != b"\x7fELF" {
+        return Err("Not an ELF file".to_string());
+    }
+    if elf[4] != 2 || elf[5] != 1 {
+        return Err("Only little-endian ELF64 binaries are supported".to_string());
+    }
+    let e_type = u16_at(elf, 16);
+    if u16_at(elf, 18) != EM_X86_64 {
+        return Err(format!("Wrong machine type {} (expected x86-64)", u16_at(elf, 18)));
+    }
+    let phoff = u64_at(elf, 32);
+    let phentsize = u16_at(elf, 54) as usize;
+    let phnum = u16_at(elf, 56) as usize;
+    if phentsize != PHDR_SIZE || phoff.checked_add(phnum * PHDR_SIZE).is_none_or(|end| end > elf.len()) {
+        return Err("Program header table lies outside the file".to_string());
+    }
+
+    let phdrs: Vec<&[u8]> = (0..phnum).map(|i| &elf[phoff + i * PHDR_SIZE..phoff + (i + 1) * PHDR_SIZE]).collect();
+    let loads: Vec<&&[u8]> = phdrs.iter().filter(|ph| u32_at(ph, 0) == PT_LOAD).collect();
+    // Static PIE is placed at the usual text base; ET_EXEC runs where it was linked
+    let bias = match e_type {
+        ET_EXEC => 0,
+        ET_DYN if loads.iter().map(|ph| u64_at(ph, 16)).min() == Some(0) => USER_TEXT_BASE,
+        ET_DYN => 0,
+        _ => return Err(format!("ELF type {} is not an executable", e_type)),
+    };
+
+    let mut segments: Vec<Segment> = Vec::new();
+    for ph in &phdrs {
+        let flags = u32_at(ph, 4);
+        match u32_at(ph, 0) {
+            PT_INTERP => return Err("Dynamically linked binary (PT_INTERP); only static binaries can be loaded".to_string()),
+            PT_GNU_STACK if flags & PF_X != 0 => return Err("Binary requests an executable stack".to_string()),
+            PT_LOAD => {},
+            _ => continue,
+        }
+        let (offset, vaddr, filesz, memsz, align) = (u64_at(ph, 8), u64_at(ph, 16).wrapping_add(bias), u64_at(ph, 32), u64_at(ph, 40), u64_at(ph, 48));
+        if offset.checked_add(filesz).is_none_or(|end| end > elf.len()) {
+            return Err(format!("Segment at {:#x} extends past the end of the file", vaddr));
+        }
+        if filesz > memsz {
+            return Err(format!("Segment at {:#x} has filesz larger than memsz", vaddr));
+        }
+        if align > 1 && (!align.is_power_of_two() || vaddr % align != offset % align) {
+            return Err(format!("Segment at {:#x} is misaligned", vaddr));
+        }
+        if flags & PF_W != 0 && flags & PF_X != 0 {
+            return Err(format!("Segment at {:#x} is both writable and executable (W^X)", vaddr));
+        }
+        let end = vaddr.saturating_add(memsz);
+        if vaddr < PAGE_SIZE || end > USER_STACK_LIMIT {
+            return Err(format!("Segment {:#x}-{:#x} is outside user space", vaddr, end));
+        }
+        if segments.iter().any(|s| vaddr < s.vaddr + s.memsz && s.vaddr < end) {
+            return Err(format!("Segment at {:#x} overlaps another segment", vaddr));
+        }
+        segments.push(Segment {
+            vaddr,
+            memsz,
+            flags,
+            data: elf[offset..offset + filesz].to_vec(),
+        });
+    }
+    if segments.is_empty() {
+        return Err("No PT_LOAD segments".to_string());
+    }
+
+    let entry = u64_at(elf, 24).wrapping_add(bias);
+    let entry_ok = segments
+        .iter()
+        .any(|s| s.flags & PF_X != 0 && entry >= s.vaddr && entry < s.vaddr + s.memsz);
+    if !entry_ok {
+        return Err(format!("Entry point {:#x} is not in an executable segment", entry));
+    }
+    Ok(ElfImage { entry, bias, segments })
+}
+
+// What exec does with a file: signature first, then structure. With
+// `enforce` off a bad signature is only reported.
+pub fn load(file: &[u8], detached: Option<(&str, [u8; 32])>, enforce: bool) -> Result<ElfImage, String> {
+    let elf = match verify_signature(file, detached) {
+        Ok(elf) => elf,
+        Err(msg) if !enforce => {
+            println!("{} (not enforced)", msg);
+            split_signature(file).0
+        },
+        Err(msg) => return Err(msg),
+    };
+    parse(elf)
+}
+
+pub fn print_headers(file: &[u8], detached: Option<(&str, [u8; 32])>) -> Result<(), String> {
+    let (elf, appended) = split_signature(file);
+    let image = parse(elf)?;
+    println!("ELF64 x86-64, entry point {:#x}, load bias {:#x}", image.entry, image.bias);
+    println!(" {:<8} {:<18} {:<10} {:<10} Flg", "Type", "VirtAddr", "FileSiz", "MemSiz");
+    for s in &image.segments {
+        println!(" {:<8} {:#018x} {:#010x} {:#010x} {}", "LOAD", s.vaddr, s.data.len(), s.memsz, flags_str(s.flags));
+    }
+    let kind = match (&appended, detached) {
+        (Some((id, _)), _) => format!("appended, by '{}'", id),
+        (None, Some((id, _))) => format!("detached, by '{}'", id),
+        (None, None) => "none".to_string(),
+    };
+    let verdict = match verify_signature(file, detached) {
+        Ok(_) => "valid".to_string(),
+        Err(msg) => msg,
+    };
+    println!("Signature: {} ({})", kind, verdict);
+    Ok(())
+}
+
+// Link a tiny static executable: text at 0x401000 that writes `message`
+// to stdout and exits, and the message in a data segment at 0x402000
+pub fn build(message: &[u8]) -> Vec<u8> {
+    const TEXT: usize = 0x401000;
+    const DATA: usize = 0x402000;
+    let mut code = vec![0xb8, 1, 0, 0, 0, 0xbf, 1, 0, 0, 0, 0xbe]; // mov eax,1 (write); mov edi,1; mov esi,
+    code.extend_from_slice(&(DATA as u32).to_le_bytes());
+    code.push(0xba); // mov edx, len
+    code.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    code.extend_from_slice(&[0x0f, 0x05, 0xb8, 60, 0, 0, 0, 0x31, 0xff, 0x0f, 0x05]); // syscall; mov eax,60 (exit); xor edi,edi; syscall
+
+    let mut elf = vec![0u8; PAGE_SIZE];
+    let header: [(usize, &[u8]); 10] = [
+        (0, b"\x7fELF\x02\x01\x01"),
+        (16, &ET_EXEC.to_le_bytes()),
+        (18, &EM_X86_64.to_le_bytes()),
+        (20, &1u32.to_le_bytes()),
+        (24, &(TEXT as u64).to_le_bytes()),
+        (32, &(EHDR_SIZE as u64).to_le_bytes()),
+        (52, &(EHDR_SIZE as u16).to_le_bytes()),
+        (54, &(PHDR_SIZE as u16).to_le_bytes()),
+        (56, &2u16.to_le_bytes()),
+        (58, &64u16.to_le_bytes()),
+    ];
+    for (offset, bytes) in header {
+        elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+    let segments = [(PAGE_SIZE, TEXT, code.len(), PF_R | PF_X), (2 * PAGE_SIZE, DATA, message.len(), PF_R | PF_W)];
+    for (i, (offset, vaddr, size, flags)) in segments.into_iter().enumerate() {
+        let at = EHDR_SIZE + i * PHDR_SIZE;
+        elf[at..at + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        elf[at + 4..at + 8].copy_from_slice(&flags.to_le_bytes());
+        for (j, field) in [offset, vaddr, vaddr, size, size, PAGE_SIZE].into_iter().enumerate() {
+            elf[at + 8 + j * 8..at + 16 + j * 8].copy_from_slice(&(field as u64).to_le_bytes());
+        }
+    }
+    elf.extend_from_slice(&code);
+    elf.resize(2 * PAGE_SIZE, 0);
+    elf.extend_from_slice(message);
+    elf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::RELEASE_KEY;
+
+    // Overwrite a u64 field of program header `index`
+    fn set_phdr(elf: &mut [u8], index: usize, field: usize, value: u64) {
+        let at = EHDR_SIZE + index * PHDR_SIZE + field;
+        elf[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_flags(elf: &mut [u8], index: usize, flags: u32) {
+        let at = EHDR_SIZE + index * PHDR_SIZE + 4;
+        elf[at..at + 4].copy_from_slice(&flags.to_le_bytes());
+    }
+
+    fn error(elf: &[u8]) -> String {
+        match parse(elf) {
+            Ok(_) => panic!("parse accepted a malformed ELF"),
+            Err(msg) => msg,
+        }
+    }
+
+    #[test]
+    fn parses_built_executable() {
+        let image = parse(&build(b"hello")).unwrap();
+        assert_eq!(image.entry, 0x401000);
+        assert_eq!(image.bias, 0);
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(image.segments[1].data, b"hello");
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let elf = build(b"hello");
+        assert_eq!(error(&elf[..EHDR_SIZE - 1]), "Not an ELF file");
+        assert!(error(&elf[..EHDR_SIZE + PHDR_SIZE]).contains("Program header table"));
+        assert!(error(&elf[..elf.len() - 1]).contains("extends past the end of the file"));
+    }
+
+    #[test]
+    fn rejects_offsets_that_overflow() {
+        let mut elf = build(b"hello");
+        elf[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(error(&elf).contains("Program header table"));
+        let mut elf = build(b"hello");
+        set_phdr(&mut elf, 1, 8, u64::MAX);
+        assert!(error(&elf).contains("extends past the end of the file"));
+    }
+
+    #[test]
+    fn rejects_overlapping_segments() {
+        let mut elf = build(b"hello");
+        // Data segment moved onto the text page, keeping its file offset congruent
+        set_phdr(&mut elf, 1, 16, 0x401000);
+        assert!(error(&elf).contains("overlaps another segment"));
+    }
+
+    #[test]
+    fn rejects_writable_and_executable_segments() {
+        let mut elf = build(b"hello");
+        set_flags(&mut elf, 0, PF_R | PF_W | PF_X);
+        assert!(error(&elf).contains("W^X"));
+    }
+
+    #[test]
+    fn rejects_entry_outside_text() {
+        let mut elf = build(b"hello");
+        elf[24..32].copy_from_slice(&0x402000u64.to_le_bytes());
+        assert!(error(&elf).contains("not in an executable segment"));
+    }
+
+    #[test]
+    fn splits_appended_signature() {
+        let elf = build(b"hello");
+        let signed = append_signature(&elf, &RELEASE_KEY);
+        let (body, sig) = split_signature(&signed);
+        assert_eq!(body, &elf[..]);
+        assert_eq!(sig.unwrap().0, RELEASE_KEY.id);
+        assert!(verify_signature(&signed, None).is_ok());
+    }
+
+    #[test]
+    fn ignores_malformed_signature_trailer() {
+        // A key id length that reaches past the start of the file
+        let mut file = b"tiny".to_vec();
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        file.extend_from_slice(SIG_MAGIC);
+        let (body, sig) = split_signature(&file);
+        assert_eq!(body, &file[..]);
+        assert!(sig.is_none());
+        assert!(split_signature(SIG_MAGIC).1.is_none());
+    }
+
+    #[test]
+    fn rejects_tampered_signed_binary() {
+        let mut signed = append_signature(&build(b"hello"), &RELEASE_KEY);
+        signed[0x1000] ^= 1;
+        assert!(verify_signature(&signed, None).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+// $t@$h
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use std::fmt::Debug;
+
+#[derive(Debug, PartialEq)]
+pub enum CommandResult {
+    Success,
+    NotVerified,
+    Failed,
+    UnknownCommand,
+}
+
+pub type InstructionHandler = fn(&[&str]);
+
+// Print a command's error, if it had one, and say how it went
+pub fn report(result: Result<(), String>) -> CommandResult {
+    match result {
+        Ok(()) => CommandResult::Success,
+        Err(msg) => {
+            println!("{}", msg);
+            CommandResult::Failed
+        },
+    }
+}
+
+// A command that moves the machine from one state to the next, and is
+// refused in any other state
+pub struct Transition<S> {
+    pub command: &'static str,
+    pub from: S,
+    pub to: S,
+}
+
+// An instruction and the state it runs in
+pub struct Instruction<S> {
+    pub name: &'static str,
+    pub handler: InstructionHandler,
+    pub state: S,
+}
+
+// What an edition plugs into the engine. The state is always read back
+// from the simulated hardware, so there is no second copy to fall out of
+// step with it.
+pub trait Machine {
+    type State: Copy + PartialEq + Debug;
+
+    fn state(&self) -> Self::State;
+    fn prompt(&self) -> String;
+    fn hint(&self);
+    // Carry out a transition from the table; the engine has checked `from`
+    fn transition(&mut self, transition: &Transition<Self::State>) -> Result<(), String>;
+    // The edition's own commands. UnknownCommand hands the line on to the instructions.
+    fn process_command(&mut self, command: &str, args: &[&str]) -> CommandResult;
+
+    // Whether an instruction may run now
+    fn guard(&mut self, instruction: &str, required: Self::State) -> Result<(), String> {
+        let current = self.state();
+        if required != current {
+            return Err(format!("Cannot access '{}' in {:?} mode", instruction, current));
+        }
+        Ok(())
+    }
+
+    fn after_instruction(&mut self) {}
+}
+
+pub struct Engine<M: Machine> {
+    machine: M,
+    transitions: Vec<Transition<M::State>>,
+    instructions: Vec<Instruction<M::State>>,
+}
+
+impl<M: Machine> Engine<M> {
+    pub fn new(machine: M, transitions: Vec<Transition<M::State>>, instructions: Vec<Instruction<M::State>>) -> Self {
+        Engine {
+            machine,
+            transitions,
+            instructions,
+        }
+    }
+
+    pub fn run(&mut self) {
+        let mut rl = Editor::<()>::new();
+        loop {
+            match rl.readline(&self.machine.prompt()) {
+                Ok(line) => {
+                    rl.add_history_entry(line.as_str());
+                    let parts: Vec<&str> = line.split_whitespace().collect();
+                    if let Some((&command, args)) = parts.split_first() {
+                        if command == "exit" {
+                            break;
+                        }
+                        self.dispatch(command, args);
+                    }
+                },
+                Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+                Err(err) => println!("Error: {:?}", err),
+            }
+        }
+    }
+
+    // Built-in commands first, then the transition table, then the
+    // edition's commands, then instructions
+    fn dispatch(&mut self, command: &str, args: &[&str]) {
+        match command {
+            "hint" => return self.machine.hint(),
+            "instructions" => return self.print_instructions(),
+            _ => {},
+        }
+        let current = self.machine.state();
+        if self.transitions.iter().any(|t| t.command == command) {
+            let result = match self.transitions.iter().find(|t| t.command == command && t.from == current) {
+                Some(transition) => self.machine.transition(transition).and_then(|_| match self.machine.state() {
+                    state if state != transition.to => Err(format!("'{}' left the machine in {:?} mode, not {:?}", command, state, transition.to)),
+                    _ => Ok(()),
+                }),
+                None => Err(format!("'{}' is not possible in {:?} mode", command, current)),
+            };
+            if let Err(msg) = result {
+                println!("{}", msg);
+            }
+            return;
+        }
+        if self.machine.process_command(command, args) != CommandResult::UnknownCommand {
+            return;
+        }
+        let instruction = match self.instructions.iter().find(|i| i.name == command) {
+            Some(instruction) => instruction,
+            None => return println!("Unknown instruction: '{}'", command),
+        };
+        match self.machine.guard(command, instruction.state) {
+            Ok(()) => {
+                (instruction.handler)(args);
+                self.machine.after_instruction();
+            },
+            Err(msg) => println!("{}", msg),
+        }
+    }
+
+    fn print_instructions(&self) {
+        println!("Available Instructions:");
+        for instruction in &self.instructions {
+            println!(" {:<22} {:?}", instruction.name, instruction.state);
+        }
+    }
+}
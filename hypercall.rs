@@ -0,0 +1,156 @@
+// $t@$h
+use crate::memory::{GUEST_MEMORY_SIZE, PAGE_SIZE};
+use crate::tpm::PCR_COUNT;
+use crate::vm::Guest;
+use crate::Mode;
+
+// VMCALL ABI: RAX holds the hypercall number, RBX/RCX/RDX the arguments and
+// RAX receives the result. Negative results are errno values.
+pub const HC_OK: u64 = 0;
+pub const HC_EPERM: u64 = -1i64 as u64;
+pub const HC_EFAULT: u64 = -14i64 as u64;
+pub const HC_EINVAL: u64 = -22i64 as u64;
+pub const HC_ENOSYS: u64 = -38i64 as u64;
+
+pub type HypercallHandler = fn(&mut Guest) -> u64;
+
+pub const HC_CONSOLE_WRITE: u64 = 1;
+pub const HC_BALLOON_INFLATE: u64 = 2;
+pub const HC_BALLOON_DEFLATE: u64 = 3;
+pub const HC_VTPM_EXTEND: u64 = 4;
+pub const HC_VTPM_READ: u64 = 5;
+pub const HC_SHUTDOWN: u64 = 6;
+
+pub const HYPERCALLS: [(u64, &str, HypercallHandler); 6] = [
+    (HC_CONSOLE_WRITE, "console_write", console_write),
+    (HC_BALLOON_INFLATE, "balloon_inflate", balloon_inflate),
+    (HC_BALLOON_DEFLATE, "balloon_deflate", balloon_deflate),
+    (HC_VTPM_EXTEND, "vtpm_extend", vtpm_extend),
+    (HC_VTPM_READ, "vtpm_read", vtpm_read),
+    (HC_SHUTDOWN, "shutdown", shutdown),
+];
+
+pub fn lookup(name_or_nr: &str) -> Option<u64> {
+    HYPERCALLS
+        .iter()
+        .find(|(nr, name, _)| *name == name_or_nr || nr.to_string() == name_or_nr)
+        .map(|(nr, _, _)| *nr)
+}
+
+// Every guest starts out allowed to use every hypercall
+pub fn default_policy() -> Vec<u64> {
+    HYPERCALLS.iter().map(|(nr, _, _)| *nr).collect()
+}
+
+// VM exit on VMCALL: look the call up, enforce the guest's policy, dispatch
+pub fn vmcall(guest: &mut Guest) {
+    let nr = guest.regs.rax;
+    let result = match HYPERCALLS.iter().find(|(n, _, _)| *n == nr) {
+        None => {
+            println!("[hv] VMCALL {:#x}: no such hypercall", nr);
+            HC_ENOSYS
+        },
+        Some((_, name, _)) if !guest.hypercall_policy.contains(&nr) => {
+            println!("[hv] VMCALL {} denied by policy for guest '{}'", name, guest.name);
+            HC_EPERM
+        },
+        Some((_, name, handler)) => {
+            println!("[hv] VMCALL {}(rbx={:#x}, rcx={:#x}, rdx={:#x})", name, guest.regs.rbx, guest.regs.rcx, guest.regs.rdx);
+            handler(guest)
+        },
+    };
+    // A guest that asked to be shut down is not resumed
+    if guest.state.current_mode() == Mode::Hypervisor {
+        return;
+    }
+    guest.regs.rax = result;
+    println!("[hv] VMRESUME rax={:#x}", result);
+}
+
+fn guest_range(addr: u64, len: u64) -> Option<(usize, usize)> {
+    let end = addr.checked_add(len)?;
+    if end as usize > GUEST_MEMORY_SIZE {
+        return None;
+    }
+    Some((addr as usize, len as usize))
+}
+
+// rbx = buffer address, rcx = length. The hypervisor reads the buffer from
+// the host side, so a confidential guest's output arrives as ciphertext.
+fn console_write(guest: &mut Guest) -> u64 {
+    let (addr, len) = match guest_range(guest.regs.rbx, guest.regs.rcx) {
+        Some(range) => range,
+        None => return HC_EFAULT,
+    };
+    match guest.memory.host_read(addr, len) {
+        Ok(bytes) => {
+            println!("[{} console] {}", guest.name, String::from_utf8_lossy(&bytes));
+            len as u64
+        },
+        Err(_) => HC_EFAULT,
+    }
+}
+
+// rbx = number of pages handed back to (or reclaimed from) the hypervisor
+fn balloon_inflate(guest: &mut Guest) -> u64 {
+    let balloon = match guest.balloon_pages.checked_add(guest.regs.rbx as usize) {
+        Some(balloon) if balloon < GUEST_MEMORY_SIZE / PAGE_SIZE => balloon,
+        _ => return HC_EINVAL,
+    };
+    let pages = balloon - guest.balloon_pages;
+    guest.balloon_pages = balloon;
+    println!("[hv] Reclaimed {} page(s) from '{}', balloon now {} page(s)", pages, guest.name, guest.balloon_pages);
+    HC_OK
+}
+
+fn balloon_deflate(guest: &mut Guest) -> u64 {
+    let pages = guest.regs.rbx as usize;
+    if pages > guest.balloon_pages {
+        return HC_EINVAL;
+    }
+    guest.balloon_pages -= pages;
+    println!("[hv] Returned {} page(s) to '{}', balloon now {} page(s)", pages, guest.name, guest.balloon_pages);
+    HC_OK
+}
+
+// rbx = PCR index, rcx = data address, rdx = data length
+fn vtpm_extend(guest: &mut Guest) -> u64 {
+    let index = guest.regs.rbx as usize;
+    if index >= PCR_COUNT {
+        return HC_EINVAL;
+    }
+    let (addr, len) = match guest_range(guest.regs.rcx, guest.regs.rdx) {
+        Some(range) => range,
+        None => return HC_EFAULT,
+    };
+    match guest.memory.host_read(addr, len) {
+        Ok(data) => {
+            guest.vtpm.pcrs.extend(index, &data);
+            HC_OK
+        },
+        Err(_) => HC_EFAULT,
+    }
+}
+
+// rbx = PCR index, rcx = address that receives the 32-byte value
+fn vtpm_read(guest: &mut Guest) -> u64 {
+    let index = guest.regs.rbx as usize;
+    if index >= PCR_COUNT {
+        return HC_EINVAL;
+    }
+    let (addr, _) = match guest_range(guest.regs.rcx, 32) {
+        Some(range) => range,
+        None => return HC_EFAULT,
+    };
+    let value = guest.vtpm.pcrs.read(index);
+    match guest.memory.host_write(addr, &value) {
+        Ok(()) => HC_OK,
+        Err(_) => HC_EFAULT,
+    }
+}
+
+fn shutdown(guest: &mut Guest) -> u64 {
+    println!("[hv] Guest '{}' requested shutdown", guest.name);
+    guest.reset();
+    HC_OK
+}
@@ -0,0 +1,241 @@
+// $t@$h
+use crate::crypto::{ct_eq, hmac_sha256, random_bytes, sha256, to_hex};
+use crate::elf::{append_signature, build};
+use crate::keys::{find_key, SigningKey, RELEASE_KEY, THIRD_PARTY_KEY};
+use crate::kmod::BUILTIN_TRUSTED_KEYS;
+use crate::tpm::{PcrBank, PCR_APPLICATION};
+
+// An executable on the guest root filesystem with its security xattrs.
+// security.ima carries the signer and a signature over the file hash;
+// security.evm is an HMAC binding the path and security.ima together.
+pub struct Executable {
+    pub path: String,
+    pub content: Vec<u8>,
+    pub ima: Option<(&'static str, [u8; 32])>,
+    pub evm: Option<[u8; 32]>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Appraise {
+    Enforce,
+    Log,
+    Off,
+}
+
+impl Appraise {
+    pub fn parse(s: &str) -> Option<Appraise> {
+        match s {
+            "enforce" => Some(Appraise::Enforce),
+            "log" => Some(Appraise::Log),
+            "off" => Some(Appraise::Off),
+            _ => None,
+        }
+    }
+}
+
+// One line of the runtime measurement list (ima-sig template)
+pub struct Measurement {
+    pub pcr: usize,
+    pub template_hash: [u8; 32],
+    pub file_hash: [u8; 32],
+    pub path: String,
+    pub signature: Option<[u8; 32]>,
+}
+
+impl Measurement {
+    fn new(path: &str, file_hash: [u8; 32], signature: Option<[u8; 32]>) -> Self {
+        let mut m = Measurement {
+            pcr: PCR_APPLICATION,
+            template_hash: [0u8; 32],
+            file_hash,
+            path: path.to_string(),
+            signature,
+        };
+        m.template_hash = sha256(&m.template_data());
+        m
+    }
+
+    // d-ng | n-ng | sig, each field length-prefixed
+    fn template_data(&self) -> Vec<u8> {
+        let mut digest = b"sha256:\0".to_vec();
+        digest.extend_from_slice(&self.file_hash);
+        let mut name = self.path.as_bytes().to_vec();
+        name.push(0);
+        let sig = self.signature.map(|s| s.to_vec()).unwrap_or_default();
+        let mut data = Vec::new();
+        for field in [digest, name, sig] {
+            data.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            data.extend_from_slice(&field);
+        }
+        data
+    }
+}
+
+pub struct Ima {
+    pub appraise: Appraise,
+    pub files: Vec<Executable>,
+    pub log: Vec<Measurement>,
+    evm_key: [u8; 32],
+}
+
+impl Ima {
+    pub fn new() -> Self {
+        let mut ima = Ima {
+            appraise: Appraise::Enforce,
+            files: Vec::new(),
+            log: Vec::new(),
+            evm_key: random_bytes(),
+        };
+        // Vendor packages ship ELF binaries with appended signatures
+        let signed = |message: &[u8], key| append_signature(&build(message), key);
+        ima.install("/bin/app", &signed(b"hello from a verified application\n", &RELEASE_KEY), Some(&RELEASE_KEY));
+        ima.install("/bin/sh", &signed(b"$ ", &RELEASE_KEY), Some(&RELEASE_KEY));
+        ima.install("/opt/miner", &signed(b"mining...\n", &THIRD_PARTY_KEY), Some(&THIRD_PARTY_KEY));
+        ima.install("/tmp/dropper", &build(b"fetching stage 2\n"), None);
+        ima
+    }
+
+    fn evm_hmac(&self, path: &str, ima: &Option<(&'static str, [u8; 32])>) -> [u8; 32] {
+        let mut buf = path.as_bytes().to_vec();
+        if let Some((key_id, sig)) = ima {
+            buf.extend_from_slice(key_id.as_bytes());
+            buf.extend_from_slice(sig);
+        }
+        hmac_sha256(&self.evm_key, &buf)
+    }
+
+    // Package installation: sign the file if a key is given and let the
+    // running kernel write security.evm
+    fn install(&mut self, path: &str, content: &[u8], key: Option<&SigningKey>) {
+        let ima = key.map(|k| (k.id, k.sign(&sha256(content))));
+        self.add_file(path, content, ima);
+    }
+
+    // Install a file with a detached signature (if any) as its security.ima
+    pub fn add_file(&mut self, path: &str, content: &[u8], ima: Option<(&'static str, [u8; 32])>) {
+        let evm = Some(self.evm_hmac(path, &ima));
+        self.files.retain(|f| f.path != path);
+        self.files.push(Executable {
+            path: path.to_string(),
+            content: content.to_vec(),
+            ima,
+            evm,
+        });
+    }
+
+    pub fn file_mut(&mut self, path: &str) -> Result<&mut Executable, String> {
+        self.files
+            .iter_mut()
+            .find(|f| f.path == path)
+            .ok_or_else(|| format!("{}: No such file or directory", path))
+    }
+
+    // New boot: the policy and measurement list start over, files persist
+    pub fn reset(&mut self) {
+        self.appraise = Appraise::Enforce;
+        self.log.clear();
+    }
+
+    // First entry of every log: the PCRs the firmware and boot loader extended
+    pub fn boot_aggregate(&mut self, pcrs: &mut PcrBank) {
+        let aggregate = pcrs.composite(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        self.record(pcrs, Measurement::new("boot_aggregate", aggregate, None));
+    }
+
+    // A buffer rather than a file, e.g. the kernel image handed to kexec
+    pub fn measure(&mut self, name: &str, data: &[u8], pcrs: &mut PcrBank) {
+        self.record(pcrs, Measurement::new(name, sha256(data), None));
+    }
+
+    fn record(&mut self, pcrs: &mut PcrBank, m: Measurement) {
+        pcrs.extend_digest(m.pcr, &m.template_hash);
+        self.log.push(m);
+    }
+
+    // Appraisal without executing, as the kernel's verify step uses it
+    pub fn appraise(&self, path: &str) -> Result<(), String> {
+        let file = self.files.iter().find(|f| f.path == path).ok_or_else(|| format!("{}: No such file or directory", path))?;
+        self.appraise_file(file, &sha256(&file.content))
+    }
+
+    fn appraise_file(&self, file: &Executable, hash: &[u8; 32]) -> Result<(), String> {
+        let evm_ok = file.evm.is_some_and(|e| ct_eq(&e, &self.evm_hmac(&file.path, &file.ima)));
+        if !evm_ok {
+            return Err("EVM: security xattrs do not match their HMAC".to_string());
+        }
+        let (key_id, sig) = file.ima.ok_or("IMA: missing security.ima signature")?;
+        if !BUILTIN_TRUSTED_KEYS.iter().any(|k| k.id == key_id) {
+            return Err(format!("IMA: signing key '{}' is not in the keyring", key_id));
+        }
+        match find_key(key_id) {
+            Some(key) if key.verify(hash, &sig) => Ok(()),
+            _ => Err("IMA: invalid signature for file contents".to_string()),
+        }
+    }
+
+    // Called on exec: measure into PCR 10, then appraise per policy
+    pub fn exec(&mut self, path: &str, pcrs: &mut PcrBank) -> Result<(), String> {
+        let (hash, signature, verdict) = {
+            let file = self.files.iter().find(|f| f.path == path).ok_or_else(|| format!("{}: No such file or directory", path))?;
+            let hash = sha256(&file.content);
+            let verdict = match self.appraise {
+                Appraise::Off => Ok(()),
+                _ => self.appraise_file(file, &hash),
+            };
+            (hash, file.ima.map(|(_, s)| s), verdict)
+        };
+        self.record(pcrs, Measurement::new(path, hash, signature));
+        match (verdict, self.appraise) {
+            (Ok(()), _) => Ok(()),
+            (Err(reason), Appraise::Log) => {
+                println!("{} (appraise=log, allowing {})", reason, path);
+                Ok(())
+            },
+            (Err(reason), _) => Err(format!("{}\n{}: Permission denied", reason, path)),
+        }
+    }
+
+    // /sys/kernel/security/ima/ascii_runtime_measurements
+    pub fn print_ascii(&self) {
+        for m in &self.log {
+            let sig = m.signature.map(|s| to_hex(&s)).unwrap_or_default();
+            println!(
+                "{} {} ima-sig sha256:{} {} {}",
+                m.pcr,
+                to_hex(&m.template_hash),
+                to_hex(&m.file_hash),
+                m.path,
+                sig
+            );
+        }
+    }
+
+    // /sys/kernel/security/ima/binary_runtime_measurements
+    pub fn binary_log(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for m in &self.log {
+            let template_name = b"ima-sig";
+            let data = m.template_data();
+            out.extend_from_slice(&(m.pcr as u32).to_le_bytes());
+            out.extend_from_slice(&m.template_hash);
+            out.extend_from_slice(&(template_name.len() as u32).to_le_bytes());
+            out.extend_from_slice(template_name);
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&data);
+        }
+        out
+    }
+
+    // What a verifier does with the list: recompute every template hash and
+    // replay the extends to see whether they reproduce the quoted PCR 10
+    pub fn replay(&self) -> Result<[u8; 32], String> {
+        let mut bank = PcrBank::new();
+        for m in &self.log {
+            if !ct_eq(&sha256(&m.template_data()), &m.template_hash) {
+                return Err(format!("Template hash mismatch for {}", m.path));
+            }
+            bank.extend_digest(m.pcr, &m.template_hash);
+        }
+        Ok(bank.read(PCR_APPLICATION))
+    }
+}
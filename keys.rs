@@ -0,0 +1,100 @@
+// $t@$h
+use crate::crypto::{ct_eq, hmac_sha256};
+
+// A vendor signing key. Signatures are HMAC-SHA256 under the key secret, so
+// holding the key (e.g. in a keyring) is what it means to trust it here.
+pub struct SigningKey {
+    pub id: &'static str,
+    secret: &'static [u8],
+}
+
+impl SigningKey {
+    pub fn sign(&self, data: &[u8]) -> [u8; 32] {
+        hmac_sha256(self.secret, data)
+    }
+
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        ct_eq(&self.sign(data), signature)
+    }
+}
+
+const ALL_KEYS: [&SigningKey; 11] = [
+    &RELEASE_KEY,
+    &THIRD_PARTY_KEY,
+    &ROT_KEY,
+    &TRUSTED_WORLD_KEY,
+    &NON_TRUSTED_WORLD_KEY,
+    &SOC_FW_CONTENT_KEY,
+    &TOS_FW_CONTENT_KEY,
+    &NT_FW_CONTENT_KEY,
+    &TA_SIGNING_KEY,
+    &RISCV_ROM_KEY,
+    &RISCV_KERNEL_KEY,
+];
+
+// Every key the simulator knows by id, trusted or not
+#[allow(dead_code)]
+pub fn find_key(id: &str) -> Option<&'static SigningKey> {
+    ALL_KEYS.into_iter().find(|k| k.id == id)
+}
+
+pub const RELEASE_KEY: SigningKey = SigningKey {
+    id: "QVLX release key",
+    secret: b"qvlx-release-signing-key-2023",
+};
+
+// A third-party vendor key the guest kernel does not ship in its keyring
+pub const THIRD_PARTY_KEY: SigningKey = SigningKey {
+    id: "Example third-party driver key",
+    secret: b"third-party-driver-signing-key",
+};
+
+// The arm64 SoC's TBBR chain of trust. The ROT key's hash is fused into the
+// SoC; it signs the world keys, which sign each image's content key.
+pub const ROT_KEY: SigningKey = SigningKey {
+    id: "QVLX SoC root of trust key",
+    secret: b"qvlx-soc-rot-key",
+};
+
+pub const TRUSTED_WORLD_KEY: SigningKey = SigningKey {
+    id: "QVLX trusted world key",
+    secret: b"qvlx-trusted-world-key",
+};
+
+pub const NON_TRUSTED_WORLD_KEY: SigningKey = SigningKey {
+    id: "QVLX non-trusted world key",
+    secret: b"qvlx-non-trusted-world-key",
+};
+
+pub const SOC_FW_CONTENT_KEY: SigningKey = SigningKey {
+    id: "QVLX SoC firmware content key",
+    secret: b"qvlx-soc-fw-content-key",
+};
+
+pub const TOS_FW_CONTENT_KEY: SigningKey = SigningKey {
+    id: "QVLX trusted OS content key",
+    secret: b"qvlx-tos-fw-content-key",
+};
+
+pub const NT_FW_CONTENT_KEY: SigningKey = SigningKey {
+    id: "QVLX non-trusted firmware content key",
+    secret: b"qvlx-nt-fw-content-key",
+};
+
+// Built into the trusted OS; every trusted application must be signed with it
+pub const TA_SIGNING_KEY: SigningKey = SigningKey {
+    id: "QVLX OP-TEE TA signing key",
+    secret: b"qvlx-optee-ta-signing-key",
+};
+
+// The riscv64 SoC's boot ROM trusts the key whose hash is in OTP to sign
+// OpenSBI; OpenSBI carries the key the S-mode kernel must be signed with.
+pub const RISCV_ROM_KEY: SigningKey = SigningKey {
+    id: "QVLX RISC-V boot ROM key",
+    secret: b"qvlx-riscv-rom-key",
+};
+
+pub const RISCV_KERNEL_KEY: SigningKey = SigningKey {
+    id: "QVLX RISC-V kernel signing key",
+    secret: b"qvlx-riscv-kernel-key",
+};
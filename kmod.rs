@@ -0,0 +1,174 @@
+// $t@$h
+use crate::keys::{SigningKey, RELEASE_KEY, THIRD_PARTY_KEY};
+
+// Keys compiled into the guest kernel's builtin trusted keyring
+pub const BUILTIN_TRUSTED_KEYS: [&SigningKey; 1] = [&RELEASE_KEY];
+
+// Kernel lockdown levels. Each level includes the restrictions of the ones below it.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Lockdown {
+    None,
+    Integrity,
+    Confidentiality,
+}
+
+impl Lockdown {
+    pub fn parse(s: &str) -> Option<Lockdown> {
+        match s {
+            "none" => Some(Lockdown::None),
+            "integrity" => Some(Lockdown::Integrity),
+            "confidentiality" => Some(Lockdown::Confidentiality),
+            _ => None,
+        }
+    }
+}
+
+// Operations lockdown can refuse, with the level that starts refusing them
+#[derive(Debug, Clone, Copy)]
+pub enum LockdownReason {
+    UnsignedModule,
+    DevMem,
+    Kcore,
+    Kexec,
+}
+
+impl LockdownReason {
+    fn level(&self) -> Lockdown {
+        match self {
+            LockdownReason::UnsignedModule | LockdownReason::DevMem | LockdownReason::Kexec => Lockdown::Integrity,
+            LockdownReason::Kcore => Lockdown::Confidentiality,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            LockdownReason::UnsignedModule => "unsigned module loading",
+            LockdownReason::DevMem => "/dev/mem,kmem,port",
+            LockdownReason::Kcore => "/proc/kcore access",
+            LockdownReason::Kexec => "kexec of unsigned images",
+        }
+    }
+}
+
+// Whether a signature was made by a key on the builtin trusted keyring
+pub fn keyring_verifies(signature: Option<(&str, [u8; 32])>, data: &[u8]) -> bool {
+    match signature {
+        Some((key_id, sig)) => BUILTIN_TRUSTED_KEYS.iter().any(|key| key.id == key_id && key.verify(data, &sig)),
+        None => false,
+    }
+}
+
+// A .ko file with an optional appended signature
+pub struct ModuleImage {
+    pub name: &'static str,
+    pub code: &'static [u8],
+    pub signature: Option<(&'static str, [u8; 32])>,
+}
+
+// The modules that ship on the guest's root filesystem
+pub fn module_catalog() -> Vec<ModuleImage> {
+    let signed = |key: &SigningKey, code: &[u8]| Some((key.id, key.sign(code)));
+    vec![
+        ModuleImage { name: "e1000", code: b"e1000 network driver", signature: signed(&RELEASE_KEY, b"e1000 network driver") },
+        ModuleImage { name: "dm_crypt", code: b"dm-crypt target", signature: signed(&RELEASE_KEY, b"dm-crypt target") },
+        ModuleImage { name: "vboxdrv", code: b"out-of-tree hypervisor driver", signature: signed(&THIRD_PARTY_KEY, b"out-of-tree hypervisor driver") },
+        ModuleImage { name: "rootkit", code: b"hide processes and files", signature: None },
+    ]
+}
+
+pub struct KernelIntegrity {
+    pub lockdown: Lockdown,
+    pub sig_enforce: bool,
+    pub loaded: Vec<&'static str>,
+    pub tainted: bool,
+}
+
+impl KernelIntegrity {
+    pub fn new() -> Self {
+        KernelIntegrity {
+            lockdown: Lockdown::None,
+            sig_enforce: false,
+            loaded: Vec::new(),
+            tainted: false,
+        }
+    }
+
+    pub fn check(&self, reason: LockdownReason) -> Result<(), String> {
+        if self.lockdown >= reason.level() {
+            return Err(format!(
+                "Lockdown: {} is restricted; see man kernel_lockdown.7",
+                reason.describe()
+            ));
+        }
+        Ok(())
+    }
+
+    // Lockdown only ever tightens until the next boot
+    pub fn set_lockdown(&mut self, level: Lockdown) -> Result<(), String> {
+        if level < self.lockdown {
+            return Err(format!("Lockdown cannot be relaxed from {:?} to {:?}", self.lockdown, level));
+        }
+        self.lockdown = level;
+        if level != Lockdown::None {
+            self.sig_enforce = true;
+        }
+        Ok(())
+    }
+
+    pub fn set_sig_enforce(&mut self, enforce: bool) -> Result<(), String> {
+        if !enforce && self.lockdown != Lockdown::None {
+            return Err("Module signature enforcement is forced on by lockdown".to_string());
+        }
+        self.sig_enforce = enforce;
+        Ok(())
+    }
+
+    pub fn insmod(&mut self, name: &str) -> Result<(), String> {
+        let catalog = module_catalog();
+        let module = catalog
+            .iter()
+            .find(|m| m.name == name)
+            .ok_or_else(|| format!("insmod: ERROR: could not load module {}: No such file", name))?;
+        if self.loaded.contains(&module.name) {
+            return Err(format!("insmod: ERROR: could not insert module {}: File exists", name));
+        }
+
+        if !keyring_verifies(module.signature, module.code) {
+            if self.sig_enforce {
+                self.check(LockdownReason::UnsignedModule)?;
+                return Err(format!("insmod: ERROR: could not insert module {}: Key was rejected by service", name));
+            }
+            println!("{}: module verification failed: signature and/or required key missing - tainting kernel", name);
+            self.tainted = true;
+        }
+        self.loaded.push(module.name);
+        println!("Module {} loaded", name);
+        Ok(())
+    }
+
+    pub fn rmmod(&mut self, name: &str) -> Result<(), String> {
+        let index = self
+            .loaded
+            .iter()
+            .position(|&m| m == name)
+            .ok_or_else(|| format!("rmmod: ERROR: Module {} is not currently loaded", name))?;
+        self.loaded.remove(index);
+        println!("Module {} unloaded", name);
+        Ok(())
+    }
+
+    pub fn lsmod(&self) {
+        println!("Module");
+        for name in &self.loaded {
+            println!(" {}", name);
+        }
+        println!("lockdown={:?} sig_enforce={} tainted={}", self.lockdown, self.sig_enforce, self.tainted);
+    }
+}
+
+pub fn print_keyring() {
+    println!(".builtin_trusted_keys:");
+    for key in BUILTIN_TRUSTED_KEYS.iter() {
+        println!(" asymmetric: {}", key.id);
+    }
+}
@@ -1,11 +1,43 @@
 // $t@$h
-use rustyline::error::ReadlineError;
-use rustyline::Editor;
+mod cc;
+mod cpu;
+// Shared with the arm64 edition, which uses the rest of it
+#[allow(dead_code)]
+mod crypto;
+mod dram;
+mod elf;
+mod engine;
+mod fde;
+mod hypercall;
+mod ima;
+mod memory;
+mod nested;
+mod net;
+mod process;
+mod smp;
+mod keys;
+mod kmod;
+mod tpm;
+mod verity;
+mod vm;
+
 use std::collections::HashMap;
 use std::sync::Mutex;
 use lazy_static::lazy_static;
 use std::io::Write;
+use cc::CcTech;
+use dram::{Temperature, DRAM};
+use engine::{report, CommandResult, Engine, Instruction, InstructionHandler, Machine, Transition};
+use fde::{VOLUME, VOLUME_KEY_ADDR};
+use memory::{hexdump, parse_addr, parse_hex, parse_u64, HOST_MEMORY};
+use nested::ExitReason;
+use net::NETWORK;
+use smp::{Smp, TRAMPOLINE_VECTOR};
+use tpm::{describe_policy, PolicyStep, SealedBlob, Tpm, PCR_COUNT, BOOTLOADER_MEASUREMENT, FIRMWARE_MEASUREMENT, HYPERVISOR_MEASUREMENT, PCR_FIRMWARE, PCR_HYPERVISOR, PCR_KERNEL, PLATFORM_TPM};
+use verity::{CorruptionMode, BLOCK_SIZE};
+use vm::{attest_guest, with_current_guest, DEFAULT_APPLICATION, HYPERVISOR};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone, Copy)]
 enum Mode {
     User,
@@ -15,14 +47,6 @@ enum Mode {
     Off,
 }
 
-enum CommandResult {
-    Success,
-    NotVerified,
-    UnknownCommand,
-}
-
-type InstructionHandler = fn();
-
 struct State {
     mode: Mode,
 }
@@ -43,76 +67,1017 @@ impl State {
     }
 }
 
-fn process_command(command: &str, state: &mut State) -> CommandResult {
+// The mode the console is driving: the selected guest's mode while the
+// platform sits in the hypervisor, otherwise the platform mode
+fn effective_mode(state: &State) -> Mode {
+    if state.current_mode() == Mode::Hypervisor {
+        if let Some(guest) = HYPERVISOR.lock().unwrap().current() {
+            return guest.state.current_mode();
+        }
+    }
+    state.current_mode()
+}
+
+fn process_vm_command(args: &[&str], state: &State) -> CommandResult {
+    if state.current_mode() != Mode::Hypervisor {
+        println!("Guests are managed by the hypervisor. Load it first.");
+        return CommandResult::Failed;
+    }
+    // A running guest's console is the guest's, not the hypervisor's
+    let attached = effective_mode(state) != Mode::Hypervisor;
+    let mut hv = HYPERVISOR.lock().unwrap();
+    let result = match args {
+        ["leave"] => {
+            hv.leave();
+            println!("Console returned to the hypervisor.");
+            Ok(())
+        },
+        _ if attached => Err(format!(
+            "Guest '{}' is running and cannot manage guests. 'vm leave' returns the console to the hypervisor.",
+            hv.current().map_or("", |g| g.name.as_str())
+        )),
+        ["list"] => {
+            hv.list();
+            Ok(())
+        },
+        ["create", name] => hv.create(name, None).map(|_| println!("Guest '{}' created.", name)),
+        ["create", name, tech] => match CcTech::parse(tech) {
+            Some(tech) => hv.create(name, Some(tech)).map(|_| println!("Confidential {:?} guest '{}' created.", tech, name)),
+            None => Err(format!("Unknown confidential computing technology '{}'. Use snp or tdx.", tech)),
+        },
+        ["destroy", name] => hv.destroy(name).map(|_| println!("Guest '{}' destroyed.", name)),
+        ["enter", name] => hv.enter(name).map(|_| println!("Console attached to guest '{}'.", name)),
+        // Host-side views of guest state, as a curious or malicious hypervisor would use them
+        ["read", name, addr, rest @ ..] => match (parse_addr(addr), rest.first().map_or(Some(64), |l| parse_addr(l))) {
+            (Some(addr), Some(len)) => hv.get_mut(name).and_then(|g| g.memory.host_read(addr, len)).map(|bytes| hexdump(addr, &bytes)),
+            _ => Err("Usage: vm read <name> <addr> [len]".to_string()),
+        },
+        ["write", name, addr, data] => match (parse_addr(addr), parse_hex(data)) {
+            (Some(addr), Some(data)) => hv.get_mut(name).and_then(|g| g.memory.host_write(addr, &data)).map(|_| println!("Wrote {} bytes into guest '{}'.", data.len(), name)),
+            _ => Err("Usage: vm write <name> <addr> <hexbytes>".to_string()),
+        },
+        ["policy", name] => hv.get_mut(name).map(|g| g.print_policy()),
+        ["allow", name, call] | ["deny", name, call] => match hypercall::lookup(call) {
+            Some(nr) => hv.get_mut(name).map(|g| {
+                g.hypercall_policy.retain(|&n| n != nr);
+                if args[0] == "allow" {
+                    g.hypercall_policy.push(nr);
+                }
+                g.print_policy();
+            }),
+            None => Err(format!("Unknown hypercall '{}'", call)),
+        },
+        ["corrupt", name, block] => match parse_addr(block) {
+            Some(index) => hv.get_mut(name).and_then(|g| match g.disk.data.get_mut(index) {
+                Some(data) => {
+                    data[0] ^= 0xff;
+                    println!("Flipped a byte in block {} of the disk of guest '{}'.", index, name);
+                    Ok(())
+                },
+                None => Err(format!("Block {} is beyond the end of the device", index)),
+            }),
+            None => Err("Usage: vm corrupt <name> <block>".to_string()),
+        },
+        ["tamper", name] | ["tamper", name, "kernel"] => hv.get_mut(name).map(|g| {
+            g.kernel_image.extend_from_slice(b" +rootkit");
+            println!("Patched the kernel image of guest '{}'.", name);
+        }),
+        ["tamper", name, "l1"] => hv.get_mut(name).map(|g| {
+            g.l1_hypervisor_image.extend_from_slice(b" +rootkit");
+            println!("Patched the L1 hypervisor image of guest '{}'.", name);
+        }),
+        _ => Err("Usage: vm list | vm create <name> [snp|tdx] | vm destroy <name> | vm enter <name> | vm leave\n       vm read <name> <addr> [len] | vm write <name> <addr> <hexbytes> | vm tamper <name> [kernel|l1] | vm corrupt <name> <block>\n       vm policy <name> | vm allow <name> <hypercall> | vm deny <name> <hypercall>".to_string()),
+    };
+    report(result)
+}
+
+// Kernel integrity beyond boot: module loading, lockdown and raw memory access
+fn process_kernel_command(command: &str, args: &[&str]) -> CommandResult {
+    let mut hv = HYPERVISOR.lock().unwrap();
+    let user_allowed = matches!(command, "devmem" | "kcore");
+    let modes: &[Mode] = if user_allowed { &[Mode::Kernel, Mode::User] } else { &[Mode::Kernel] };
+    let guest = match hv.running_guest(modes) {
+        Some(guest) => guest,
+        None => {
+            println!("'{}' needs a running guest {}.", command, if user_allowed { "kernel or application" } else { "kernel" });
+            return CommandResult::Failed;
+        },
+    };
+    let result = match (command, args) {
+        ("insmod", [name]) => guest.kmod.insmod(name),
+        ("rmmod", [name]) => guest.kmod.rmmod(name),
+        ("lsmod", []) => {
+            guest.kmod.lsmod();
+            Ok(())
+        },
+        ("keyring", []) => {
+            kmod::print_keyring();
+            Ok(())
+        },
+        ("lockdown", []) => {
+            println!("Lockdown: {:?}", guest.kmod.lockdown);
+            Ok(())
+        },
+        ("lockdown", [level]) => match kmod::Lockdown::parse(level) {
+            Some(level) => guest.kmod.set_lockdown(level).map(|_| println!("Lockdown: {:?}", level)),
+            None => Err("Usage: lockdown [none|integrity|confidentiality]".to_string()),
+        },
+        ("modsign", [setting @ ("on" | "off")]) => guest
+            .kmod
+            .set_sig_enforce(*setting == "on")
+            .map(|_| println!("Module signature enforcement {}", setting)),
+        ("devmem", ["read", addr, rest @ ..]) => match (parse_addr(addr), rest.first().map_or(Some(64), |l| parse_addr(l))) {
+            (Some(addr), Some(len)) => guest
+                .kmod
+                .check(kmod::LockdownReason::DevMem)
+                .and_then(|_| guest.memory.guest_read(addr, len))
+                .map(|bytes| hexdump(addr, &bytes)),
+            _ => Err("Usage: devmem read <addr> [len]".to_string()),
+        },
+        ("devmem", ["write", addr, data]) => match (parse_addr(addr), parse_hex(data)) {
+            (Some(addr), Some(data)) => guest
+                .kmod
+                .check(kmod::LockdownReason::DevMem)
+                .and_then(|_| guest.memory.guest_write(addr, &data)),
+            _ => Err("Usage: devmem write <addr> <hexbytes>".to_string()),
+        },
+        ("kcore", []) => guest
+            .kmod
+            .check(kmod::LockdownReason::Kcore)
+            .and_then(|_| guest.memory.guest_read(0, guest.kernel_image.len()))
+            .map(|bytes| hexdump(0, &bytes)),
+        ("kexec", []) => {
+            println!("Running kernel: {}", guest.running_kernel);
+            for image in vm::kexec_catalog() {
+                let signer = image.signature.map_or("unsigned", |(id, _)| id);
+                println!(" /boot/{:<22} {}", image.name, signer);
+            }
+            Ok(())
+        },
+        ("kexec", [name]) => guest.kexec(name),
+        _ => Err("Usage: insmod <module> | rmmod <module> | lsmod | keyring | lockdown [level] | modsign <on|off>\n       devmem read <addr> [len] | devmem write <addr> <hexbytes> | kcore | kexec [image]".to_string()),
+    };
+    report(result)
+}
+
+// The console drives one CPU at a time. Starting an AP takes an IPI from a
+// CPU running host firmware or the hypervisor: guests only have vCPUs.
+fn process_smp_command(command: &str, args: &[&str], smp: &mut Smp) -> CommandResult {
+    let apic_id = |s: &str| parse_addr(s).ok_or_else(|| format!("Invalid APIC ID '{}'", s));
+    let sender = effective_mode(&smp.current().state);
+    let result = match (command, args) {
+        ("cpu", []) => {
+            smp.print();
+            Ok(())
+        },
+        ("cpu", [id]) => apic_id(id).and_then(|id| smp.switch(id)).map(|_| println!("Console on cpu{}", smp.current)),
+        ("sipi" | "init", _) if !matches!(sender, Mode::UEFI | Mode::Hypervisor) => {
+            Err(format!("'{}' programs the local APIC, which host firmware or the hypervisor owns, not {:?} mode", command, sender))
+        },
+        ("init", [id]) => apic_id(id).and_then(|id| smp.init(id)),
+        ("sipi", [id, rest @ ..]) if rest.len() <= 1 => {
+            let vector = rest.first().map_or(Some(TRAMPOLINE_VECTOR), |v| parse_addr(v).filter(|&v| v <= 0xff).map(|v| v as u8));
+            match (apic_id(id), vector) {
+                // INIT, then two SIPIs as the MP spec has it; an AP that started on the first ignores the second
+                (Ok(id), Some(vector)) => {
+                    let dram = HOST_MEMORY.lock().unwrap();
+                    smp.init(id).and_then(|_| smp.sipi(id, vector, &dram)).and_then(|_| smp.sipi(id, vector, &dram))
+                },
+                (Err(msg), _) => Err(msg),
+                _ => Err(format!("Invalid SIPI vector '{}'", rest[0])),
+            }
+        },
+        _ => Err("Usage: cpu [n] | sipi <apic id> [vector] | init <apic id>".to_string()),
+    };
+    report(result)
+}
+
+// The host's encrypted data volume
+fn process_disk_command(args: &[&str], state: &State) -> CommandResult {
+    let mut volume = VOLUME.lock().unwrap();
+    let mut dram = HOST_MEMORY.lock().unwrap();
+    let host_up = state.current_mode() == Mode::Hypervisor;
+    let in_guest = host_up && effective_mode(state) != Mode::Hypervisor;
+    let sector = |s: &str| parse_addr(s).ok_or_else(|| format!("Invalid sector '{}'", s));
+    let result = match args {
+        // Physical access to the drive works whatever the platform is doing
+        ["raw", index] => sector(index).and_then(|i| volume.raw(i)).map(|bytes| hexdump(0, &bytes[..64])),
+        _ if !host_up => Err("The data volume is mounted by the host. Load the hypervisor first.".to_string()),
+        _ if in_guest => Err("The data volume is the host's, not the running guest's. 'vm leave' returns the console to the hypervisor.".to_string()),
+        ["status"] => {
+            volume.print_status(&dram);
+            Ok(())
+        },
+        ["read", index] => sector(index).and_then(|i| volume.read(i, &dram)).map(|bytes| hexdump(0, &bytes[..64])),
+        ["write", index, text @ ..] if !text.is_empty() => sector(index).and_then(|i| volume.write(i, text.join(" ").as_bytes(), &dram)),
+        ["unlock"] => volume
+            .unlock(&PLATFORM_TPM.lock().unwrap(), &mut dram)
+            .map(|_| println!("Data volume unlocked.")),
+        ["lock"] => {
+            volume.lock(&mut dram, true);
+            println!("Data volume locked and key zeroed.");
+            Ok(())
+        },
+        _ => Err("Usage: disk status | disk read <sector> | disk write <sector> <text> | disk raw <sector> | disk unlock | disk lock".to_string()),
+    };
+    report(result)
+}
+
+// Attested, encrypted channels from a guest application to other platforms
+fn process_net_command(args: &[&str]) -> CommandResult {
+    let mut hv = HYPERVISOR.lock().unwrap();
+    let mut network = NETWORK.lock().unwrap();
+    let guest = match (hv.running_guest(&[Mode::User]), args) {
+        (_, ["peers"]) | (_, []) => {
+            network.list();
+            return CommandResult::Success;
+        },
+        (Some(guest), _) => guest,
+        (None, _) => {
+            println!("Network channels are opened by a running guest application.");
+            return CommandResult::Failed;
+        },
+    };
+    let result = match args {
+        ["connect", peer] => network.connect(guest, peer),
+        ["send", text @ ..] if !text.is_empty() => network.send(guest, &text.join(" ")).map(|reply| println!("< {}", reply)),
+        ["tamper"] => {
+            network.tamper_next = true;
+            println!("The next record on the wire will be modified in transit.");
+            Ok(())
+        },
+        ["mitm", "on"] | ["mitm", "off"] => {
+            network.mitm = args[1] == "on";
+            println!("Man-in-the-middle on the link: {}", args[1]);
+            Ok(())
+        },
+        ["close"] => {
+            network.close();
+            Ok(())
+        },
+        _ => Err("Usage: net peers | net connect <peer> | net send <text> | net tamper | net mitm <on|off> | net close".to_string()),
+    };
+    report(result)
+}
+
+// Builds a policy from `pcrs=0,4 auth=<value>` terms; `or` separates PolicyOR branches
+fn parse_policy(terms: &[&str]) -> Result<(Vec<PolicyStep>, Vec<u8>), String> {
+    let mut auth: Option<&str> = None;
+    let mut branches = Vec::new();
+    for branch in terms.split(|t| *t == "or") {
+        let mut steps = Vec::new();
+        for term in branch {
+            match term.split_once('=') {
+                Some(("pcrs", list)) => {
+                    let selection = list
+                        .split(',')
+                        .map(|i| i.parse::<usize>().ok().filter(|&i| i < PCR_COUNT))
+                        .collect::<Option<Vec<usize>>>()
+                        .ok_or_else(|| format!("Invalid PCR selection '{}'", list))?;
+                    steps.push(PolicyStep::Pcr(selection, [0u8; 32]));
+                },
+                Some(("auth", value)) if auth.is_none_or(|a| a == value) => {
+                    auth = Some(value);
+                    steps.push(PolicyStep::AuthValue);
+                },
+                Some(("auth", _)) => return Err("An object has a single auth value; every auth= must match".to_string()),
+                _ => return Err(format!("Unknown policy term '{}'", term)),
+            }
+        }
+        if steps.is_empty() {
+            return Err("Every policy branch needs a pcrs= or auth= term".to_string());
+        }
+        branches.push(steps);
+    }
+    let policy = if branches.len() == 1 { branches.remove(0) } else { vec![PolicyStep::Or(branches)] };
+    Ok((policy, auth.unwrap_or("").as_bytes().to_vec()))
+}
+
+// PolicyPCR terms seal to the PCR values the TPM holds right now
+fn bind_to_current_pcrs(policy: &mut [PolicyStep], tpm: &Tpm) {
+    for step in policy {
+        match step {
+            PolicyStep::Pcr(selection, digest) => *digest = tpm.pcrs.composite(selection),
+            PolicyStep::Or(branches) => branches.iter_mut().for_each(|b| bind_to_current_pcrs(b, tpm)),
+            PolicyStep::AuthValue => {},
+        }
+    }
+}
+
+// Sealing uses the TPM of whatever is running: a guest's vTPM, otherwise the platform TPM
+fn process_seal_command(command: &str, args: &[&str]) -> CommandResult {
+    let mut hv = HYPERVISOR.lock().unwrap();
+    let platform = PLATFORM_TPM.lock().unwrap();
+    let (owner, tpm) = match hv.running_guest(&[Mode::Kernel, Mode::User]) {
+        Some(guest) => (format!("the vTPM of guest '{}'", guest.name), &guest.vtpm),
+        None => ("the platform TPM".to_string(), &*platform),
+    };
+    let mut sealed = SEALED_OBJECTS.lock().unwrap();
+    let result = match (command, args) {
+        ("seal", [file, terms @ ..]) if !terms.is_empty() => parse_policy(terms).and_then(|(mut policy, auth)| {
+            let secret = std::fs::read(file).map_err(|e| format!("seal: {}: {}", file, e))?;
+            bind_to_current_pcrs(&mut policy, tpm);
+            let blob = tpm.seal_with_policy(&secret, policy, &auth);
+            println!("Sealed {} ({} bytes) with {}.", file, secret.len(), owner);
+            println!(" policy        {}", describe_policy(&blob.policy));
+            println!(" policy digest {}", crypto::to_hex(&blob.auth_policy));
+            sealed.insert(file.to_string(), blob);
+            Ok(())
+        }),
+        ("unseal", [file, rest @ ..]) if rest.len() <= 1 => {
+            let auth = match rest {
+                [term] => term.strip_prefix("auth=").ok_or_else(|| format!("Unknown argument '{}'", term)),
+                _ => Ok(""),
+            };
+            let blob = sealed.get(*file).ok_or_else(|| format!("Nothing sealed under '{}'", file));
+            auth.and_then(|auth| blob.and_then(|blob| tpm.unseal(blob, auth.as_bytes()))).map(|secret| {
+                println!("Unsealed {} with {}:", file, owner);
+                println!("{}", String::from_utf8_lossy(&secret));
+            })
+        },
+        ("seal", _) => Err("Usage: seal <host file> [pcrs=<i,j,..>] [auth=<value>] [or <more terms>]".to_string()),
+        _ => Err("Usage: unseal <host file> [auth=<value>]".to_string()),
+    };
+    report(result)
+}
+
+// Memory controller settings, and the cold-boot attacker who images DRAM
+fn process_dram_command(command: &str, args: &[&str], state: &State) -> CommandResult {
+    let mut dram = DRAM.lock().unwrap();
+    let switch = |s: &str| match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("Expected on or off, not '{}'", s)),
+    };
+    let result = match (command, args) {
+        ("dram", ["status"]) => {
+            dram.print_status();
+            Ok(())
+        },
+        ("dram", ["decay", model]) => Temperature::parse(model)
+            .map(|t| dram.temperature = t)
+            .ok_or_else(|| "Usage: dram decay <room|chilled|frozen>".to_string()),
+        ("dram", ["tme", setting]) => switch(setting).map(|on| dram.tme = on),
+        ("dram", ["mor", setting]) => switch(setting).map(|on| dram.mor = on),
+        ("dram", _) => Err("Usage: dram status | dram decay <room|chilled|frozen> | dram tme <on|off> | dram mor <on|off>".to_string()),
+        ("coldboot", rest) if rest.len() <= 2 => match rest.first().map_or(Ok(0.0), |s| s.parse::<f64>()) {
+            Ok(_) if !matches!(state.current_mode(), Mode::Off | Mode::UEFI) => {
+                Err("The OS is still running. Cut the power or reset the platform first.".to_string())
+            },
+            Ok(seconds) if seconds >= 0.0 => dram.dump(&mut HOST_MEMORY.lock().unwrap(), seconds).and_then(|_| match rest.get(1) {
+                Some(file) => std::fs::write(file, dram.image().unwrap())
+                    .map(|_| println!("Wrote DRAM image to {}", file))
+                    .map_err(|e| format!("coldboot: {}: {}", file, e)),
+                None => Ok(()),
+            }),
+            _ => Err(format!("Invalid number of seconds '{}'", rest[0])),
+        },
+        ("coldboot", _) => Err("Usage: coldboot [seconds after power loss] [dump file]".to_string()),
+        ("keyfind", []) => match dram.image() {
+            Some(image) => dram::keyfind(image),
+            None => Err("No DRAM image yet. Type 'coldboot' after a shutdown or reset.".to_string()),
+        },
+        ("keyfind", [file]) => std::fs::read(file).map_err(|e| format!("keyfind: {}: {}", file, e)).and_then(|image| dram::keyfind(&image)),
+        _ => Err("Usage: keyfind [dump file]".to_string()),
+    };
+    report(result)
+}
+
+// Every platform reset restarts the firmware, tears down the hypervisor and
+// its guests, and forgets what was verified. The TPM sees TPM2_Startup(CLEAR):
+// its PCRs start over so they can only describe the new boot, but it is not
+// cleared, so the endorsement identity survives.
+fn reset_platform(smp: &mut Smp, next: Mode) {
+    HYPERVISOR.lock().unwrap().reset();
+    NETWORK.lock().unwrap().close();
+    VOLUME.lock().unwrap().lock(&mut HOST_MEMORY.lock().unwrap(), false);
+    // Nothing is wiped here: DRAM holds its charge for a while without power
+    // and all of it across a warm reset, unless MOR or TME step in
+    let mut dram = DRAM.lock().unwrap();
+    if next == Mode::Off {
+        dram.power_off();
+    } else {
+        dram.reset(&mut HOST_MEMORY.lock().unwrap());
+    }
+    drop(dram);
+    PLATFORM_TPM.lock().unwrap().startup_clear();
+    *BOOT_STAGE.lock().unwrap() = BootStage::Reset;
+    smp.reset(next);
+}
+
+// Reads a `<binary>.sig` file: the signer's key id, then the hex signature
+fn read_detached_signature(file: &str) -> Result<Option<(&'static str, [u8; 32])>, String> {
+    let text = match std::fs::read_to_string(format!("{}.sig", file)) {
+        Ok(text) => text,
+        Err(_) => return Ok(None),
+    };
+    let mut lines = text.lines();
+    let key = lines.next().and_then(keys::find_key).ok_or("Detached signature names an unknown key")?;
+    match lines.next().and_then(parse_hex) {
+        Some(sig) if sig.len() == 32 => Ok(Some((key.id, sig.try_into().unwrap()))),
+        _ => Err("Detached signature is malformed".to_string()),
+    }
+}
+
+// Executables on the guest root filesystem
+fn process_binary_command(command: &str, args: &[&str]) -> CommandResult {
+    let mut hv = HYPERVISOR.lock().unwrap();
+    let guest = match hv.running_guest(&[Mode::Kernel, Mode::User]) {
+        Some(guest) => guest,
+        None => {
+            println!("'{}' needs a running guest kernel.", command);
+            return CommandResult::Failed;
+        },
+    };
+    let result = match (command, args) {
+        ("readelf", [path]) => guest.ima.file_mut(path).and_then(|f| elf::print_headers(&f.content, f.ima)),
+        // Package installation from the host: copy the binary in, with its
+        // detached signature (<file>.sig) as security.ima
+        ("install", [file, rest @ ..]) if rest.len() <= 1 && guest.state.current_mode() == Mode::Kernel => {
+            let name = file.rsplit('/').next().unwrap_or(file);
+            let path = rest.first().map_or(format!("/usr/local/bin/{}", name), |p| p.to_string());
+            std::fs::read(file)
+                .map_err(|e| format!("install: {}: {}", file, e))
+                .and_then(|bytes| read_detached_signature(file).map(|sig| (bytes, sig)))
+                .map(|(bytes, sig)| {
+                    guest.ima.add_file(&path, &bytes, sig);
+                    let signer = sig.map_or("unsigned", |(id, _)| id);
+                    println!("Installed {} as {} ({} bytes, {})", file, path, bytes.len(), signer);
+                })
+        },
+        ("install", _) if guest.state.current_mode() != Mode::Kernel => Err("Only the kernel installs files".to_string()),
+        _ => Err("Usage: readelf <path> | install <host file> [path]".to_string()),
+    };
+    report(result)
+}
+
+// The most timer interrupts one `tick` delivers
+const MAX_TICKS: u64 = 1000;
+
+// User processes of the running guest and the scheduler that multiplexes them
+fn process_sched_command(command: &str, args: &[&str]) -> CommandResult {
+    let mut hv = HYPERVISOR.lock().unwrap();
+    let guest = match hv.running_guest(&[Mode::Kernel, Mode::User]) {
+        Some(guest) => guest,
+        None => {
+            println!("Processes live in a running guest kernel.");
+            return CommandResult::Failed;
+        },
+    };
+    let parse_pid = |s: &str| s.parse::<u32>().map_err(|_| format!("Invalid pid '{}'", s));
+    let result = match (command, args) {
+        ("ps", []) => {
+            guest.sched.ps(&guest.regs);
+            Ok(())
+        },
+        ("spawn", [path]) => guest.spawn(path, 1).map(|_| ()),
+        ("spawn", [path, priority]) => match priority.parse::<u8>() {
+            Ok(priority) => guest.spawn(path, priority).map(|_| ()),
+            Err(_) => Err("Priority is a number from 0 to 255".to_string()),
+        },
+        ("kill", [pid]) => parse_pid(pid).and_then(|pid| guest.kill(pid)),
+        ("maps", [pid]) => parse_pid(pid).and_then(|pid| guest.sched.print_maps(pid)),
+        // Switching and the timer only mean something once user space is running
+        ("switch", _) | ("tick", _) if guest.state.current_mode() != Mode::User => {
+            Err("No user space yet. Type 'load_application' first.".to_string())
+        },
+        ("switch", [pid]) => parse_pid(pid).and_then(|pid| guest.sched.switch_to(pid, &mut guest.regs)),
+        ("tick", rest @ ([] | [_])) => match rest.first().map_or(Some(1), |n| parse_u64(n)) {
+            Some(n) if n > MAX_TICKS => Err(format!("tick delivers at most {} timer interrupts at a time", MAX_TICKS)),
+            Some(n) => {
+                guest.sched.tick(n, &mut guest.regs);
+                Ok(())
+            },
+            None => Err("Usage: tick [n]".to_string()),
+        },
+        ("sched", []) => {
+            println!("Scheduler policy: {:?}", guest.sched.policy);
+            Ok(())
+        },
+        ("sched", [policy]) => match process::Policy::parse(policy) {
+            Some(policy) => {
+                guest.sched.policy = policy;
+                println!("Scheduler policy: {:?}", policy);
+                Ok(())
+            },
+            None => Err("Usage: sched [rr|prio]".to_string()),
+        },
+        _ => Err("Usage: ps | spawn <path> [priority] | kill <pid> | switch <pid> | tick [n] | sched [rr|prio] | maps <pid>".to_string()),
+    };
+    report(result)
+}
+
+fn process_command(command: &str, args: &[&str], smp: &mut Smp) -> CommandResult {
+    // An AP that started from a page the hypervisor did not install is not
+    // part of the verified platform, whatever mode it has reached
+    if let Some(addr) = smp.current().unverified {
+        if matches!(command, "vm" | "disk" | "seal" | "unseal" | "load_hypervisor" | "load_kernel" | "load_application") {
+            println!("cpu{} is running unverified code from {:#x}. '{}' is a host service it cannot use.", smp.current, addr, command);
+            return CommandResult::Failed;
+        }
+    }
     match command {
+        "insmod" | "rmmod" | "lsmod" | "keyring" | "lockdown" | "modsign" | "devmem" | "kcore" | "kexec" => process_kernel_command(command, args),
+        "readelf" | "install" => process_binary_command(command, args),
+        // The vendor's build server: sign a host binary with the release key
+        "elfsign" => {
+            let result = match args {
+                [file] => std::fs::read(file).map_err(|e| format!("elfsign: {}: {}", file, e)).and_then(|bytes| {
+                    let sig_file = format!("{}.sig", file);
+                    std::fs::write(&sig_file, elf::detached_signature(&bytes, &keys::RELEASE_KEY))
+                        .map(|_| println!("Wrote detached signature {}", sig_file))
+                        .map_err(|e| format!("elfsign: {}: {}", sig_file, e))
+                }),
+                _ => Err("Usage: elfsign <host file>".to_string()),
+            };
+            report(result)
+        },
+        "ps" | "spawn" | "kill" | "switch" | "tick" | "sched" | "maps" => process_sched_command(command, args),
+        "vm" => process_vm_command(args, &smp.current().state),
+        "disk" => process_disk_command(args, &smp.current().state),
+        "net" => process_net_command(args),
+        "seal" | "unseal" => process_seal_command(command, args),
+        "dram" | "coldboot" | "keyfind" => process_dram_command(command, args, &smp.current().state),
+        "cpu" | "sipi" | "init" => process_smp_command(command, args, smp),
+        "pcrs" => {
+            let hv = HYPERVISOR.lock().unwrap();
+            match hv.current() {
+                Some(guest) if smp.mode() == Mode::Hypervisor && args.first() != Some(&"platform") => {
+                    println!("vTPM PCR bank of guest '{}':", guest.name);
+                    guest.vtpm.pcrs.print();
+                },
+                _ => {
+                    println!("Platform TPM PCR bank:");
+                    PLATFORM_TPM.lock().unwrap().pcrs.print();
+                },
+            }
+            CommandResult::Success
+        },
+        "mem" => {
+            let mut hv = HYPERVISOR.lock().unwrap();
+            let guest = match hv.current_mut() {
+                Some(guest) if guest.state.current_mode() != Mode::Hypervisor => guest,
+                _ => {
+                    println!("Guest memory is accessed from a running guest.");
+                    return CommandResult::Failed;
+                },
+            };
+            // User space only ever sees the virtual address space of the running process
+            let in_user = guest.state.current_mode() == Mode::User;
+            let result = match args {
+                ["read", addr, rest @ ..] => match (parse_addr(addr), rest.first().map_or(Some(64), |l| parse_addr(l))) {
+                    (Some(addr), Some(len)) if in_user => match guest.sched.running() {
+                        Some(process) => process.memory.read(addr, len).map(|bytes| hexdump(addr, &bytes)),
+                        None => Err("No process is running".to_string()),
+                    },
+                    (Some(addr), Some(len)) => guest.memory.guest_read(addr, len).map(|bytes| hexdump(addr, &bytes)),
+                    _ => Err("Usage: mem read <addr> [len]".to_string()),
+                },
+                ["write", addr, data] => match (parse_addr(addr), parse_hex(data)) {
+                    (Some(addr), Some(data)) if in_user => match guest.sched.running_mut() {
+                        Some(process) => process.memory.write(addr, &data),
+                        None => Err("No process is running".to_string()),
+                    },
+                    (Some(addr), Some(data)) => guest.memory.guest_write(addr, &data),
+                    _ => Err("Usage: mem write <addr> <hexbytes>".to_string()),
+                },
+                _ => Err("Usage: mem read <addr> [len] | mem write <addr> <hexbytes>".to_string()),
+            };
+            report(result)
+        },
+        "regs" | "reg" => {
+            let mut hv = HYPERVISOR.lock().unwrap();
+            // A running guest's vCPU, otherwise the physical CPU the console drives
+            let regs = match hv.current_mut() {
+                Some(guest) if guest.state.current_mode() != Mode::Hypervisor => &mut guest.regs,
+                _ if smp.mode() != Mode::Off => &mut smp.current_mut().regs,
+                _ => {
+                    println!("Registers belong to a running CPU.");
+                    return CommandResult::Failed;
+                },
+            };
+            match args {
+                [] => regs.print(),
+                [name, value] => match (parse_u64(value), regs.get_mut(name)) {
+                    (Some(value), Some(reg)) => *reg = value,
+                    _ => {
+                        println!("Usage: reg <rax|rbx|rcx|rdx|rsi|rdi|rsp|rip> <value>");
+                        return CommandResult::Failed;
+                    },
+                },
+                _ => {
+                    println!("Usage: regs | reg <name> <value>");
+                    return CommandResult::Failed;
+                },
+            }
+            CommandResult::Success
+        },
+        "fs" => {
+            let mut hv = HYPERVISOR.lock().unwrap();
+            let guest = match hv.current_mut() {
+                Some(guest) if matches!(guest.state.current_mode(), Mode::Kernel | Mode::User) => guest,
+                _ => {
+                    println!("The filesystem is accessed from a running guest.");
+                    return CommandResult::Failed;
+                },
+            };
+            let result = match args {
+                ["read", block] => match parse_addr(block) {
+                    Some(index) => guest.read_block(index).map(|data| hexdump(index * BLOCK_SIZE, &data[..64])),
+                    None => Err("Usage: fs read <block>".to_string()),
+                },
+                ["verify"] => (0..guest.disk.data.len())
+                    .try_for_each(|i| guest.read_block(i).map(|_| ()))
+                    .map(|_| println!("All {} blocks verified.", guest.disk.data.len())),
+                // Raw device access underneath dm-verity, as a root attacker would
+                ["write", block, data] if guest.state.current_mode() == Mode::Kernel => match (parse_addr(block), parse_hex(data)) {
+                    (Some(index), Some(bytes)) if index < guest.disk.data.len() && bytes.len() <= BLOCK_SIZE => {
+                        guest.disk.data[index][..bytes.len()].copy_from_slice(&bytes);
+                        println!("Wrote {} bytes to raw block {}.", bytes.len(), index);
+                        Ok(())
+                    },
+                    _ => Err("Usage: fs write <block> <hexbytes>".to_string()),
+                },
+                ["write", ..] => Err("Permission denied: raw block device access needs the kernel".to_string()),
+                ["mode", mode] => match CorruptionMode::parse(mode) {
+                    Some(mode) => {
+                        guest.verity_mode = mode;
+                        if let Some(target) = guest.verity.as_mut() {
+                            target.mode = mode;
+                        }
+                        println!("dm-verity corruption behaviour set to {:?}", mode);
+                        Ok(())
+                    },
+                    None => Err("Usage: fs mode <eio|restart|panic>".to_string()),
+                },
+                ["status"] => {
+                    match &guest.verity {
+                        Some(target) => target.print_status(),
+                        None => println!("No dm-verity mapping. Type 'verify_filesystem' in the kernel."),
+                    }
+                    println!(" cmdline: {}", guest.cmdline);
+                    Ok(())
+                },
+                _ => Err("Usage: fs read <block> | fs write <block> <hexbytes> | fs verify | fs mode <eio|restart|panic> | fs status".to_string()),
+            };
+            if let Err(msg) = result {
+                println!("{}", msg);
+            }
+            // Corruption handling may have restarted or halted the guest
+            CommandResult::Success
+        },
+        "exec" => {
+            let mut hv = HYPERVISOR.lock().unwrap();
+            match (hv.running_guest(&[Mode::User]), args) {
+                (Some(guest), [path]) => match guest.exec(path) {
+                    Ok(()) => println!("Executed {}", path),
+                    Err(msg) => println!("{}", msg),
+                },
+                (Some(_), _) => println!("Usage: exec <path>"),
+                (None, _) => println!("Programs are executed from a running application (User mode)."),
+            }
+            CommandResult::Success
+        },
+        "ima" => {
+            let mut hv = HYPERVISOR.lock().unwrap();
+            let guest = match hv.running_guest(&[Mode::Kernel, Mode::User]) {
+                Some(guest) => guest,
+                None => {
+                    println!("IMA lives in a running guest kernel.");
+                    return CommandResult::Failed;
+                },
+            };
+            let in_kernel = guest.state.current_mode() == Mode::Kernel;
+            let result = match args {
+                ["log"] => {
+                    guest.ima.print_ascii();
+                    Ok(())
+                },
+                ["export", file] => std::fs::write(file, guest.ima.binary_log())
+                    .map(|_| println!("Wrote {} measurements to {}", guest.ima.log.len(), file))
+                    .map_err(|e| format!("Export failed: {}", e)),
+                ["verify"] => guest.ima.replay().map(|pcr10| {
+                    let matches = pcr10 == guest.vtpm.pcrs.read(tpm::PCR_APPLICATION);
+                    println!("Replayed PCR 10: {}", crypto::to_hex(&pcr10));
+                    println!("Measurement list {} the vTPM", if matches { "matches" } else { "DOES NOT match" });
+                }),
+                ["files"] => {
+                    for f in &guest.ima.files {
+                        let signer = f.ima.map_or("unsigned", |(id, _)| id);
+                        println!(" {:<14} {}", f.path, signer);
+                    }
+                    Ok(())
+                },
+                ["appraise", mode] if in_kernel => match ima::Appraise::parse(mode) {
+                    Some(mode) => {
+                        guest.ima.appraise = mode;
+                        println!("IMA appraisal: {:?}", mode);
+                        Ok(())
+                    },
+                    None => Err("Usage: ima appraise <enforce|log|off>".to_string()),
+                },
+                // Root modifying a file in place; its xattrs stay as they were
+                ["tamper", path] if in_kernel => guest.ima.file_mut(path).map(|f| {
+                    f.content.extend_from_slice(b" +backdoor");
+                    println!("Modified {}", path);
+                }),
+                // Offline attack: copy a good security.ima onto another file
+                ["setxattr", path, from] if in_kernel => {
+                    let sig = guest.ima.file_mut(from).map(|f| f.ima);
+                    sig.and_then(|sig| guest.ima.file_mut(path).map(|f| {
+                        f.ima = sig;
+                        println!("Copied security.ima from {} to {}", from, path);
+                    }))
+                },
+                ["appraise", ..] | ["tamper", ..] | ["setxattr", ..] => Err("Permission denied: only the kernel changes IMA policy or files".to_string()),
+                _ => Err("Usage: ima log | ima export <file> | ima verify | ima files | ima appraise <enforce|log|off>\n       ima tamper <path> | ima setxattr <path> <from>".to_string()),
+            };
+            report(result)
+        },
+        "nested" => {
+            let mut hv = HYPERVISOR.lock().unwrap();
+            let guest = match hv.current_mut() {
+                Some(guest) if guest.state.current_mode() == Mode::Kernel => guest,
+                _ => {
+                    println!("Nested virtualization is driven from a running guest kernel.");
+                    return CommandResult::Failed;
+                },
+            };
+            let result = match args {
+                ["load"] => guest.load_l1_hypervisor(),
+                ["enter"] => nested::enter_l2(guest),
+                ["leave"] => nested::leave_l2(guest),
+                ["shadow", setting @ ("on" | "off")] => match guest.nested.as_mut() {
+                    Some(n) => {
+                        n.shadowing = *setting == "on";
+                        println!("VMCS shadowing {}", setting);
+                        Ok(())
+                    },
+                    None => Err("No L1 hypervisor loaded".to_string()),
+                },
+                ["status"] => match &guest.nested {
+                    Some(n) => {
+                        n.print_status();
+                        Ok(())
+                    },
+                    None => Err("No L1 hypervisor loaded".to_string()),
+                },
+                _ => Err("Usage: nested load | nested enter | nested leave | nested shadow <on|off> | nested status".to_string()),
+            };
+            report(result)
+        },
+        "vmread" | "vmwrite" => {
+            let mut hv = HYPERVISOR.lock().unwrap();
+            let nested = match hv.current_mut().and_then(|g| g.nested.as_mut()) {
+                Some(n) if !n.in_l2 => n,
+                _ => {
+                    println!("VMREAD/VMWRITE are issued by a loaded L1 hypervisor.");
+                    return CommandResult::Failed;
+                },
+            };
+            let result = match (command, args) {
+                ("vmread", [field]) => nested.vmread(field).map(|v| println!("{} = {:#x}", field, v)),
+                ("vmwrite", [field, value]) => match parse_u64(value) {
+                    Some(value) => nested.vmwrite(field, value),
+                    None => Err(format!("Invalid value '{}'", value)),
+                },
+                _ => Err("Usage: vmread <field> | vmwrite <field> <value>".to_string()),
+            };
+            report(result)
+        },
+        "hypercalls" => {
+            match HYPERVISOR.lock().unwrap().current() {
+                Some(guest) => guest.print_policy(),
+                None => println!("No guest selected. Type 'vm enter <name>' first."),
+            }
+            CommandResult::Success
+        },
+        "report" => {
+            let hv = HYPERVISOR.lock().unwrap();
+            match hv.current() {
+                Some(guest) if matches!(guest.state.current_mode(), Mode::Kernel | Mode::User) => {
+                    let data = args.first().map_or(&b""[..], |d| d.as_bytes());
+                    if guest.request_report(data) {
+                        println!("Confidential guest attestation succeeded.");
+                    } else if guest.cc.is_some() {
+                        println!("Confidential guest attestation FAILED.");
+                    }
+                    CommandResult::Success
+                },
+                _ => {
+                    println!("Attestation reports are requested from a running guest.");
+                    CommandResult::Failed
+                },
+            }
+        },
+        "attest" => {
+            let hv = HYPERVISOR.lock().unwrap();
+            match hv.current() {
+                Some(guest) if matches!(guest.state.current_mode(), Mode::Kernel | Mode::User) => {
+                    let nonce = match args.first() {
+                        Some(n) => n.as_bytes().to_vec(),
+                        None => crypto::random_bytes().to_vec(),
+                    };
+                    if attest_guest(guest, &nonce) {
+                        println!("Layered attestation succeeded.");
+                    } else {
+                        println!("Layered attestation FAILED.");
+                    }
+                    CommandResult::Success
+                },
+                _ => {
+                    println!("Attestation is requested from a running guest kernel or application.");
+                    CommandResult::Failed
+                },
+            }
+        },
 		"shutdown" => {
+            // A running guest powers off on its own; the rest of the platform stays up
+            if smp.mode() == Mode::Hypervisor {
+                let mut hv = HYPERVISOR.lock().unwrap();
+                if let Some(guest) = hv.current_mut() {
+                    if guest.state.current_mode() != Mode::Hypervisor {
+                        println!("Guest '{}' shutting down...", guest.name);
+                        guest.reset();
+                        return CommandResult::Success;
+                    }
+                }
+            }
 			println!("ACPI shutdown received.");
 			println!("Shredding sensitive data.");
-			println!("Encrypting disk and memory.");
+            VOLUME.lock().unwrap().lock(&mut HOST_MEMORY.lock().unwrap(), true);
+            println!("Volume key zeroed in DRAM at {:#x}.", VOLUME_KEY_ADDR);
+            println!("Data volume locked; only XTS ciphertext remains at rest.");
             println!("System shutting down...");
-            state.change_mode(Mode::Off);
+            reset_platform(smp, Mode::Off);
             CommandResult::Success
         },
-        "load_hypervisor" => {
-            let is_vm = IS_VERIFIED_VM.lock().unwrap();
-            if !*is_vm {
-                println!("Hypervisor not verified. Aborting.");
-                CommandResult::NotVerified
-            } else {
-                println!("Hypervisor loaded.");
-                state.change_mode(Mode::Hypervisor);
+        "reset" => match args {
+            ["warm"] => {
+                println!("Warm reset: CPUs and chipset reset, DRAM stays powered.");
+                reset_platform(smp, Mode::UEFI);
+                println!("Firmware restarted. The boot chain has to be verified and measured again.");
                 CommandResult::Success
-            }
-        },
-        "load_kernel" => {
-            let is_os = IS_VERIFIED_OS.lock().unwrap();
-            if !*is_os {
-                println!("Kernel not verified. Aborting.");
-                CommandResult::NotVerified
-            } else {
-                println!("Kernel loaded.");
-                state.change_mode(Mode::Kernel);
+            },
+            ["cold"] => {
+                println!("Cold reset: power removed from the whole board.");
+                reset_platform(smp, Mode::Off);
                 CommandResult::Success
-            }
-        },
-        "load_application" => {
-            let is_ap = IS_VERIFIED_AP.lock().unwrap();
-            if !*is_ap {
-                println!("Application not verified. Aborting.");
-                CommandResult::NotVerified
-            } else {
-                println!("Application loaded.");
-                state.change_mode(Mode::User);
-                CommandResult::Success
-            }
+            },
+            _ => {
+                println!("Usage: reset <warm|cold>");
+                CommandResult::Failed
+            },
         },
+        "load_hypervisor" => report_load(load_hypervisor(smp)),
+        "load_kernel" => report_load(load_kernel(smp)),
+        "load_application" => report_load(load_application(smp, args.first().copied().unwrap_or(DEFAULT_APPLICATION))),
         _ => CommandResult::UnknownCommand,
     }
 }
 
+fn bsp_only(smp: &Smp) -> Result<(), String> {
+    match smp.current {
+        0 => Ok(()),
+        id => Err(format!("Only the BSP walks the boot chain. cpu{} came up through a SIPI; 'cpu 0' returns to the BSP.", id)),
+    }
+}
+
+// Each load runs on the BSP from the mode just before the one it enters
+fn check_load(command: &str, smp: &Smp, from: Mode) -> Result<(), String> {
+    bsp_only(smp)?;
+    match effective_mode(&smp.current().state) {
+        mode if mode != from => Err(format!("'{}' is not possible in {:?} mode", command, mode)),
+        _ => Ok(()),
+    }
+}
+
+// Why a load was refused: the image it needs is not verified, or the load
+// is not possible at all
+enum LoadError {
+    NotVerified(String),
+    Failed(String),
+}
+
+impl LoadError {
+    fn message(self) -> String {
+        match self {
+            LoadError::NotVerified(msg) | LoadError::Failed(msg) => msg,
+        }
+    }
+}
+
+impl From<String> for LoadError {
+    fn from(msg: String) -> Self {
+        LoadError::Failed(msg)
+    }
+}
+
+fn report_load(result: Result<(), LoadError>) -> CommandResult {
+    match result {
+        Ok(()) => CommandResult::Success,
+        Err(LoadError::NotVerified(msg)) => {
+            println!("{}", msg);
+            CommandResult::NotVerified
+        },
+        Err(LoadError::Failed(msg)) => report(Err(msg)),
+    }
+}
+
+// The load commands and powerup past firmware share these, so every way
+// into the next mode goes through the same checks
+fn load_hypervisor(smp: &mut Smp) -> Result<(), LoadError> {
+    check_load("load_hypervisor", smp, Mode::UEFI)?;
+    let stage = *BOOT_STAGE.lock().unwrap();
+    if stage < BootStage::HypervisorVerified {
+        return Err(LoadError::NotVerified("Hypervisor not verified. Aborting.".to_string()));
+    }
+    advance_boot(BootStage::HypervisorLoaded)?;
+    println!("Hypervisor loaded.");
+    PLATFORM_TPM.lock().unwrap().pcrs.extend(PCR_HYPERVISOR, HYPERVISOR_MEASUREMENT);
+    // The host unlocks its data volume only if the boot chain measured as expected
+    let mut volume = VOLUME.lock().unwrap();
+    match volume.unlock(&PLATFORM_TPM.lock().unwrap(), &mut HOST_MEMORY.lock().unwrap()) {
+        Ok(()) => println!("Data volume unlocked: PCRs match the policy its key is sealed to."),
+        Err(msg) => println!("Data volume stays locked. {}", msg),
+    }
+    // APs started from here on run the hypervisor's own startup code
+    if let Err(msg) = smp.install_trampoline(&mut HOST_MEMORY.lock().unwrap()) {
+        println!("{}", msg);
+    }
+    let mut hv = HYPERVISOR.lock().unwrap();
+    if hv.create("guest0", None).is_ok() {
+        hv.enter("guest0").unwrap();
+        println!("Guest 'guest0' created. Type 'vm list' to see all guests.");
+    }
+    smp.current_mut().state.change_mode(Mode::Hypervisor);
+    Ok(())
+}
+
+fn load_kernel(smp: &Smp) -> Result<(), LoadError> {
+    check_load("load_kernel", smp, Mode::Hypervisor)?;
+    let mut hv = HYPERVISOR.lock().unwrap();
+    match hv.current_mut() {
+        None => Err(LoadError::Failed("No guest selected. Type 'vm enter <name>' first.".to_string())),
+        Some(guest) if guest.cc.is_none() && !guest.is_verified_os => Err(LoadError::NotVerified("Kernel not verified. Aborting.".to_string())),
+        Some(guest) => {
+            guest.launch_kernel();
+            println!("Kernel loaded in guest '{}'.", guest.name);
+            Ok(())
+        },
+    }
+}
+
+fn load_application(smp: &Smp, path: &str) -> Result<(), LoadError> {
+    check_load("load_application", smp, Mode::Kernel)?;
+    let mut hv = HYPERVISOR.lock().unwrap();
+    match hv.current_mut() {
+        None => Err(LoadError::Failed("No guest selected. Type 'vm enter <name>' first.".to_string())),
+        Some(guest) if !guest.is_verified_ap => Err(LoadError::NotVerified("Application not verified. Aborting.".to_string())),
+        Some(guest) => {
+            guest.start_application(path).map_err(LoadError::NotVerified)?;
+            println!("Application {} loaded in guest '{}'.", path, guest.name);
+            Ok(())
+        },
+    }
+}
+
 fn provide_hint(mode: Mode) {
     match mode {
         Mode::Off => println!("Hint: Type 'powerup' to start the board"),
         Mode::UEFI => println!("Hint: Type 'load_hypervisor' to load Hypervisor mode"),
-        Mode::Hypervisor => println!("Hint: Type 'load_kernel' to load the Kernel mode"),
+        Mode::Hypervisor => println!("Hint: Type 'load_kernel' to load the Kernel mode, 'vm list' to see the guests, or 'sipi <n>' to start AP n"),
         Mode::Kernel => println!("Hint: Type 'start_user_space' to start user space applications"),
         Mode::User => println!("Hint: Execute user-level instructions like 'ADD', 'SUB', etc."),
     }
 }
 
-fn print_instructions_list(instruction_map: &HashMap<&str, InstructionHandler>) {
-    println!("Available Instructions:");
-    for instruction in instruction_map.keys() {
-        println!(" {}", instruction);
+// How far the host boot chain has got since the last reset. It only moves
+// forward, so each stage extends its PCR once and a repeated or late step
+// cannot change what the keyslot policy and platform quotes see.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+enum BootStage {
+    Reset,
+    FirmwareInitialized,
+    BootloaderVerified,
+    HypervisorVerified,
+    HypervisorLoaded,
+}
+
+fn advance_boot(next: BootStage) -> Result<(), String> {
+    let mut stage = BOOT_STAGE.lock().unwrap();
+    if *stage >= next {
+        return Err(format!("The boot chain is already at {:?}; nothing is measured twice. Reset the platform to boot again.", *stage));
     }
+    *stage = next;
+    Ok(())
 }
 
 lazy_static! {
-	static ref IS_VERIFIED_BL: Mutex<bool> = Mutex::new(false);
-	static ref IS_VERIFIED_VM: Mutex<bool> = Mutex::new(false);
-	static ref IS_VERIFIED_OS: Mutex<bool> = Mutex::new(false);
-	static ref IS_VERIFIED_FS: Mutex<bool> = Mutex::new(false);
-	static ref IS_VERIFIED_AP: Mutex<bool> = Mutex::new(false);
+	static ref BOOT_STAGE: Mutex<BootStage> = Mutex::new(BootStage::Reset);
+	// Sealed objects by the host file they were made from; like a TPM's
+	// outputs they live outside it and survive resets
+	static ref SEALED_OBJECTS: Mutex<HashMap<String, SealedBlob>> = Mutex::new(HashMap::new());
 }
 
 fn get_prompt_color(mode: Mode) -> &'static str {
@@ -125,6 +1090,66 @@ fn get_prompt_color(mode: Mode) -> &'static str {
     }
 }
 
+// The x8664 edition on the shared engine
+struct X8664 {
+    smp: Smp,
+}
+
+impl Machine for X8664 {
+    type State = Mode;
+
+    fn state(&self) -> Mode {
+        effective_mode(&self.smp.current().state)
+    }
+
+    fn prompt(&self) -> String {
+        let mode = self.state();
+        let guest_label = match HYPERVISOR.lock().unwrap().current() {
+            Some(guest) if self.smp.mode() == Mode::Hypervisor => format!("[{}]", guest.console_label()),
+            _ => String::new(),
+        };
+        let cpu_label = match self.smp.current {
+            0 => String::new(),
+            id => format!("@cpu{}", id),
+        };
+        format!("{}{:?}{}{}>>{}", get_prompt_color(mode), mode, guest_label, cpu_label, get_prompt_color(Mode::Off))
+    }
+
+    fn hint(&self) {
+        provide_hint(self.state());
+    }
+
+    fn transition(&mut self, transition: &Transition<Mode>) -> Result<(), String> {
+        bsp_only(&self.smp)?;
+        // Only power-on is a plain mode change. After that powerup stands in
+        // for the next load command, which drives the attached guest once the
+        // hypervisor is up; a refused load leaves the mode as it was.
+        let loaded = match transition.from {
+            Mode::Off => {
+                DRAM.lock().unwrap().power_on(&mut HOST_MEMORY.lock().unwrap());
+                self.smp.current_mut().state.change_mode(transition.to);
+                println!("Switched to {:?} mode", transition.to);
+                Ok(())
+            },
+            Mode::UEFI => load_hypervisor(&mut self.smp),
+            Mode::Hypervisor => load_kernel(&self.smp),
+            _ => load_application(&self.smp, DEFAULT_APPLICATION),
+        };
+        loaded.map_err(LoadError::message)
+    }
+
+    fn process_command(&mut self, command: &str, args: &[&str]) -> CommandResult {
+        process_command(command, args, &mut self.smp)
+    }
+
+    // Each user instruction is a timer tick for the running process
+    fn after_instruction(&mut self) {
+        if let Some(guest) = HYPERVISOR.lock().unwrap().running_guest(&[Mode::User]) {
+            guest.sched.tick(1, &mut guest.regs);
+        }
+    }
+}
+
 fn main() {
     let ansi_color_code = "\x1b[38;5;197m"; // 197 is a close approximation for #FF6699 in the 256-color palette
     let reset_code = "\x1b[0m"; // Resets the color
@@ -134,186 +1159,199 @@ fn main() {
     println!("** x8664 Edition **");
     println!("type powerup once to start");
     println!("type instructions for superset");
-    let mut rl = Editor::<()>::new();
-    let mut mode = Mode::Off;
-    let mut instruction_map: HashMap<&str, InstructionHandler> = HashMap::new();
-    let mut instruction_modes: HashMap<&str, Mode> = HashMap::new();
 
     // x86/64 Instruction Handlers
-    fn add_handler() { println!("Executed ADD instruction"); }
-    fn sub_handler() { println!("Executed SUB instruction"); }
-    fn mul_handler() { println!("Executed MUL instruction"); }
-    fn div_handler() { println!("Executed DIV instruction"); }
-    fn xor_handler() { println!("Executed XOR instruction"); }
-    fn and_handler() { println!("Executed AND instruction"); }
-    fn or_handler() { println!("Executed OR instruction"); }
-    fn mov_handler() { println!("Executed MOV instruction"); }
-    fn jmp_handler() { println!("Executed JMP instruction"); }
-    fn cmp_handler() { println!("Executed CMP instruction"); }
-    fn inc_handler() { println!("Executed INC instruction"); }
-    fn dec_handler() { println!("Executed DEC instruction"); }
-    fn push_handler() { println!("Executed PUSH instruction"); }
-    fn pop_handler() { println!("Executed POP instruction"); }
-    fn call_handler() { println!("Executed CALL instruction"); }
-    fn ret_handler() { println!("Executed RET instruction"); }
-    fn nop_handler() { println!("Executed NOP instruction"); }
-    fn lea_handler() { println!("Executed LEA instruction"); }
-
-    // x86/64 System-level Instruction Handlers with Secure Boot
-    fn init_initial_hw() { println!("Initialized UEFI firmware mode"); }
-	
-	let mut state = State::new();
-
-	fn change_to_hypervisor_mode(mode: &mut Mode) {
-       *mode = Mode::Hypervisor;
+    fn add_handler(_: &[&str]) { println!("Executed ADD instruction"); }
+    fn sub_handler(_: &[&str]) { println!("Executed SUB instruction"); }
+    fn mul_handler(_: &[&str]) { println!("Executed MUL instruction"); }
+    fn div_handler(_: &[&str]) { println!("Executed DIV instruction"); }
+    fn xor_handler(_: &[&str]) { println!("Executed XOR instruction"); }
+    fn and_handler(_: &[&str]) { println!("Executed AND instruction"); }
+    fn or_handler(_: &[&str]) { println!("Executed OR instruction"); }
+    fn mov_handler(_: &[&str]) { println!("Executed MOV instruction"); }
+    fn jmp_handler(_: &[&str]) { println!("Executed JMP instruction"); }
+    fn cmp_handler(_: &[&str]) { println!("Executed CMP instruction"); }
+    fn inc_handler(_: &[&str]) { println!("Executed INC instruction"); }
+    fn dec_handler(_: &[&str]) { println!("Executed DEC instruction"); }
+    fn push_handler(_: &[&str]) { println!("Executed PUSH instruction"); }
+    fn pop_handler(_: &[&str]) { println!("Executed POP instruction"); }
+    fn call_handler(_: &[&str]) { println!("Executed CALL instruction"); }
+    fn ret_handler(_: &[&str]) { println!("Executed RET instruction"); }
+    fn nop_handler(_: &[&str]) { println!("Executed NOP instruction"); }
+    fn lea_handler(_: &[&str]) { println!("Executed LEA instruction"); }
+    fn vmcall_handler(_: &[&str]) {
+        with_current_guest(|guest| match &guest.nested {
+            Some(n) if n.in_l2 => nested::l2_exit(guest, ExitReason::Vmcall),
+            _ => hypercall::vmcall(guest),
+        });
     }
-	
-	fn change_to_kernel_mode(mode: &mut Mode) {
-       *mode = Mode::Kernel;
+    fn cpuid_handler(_: &[&str]) {
+        with_current_guest(|guest| match &guest.nested {
+            Some(n) if n.in_l2 => nested::l2_exit(guest, ExitReason::Cpuid),
+            _ => nested::l0_cpuid(&mut guest.regs),
+        });
     }
-	
-	fn change_to_user_mode(mode: &mut Mode) {
-       *mode = Mode::User;
+    fn hlt_handler(_: &[&str]) {
+        with_current_guest(|guest| match &guest.nested {
+            Some(n) if n.in_l2 => nested::l2_exit(guest, ExitReason::Hlt),
+            _ => println!("[L0] VM exit (HLT), vCPU parked until the next interrupt"),
+        });
+    }
+    fn verify_l1_hypervisor(_: &[&str]) { with_current_guest(|guest| guest.verify_l1_hypervisor()); }
+
+    // x86/64 System-level Instruction Handlers with Secure Boot
+    fn init_initial_hw(_: &[&str]) {
+        match advance_boot(BootStage::FirmwareInitialized) {
+            Ok(()) => {
+                println!("Initialized UEFI firmware mode");
+                PLATFORM_TPM.lock().unwrap().pcrs.extend(PCR_FIRMWARE, FIRMWARE_MEASUREMENT);
+            },
+            Err(msg) => println!("{}", msg),
+        }
     }
 	
-	fn verify_bootloader() {
-		println!("Verified Bootloader");
-		let mut is_bl = IS_VERIFIED_BL.lock().unwrap();
-		*is_bl = true;
+	fn verify_bootloader(_: &[&str]) {
+		match advance_boot(BootStage::BootloaderVerified) {
+			Ok(()) => {
+				println!("Verified Bootloader");
+				PLATFORM_TPM.lock().unwrap().pcrs.extend(PCR_KERNEL, BOOTLOADER_MEASUREMENT);
+			},
+			Err(msg) => println!("{}", msg),
+		}
 	}
 
-	fn verify_hypervisor() {
-		println!("Verified Hypervisor");
-		let mut is_vm = IS_VERIFIED_VM.lock().unwrap();
-		*is_vm = true;
+	fn verify_hypervisor(_: &[&str]) {
+		match advance_boot(BootStage::HypervisorVerified) {
+			Ok(()) => println!("Verified Hypervisor"),
+			Err(msg) => println!("{}", msg),
+		}
 	}
 	
-	fn verify_kernel() {
-		println!("Verified Guest OS kernel");
-		let mut is_os = IS_VERIFIED_OS.lock().unwrap();
-		*is_os = true;
+	fn verify_kernel(_: &[&str]) {
+		with_current_guest(|guest| guest.verify_kernel());
 	}
 	
-	fn verify_filesystem() {
-		println!("Verified filesystem");
-		let mut is_fs = IS_VERIFIED_FS.lock().unwrap();
-		*is_fs = true;
+	fn verify_filesystem(_: &[&str]) {
+		with_current_guest(|guest| guest.verify_filesystem());
 	}
 	
-	fn verify_application() {
-		println!("Verified Application");
-		let mut is_ap = IS_VERIFIED_AP.lock().unwrap();
-		*is_ap = true;
+	fn verify_application(_: &[&str]) {
+		with_current_guest(|guest| guest.verify_application());
 	}
 
-    fn init_full_hw() { println!("Hypervisor managed hardware"); }
-    fn start_user_space() { println!("User space started"); }
+    fn init_full_hw(_: &[&str]) { println!("Hypervisor managed hardware"); }
+    fn start_user_space(_: &[&str]) { println!("User space started"); }
 
     // Instructions
-    let instructions = [
-        ("ADD", add_handler as InstructionHandler, Mode::User),
-        ("SUB", sub_handler as InstructionHandler, Mode::User),
-        ("MUL", mul_handler as InstructionHandler, Mode::User),
-        ("DIV", div_handler as InstructionHandler, Mode::User),
-        ("XOR", xor_handler as InstructionHandler, Mode::User),
-        ("AND", and_handler as InstructionHandler, Mode::User),
-        ("OR", or_handler as InstructionHandler, Mode::User),
-        ("MOV", mov_handler as InstructionHandler, Mode::User),
-        ("JMP", jmp_handler as InstructionHandler, Mode::User),
-        ("CMP", cmp_handler as InstructionHandler, Mode::User),
-        ("INC", inc_handler as InstructionHandler, Mode::User),
-        ("DEC", dec_handler as InstructionHandler, Mode::User),
-        ("PUSH", push_handler as InstructionHandler, Mode::User),
-        ("POP", pop_handler as InstructionHandler, Mode::User),
-        ("CALL", call_handler as InstructionHandler, Mode::User),
-        ("RET", ret_handler as InstructionHandler, Mode::User),
-        ("NOP", nop_handler as InstructionHandler, Mode::User),
-        ("LEA", lea_handler as InstructionHandler, Mode::User),
+    let instructions = vec![
+        Instruction { name: "ADD", handler: add_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "SUB", handler: sub_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "MUL", handler: mul_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "DIV", handler: div_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "XOR", handler: xor_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "AND", handler: and_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "OR", handler: or_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "MOV", handler: mov_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "JMP", handler: jmp_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "CMP", handler: cmp_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "INC", handler: inc_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "DEC", handler: dec_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "PUSH", handler: push_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "POP", handler: pop_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "CALL", handler: call_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "RET", handler: ret_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "NOP", handler: nop_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "LEA", handler: lea_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "VMCALL", handler: vmcall_handler as InstructionHandler, state: Mode::Kernel },
+        Instruction { name: "CPUID", handler: cpuid_handler as InstructionHandler, state: Mode::Kernel },
+        Instruction { name: "HLT", handler: hlt_handler as InstructionHandler, state: Mode::Kernel },
+        Instruction { name: "verify_l1_hypervisor", handler: verify_l1_hypervisor as InstructionHandler, state: Mode::Kernel },
 
         // System instructions (ish). I need to rework this
-        ("init_initial_hw", init_initial_hw as InstructionHandler, Mode::UEFI),
-		    ("verify_bootloader", verify_bootloader as InstructionHandler, Mode::UEFI),
-        ("verify_hypervisor", verify_hypervisor as InstructionHandler, Mode::UEFI),
-        ("init_full_hw", init_full_hw as InstructionHandler, Mode::Hypervisor),
-	    	("verify_kernel", verify_kernel as InstructionHandler, Mode::Hypervisor),
-        ("verify_filesystem", verify_filesystem as InstructionHandler, Mode::Kernel),
-        ("verify_application", verify_application as InstructionHandler, Mode::Kernel),
-        ("start_user_space", start_user_space as InstructionHandler, Mode::Kernel),
-        // TODOs: data at rest and in motion encryption logic
+        Instruction { name: "init_initial_hw", handler: init_initial_hw as InstructionHandler, state: Mode::UEFI },
+		    Instruction { name: "verify_bootloader", handler: verify_bootloader as InstructionHandler, state: Mode::UEFI },
+        Instruction { name: "verify_hypervisor", handler: verify_hypervisor as InstructionHandler, state: Mode::UEFI },
+        Instruction { name: "init_full_hw", handler: init_full_hw as InstructionHandler, state: Mode::Hypervisor },
+	    	Instruction { name: "verify_kernel", handler: verify_kernel as InstructionHandler, state: Mode::Hypervisor },
+        Instruction { name: "verify_filesystem", handler: verify_filesystem as InstructionHandler, state: Mode::Kernel },
+        Instruction { name: "verify_application", handler: verify_application as InstructionHandler, state: Mode::Kernel },
+        Instruction { name: "start_user_space", handler: start_user_space as InstructionHandler, state: Mode::Kernel },
         // TODOs in Mode::User
     ];
     
-    for &(inst, handler, mode) in &instructions {
-        instruction_map.insert(inst, handler);
-        instruction_modes.insert(inst, mode);
-    }
+    let transitions = vec![
+        Transition { command: "powerup", from: Mode::Off, to: Mode::UEFI },
+        Transition { command: "powerup", from: Mode::UEFI, to: Mode::Hypervisor },
+        Transition { command: "powerup", from: Mode::Hypervisor, to: Mode::Kernel },
+        Transition { command: "powerup", from: Mode::Kernel, to: Mode::User },
+    ];
 
-    let mut state = State::new();
     std::io::stdout().flush().unwrap();
-    'shell: loop {
-        let prompt_color = get_prompt_color(mode);
-        let prompt = format!("{}{:?}>>{}", prompt_color.trim(), mode, reset_code.trim());
-		match rl.readline(&prompt) {
-			Ok(line) => {
-				rl.add_history_entry(line.as_str());
-				let parts: Vec<&str> = line.split_whitespace().collect();
-				if parts.is_empty() { continue; }
-				let cmd = parts[0];
-
-				match process_command(cmd, &mut state) {
-					CommandResult::Success => {
-                                            mode = state.current_mode(); // Update mode only on success
-                                            continue 'shell;
-                                        },
-                                        CommandResult::NotVerified => continue 'shell,
-					CommandResult::UnknownCommand => {
-						match cmd {
-							"hint" => provide_hint(mode),
-							"instructions" => print_instructions_list(&instruction_map),
-							"powerup" => {
-								mode = match mode {
-									Mode::Off => Mode::UEFI,
-									Mode::UEFI => Mode::Hypervisor,
-									Mode::Hypervisor => Mode::Kernel,
-									Mode::Kernel => Mode::User,
-									Mode::User => Mode::User,
-								};
-								println!("Switched to {:?} mode", mode);
-							},
-							"exit" => break,
-							_ => {
-								if let Some(&required_mode) = instruction_modes.get(cmd) {
-									if required_mode == mode {
-										execute_instruction(cmd, &instruction_map, &instruction_modes, mode);
-									} else {
-										println!("Cannot access '{}' in {:?} mode", cmd, mode);
-									}
-								} else {
-									println!("Unknown instruction: '{}'", cmd);
-								}
-							},
-						}
-					}
-				}
-			},
-			Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
-			Err(err) => println!("Error: {:?}", err),
-		}
-	}
+    Engine::new(X8664 { smp: Smp::new() }, transitions, instructions).run();
 }
 
-fn execute_instruction(instruction: &str, instruction_map: &HashMap<&str, InstructionHandler>, instruction_modes: &HashMap<&str, Mode>, mode: Mode) {
-    if let Some(&handler) = instruction_map.get(instruction) {
-        if let Some(&required_mode) = instruction_modes.get(instruction) {
-            if required_mode == mode {
-                handler();
-            } else {
-                println!("Error: '{}' cannot be executed in current mode", instruction);
-            }
-        } else {
-            println!("Unknown instruction mode requirement");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(terms: &str) -> Result<(Vec<PolicyStep>, Vec<u8>), String> {
+        parse_policy(&terms.split_whitespace().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parses_a_single_branch() {
+        let (policy, auth) = parse("pcrs=0,4 auth=pin").unwrap();
+        assert!(matches!(&policy[..], [PolicyStep::Pcr(selection, _), PolicyStep::AuthValue] if *selection == [0, 4]));
+        assert_eq!(auth, b"pin");
+    }
+
+    #[test]
+    fn or_splits_branches() {
+        let (policy, auth) = parse("pcrs=0,4,5 or auth=recovery").unwrap();
+        match &policy[..] {
+            [PolicyStep::Or(branches)] => {
+                assert_eq!(branches.len(), 2);
+                assert!(matches!(&branches[0][..], [PolicyStep::Pcr(selection, _)] if *selection == [0, 4, 5]));
+                assert!(matches!(&branches[1][..], [PolicyStep::AuthValue]));
+            },
+            _ => panic!("expected a single PolicyOR, got {}", describe_policy(&policy)),
         }
-    } else {
-        println!("Unknown instruction");
+        assert_eq!(auth, b"recovery");
+    }
+
+    #[test]
+    fn rejects_malformed_policies() {
+        assert!(parse("pcrs=0,24").unwrap_err().starts_with("Invalid PCR selection"));
+        assert!(parse("pcrs=").unwrap_err().starts_with("Invalid PCR selection"));
+        assert!(parse("pcrs=0 or").unwrap_err().starts_with("Every policy branch"));
+        assert!(parse("or auth=x").unwrap_err().starts_with("Every policy branch"));
+        assert!(parse("auth=a or auth=b").unwrap_err().starts_with("An object has a single auth value"));
+        assert!(parse("locality=3").unwrap_err().starts_with("Unknown policy term"));
+    }
+
+    #[test]
+    fn sealed_policy_unseals_through_either_branch() {
+        let mut tpm = Tpm::new();
+        tpm.pcrs.extend(PCR_KERNEL, BOOTLOADER_MEASUREMENT);
+        let (mut policy, auth) = parse("pcrs=4 or auth=recovery").unwrap();
+        bind_to_current_pcrs(&mut policy, &tpm);
+        let blob = tpm.seal_with_policy(b"secret", policy, &auth);
+        assert_eq!(tpm.unseal(&blob, b"").unwrap(), b"secret");
+        tpm.pcrs.extend(PCR_KERNEL, b"other bootloader");
+        assert!(tpm.unseal(&blob, b"").is_err());
+        assert_eq!(tpm.unseal(&blob, b"recovery").unwrap(), b"secret");
+    }
+
+    #[test]
+    fn auth_branch_first_still_unseals_through_pcrs() {
+        let mut tpm = Tpm::new();
+        tpm.pcrs.extend(PCR_KERNEL, BOOTLOADER_MEASUREMENT);
+        let (mut policy, auth) = parse("auth=pin or pcrs=4").unwrap();
+        bind_to_current_pcrs(&mut policy, &tpm);
+        let blob = tpm.seal_with_policy(b"secret", policy, &auth);
+        assert_eq!(tpm.unseal(&blob, b"").unwrap(), b"secret");
+        tpm.pcrs.extend(PCR_KERNEL, b"other bootloader");
+        assert!(tpm.unseal(&blob, b"").unwrap_err().starts_with("TPM_RC_AUTH_FAIL"));
+        assert_eq!(tpm.unseal(&blob, b"pin").unwrap(), b"secret");
     }
 }
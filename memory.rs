@@ -0,0 +1,190 @@
+// $t@$h
+use crate::crypto::{random_bytes, xor_into, Aes};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+pub const PAGE_SIZE: usize = 4096;
+pub const GUEST_MEMORY_SIZE: usize = 4 * PAGE_SIZE;
+// Unpowered DRAM rows alternate between leaking charge to 0 and to 1
+const GROUND_STATE_ROW: usize = 1024;
+
+// Guest physical memory. When a memory encryption key is installed the
+// backing bytes hold AES-XEX ciphertext tweaked by physical address, the
+// way SEV and TDX encrypt DRAM. The guest always sees plaintext; the host
+// sees whatever is stored in the backing bytes.
+pub struct GuestMemory {
+    bytes: Vec<u8>,
+    encryption: Option<Aes>,
+}
+
+#[allow(dead_code)]
+impl GuestMemory {
+    pub fn new() -> Self {
+        GuestMemory {
+            bytes: vec![0u8; GUEST_MEMORY_SIZE],
+            encryption: None,
+        }
+    }
+
+    pub fn new_encrypted(key: &[u8; 16]) -> Self {
+        let mut memory = GuestMemory {
+            bytes: vec![0u8; GUEST_MEMORY_SIZE],
+            encryption: Some(Aes::new(key)),
+        };
+        // Freshly assigned pages read back as zeroes inside the guest
+        memory.guest_write(0, &vec![0u8; GUEST_MEMORY_SIZE]).unwrap();
+        memory
+    }
+
+    // A new memory encryption key over the same cells: what they held now reads as noise
+    pub fn rekey(&mut self, key: &[u8; 16]) {
+        self.encryption = Some(Aes::new(key));
+    }
+
+    // Charge leaking away without power: every bit not yet at its row's
+    // ground state gets there with probability `fraction`. Returns the
+    // number of bits that changed.
+    pub fn decay(&mut self, fraction: f64) -> usize {
+        let mut rng = u64::from_le_bytes(random_bytes()[..8].try_into().unwrap()) | 1;
+        let mut decayed = 0;
+        for (addr, byte) in self.bytes.iter_mut().enumerate() {
+            let ground = if (addr / GROUND_STATE_ROW).is_multiple_of(2) { 0x00 } else { 0xff };
+            for bit in 0..8 {
+                let mask = 1u8 << bit;
+                if (*byte ^ ground) & mask == 0 {
+                    continue;
+                }
+                rng ^= rng << 13;
+                rng ^= rng >> 7;
+                rng ^= rng << 17;
+                if ((rng >> 11) as f64 / (1u64 << 53) as f64) < fraction {
+                    *byte ^= mask;
+                    decayed += 1;
+                }
+            }
+        }
+        decayed
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    fn check_range(addr: usize, len: usize) -> Result<(), String> {
+        match addr.checked_add(len) {
+            Some(end) if end <= GUEST_MEMORY_SIZE => Ok(()),
+            _ => Err(format!("Address range {:#x}+{:#x} is outside guest memory", addr, len)),
+        }
+    }
+
+    fn tweak(aes: &Aes, block_addr: usize) -> [u8; 16] {
+        let mut t = [0u8; 16];
+        t[..8].copy_from_slice(&(block_addr as u64).to_le_bytes());
+        aes.encrypt_block(&mut t);
+        t
+    }
+
+    fn crypt_block(&self, block_addr: usize, block: &mut [u8; 16], encrypt: bool) {
+        if let Some(aes) = &self.encryption {
+            let t = GuestMemory::tweak(aes, block_addr);
+            xor_into(block, &t);
+            if encrypt {
+                aes.encrypt_block(block);
+            } else {
+                aes.decrypt_block(block);
+            }
+            xor_into(block, &t);
+        }
+    }
+
+    pub fn guest_read(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        GuestMemory::check_range(addr, len)?;
+        let mut out = Vec::with_capacity(len);
+        let mut block_addr = addr - addr % 16;
+        while block_addr < addr + len {
+            let mut block = [0u8; 16];
+            block.copy_from_slice(&self.bytes[block_addr..block_addr + 16]);
+            self.crypt_block(block_addr, &mut block, false);
+            for (i, b) in block.iter().enumerate() {
+                let a = block_addr + i;
+                if a >= addr && a < addr + len {
+                    out.push(*b);
+                }
+            }
+            block_addr += 16;
+        }
+        Ok(out)
+    }
+
+    pub fn guest_write(&mut self, addr: usize, data: &[u8]) -> Result<(), String> {
+        GuestMemory::check_range(addr, data.len())?;
+        let mut block_addr = addr - addr % 16;
+        while block_addr < addr + data.len() {
+            let mut block = [0u8; 16];
+            block.copy_from_slice(&self.bytes[block_addr..block_addr + 16]);
+            self.crypt_block(block_addr, &mut block, false);
+            for (i, b) in block.iter_mut().enumerate() {
+                let a = block_addr + i;
+                if a >= addr && a < addr + data.len() {
+                    *b = data[a - addr];
+                }
+            }
+            self.crypt_block(block_addr, &mut block, true);
+            self.bytes[block_addr..block_addr + 16].copy_from_slice(&block);
+            block_addr += 16;
+        }
+        Ok(())
+    }
+
+    pub fn host_read(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        GuestMemory::check_range(addr, len)?;
+        Ok(self.bytes[addr..addr + len].to_vec())
+    }
+
+    // Encrypted pages are guest-owned: the ownership table rejects host writes
+    pub fn host_write(&mut self, addr: usize, data: &[u8]) -> Result<(), String> {
+        GuestMemory::check_range(addr, data.len())?;
+        if self.is_encrypted() {
+            return Err(format!("Page {:#x} is guest-private. Host write blocked.", addr - addr % PAGE_SIZE));
+        }
+        self.bytes[addr..addr + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+lazy_static! {
+    // Platform DRAM the host OS keeps its own secrets in
+    pub static ref HOST_MEMORY: Mutex<GuestMemory> = Mutex::new(GuestMemory::new());
+}
+
+// "0x1000" or "4096". An A64 immediate may also carry its '#'.
+#[allow(dead_code)]
+pub fn parse_u64(s: &str) -> Option<u64> {
+    let s = s.strip_prefix('#').unwrap_or(s);
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[allow(dead_code)]
+pub fn parse_addr(s: &str) -> Option<usize> {
+    parse_u64(s).and_then(|v| usize::try_from(v).ok())
+}
+
+pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if s.is_empty() || !s.len().is_multiple_of(2) {
+        return None;
+    }
+    let digit = |b: u8| (b as char).to_digit(16);
+    s.as_bytes().chunks(2).map(|pair| Some((digit(pair[0])? << 4 | digit(pair[1])?) as u8)).collect()
+}
+
+pub fn hexdump(base: usize, bytes: &[u8]) {
+    for (i, row) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = row.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        println!(" {:08x}  {:<47}  {}", base + i * 16, hex.join(" "), ascii);
+    }
+}
@@ -0,0 +1,100 @@
+// $t@$h
+use crate::crypto::hmac_sha256;
+
+// With top byte ignore on, a user pointer carries its MTE tag in bits
+// 59:56 and its pointer authentication code in bits 54:48
+const PAC_MASK: u64 = 0x007f_0000_0000_0000;
+const TAG_SHIFT: u64 = 56;
+const TOP_BYTE: u64 = 0xff00_0000_0000_0000;
+pub const TAG_GRANULE: u64 = 16;
+
+// PSTATE.BTYPE: what kind of indirect branch got us here
+const BTYPE_JUMP_X16_X17: u8 = 0b01;
+const BTYPE_CALL: u8 = 0b10;
+const BTYPE_JUMP: u8 = 0b11;
+
+// The SCTLR_EL1 controls the kernel sets for its processes: EnIA for
+// pointer authentication, BT0 for branch target identification, TCF0 for
+// synchronous tag check faults
+pub struct Mitigations {
+    pub pac: bool,
+    pub bti: bool,
+    pub mte: bool,
+}
+
+impl Mitigations {
+    pub fn new() -> Self {
+        Mitigations {
+            pac: false,
+            bti: false,
+            mte: false,
+        }
+    }
+
+    pub fn set(&mut self, feature: &str, on: bool) -> Result<(), String> {
+        match feature {
+            "pac" => self.pac = on,
+            "bti" => self.bti = on,
+            "mte" => self.mte = on,
+            _ => return Err(format!("Unknown mitigation '{}'. Use pac, bti or mte.", feature)),
+        }
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        let state = |on: bool| if on { "on" } else { "off" };
+        println!(" pac  {:<4} BL/BLR sign the return address with APIAKey and SP; RET authenticates it", state(self.pac));
+        println!(" bti  {:<4} BR/BLR must land on a matching 'BTI c|j|jc'", state(self.bti));
+        println!(" mte  {:<4} LDR/STR/LD1/ST1 check the pointer tag against the granule's allocation tag", state(self.mte));
+    }
+}
+
+// Hardware uses QARMA; a keyed hash truncated to the PAC field behaves the
+// same for our purposes: forging one means guessing 7 bits
+pub fn compute_pac(key: u128, ptr: u64, modifier: u64) -> u64 {
+    let mut message = [0u8; 16];
+    message[..8].copy_from_slice(&strip_pac(ptr).to_le_bytes());
+    message[8..].copy_from_slice(&modifier.to_le_bytes());
+    let mac = hmac_sha256(&key.to_le_bytes(), &message);
+    u64::from_le_bytes(mac[..8].try_into().unwrap()) & PAC_MASK
+}
+
+pub fn strip_pac(ptr: u64) -> u64 {
+    ptr & !PAC_MASK
+}
+
+pub fn logical_tag(addr: u64) -> u8 {
+    ((addr >> TAG_SHIFT) & 0xf) as u8
+}
+
+pub fn with_tag(addr: u64, tag: u8) -> u64 {
+    (addr & !(0xf << TAG_SHIFT)) | ((tag as u64 & 0xf) << TAG_SHIFT)
+}
+
+// The address the memory system sees once the top byte is ignored
+pub fn untagged(addr: u64) -> u64 {
+    addr & !TOP_BYTE
+}
+
+// BR through X16/X17 is how linker veneers jump, so 'BTI c' accepts it too
+pub fn branch_type(mnemonic: &str, register: usize) -> u8 {
+    match (mnemonic, register) {
+        ("BLR", _) => BTYPE_CALL,
+        (_, 16) | (_, 17) => BTYPE_JUMP_X16_X17,
+        _ => BTYPE_JUMP,
+    }
+}
+
+// Whether the first instruction at an indirect branch target is a landing
+// pad for that kind of branch
+pub fn is_landing_pad(mnemonic: &str, ops: &[&str], btype: u8) -> bool {
+    if mnemonic != "BTI" {
+        return false;
+    }
+    match ops.first().map(|op| op.to_ascii_lowercase()).as_deref() {
+        Some("c") => btype == BTYPE_CALL || btype == BTYPE_JUMP_X16_X17,
+        Some("j") => btype == BTYPE_JUMP || btype == BTYPE_JUMP_X16_X17,
+        Some("jc") => true,
+        _ => false,
+    }
+}
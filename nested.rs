@@ -0,0 +1,171 @@
+// $t@$h
+use crate::cpu::Registers;
+use crate::vm::Guest;
+use std::collections::BTreeMap;
+
+// The hypervisor a guest kernel may run as L1 inside the L0 hypervisor
+pub const L1_HYPERVISOR_IMAGE: &[u8] = b"SIMHV l1-hypervisor 2.0, signed by the QVLX release key";
+
+// Basic VM exit reasons, numbered as in the Intel SDM
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExitReason {
+    Cpuid = 10,
+    Hlt = 12,
+    Vmcall = 18,
+    Vmread = 23,
+    Vmwrite = 25,
+}
+
+// VMCS fields an L1 hypervisor can program for its L2 guest
+const VMCS12_FIELDS: [&str; 5] = ["guest_rip", "guest_rsp", "hlt_exiting", "exit_reason", "exit_qualification"];
+
+// L1's view of its VMCS (vmcs12). L0 merges it with its own controls into
+// the VMCS the CPU really runs L2 on (vmcs02).
+pub struct Nested {
+    vmcs12: BTreeMap<&'static str, u64>,
+    pub shadowing: bool,
+    pub in_l2: bool,
+    // Registers of whichever level is not currently running
+    saved_regs: Registers,
+    pub exits_to_l0: u64,
+    pub reflected_exits: u64,
+}
+
+impl Nested {
+    pub fn new() -> Self {
+        Nested {
+            vmcs12: VMCS12_FIELDS.iter().map(|&f| (f, 0)).collect(),
+            shadowing: true,
+            in_l2: false,
+            saved_regs: Registers::new(),
+            exits_to_l0: 0,
+            reflected_exits: 0,
+        }
+    }
+
+    fn field(name: &str) -> Result<&'static str, String> {
+        VMCS12_FIELDS
+            .iter()
+            .find(|&&f| f == name)
+            .copied()
+            .ok_or_else(|| format!("Unknown VMCS field '{}'. Fields: {}", name, VMCS12_FIELDS.join(", ")))
+    }
+
+    // With VMCS shadowing the CPU serves VMREAD/VMWRITE from the shadow
+    // VMCS; without it every access traps to L0 for emulation.
+    fn vmcs_access(&mut self, reason: ExitReason) {
+        if self.shadowing {
+            println!("[L1] {:?} served from the shadow VMCS, no VM exit", reason);
+        } else {
+            self.exits_to_l0 += 1;
+            println!("[L0] VM exit from L1 ({:?}), emulated against vmcs12", reason);
+        }
+    }
+
+    pub fn vmread(&mut self, name: &str) -> Result<u64, String> {
+        let field = Nested::field(name)?;
+        self.vmcs_access(ExitReason::Vmread);
+        Ok(self.vmcs12[field])
+    }
+
+    pub fn vmwrite(&mut self, name: &str, value: u64) -> Result<(), String> {
+        let field = Nested::field(name)?;
+        if field == "exit_reason" || field == "exit_qualification" {
+            return Err(format!("VMCS field '{}' is read-only", field));
+        }
+        self.vmcs_access(ExitReason::Vmwrite);
+        self.vmcs12.insert(field, value);
+        Ok(())
+    }
+
+    pub fn print_status(&self) {
+        println!("Nested virtualization:");
+        println!(" running level       {}", if self.in_l2 { "L2" } else { "L1" });
+        println!(" VMCS shadowing      {}", if self.shadowing { "on" } else { "off" });
+        println!(" exits taken by L0   {}", self.exits_to_l0);
+        println!(" exits reflected     {}", self.reflected_exits);
+        for (field, value) in &self.vmcs12 {
+            println!(" vmcs12.{:<19} {:#x}", field, value);
+        }
+    }
+}
+
+// VMLAUNCH from L1: L0 builds vmcs02 from vmcs12 and switches to L2
+pub fn enter_l2(guest: &mut Guest) -> Result<(), String> {
+    let nested = guest.nested.as_mut().ok_or("No L1 hypervisor loaded. Type 'nested load' first.")?;
+    if nested.in_l2 {
+        return Err("Already running L2".to_string());
+    }
+    nested.exits_to_l0 += 1;
+    println!("[L0] VM exit from L1 (VMLAUNCH), merging vmcs12 into vmcs02");
+    let mut l2_regs = Registers::new();
+    l2_regs.rip = nested.vmcs12["guest_rip"];
+    l2_regs.rsp = nested.vmcs12["guest_rsp"];
+    nested.saved_regs = std::mem::replace(&mut guest.regs, l2_regs);
+    nested.in_l2 = true;
+    println!("[L0] VM entry into L2 at rip={:#x}", guest.regs.rip);
+    Ok(())
+}
+
+// L1 tears its guest down and takes the console back
+pub fn leave_l2(guest: &mut Guest) -> Result<(), String> {
+    let nested = guest.nested.as_mut().ok_or("No L1 hypervisor loaded")?;
+    if !nested.in_l2 {
+        return Err("Not running L2".to_string());
+    }
+    guest.regs = std::mem::take(&mut nested.saved_regs);
+    nested.in_l2 = false;
+    println!("[L1] L2 guest stopped");
+    Ok(())
+}
+
+fn set_vendor(regs: &mut Registers, vendor: &[u8; 12]) {
+    regs.rbx = u32::from_le_bytes([vendor[0], vendor[1], vendor[2], vendor[3]]) as u64;
+    regs.rdx = u32::from_le_bytes([vendor[4], vendor[5], vendor[6], vendor[7]]) as u64;
+    regs.rcx = u32::from_le_bytes([vendor[8], vendor[9], vendor[10], vendor[11]]) as u64;
+}
+
+// CPUID executed by a guest kernel that is not running nested
+pub fn l0_cpuid(regs: &mut Registers) {
+    println!("[L0] VM exit (CPUID), reporting the L0 hypervisor");
+    set_vendor(regs, b"QVLXSimHyper");
+    regs.rax = 0;
+}
+
+// Every L2 exit lands in L0 first. L0 reflects it to L1 when L1 asked for
+// it (or the exit is unconditional for any hypervisor), and otherwise
+// handles it itself so L1 never notices.
+pub fn l2_exit(guest: &mut Guest, reason: ExitReason) {
+    let nested = match guest.nested.as_mut() {
+        Some(n) if n.in_l2 => n,
+        _ => return,
+    };
+    nested.exits_to_l0 += 1;
+    println!("[L0] VM exit from L2: {:?} (reason {})", reason, reason as u64);
+    let reflect = match reason {
+        ExitReason::Hlt => nested.vmcs12["hlt_exiting"] != 0,
+        _ => true,
+    };
+    if !reflect {
+        println!("[L0] L1 did not request {:?} exits; handled in L0 and resumed L2", reason);
+        return;
+    }
+
+    nested.reflected_exits += 1;
+    nested.vmcs12.insert("exit_reason", reason as u64);
+    nested.vmcs12.insert("guest_rip", guest.regs.rip);
+    println!("[L0] Reflecting to L1: exit_reason={} written to vmcs12", reason as u64);
+    match reason {
+        ExitReason::Vmcall => {
+            println!("[L1] Hypercall {:#x} from L2 handled", guest.regs.rax);
+            guest.regs.rax = 0;
+        },
+        ExitReason::Cpuid => {
+            println!("[L1] CPUID from L2, reporting the L1 hypervisor");
+            set_vendor(&mut guest.regs, b"QVLXSimNestd");
+            guest.regs.rax = 0;
+        },
+        _ => println!("[L1] {:?} from L2 handled", reason),
+    }
+    println!("[L1] VMRESUME -> [L0] merges vmcs12 into vmcs02 and resumes L2");
+}
@@ -0,0 +1,339 @@
+// $t@$h
+use crate::crypto::{aes_ctr, ct_eq, hmac_sha256, random_bytes, sha256, to_hex, x25519, x25519_public};
+use crate::fde::reference_pcrs;
+use crate::tpm::{
+    PcrBank, Quote, Tpm, BOOTLOADER_MEASUREMENT, FIRMWARE_MEASUREMENT, HYPERVISOR_MEASUREMENT, PCR_FIRMWARE,
+    PCR_HYPERVISOR, PCR_KERNEL, PLATFORM_QUOTE_PCRS, PLATFORM_TPM,
+};
+use crate::vm::{Guest, KERNEL_IMAGE};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+// What a client's guest has to prove: the vendor kernel was launched
+const GUEST_EVIDENCE_PCRS: [usize; 1] = [PCR_KERNEL];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum PeerBoot {
+    Secure,
+    // Booted a patched bootloader
+    Tampered,
+    // Booted a patched bootloader but kept a quote from an earlier, good boot
+    Replay,
+}
+
+// Another simulated platform on the link, with its own TPM and boot history
+pub struct Peer {
+    pub name: &'static str,
+    boot: PeerBoot,
+    tpm: Tpm,
+    stale_quote: Option<Quote>,
+    // Whether the peer insists on attestation evidence from its clients
+    requires_attestation: bool,
+}
+
+impl Peer {
+    fn new(name: &'static str, boot: PeerBoot, requires_attestation: bool) -> Self {
+        let mut tpm = Tpm::new();
+        tpm.pcrs.extend(PCR_FIRMWARE, FIRMWARE_MEASUREMENT);
+        tpm.pcrs.extend(PCR_KERNEL, BOOTLOADER_MEASUREMENT);
+        tpm.pcrs.extend(PCR_HYPERVISOR, HYPERVISOR_MEASUREMENT);
+        let mut stale_quote = None;
+        if boot != PeerBoot::Secure {
+            if boot == PeerBoot::Replay {
+                stale_quote = Some(tpm.quote(&PLATFORM_QUOTE_PCRS, &random_bytes()));
+            }
+            tpm.startup_clear();
+            tpm.pcrs.extend(PCR_FIRMWARE, FIRMWARE_MEASUREMENT);
+            tpm.pcrs.extend(PCR_KERNEL, b"patched bootloader");
+            tpm.pcrs.extend(PCR_HYPERVISOR, HYPERVISOR_MEASUREMENT);
+        }
+        Peer {
+            name,
+            boot,
+            tpm,
+            stale_quote,
+            requires_attestation,
+        }
+    }
+
+    fn evidence(&self, nonce: &[u8; 32]) -> Quote {
+        match &self.stale_quote {
+            Some(quote) => quote.clone(),
+            None => self.tpm.quote(&PLATFORM_QUOTE_PCRS, nonce),
+        }
+    }
+
+    // The service each peer runs behind its end of the channel
+    fn respond(&self, request: &str) -> String {
+        match (self.name, request) {
+            ("bank", "balance") => "Balance: 1,000,000 credits".to_string(),
+            ("bank", _) => "bank: unknown request. Try 'balance'.".to_string(),
+            (_, _) => format!("{}: got '{}'", self.name, request),
+        }
+    }
+}
+
+// One direction of the channel
+struct Direction {
+    enc: [u8; 32],
+    mac: [u8; 32],
+    seq: u64,
+}
+
+impl Direction {
+    fn new(shared: &[u8; 32], transcript: &[u8; 32], label: &str) -> Self {
+        let derive = |purpose: &str| {
+            let mut info = format!("{} {}", label, purpose).into_bytes();
+            info.extend_from_slice(transcript);
+            hmac_sha256(shared, &info)
+        };
+        Direction {
+            enc: derive("key"),
+            mac: derive("mac"),
+            seq: 0,
+        }
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let mut record = self.seq.to_be_bytes().to_vec();
+        let mut body = plaintext.to_vec();
+        aes_ctr(&self.enc, self.seq, &mut body);
+        record.extend_from_slice(&body);
+        let tag = hmac_sha256(&self.mac, &record);
+        record.extend_from_slice(&tag);
+        self.seq += 1;
+        record
+    }
+
+    // Encrypt-then-MAC with an explicit sequence number, so altered,
+    // replayed and reordered records are all rejected
+    fn open(&mut self, record: &[u8]) -> Result<Vec<u8>, String> {
+        if record.len() < 40 {
+            return Err("truncated record".to_string());
+        }
+        let (authenticated, tag) = record.split_at(record.len() - 32);
+        if !ct_eq(tag, &hmac_sha256(&self.mac, authenticated)) {
+            return Err("bad record MAC".to_string());
+        }
+        let seq = u64::from_be_bytes(authenticated[..8].try_into().unwrap());
+        if seq != self.seq {
+            return Err(format!("record {} out of sequence (expected {})", seq, self.seq));
+        }
+        let mut body = authenticated[8..].to_vec();
+        aes_ctr(&self.enc, seq, &mut body);
+        self.seq += 1;
+        Ok(body)
+    }
+}
+
+// Each end keeps its own keys; they only agree if the handshake was honest
+struct Session {
+    peer: usize,
+    guest: String,
+    client_send: Direction,
+    client_recv: Direction,
+    server_send: Direction,
+    server_recv: Direction,
+}
+
+pub struct Network {
+    peers: Vec<Peer>,
+    pub mitm: bool,
+    pub tamper_next: bool,
+    session: Option<Session>,
+}
+
+fn transcript(client_random: &[u8; 32], client_pub: &[u8; 32], server_random: &[u8; 32], server_pub: &[u8; 32]) -> [u8; 32] {
+    let mut buf = b"secboot attested channel v1".to_vec();
+    for part in [client_random, client_pub, server_random, server_pub] {
+        buf.extend_from_slice(part);
+    }
+    sha256(&buf)
+}
+
+// What a client guest would quote after a good boot of the vendor kernel
+fn reference_guest_pcrs() -> PcrBank {
+    let mut pcrs = PcrBank::new();
+    pcrs.extend(PCR_KERNEL, KERNEL_IMAGE);
+    pcrs
+}
+
+fn check(label: &str, ok: bool) -> bool {
+    println!("  {:<46} {}", label, if ok { "ok" } else { "FAILED" });
+    ok
+}
+
+impl Network {
+    fn new() -> Self {
+        Network {
+            peers: vec![
+                Peer::new("bank", PeerBoot::Secure, true),
+                Peer::new("rogue", PeerBoot::Tampered, false),
+                Peer::new("relay", PeerBoot::Replay, false),
+            ],
+            mitm: false,
+            tamper_next: false,
+            session: None,
+        }
+    }
+
+    pub fn list(&self) {
+        println!("Peers on the link:");
+        for peer in &self.peers {
+            let client_policy = if peer.requires_attestation { "attested clients only" } else { "accepts anyone" };
+            let history = match peer.boot {
+                PeerBoot::Secure => "booted securely",
+                PeerBoot::Tampered => "booted a patched bootloader",
+                PeerBoot::Replay => "booted a patched bootloader, kept a quote from an earlier boot",
+            };
+            println!(" {:<8} {:<22} {}", peer.name, client_policy, history);
+        }
+        if let Some(session) = &self.session {
+            println!("Connected to '{}' from guest '{}'", self.peers[session.peer].name, session.guest);
+        }
+    }
+
+    // The client's side of verifying a peer's evidence
+    fn verify_peer(peer: &Peer, quote: &Quote, nonce: &[u8; 32]) -> bool {
+        println!("Evidence from '{}':", peer.name);
+        let signed = check("quote signed by the peer's TPM", peer.tpm.verify_quote(quote));
+        let fresh = check("quote bound to this handshake", ct_eq(&quote.nonce, nonce));
+        let good_boot = check(
+            "PCRs 0,4,5 match a secure boot",
+            ct_eq(&quote.pcr_digest, &reference_pcrs().composite(&PLATFORM_QUOTE_PCRS)),
+        );
+        signed && fresh && good_boot
+    }
+
+    // The server's side: layered evidence from the client's platform and guest
+    fn verify_client(guest: &Guest, nonce: &[u8; 32]) -> bool {
+        let platform = PLATFORM_TPM.lock().unwrap();
+        let platform_quote = platform.quote(&PLATFORM_QUOTE_PCRS, nonce);
+        let mut binding = nonce.to_vec();
+        binding.extend_from_slice(&platform_quote.signature);
+        let guest_quote = guest.vtpm.quote(&GUEST_EVIDENCE_PCRS, &sha256(&binding));
+
+        println!("Evidence from guest '{}':", guest.name);
+        let platform_ok = check("platform quote signed and fresh", platform.verify_quote(&platform_quote));
+        let platform_boot = check(
+            "platform PCRs 0,4,5 match a secure boot",
+            ct_eq(&platform_quote.pcr_digest, &reference_pcrs().composite(&PLATFORM_QUOTE_PCRS)),
+        );
+        let cert_ok = check(
+            "vTPM endorsed by the platform TPM",
+            guest
+                .vtpm
+                .ek_cert
+                .as_ref()
+                .is_some_and(|cert| platform.verify_certificate(cert) && ct_eq(&cert.subject, &guest_quote.signer)),
+        );
+        let guest_ok = check("guest quote signed and bound", guest.vtpm.verify_quote(&guest_quote));
+        let guest_boot = check(
+            "guest PCR 4 matches the vendor kernel",
+            ct_eq(&guest_quote.pcr_digest, &reference_guest_pcrs().composite(&GUEST_EVIDENCE_PCRS)),
+        );
+        platform_ok && platform_boot && cert_ok && guest_ok && guest_boot
+    }
+
+    // Handshake: X25519 key exchange, then each side sends attestation
+    // evidence whose nonce is the handshake transcript, then key confirmation
+    pub fn connect(&mut self, guest: &Guest, name: &str) -> Result<(), String> {
+        let index = self
+            .peers
+            .iter()
+            .position(|p| p.name == name)
+            .ok_or_else(|| format!("No peer named '{}'", name))?;
+        self.session = None;
+        let peer = &self.peers[index];
+
+        let (client_secret, client_random) = (random_bytes(), random_bytes());
+        let client_pub = x25519_public(&client_secret);
+        let (server_secret, server_random) = (random_bytes(), random_bytes());
+        let server_pub = x25519_public(&server_secret);
+        println!("[wire] ClientHello  random={}.. key_share={}..", &to_hex(&client_random)[..16], &to_hex(&client_pub)[..16]);
+
+        // An attacker on the link swaps in key shares of its own
+        let (client_pub_seen, server_pub_seen) = if self.mitm {
+            let attacker_pub = x25519_public(&random_bytes());
+            println!("[mitm] Replacing both key shares with {}..", &to_hex(&attacker_pub)[..16]);
+            (attacker_pub, attacker_pub)
+        } else {
+            (client_pub, server_pub)
+        };
+        println!("[wire] ServerHello  random={}.. key_share={}..", &to_hex(&server_random)[..16], &to_hex(&server_pub_seen)[..16]);
+
+        let client_transcript = transcript(&client_random, &client_pub, &server_random, &server_pub_seen);
+        let server_transcript = transcript(&client_random, &client_pub_seen, &server_random, &server_pub);
+        let evidence = peer.evidence(&server_transcript);
+        println!("[wire] Evidence     quote over PCRs {:?}", evidence.selection);
+        if !Network::verify_peer(peer, &evidence, &client_transcript) {
+            return Err(format!("Refusing to talk to '{}': it could not prove a secure boot", name));
+        }
+
+        println!("[wire] Evidence     from guest '{}'", guest.name);
+        if peer.requires_attestation && !Network::verify_client(guest, &server_transcript) {
+            return Err(format!("'{}' refused the connection: this guest could not prove a secure boot", name));
+        }
+
+        let client_shared = x25519(&client_secret, &server_pub_seen);
+        let server_shared = x25519(&server_secret, &client_pub_seen);
+        let mut client_send = Direction::new(&client_shared, &client_transcript, "client");
+        let client_recv = Direction::new(&client_shared, &client_transcript, "server");
+        let server_send = Direction::new(&server_shared, &server_transcript, "server");
+        let mut server_recv = Direction::new(&server_shared, &server_transcript, "client");
+
+        // Finished: proves both ends derived the same keys from the same transcript
+        let finished = client_send.seal(b"client finished");
+        println!("[wire] Finished");
+        if server_recv.open(&finished).is_err() {
+            return Err("Handshake failed: Finished message did not verify".to_string());
+        }
+        println!("Attested channel to '{}' established (X25519, AES-256-CTR + HMAC-SHA256).", name);
+        self.session = Some(Session {
+            peer: index,
+            guest: guest.name.clone(),
+            client_send,
+            client_recv,
+            server_send,
+            server_recv,
+        });
+        Ok(())
+    }
+
+    // Send one request and wait for the peer's reply
+    pub fn send(&mut self, guest: &Guest, message: &str) -> Result<String, String> {
+        let session = match &mut self.session {
+            Some(s) if s.guest == guest.name => s,
+            _ => return Err("No channel open from this guest. Type 'net connect <peer>' first.".to_string()),
+        };
+        let peer = &self.peers[session.peer];
+        let mut record = session.client_send.seal(message.as_bytes());
+        println!("[wire] {} bytes: {}..", record.len(), &to_hex(&record)[..32]);
+        if self.tamper_next {
+            self.tamper_next = false;
+            record[10] ^= 0x01;
+            println!("[mitm] Flipped one bit of the record in transit");
+        }
+        let request = match session.server_recv.open(&record) {
+            Ok(request) => request,
+            Err(msg) => {
+                self.session = None;
+                return Err(format!("'{}' closed the channel: {}", peer.name, msg));
+            },
+        };
+        let reply = peer.respond(&String::from_utf8_lossy(&request));
+        let record = session.server_send.seal(reply.as_bytes());
+        let reply = session.client_recv.open(&record)?;
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    }
+
+    pub fn close(&mut self) {
+        if let Some(session) = self.session.take() {
+            println!("Channel to '{}' closed.", self.peers[session.peer].name);
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref NETWORK: Mutex<Network> = Mutex::new(Network::new());
+}
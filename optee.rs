@@ -0,0 +1,334 @@
+// $t@$h
+use crate::aarch64::BL32_BASE;
+use crate::crypto::{aes_ctr, aes_gcm_open, aes_gcm_seal, ct_eq, hmac_sha256, random_bytes, to_hex};
+use crate::keys::{SigningKey, TA_SIGNING_KEY, THIRD_PARTY_KEY};
+use crate::memory::parse_hex;
+use crate::tzasc::PHYS_MEMORY;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+// Where the trusted OS keeps things in its part of trusted SRAM
+pub const SSK_ADDR: u64 = BL32_BASE + 0x1_0000;
+pub const TA_LOAD_ADDR: u64 = BL32_BASE + 0x2_0000;
+pub const KEYSTORE_ADDR: u64 = BL32_BASE + 0x3_0000;
+const TA_SLOT_SIZE: u64 = 0x1000;
+const KEY_SLOTS: usize = 16;
+const NONCE_SIZE: usize = 12;
+
+// A trusted application as it sits in the normal world's filesystem under
+// /lib/optee_armtz, waiting for tee-supplicant to hand it to the trusted OS
+pub struct TaBinary {
+    pub name: &'static str,
+    pub uuid: &'static str,
+    code: Vec<u8>,
+    signer: &'static SigningKey,
+    signature: [u8; 32],
+}
+
+impl TaBinary {
+    fn signed(name: &'static str, uuid: &'static str, signer: &'static SigningKey) -> Self {
+        let code = format!("TA {} ({})", name, uuid).into_bytes();
+        let mut ta = TaBinary {
+            name,
+            uuid,
+            code,
+            signer,
+            signature: [0; 32],
+        };
+        ta.signature = signer.sign(&ta.signed_bytes());
+        ta
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buf = self.uuid.as_bytes().to_vec();
+        buf.extend_from_slice(&self.code);
+        buf
+    }
+
+    // What the trusted OS checks before a TA may run: a signature under
+    // the key it was built with, whoever claims to have signed it
+    fn verify(&self) -> bool {
+        TA_SIGNING_KEY.verify(&self.signed_bytes(), &self.signature)
+    }
+}
+
+// A secure storage object: the TA and object id stay in the trusted OS,
+// the normal world only ever holds an opaque file under /data/tee
+struct StoredObject {
+    uuid: &'static str,
+    id: String,
+    file: String,
+    blob: Vec<u8>,
+}
+
+pub struct TrustedOs {
+    pub running: bool,
+    // Hardware unique key, fused per device and readable only in Secure state
+    huk: [u8; 32],
+    ree_fs: Vec<TaBinary>,
+    storage: Vec<StoredObject>,
+    next_file: u32,
+    // Open sessions, by TA name
+    sessions: Vec<&'static str>,
+    // Names of the keys in each key store slot; the keys themselves sit at
+    // KEYSTORE_ADDR in trusted SRAM
+    key_slots: Vec<Option<String>>,
+}
+
+impl TrustedOs {
+    fn new() -> Self {
+        TrustedOs {
+            running: false,
+            huk: random_bytes(),
+            ree_fs: vec![
+                TaBinary::signed("hello", "8aaaf200-2450-11e4-abe2-0002a5d5c51b", &TA_SIGNING_KEY),
+                TaBinary::signed("storage", "f4e750bb-1437-4fbf-8785-8d3580c34994", &TA_SIGNING_KEY),
+                TaBinary::signed("crypto", "a734eed9-d6a1-4244-aa50-7c99719e7b7b", &TA_SIGNING_KEY),
+                TaBinary::signed("rogue", "c0ffee00-0bad-4bad-8bad-00000000beef", &THIRD_PARTY_KEY),
+            ],
+            storage: Vec::new(),
+            next_file: 0,
+            sessions: Vec::new(),
+            key_slots: vec![None; KEY_SLOTS],
+        }
+    }
+
+    // Secure storage key: derived from the HUK at boot and never leaves
+    // trusted SRAM
+    fn ssk(&self) -> [u8; 32] {
+        hmac_sha256(&self.huk, b"ONLY_FOR_tee_fs_ssk")
+    }
+
+    // Each TA gets its own storage key, so one TA cannot open another's objects
+    fn tsk(&self, uuid: &str) -> [u8; 32] {
+        hmac_sha256(&self.ssk(), uuid.as_bytes())
+    }
+
+    // Sessions and the key store live in trusted SRAM; the HUK and the REE
+    // file system do not care about resets
+    pub fn reset(&mut self) {
+        self.running = false;
+        self.sessions.clear();
+        self.key_slots = vec![None; KEY_SLOTS];
+    }
+
+    // Runs in S-EL1 when BL31 first enters BL32
+    pub fn init(&mut self) -> Result<(), String> {
+        PHYS_MEMORY.lock().unwrap().write(SSK_ADDR, &self.ssk(), true)?;
+        self.running = true;
+        println!("OP-TEE: initialized; secure storage key derived from the HUK at {:#x}", SSK_ADDR);
+        if !self.key_slots.iter().flatten().any(|k| k == "default") {
+            println!("OP-TEE: {}", self.key_generate("default")?);
+        }
+        Ok(())
+    }
+
+    fn installed(&self, name: &str) -> Result<&TaBinary, String> {
+        self.ree_fs
+            .iter()
+            .find(|ta| ta.name == name)
+            .ok_or_else(|| format!("TEE_ERROR_ITEM_NOT_FOUND: no TA '{}' in /lib/optee_armtz", name))
+    }
+
+    pub fn list(&self) {
+        println!("/lib/optee_armtz:");
+        for ta in &self.ree_fs {
+            println!(
+                " {}.ta  {:<8} signed by {}{}",
+                ta.uuid,
+                ta.name,
+                ta.signer.id,
+                if self.sessions.contains(&ta.name) { "  [session open]" } else { "" }
+            );
+        }
+        println!("/data/tee:");
+        for object in &self.storage {
+            println!(" {}  {} bytes  {}...", object.file, object.blob.len(), &to_hex(&object.blob)[..24]);
+        }
+    }
+
+    pub fn tamper(&mut self, name: &str) -> Result<(), String> {
+        let ta = self.ree_fs.iter_mut().find(|ta| ta.name == name).ok_or_else(|| format!("No TA '{}'", name))?;
+        ta.code[0] ^= 0x01;
+        println!("Patched one byte of {}.ta in the normal world filesystem.", ta.uuid);
+        Ok(())
+    }
+
+    // TEEC_OpenSession: load the TA into secure memory and check its signature
+    pub fn open_session(&mut self, name: &str) -> Result<(), String> {
+        if !self.running {
+            return Err("TEE_ERROR_COMMUNICATION: no trusted OS is running".to_string());
+        }
+        if self.sessions.contains(&name) {
+            return Err(format!("A session with '{}' is already open", name));
+        }
+        let ta = self.installed(name)?;
+        let slot = self.ree_fs.iter().position(|t| t.name == name).unwrap() as u64;
+        let addr = TA_LOAD_ADDR + slot * TA_SLOT_SIZE;
+        PHYS_MEMORY.lock().unwrap().write(addr, &ta.code, true)?;
+        if !ta.verify() {
+            return Err(format!(
+                "TEE_ERROR_SECURITY: {}.ta signature does not verify against '{}'. TA not loaded.",
+                ta.uuid, TA_SIGNING_KEY.id
+            ));
+        }
+        let name = ta.name;
+        println!("OP-TEE: TA {} ({}) loaded at {:#x}, signature ok", name, ta.uuid, addr);
+        self.sessions.push(name);
+        Ok(())
+    }
+
+    pub fn close_session(&mut self, name: &str) -> Result<(), String> {
+        let index = self.sessions.iter().position(|s| *s == name).ok_or_else(|| format!("No session with '{}'", name))?;
+        self.sessions.remove(index);
+        Ok(())
+    }
+
+    // TEEC_InvokeCommand
+    pub fn invoke(&mut self, name: &str, command: &str, args: &[&str]) -> Result<String, String> {
+        if !self.sessions.contains(&name) {
+            return Err(format!("TEE_ERROR_BAD_STATE: no session with '{}'. Open one first.", name));
+        }
+        let uuid = self.installed(name)?.uuid;
+        match (name, command, args) {
+            ("hello", "inc", [value]) => {
+                let value: u32 = value.parse().map_err(|_| "TEE_ERROR_BAD_PARAMETERS".to_string())?;
+                Ok(format!("{}", value.wrapping_add(1)))
+            },
+            ("storage", "put", [id, data @ ..]) if !data.is_empty() => self.storage_put(uuid, id, data.join(" ").as_bytes()),
+            ("storage", "get", [id]) => self.storage_get(uuid, id).map(|data| String::from_utf8_lossy(&data).into_owned()),
+            ("storage", "delete", [id]) => {
+                let before = self.storage.len();
+                self.storage.retain(|o| !(o.uuid == uuid && o.id == *id));
+                if before == self.storage.len() {
+                    Err("TEE_ERROR_ITEM_NOT_FOUND".to_string())
+                } else {
+                    Ok(format!("deleted '{}'", id))
+                }
+            },
+            ("crypto", "encrypt", [key, text @ ..]) if !text.is_empty() => self.encrypt(key, text.join(" ").as_bytes()),
+            ("crypto", "decrypt", [key, hex]) => self.decrypt(key, hex).map(|data| format!("'{}'", String::from_utf8_lossy(&data))),
+            ("crypto", "keys", []) => Ok(self.key_list()),
+            ("crypto", "keygen", [key]) => self.key_generate(key),
+            ("crypto", "keydel", [key]) => self.key_delete(key),
+            _ => Err(format!("TEE_ERROR_NOT_SUPPORTED: '{}' does not implement '{}' with those parameters", name, command)),
+        }
+    }
+
+    // Encrypt-then-MAC under keys derived from the owning TA's storage key,
+    // with the object id bound into the MAC so blobs cannot be swapped
+    fn storage_put(&mut self, uuid: &'static str, id: &str, data: &[u8]) -> Result<String, String> {
+        let tsk = self.tsk(uuid);
+        let nonce = u64::from_be_bytes(random_bytes()[..8].try_into().unwrap());
+        let mut blob = nonce.to_be_bytes().to_vec();
+        let mut body = data.to_vec();
+        aes_ctr(&hmac_sha256(&tsk, b"enc"), nonce, &mut body);
+        blob.extend_from_slice(&body);
+        let mut authenticated = id.as_bytes().to_vec();
+        authenticated.extend_from_slice(&blob);
+        blob.extend_from_slice(&hmac_sha256(&hmac_sha256(&tsk, b"mac"), &authenticated));
+        self.storage.retain(|o| !(o.uuid == uuid && o.id == id));
+        let file = format!("/data/tee/{}", self.next_file);
+        self.next_file += 1;
+        self.storage.push(StoredObject {
+            uuid,
+            id: id.to_string(),
+            file: file.clone(),
+            blob,
+        });
+        Ok(format!("stored '{}' as {}", id, file))
+    }
+
+    fn storage_get(&self, uuid: &str, id: &str) -> Result<Vec<u8>, String> {
+        let object = self
+            .storage
+            .iter()
+            .find(|o| o.uuid == uuid && o.id == id)
+            .ok_or_else(|| "TEE_ERROR_ITEM_NOT_FOUND".to_string())?;
+        let tsk = self.tsk(uuid);
+        let (authenticated, tag) = object.blob.split_at(object.blob.len() - 32);
+        let mut mac_input = id.as_bytes().to_vec();
+        mac_input.extend_from_slice(authenticated);
+        if !ct_eq(tag, &hmac_sha256(&hmac_sha256(&tsk, b"mac"), &mac_input)) {
+            return Err(format!("TEE_ERROR_CORRUPT_OBJECT: {} fails its MAC", object.file));
+        }
+        let nonce = u64::from_be_bytes(authenticated[..8].try_into().unwrap());
+        let mut data = authenticated[8..].to_vec();
+        aes_ctr(&hmac_sha256(&tsk, b"enc"), nonce, &mut data);
+        Ok(data)
+    }
+
+    // Key store keys are derived from the HUK by name, so a key is the same
+    // after every boot of this device and useless on any other
+    fn derive_key(&self, name: &str) -> [u8; 32] {
+        let mut info = b"keystore:".to_vec();
+        info.extend_from_slice(name.as_bytes());
+        hmac_sha256(&self.huk, &info)
+    }
+
+    fn key_slot(&self, name: &str) -> Result<usize, String> {
+        self.key_slots
+            .iter()
+            .position(|k| k.as_deref() == Some(name))
+            .ok_or_else(|| format!("TEE_ERROR_ITEM_NOT_FOUND: no key '{}' in the key store", name))
+    }
+
+    fn slot_addr(slot: usize) -> u64 {
+        KEYSTORE_ADDR + slot as u64 * 32
+    }
+
+    pub fn key_generate(&mut self, name: &str) -> Result<String, String> {
+        if self.key_slot(name).is_ok() {
+            return Err(format!("TEE_ERROR_ACCESS_CONFLICT: key '{}' already exists", name));
+        }
+        let slot = self.key_slots.iter().position(|k| k.is_none()).ok_or_else(|| "TEE_ERROR_STORAGE_NO_SPACE: key store full".to_string())?;
+        PHYS_MEMORY.lock().unwrap().write(Self::slot_addr(slot), &self.derive_key(name), true)?;
+        self.key_slots[slot] = Some(name.to_string());
+        Ok(format!("key '{}' derived from the HUK into slot {} at {:#x}", name, slot, Self::slot_addr(slot)))
+    }
+
+    pub fn key_delete(&mut self, name: &str) -> Result<String, String> {
+        let slot = self.key_slot(name)?;
+        PHYS_MEMORY.lock().unwrap().write(Self::slot_addr(slot), &[0; 32], true)?;
+        self.key_slots[slot] = None;
+        Ok(format!("key '{}' erased from slot {}", name, slot))
+    }
+
+    pub fn key_list(&self) -> String {
+        let names: Vec<String> = self
+            .key_slots
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, k)| k.as_ref().map(|name| format!("{}:{}", slot, name)))
+            .collect();
+        names.join(" ")
+    }
+
+    // The operation only ever sees the key in trusted SRAM, read with a
+    // Secure access
+    fn key(&self, name: &str) -> Result<[u8; 32], String> {
+        let slot = self.key_slot(name)?;
+        Ok(PHYS_MEMORY.lock().unwrap().read(Self::slot_addr(slot), 32, true)?.try_into().unwrap())
+    }
+
+    // AES-256-GCM with a fresh nonce and the key name as AAD. Returns
+    // hex(nonce || ciphertext || tag).
+    pub fn encrypt(&self, name: &str, plaintext: &[u8]) -> Result<String, String> {
+        let key = self.key(name)?;
+        let nonce: [u8; NONCE_SIZE] = random_bytes()[..NONCE_SIZE].try_into().unwrap();
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&aes_gcm_seal(&key, &nonce, name.as_bytes(), plaintext));
+        Ok(to_hex(&out))
+    }
+
+    pub fn decrypt(&self, name: &str, hex: &str) -> Result<Vec<u8>, String> {
+        let key = self.key(name)?;
+        let sealed = parse_hex(hex).filter(|b| b.len() >= NONCE_SIZE).ok_or_else(|| "TEE_ERROR_BAD_PARAMETERS: expected hex nonce || ciphertext || tag".to_string())?;
+        let (nonce, rest) = sealed.split_at(NONCE_SIZE);
+        aes_gcm_open(&key, nonce.try_into().unwrap(), name.as_bytes(), rest).map_err(|msg| format!("TEE_ERROR_MAC_INVALID: {}", msg))
+    }
+}
+
+lazy_static! {
+    pub static ref OPTEE: Mutex<TrustedOs> = Mutex::new(TrustedOs::new());
+}
@@ -0,0 +1,237 @@
+// $t@$h
+pub const PMP_ENTRIES: usize = 16;
+
+// pmpNcfg bits
+pub const PMP_R: u8 = 0x01;
+pub const PMP_W: u8 = 0x02;
+pub const PMP_X: u8 = 0x04;
+const PMP_A: u8 = 0x18;
+pub const PMP_TOR: u8 = 0x08;
+const PMP_NA4: u8 = 0x10;
+pub const PMP_NAPOT: u8 = 0x18;
+pub const PMP_L: u8 = 0x80;
+// pmpaddr holds bits 55:2 of the address
+const PMPADDR_MASK: u64 = (1 << 54) - 1;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn permission(self) -> u8 {
+        match self {
+            Access::Fetch => PMP_X,
+            Access::Load => PMP_R,
+            Access::Store => PMP_W,
+        }
+    }
+}
+
+// pmpaddr for a naturally aligned power-of-two region of at least 8 bytes
+pub fn napot(base: u64, size: u64) -> u64 {
+    ((base >> 2) | ((size >> 3) - 1)) & PMPADDR_MASK
+}
+
+// Physical Memory Protection: sixteen entries only M-mode can program. The
+// lowest-numbered entry that matches an access decides it.
+pub struct Pmp {
+    pub cfg: [u8; PMP_ENTRIES],
+    pub addr: [u64; PMP_ENTRIES],
+}
+
+impl Pmp {
+    pub fn new() -> Self {
+        Pmp {
+            cfg: [0; PMP_ENTRIES],
+            addr: [0; PMP_ENTRIES],
+        }
+    }
+
+    // The [start, end) an entry covers, if it is on. u128 so the entry
+    // covering the whole address space has an end.
+    fn range(&self, i: usize) -> Option<(u128, u128)> {
+        let addr = self.addr[i] as u128;
+        match self.cfg[i] & PMP_A {
+            PMP_TOR => {
+                let start = if i == 0 { 0 } else { (self.addr[i - 1] as u128) << 2 };
+                Some((start, addr << 2))
+            },
+            PMP_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            PMP_NAPOT => {
+                let ones = self.addr[i].trailing_ones();
+                let base = (addr & !((1u128 << ones) - 1)) << 2;
+                Some((base, base + (1u128 << (ones + 3))))
+            },
+            _ => None,
+        }
+    }
+
+    // M-mode gets through anything but a locked entry. S and U need an
+    // entry granting the access: with PMP implemented, no match is a fault.
+    pub fn check(&self, addr: u64, len: usize, access: Access, machine: bool) -> Result<(), String> {
+        let (start, end) = (addr as u128, addr as u128 + len as u128);
+        for i in 0..PMP_ENTRIES {
+            let (base, limit) = match self.range(i) {
+                Some(range) => range,
+                None => continue,
+            };
+            if end <= base || start >= limit {
+                continue;
+            }
+            let cfg = self.cfg[i];
+            if start < base || end > limit {
+                return Err(format!("PMP: {:?} of {:#x} only partly matches pmp{}", access, addr, i));
+            }
+            if (machine && cfg & PMP_L == 0) || cfg & access.permission() != 0 {
+                return Ok(());
+            }
+            return Err(format!("PMP: {:?} of {:#x} denied by pmp{} ({})", access, addr, i, describe(cfg)));
+        }
+        if machine {
+            return Ok(());
+        }
+        Err(format!("PMP: {:?} of {:#x} matches no entry", access, addr))
+    }
+
+    pub fn locked(&self, i: usize) -> bool {
+        self.cfg[i] & PMP_L != 0
+    }
+
+    // pmpcfg0 holds entries 0-7 and pmpcfg2 entries 8-15, a byte each.
+    // Locked entries ignore writes until reset.
+    pub fn read_cfg(&self, first: usize) -> u64 {
+        (0..8).fold(0, |acc, i| acc | (self.cfg[first + i] as u64) << (i * 8))
+    }
+
+    pub fn write_cfg(&mut self, first: usize, value: u64) {
+        for i in 0..8 {
+            if !self.locked(first + i) {
+                // W without R is reserved
+                let cfg = (value >> (i * 8)) as u8 & !0x60;
+                self.cfg[first + i] = if cfg & (PMP_R | PMP_W) == PMP_W { cfg & !PMP_W } else { cfg };
+            }
+        }
+    }
+
+    // A locked TOR entry locks the address below it too
+    pub fn write_addr(&mut self, i: usize, value: u64) {
+        let next_locks = i + 1 < PMP_ENTRIES && self.locked(i + 1) && self.cfg[i + 1] & PMP_A == PMP_TOR;
+        if !self.locked(i) && !next_locks {
+            self.addr[i] = value & PMPADDR_MASK;
+        }
+    }
+
+    pub fn print(&self) {
+        let mut any = false;
+        for i in 0..PMP_ENTRIES {
+            if let Some((base, limit)) = self.range(i) {
+                any = true;
+                let mode = match self.cfg[i] & PMP_A {
+                    PMP_TOR => "TOR",
+                    PMP_NA4 => "NA4",
+                    _ => "NAPOT",
+                };
+                println!(" pmp{:<2} {:#012x}-{:#012x}  {:<5} {}", i, base, limit - 1, mode, describe(self.cfg[i]));
+            }
+        }
+        if !any {
+            println!(" No PMP entry is on: M-mode reaches everything, S and U-mode nothing");
+        }
+    }
+}
+
+fn describe(cfg: u8) -> String {
+    let bit = |mask: u8, c: char| if cfg & mask != 0 { c } else { '-' };
+    format!("{}{}{}{}", bit(PMP_R, 'r'), bit(PMP_W, 'w'), bit(PMP_X, 'x'), if cfg & PMP_L != 0 { " locked" } else { "" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s_mode(pmp: &Pmp, addr: u64, len: usize, access: Access) -> Result<(), String> {
+        pmp.check(addr, len, access, false)
+    }
+
+    #[test]
+    fn tor_covers_up_to_its_address() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x1000 >> 2);
+        pmp.write_addr(1, 0x2000 >> 2);
+        pmp.write_cfg(0, ((PMP_TOR | PMP_R) as u64) << 8);
+        assert!(s_mode(&pmp, 0x1000, 4, Access::Load).is_ok());
+        assert!(s_mode(&pmp, 0x1ffc, 4, Access::Load).is_ok());
+        assert!(s_mode(&pmp, 0x2000, 4, Access::Load).unwrap_err().ends_with("matches no entry"));
+        assert!(s_mode(&pmp, 0xffc, 4, Access::Load).unwrap_err().ends_with("matches no entry"));
+        assert!(s_mode(&pmp, 0x1000, 4, Access::Store).unwrap_err().contains("denied by pmp1"));
+    }
+
+    #[test]
+    fn na4_and_napot_ranges() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x8000 >> 2);
+        pmp.write_addr(1, napot(0x10000, 0x1000));
+        pmp.write_cfg(0, (PMP_NA4 | PMP_X) as u64 | ((PMP_NAPOT | PMP_R | PMP_W) as u64) << 8);
+        assert!(s_mode(&pmp, 0x8000, 4, Access::Fetch).is_ok());
+        assert!(s_mode(&pmp, 0x8004, 4, Access::Fetch).unwrap_err().ends_with("matches no entry"));
+        assert!(s_mode(&pmp, 0x10000, 8, Access::Store).is_ok());
+        assert!(s_mode(&pmp, 0x10ff8, 8, Access::Load).is_ok());
+        assert!(s_mode(&pmp, 0x11000, 4, Access::Load).unwrap_err().ends_with("matches no entry"));
+        assert!(s_mode(&pmp, 0x10000, 4, Access::Fetch).unwrap_err().contains("denied by pmp1"));
+    }
+
+    #[test]
+    fn an_access_straddling_an_entry_faults() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x8000 >> 2);
+        pmp.write_addr(1, napot(0x10000, 0x1000));
+        pmp.write_addr(2, napot(0, 1 << 40));
+        pmp.write_cfg(0, (PMP_NA4 | PMP_R) as u64 | ((PMP_NAPOT | PMP_R) as u64) << 8 | ((PMP_NAPOT | PMP_R) as u64) << 16);
+        assert!(s_mode(&pmp, 0x7ffc, 8, Access::Load).unwrap_err().ends_with("only partly matches pmp0"));
+        assert!(s_mode(&pmp, 0x10ffc, 8, Access::Load).unwrap_err().ends_with("only partly matches pmp1"));
+        // pmp2 covers both bytes, but pmp1 is the lowest entry that matches
+        assert!(pmp.check(0x10ffc, 8, Access::Load, true).is_err());
+        assert!(s_mode(&pmp, 0x20000, 8, Access::Load).is_ok());
+    }
+
+    #[test]
+    fn lock_bit_applies_to_machine_mode() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, napot(0x80000000, 0x10000));
+        pmp.write_addr(1, napot(0x80010000, 0x10000));
+        pmp.write_cfg(0, (PMP_NAPOT | PMP_R) as u64 | ((PMP_NAPOT | PMP_R | PMP_L) as u64) << 8);
+        // Unlocked entries and unmatched addresses do not stop M-mode
+        assert!(pmp.check(0x80000000, 4, Access::Store, true).is_ok());
+        assert!(pmp.check(0x1000, 4, Access::Store, true).is_ok());
+        assert!(pmp.check(0x80010000, 4, Access::Load, true).is_ok());
+        assert!(pmp.check(0x80010000, 4, Access::Store, true).unwrap_err().contains("denied by pmp1 (r-- locked)"));
+    }
+
+    #[test]
+    fn locked_entries_ignore_writes() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x1000 >> 2);
+        pmp.write_addr(1, 0x2000 >> 2);
+        pmp.write_cfg(0, ((PMP_TOR | PMP_R | PMP_L) as u64) << 8);
+        pmp.write_cfg(0, (PMP_NAPOT | PMP_R) as u64 | ((PMP_NAPOT | PMP_R | PMP_W | PMP_X) as u64) << 8);
+        assert_eq!(pmp.cfg[0], PMP_NAPOT | PMP_R);
+        assert_eq!(pmp.cfg[1], PMP_TOR | PMP_R | PMP_L);
+        // A locked TOR entry also holds the address below it
+        pmp.write_addr(1, 0x3000 >> 2);
+        pmp.write_addr(0, 0);
+        assert_eq!(pmp.addr[..2], [0x1000 >> 2, 0x2000 >> 2]);
+        pmp.write_addr(2, 0x3000 >> 2);
+        assert_eq!(pmp.addr[2], 0x3000 >> 2);
+    }
+
+    #[test]
+    fn write_only_is_reserved() {
+        let mut pmp = Pmp::new();
+        pmp.write_cfg(8, (PMP_NAPOT | PMP_W) as u64 | 0x60);
+        assert_eq!(pmp.cfg[8], PMP_NAPOT);
+        assert_eq!(pmp.read_cfg(8), PMP_NAPOT as u64);
+    }
+}
@@ -0,0 +1,301 @@
+// $t@$h
+use crate::cpu::Registers;
+use crate::elf::{flags_str, ElfImage, PF_R, PF_W};
+use crate::memory::PAGE_SIZE;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+// Where static PIE images and the stack are mapped in every address space
+pub const USER_TEXT_BASE: usize = 0x400000;
+pub const USER_STACK_TOP: usize = 0x7fff_f000;
+const STACK_PAGES: usize = 2;
+// Segments must end below the stack
+pub const USER_STACK_LIMIT: usize = USER_STACK_TOP - STACK_PAGES * PAGE_SIZE;
+// Timer ticks a process runs before it is preempted
+pub const TIME_SLICE: u64 = 3;
+
+// A process's private view of memory, mapped page by page with ELF-style
+// permissions. Two processes can use the same virtual address and never
+// see each other's bytes.
+pub struct AddressSpace {
+    pages: BTreeMap<usize, (Vec<u8>, u32)>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        AddressSpace { pages: BTreeMap::new() }
+    }
+
+    // Pages shared by two segments get the union of their permissions
+    pub fn map(&mut self, addr: usize, len: usize, flags: u32) {
+        let first = addr / PAGE_SIZE;
+        let last = (addr + len.max(1) - 1) / PAGE_SIZE;
+        for page in first..=last {
+            self.pages.entry(page).or_insert_with(|| (vec![0u8; PAGE_SIZE], 0)).1 |= flags;
+        }
+    }
+
+    fn check(&self, addr: usize, len: usize, access: u32) -> Result<(), String> {
+        for a in addr..addr.saturating_add(len) {
+            match self.pages.get(&(a / PAGE_SIZE)) {
+                Some((_, flags)) if flags & access == access => {},
+                Some(_) => return Err(format!("Segmentation fault: {:#x} does not allow {} access", a, flags_str(access).trim_matches('-'))),
+                None => return Err(format!("Segmentation fault: {:#x} is not mapped in this process", a)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        self.check(addr, len, PF_R)?;
+        Ok((addr..addr + len).map(|a| self.pages[&(a / PAGE_SIZE)].0[a % PAGE_SIZE]).collect())
+    }
+
+    // Faults before touching anything so a bad write leaves no partial update
+    pub fn write(&mut self, addr: usize, data: &[u8]) -> Result<(), String> {
+        self.check(addr, data.len(), PF_W)?;
+        self.poke(addr, data);
+        Ok(())
+    }
+
+    // The kernel filling in pages it has just mapped, whatever their permissions
+    fn poke(&mut self, addr: usize, data: &[u8]) {
+        for (i, b) in data.iter().enumerate() {
+            let a = addr + i;
+            self.pages.get_mut(&(a / PAGE_SIZE)).unwrap().0[a % PAGE_SIZE] = *b;
+        }
+    }
+
+    // One line per run of contiguous pages with the same permissions, like /proc/<pid>/maps
+    pub fn print_maps(&self) {
+        let mut runs: Vec<(usize, usize, u32)> = Vec::new();
+        for (&page, &(_, flags)) in &self.pages {
+            match runs.last_mut() {
+                Some((_, end, f)) if *end == page && *f == flags => *end = page + 1,
+                _ => runs.push((page, page + 1, flags)),
+            }
+        }
+        for (start, end, flags) in runs {
+            println!(" {:08x}-{:08x} {}p", start * PAGE_SIZE, end * PAGE_SIZE, flags_str(flags));
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ProcState {
+    Ready,
+    Running,
+}
+
+pub struct Process {
+    pub pid: u32,
+    pub path: String,
+    // Higher runs first under the priority policy
+    pub priority: u8,
+    pub state: ProcState,
+    // Saved register file; the live one is on the vCPU while the process runs
+    pub regs: Registers,
+    pub memory: AddressSpace,
+    pub ticks: u64,
+}
+
+impl Process {
+    // Map every PT_LOAD segment (bss zero filled), give the process a fresh
+    // stack and point it at the entry point
+    fn load(&mut self, path: &str, image: &ElfImage) {
+        self.path = path.to_string();
+        self.memory = AddressSpace::new();
+        for segment in &image.segments {
+            self.memory.map(segment.vaddr, segment.memsz, segment.flags);
+            self.memory.poke(segment.vaddr, &segment.data);
+        }
+        self.memory.map(USER_STACK_LIMIT, STACK_PAGES * PAGE_SIZE, PF_R | PF_W);
+        self.regs = Registers::new();
+        self.regs.rip = image.entry as u64;
+        self.regs.rsp = USER_STACK_TOP as u64;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Policy {
+    RoundRobin,
+    Priority,
+}
+
+impl Policy {
+    pub fn parse(s: &str) -> Option<Policy> {
+        match s {
+            "rr" => Some(Policy::RoundRobin),
+            "prio" | "priority" => Some(Policy::Priority),
+            _ => None,
+        }
+    }
+}
+
+// The guest kernel's process table and scheduler
+pub struct Scheduler {
+    pub policy: Policy,
+    procs: Vec<Process>,
+    running: Option<u32>,
+    next_pid: u32,
+    slice_left: u64,
+    pub uptime: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            policy: Policy::RoundRobin,
+            procs: Vec::new(),
+            running: None,
+            next_pid: 1,
+            slice_left: TIME_SLICE,
+            uptime: 0,
+        }
+    }
+
+    pub fn spawn(&mut self, path: &str, image: &ElfImage, priority: u8) -> u32 {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+        let mut process = Process {
+            pid,
+            path: String::new(),
+            priority,
+            state: ProcState::Ready,
+            regs: Registers::new(),
+            memory: AddressSpace::new(),
+            ticks: 0,
+        };
+        process.load(path, image);
+        self.procs.push(process);
+        pid
+    }
+
+    fn index_of(&self, pid: u32) -> Result<usize, String> {
+        self.procs
+            .iter()
+            .position(|p| p.pid == pid)
+            .ok_or_else(|| format!("No process with pid {}", pid))
+    }
+
+    pub fn running(&self) -> Option<&Process> {
+        self.running.and_then(|pid| self.procs.iter().find(|p| p.pid == pid))
+    }
+
+    pub fn running_mut(&mut self) -> Option<&mut Process> {
+        let pid = self.running?;
+        self.procs.iter_mut().find(|p| p.pid == pid)
+    }
+
+    // Save the vCPU registers into the outgoing process and load the incoming one's
+    pub fn switch_to(&mut self, pid: u32, cpu: &mut Registers) -> Result<(), String> {
+        let next = self.index_of(pid)?;
+        let previous = self.running;
+        if previous == Some(pid) {
+            self.slice_left = TIME_SLICE;
+            return Ok(());
+        }
+        if let Some(current) = self.running_mut() {
+            current.regs = cpu.clone();
+            current.state = ProcState::Ready;
+        }
+        *cpu = self.procs[next].regs.clone();
+        self.procs[next].state = ProcState::Running;
+        self.running = Some(pid);
+        self.slice_left = TIME_SLICE;
+        match previous {
+            Some(prev) => println!("[sched] context switch pid {} -> pid {} ({})", prev, pid, self.procs[next].path),
+            None => println!("[sched] running pid {} ({})", pid, self.procs[next].path),
+        }
+        Ok(())
+    }
+
+    // Candidates in round-robin order, starting after the running process
+    fn pick_next(&self) -> Option<u32> {
+        let start = match self.running.and_then(|pid| self.index_of(pid).ok()) {
+            Some(i) => i + 1,
+            None => 0,
+        };
+        let n = self.procs.len();
+        let rotation = (0..n).map(|i| &self.procs[(start + i) % n]);
+        match self.policy {
+            Policy::RoundRobin => rotation.map(|p| p.pid).next(),
+            Policy::Priority => rotation.min_by_key(|p| Reverse(p.priority)).map(|p| p.pid),
+        }
+    }
+
+    pub fn schedule(&mut self, cpu: &mut Registers) {
+        if let Some(pid) = self.pick_next() {
+            self.switch_to(pid, cpu).unwrap();
+        }
+    }
+
+    // Timer interrupts: charge the running process and preempt it when its slice is used up
+    pub fn tick(&mut self, count: u64, cpu: &mut Registers) {
+        for _ in 0..count {
+            self.uptime += 1;
+            let pid = match self.running_mut() {
+                Some(current) => {
+                    current.ticks += 1;
+                    current.pid
+                },
+                None => continue,
+            };
+            self.slice_left = self.slice_left.saturating_sub(1);
+            if self.slice_left == 0 {
+                println!("[timer] pid {} used its time slice", pid);
+                self.schedule(cpu);
+            }
+        }
+    }
+
+    pub fn kill(&mut self, pid: u32, cpu: &mut Registers) -> Result<(), String> {
+        let index = self.index_of(pid)?;
+        let process = self.procs.remove(index);
+        println!("Killed pid {} ({})", pid, process.path);
+        if self.running == Some(pid) {
+            // Nothing to save: the process is gone
+            self.running = None;
+            *cpu = Registers::new();
+            self.schedule(cpu);
+        }
+        Ok(())
+    }
+
+    // exec(): the running process keeps its pid but gets a new image
+    pub fn exec(&mut self, path: &str, image: &ElfImage, cpu: &mut Registers) -> Result<(), String> {
+        let current = self.running_mut().ok_or("No process is running")?;
+        current.load(path, image);
+        *cpu = current.regs.clone();
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.procs.is_empty()
+    }
+
+    pub fn ps(&self, cpu: &Registers) {
+        println!("  PID PRI STATE    TICKS  RIP       CMD");
+        for p in &self.procs {
+            let (marker, rip) = if self.running == Some(p.pid) { ("*", cpu.rip) } else { (" ", p.regs.rip) };
+            println!(
+                "{}{:>4} {:>3} {:<8} {:>5}  {:#08x}  {}",
+                marker,
+                p.pid,
+                p.priority,
+                format!("{:?}", p.state),
+                p.ticks,
+                rip,
+                p.path
+            );
+        }
+        println!("policy={:?} uptime={} ticks", self.policy, self.uptime);
+    }
+
+    pub fn print_maps(&self, pid: u32) -> Result<(), String> {
+        let process = &self.procs[self.index_of(pid)?];
+        println!("Address space of pid {} ({}):", pid, process.path);
+        process.memory.print_maps();
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+// $t@$h
+use crate::aarch64::{KERNEL_BASE, KERNEL_SIZE};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+pub const NUM_CORES: usize = 4;
+// OP-TEE is a uniprocessor trusted OS, resident on the core that booted it
+pub const TRUSTED_OS_CORE: usize = 0;
+
+// PSCI function IDs; the ones that take addresses use the SMC64 range
+pub const PSCI_VERSION: u64 = 0x8400_0000;
+pub const CPU_OFF: u64 = 0x8400_0002;
+pub const CPU_ON: u64 = 0xc400_0003;
+pub const AFFINITY_INFO: u64 = 0xc400_0004;
+pub const MIGRATE_INFO_TYPE: u64 = 0x8400_0006;
+pub const SYSTEM_OFF: u64 = 0x8400_0008;
+pub const SYSTEM_RESET: u64 = 0x8400_0009;
+pub const SYSTEM_SUSPEND: u64 = 0xc400_000e;
+
+pub fn function_name(id: u64) -> Option<&'static str> {
+    match id {
+        PSCI_VERSION => Some("PSCI_VERSION"),
+        CPU_OFF => Some("CPU_OFF"),
+        CPU_ON => Some("CPU_ON"),
+        AFFINITY_INFO => Some("AFFINITY_INFO"),
+        MIGRATE_INFO_TYPE => Some("MIGRATE_INFO_TYPE"),
+        SYSTEM_OFF => Some("SYSTEM_OFF"),
+        SYSTEM_RESET => Some("SYSTEM_RESET"),
+        SYSTEM_SUSPEND => Some("SYSTEM_SUSPEND"),
+        _ => None,
+    }
+}
+
+// What comes back in X0 when a call fails
+#[derive(Debug, Clone, Copy)]
+pub enum PsciError {
+    InvalidParameters = -2,
+    Denied = -3,
+    AlreadyOn = -4,
+    InvalidAddress = -9,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PowerState {
+    On,
+    Off,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SystemState {
+    Running,
+    Suspended,
+    Off,
+}
+
+// BL31's view of the power domains: each core, and the system as a whole
+pub struct Psci {
+    pub cores: [PowerState; NUM_CORES],
+    // Where each core last entered the Non-secure world
+    entries: [Option<u64>; NUM_CORES],
+    pub system: SystemState,
+    // SYSTEM_SUSPEND's entry point and context ID, for the wake-up
+    resume: Option<(u64, u64)>,
+}
+
+impl Psci {
+    fn new() -> Self {
+        let mut cores = [PowerState::Off; NUM_CORES];
+        cores[0] = PowerState::On;
+        Psci {
+            cores,
+            entries: [None; NUM_CORES],
+            system: SystemState::Running,
+            resume: None,
+        }
+    }
+
+    // BL31 only ever releases a core into the kernel image BL33 loaded.
+    // Trusted SRAM, or any address an attacker picked, is refused.
+    fn validate_entry(entry: u64) -> Result<(), PsciError> {
+        if !entry.is_multiple_of(4) || !(KERNEL_BASE..KERNEL_BASE + KERNEL_SIZE).contains(&entry) {
+            return Err(PsciError::InvalidAddress);
+        }
+        Ok(())
+    }
+
+    fn core(target: u64) -> Result<usize, PsciError> {
+        // MPIDR_EL1: one cluster, Aff0 is the core number
+        match target as usize {
+            core if core < NUM_CORES => Ok(core),
+            _ => Err(PsciError::InvalidParameters),
+        }
+    }
+
+    pub fn cpu_on(&mut self, target: u64, entry: u64) -> Result<usize, PsciError> {
+        let core = Psci::core(target)?;
+        if self.cores[core] == PowerState::On {
+            return Err(PsciError::AlreadyOn);
+        }
+        Psci::validate_entry(entry)?;
+        self.cores[core] = PowerState::On;
+        self.entries[core] = Some(entry);
+        Ok(core)
+    }
+
+    // A UP trusted OS cannot migrate, so its core has to stay up
+    pub fn cpu_off(&mut self, caller: usize) -> Result<(), PsciError> {
+        if caller == TRUSTED_OS_CORE {
+            return Err(PsciError::Denied);
+        }
+        self.cores[caller] = PowerState::Off;
+        self.entries[caller] = None;
+        Ok(())
+    }
+
+    // 0: on, 1: off
+    pub fn affinity_info(&self, target: u64) -> Result<u64, PsciError> {
+        let core = Psci::core(target)?;
+        Ok(if self.cores[core] == PowerState::On { 0 } else { 1 })
+    }
+
+    // Only the last core standing may suspend the system
+    pub fn system_suspend(&mut self, caller: usize, entry: u64, context: u64) -> Result<(), PsciError> {
+        if self.cores.iter().enumerate().any(|(core, &state)| core != caller && state == PowerState::On) {
+            return Err(PsciError::Denied);
+        }
+        Psci::validate_entry(entry)?;
+        self.system = SystemState::Suspended;
+        self.resume = Some((entry, context));
+        Ok(())
+    }
+
+    // A wake-up event: the core that suspended comes back through BL31
+    pub fn wake(&mut self) -> Option<(u64, u64)> {
+        if self.system != SystemState::Suspended {
+            return None;
+        }
+        self.system = SystemState::Running;
+        self.resume.take()
+    }
+
+    // Every reset, and a cold boot, starts with only the boot core running
+    pub fn reset(&mut self, system: SystemState) {
+        *self = Psci::new();
+        if system == SystemState::Off {
+            self.cores[0] = PowerState::Off;
+        }
+        self.system = system;
+    }
+
+    pub fn print(&self) {
+        println!("System: {:?}", self.system);
+        for (core, state) in self.cores.iter().enumerate() {
+            let entry = match self.entries[core] {
+                Some(entry) => format!("  entered the kernel at {:#x}", entry),
+                None if core == 0 && *state == PowerState::On => "  boot core".to_string(),
+                None => String::new(),
+            };
+            println!(" core {}  {:?}{}{}", core, state, entry, if core == TRUSTED_OS_CORE { "  (OP-TEE resident)" } else { "" });
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref PSCI: Mutex<Psci> = Mutex::new(Psci::new());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aarch64::BL31_BASE;
+
+    #[test]
+    fn entry_must_be_an_aligned_kernel_address() {
+        assert!(Psci::validate_entry(KERNEL_BASE).is_ok());
+        assert!(Psci::validate_entry(KERNEL_BASE + KERNEL_SIZE - 4).is_ok());
+        assert!(matches!(Psci::validate_entry(KERNEL_BASE + 2), Err(PsciError::InvalidAddress)));
+        assert!(matches!(Psci::validate_entry(KERNEL_BASE + KERNEL_SIZE), Err(PsciError::InvalidAddress)));
+        assert!(matches!(Psci::validate_entry(KERNEL_BASE - 4), Err(PsciError::InvalidAddress)));
+        assert!(matches!(Psci::validate_entry(BL31_BASE), Err(PsciError::InvalidAddress)));
+        assert!(matches!(Psci::validate_entry(0xffff_ffff_ffff_fffc), Err(PsciError::InvalidAddress)));
+    }
+
+    #[test]
+    fn cpu_on_checks_target_state_and_entry() {
+        let mut psci = Psci::new();
+        assert!(matches!(psci.cpu_on(0, KERNEL_BASE), Err(PsciError::AlreadyOn)));
+        assert!(matches!(psci.cpu_on(NUM_CORES as u64, KERNEL_BASE), Err(PsciError::InvalidParameters)));
+        assert!(matches!(psci.cpu_on(1, BL31_BASE), Err(PsciError::InvalidAddress)));
+        assert_eq!(psci.cores[1], PowerState::Off);
+        assert_eq!(psci.cpu_on(1, KERNEL_BASE).unwrap(), 1);
+        assert_eq!(psci.affinity_info(1).unwrap(), 0);
+        assert!(matches!(psci.cpu_on(1, KERNEL_BASE), Err(PsciError::AlreadyOn)));
+    }
+
+    #[test]
+    fn cpu_off_is_denied_on_the_trusted_os_core() {
+        let mut psci = Psci::new();
+        assert!(matches!(psci.cpu_off(TRUSTED_OS_CORE), Err(PsciError::Denied)));
+        assert_eq!(psci.cores[0], PowerState::On);
+        psci.cpu_on(2, KERNEL_BASE).unwrap();
+        psci.cpu_off(2).unwrap();
+        assert_eq!(psci.affinity_info(2).unwrap(), 1);
+    }
+
+    #[test]
+    fn system_suspend_needs_the_last_core_and_a_valid_entry() {
+        let mut psci = Psci::new();
+        psci.cpu_on(3, KERNEL_BASE).unwrap();
+        assert!(matches!(psci.system_suspend(0, KERNEL_BASE, 7), Err(PsciError::Denied)));
+        psci.cpu_off(3).unwrap();
+        assert!(matches!(psci.system_suspend(0, BL31_BASE, 7), Err(PsciError::InvalidAddress)));
+        assert_eq!(psci.system, SystemState::Running);
+        psci.system_suspend(0, KERNEL_BASE, 7).unwrap();
+        assert_eq!(psci.system, SystemState::Suspended);
+        assert_eq!(psci.wake(), Some((KERNEL_BASE, 7)));
+        assert_eq!(psci.wake(), None);
+    }
+}
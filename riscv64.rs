@@ -0,0 +1,480 @@
+// $t@$h
+// Shared with the other editions; what this one leaves unused is marked
+// allow(dead_code) where it is defined
+mod crypto;
+mod engine;
+mod keys;
+mod memory;
+mod pmp;
+mod rv64;
+mod sbi;
+
+use engine::{report, CommandResult, Engine, Instruction, InstructionHandler, Machine, Transition};
+use memory::{hexdump, parse_hex};
+use pmp::{napot, PMP_NAPOT, PMP_R, PMP_W, PMP_X};
+use rv64::{
+    parse_imm, parse_reg, Hart, Privilege, A0, A1, A6, A7, HART, KERNEL_BASE, ROM_BASE, SBI_BASE, SBI_SIZE, TVEC_OFFSET,
+    USER_BASE, USER_STACK,
+};
+use sbi::{
+    extension_name, Flash, Image, SbiError, Stage, EXT_BASE, EXT_DBCN, EXT_SRST, EXT_TIME, FIRMWARE, SBI_IMPL_ID,
+    SBI_IMPL_VERSION, SBI_SPEC_VERSION,
+};
+
+// The privilege mode the hart runs in, or no power at all
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Mode {
+    Machine,
+    Supervisor,
+    User,
+    Off,
+}
+
+impl Mode {
+    fn privilege(self) -> Option<Privilege> {
+        match self {
+            Mode::Machine => Some(Privilege::Machine),
+            Mode::Supervisor => Some(Privilege::Supervisor),
+            Mode::User => Some(Privilege::User),
+            Mode::Off => None,
+        }
+    }
+}
+
+// Main Instruction Handlers, run on the hart's register file
+fn run(mnemonic: &str, args: &[&str]) {
+    match HART.lock().unwrap().execute(mnemonic, args) {
+        Ok(effect) => println!("Executed {} instruction: {}", mnemonic, effect),
+        Err(msg) => println!("{}", msg),
+    }
+}
+
+fn add_handler(args: &[&str]) { run("ADD", args); }
+fn sub_handler(args: &[&str]) { run("SUB", args); }
+fn and_handler(args: &[&str]) { run("AND", args); }
+fn or_handler(args: &[&str]) { run("OR", args); }
+fn xor_handler(args: &[&str]) { run("XOR", args); }
+fn addi_handler(args: &[&str]) { run("ADDI", args); }
+fn li_handler(args: &[&str]) { run("LI", args); }
+fn mv_handler(args: &[&str]) { run("MV", args); }
+fn ld_handler(args: &[&str]) { run("LD", args); }
+fn sd_handler(args: &[&str]) { run("SD", args); }
+fn nop_handler(args: &[&str]) { run("NOP", args); }
+
+// System Instruction Handlers. Each stage installs its trap vector, points
+// xPP/xepc at the next stage and drops into it with xRET.
+fn setup_pmp(_: &[&str]) {
+    if FIRMWARE.lock().unwrap().stage != Stage::Sbi {
+        println!("OpenSBI not loaded. Aborting.");
+        return;
+    }
+    let mut hart = HART.lock().unwrap();
+    // pmp0 hides OpenSBI from S and U-mode; the last entry opens the rest
+    // of the address space to them
+    hart.pmp.write_addr(0, napot(SBI_BASE, SBI_SIZE));
+    hart.pmp.write_addr(15, u64::MAX);
+    hart.pmp.write_cfg(0, PMP_NAPOT as u64);
+    hart.pmp.write_cfg(8, ((PMP_NAPOT | PMP_R | PMP_W | PMP_X) as u64) << 56);
+    println!("OpenSBI: PMP set up");
+    hart.pmp.print();
+    hart.step();
+}
+fn init_kernel(_: &[&str]) {
+    let mut hart = HART.lock().unwrap();
+    println!("Kernel initialized in S-mode");
+    hart.install_tvec(Privilege::Supervisor, KERNEL_BASE + TVEC_OFFSET, "kernel");
+    hart.x[2] = USER_STACK;
+    report(hart.enter_lower(Privilege::User, USER_BASE));
+}
+fn start_user_apps(_: &[&str]) { println!("User space applications started in U-mode"); }
+
+// The mode each system instruction's stage runs in
+fn system_level(instruction: &str) -> Option<Mode> {
+    match instruction {
+        "setup_pmp" => Some(Mode::Machine),
+        "init_kernel" => Some(Mode::Supervisor),
+        "start_user_apps" => Some(Mode::User),
+        _ => None,
+    }
+}
+
+// Every reset puts the hart back in the boot ROM with nothing verified.
+// Flash and the fuses survive, and DRAM does too unless the power went.
+fn reset_platform(hart: &mut Hart, powered: bool) {
+    hart.reset(powered);
+    FIRMWARE.lock().unwrap().reset();
+}
+
+// OpenSBI's runtime service: an ECALL from S-mode traps to M-mode with
+// the extension in a7 and the function in a6. The error comes back in a0,
+// the value in a1.
+fn service_sbi(hart: &mut Hart) -> Result<(), String> {
+    let (eid, fid, arg0, arg1) = (hart.x[A7], hart.x[A6], hart.x[A0], hart.x[A1]);
+    println!("OpenSBI: {}({:#x}) fid {}, a0={:#x} a1={:#x}", extension_name(eid).unwrap_or("unknown extension"), eid, fid, arg0, arg1);
+    let mut firmware = FIRMWARE.lock().unwrap();
+    let result = match (eid, fid) {
+        (EXT_BASE, 0) => Ok(SBI_SPEC_VERSION),
+        (EXT_BASE, 1) => Ok(SBI_IMPL_ID),
+        (EXT_BASE, 2) => Ok(SBI_IMPL_VERSION),
+        (EXT_BASE, 3) => Ok(extension_name(arg0).is_some() as u64),
+        // mvendorid, marchid, mimpid
+        (EXT_BASE, 4..=6) => Ok(0),
+        (EXT_TIME, 0) => {
+            firmware.timer = Some(arg0);
+            println!("OpenSBI: S-mode timer interrupt due at time {:#x}", arg0);
+            Ok(0)
+        },
+        (EXT_DBCN, 2) => {
+            println!("console: '{}'", (arg0 as u8) as char);
+            Ok(0)
+        },
+        // Shutdown, cold reboot, warm reboot
+        (EXT_SRST, 0) if arg0 <= 2 => {
+            drop(firmware);
+            if arg0 == 0 {
+                println!("OpenSBI: powering the SoC down");
+                reset_platform(hart, false);
+                println!("System off. Type 'powerup' to cold boot.");
+            } else {
+                println!("OpenSBI: resetting the SoC");
+                reset_platform(hart, true);
+                println!("Hart 0 back in the boot ROM at {:#x}. OpenSBI and the kernel have to be verified again.", ROM_BASE);
+            }
+            return Ok(());
+        },
+        (EXT_SRST, 0) => Err(SbiError::InvalidParam),
+        _ => Err(SbiError::NotSupported),
+    };
+    let (error, value) = match result {
+        Ok(value) => {
+            println!("OpenSBI: returns SBI_SUCCESS, a1={:#x}", value);
+            (0, value)
+        },
+        Err(err) => {
+            println!("OpenSBI: returns {:?} ({})", err, err as i64);
+            (err as i64 as u64, 0)
+        },
+    };
+    hart.x[A0] = error;
+    hart.x[A1] = value;
+    hart.mret()
+}
+
+// The kernel's side of SBI: extension and function in a7/a6, arguments in a0/a1, then ECALL
+fn process_sbi_command(args: &[&str]) -> Result<(), String> {
+    let number = |s: &str| parse_imm(s).ok_or_else(|| format!("Invalid argument '{}'", s));
+    let call = match args {
+        [] | ["status"] => {
+            println!("SBI v2.0, OpenSBI v1.3: BASE, TIME, SRST and DBCN");
+            match FIRMWARE.lock().unwrap().timer {
+                Some(time) => println!(" S-mode timer due at {:#x}", time),
+                None => println!(" S-mode timer not set"),
+            }
+            return Ok(());
+        },
+        ["version"] => [EXT_BASE, 0, 0, 0],
+        ["impl"] => [EXT_BASE, 1, 0, 0],
+        ["probe", eid] => [EXT_BASE, 3, number(eid)?, 0],
+        ["set_timer", time] => [EXT_TIME, 0, number(time)?, 0],
+        ["putchar", c] if c.len() == 1 => [EXT_DBCN, 2, c.as_bytes()[0] as u64, 0],
+        ["shutdown"] => [EXT_SRST, 0, 0, 0],
+        ["reboot"] => [EXT_SRST, 0, 1, 0],
+        _ => {
+            return Err("Usage: sbi [status] | sbi version | sbi impl | sbi probe <eid> | sbi set_timer <time> | \
+                        sbi putchar <c> | sbi shutdown | sbi reboot"
+                .to_string())
+        },
+    };
+    let mut hart = HART.lock().unwrap();
+    if hart.privilege != Privilege::Supervisor {
+        return Err(format!("SBI calls come from the S-mode kernel, not {:?} mode", hart.privilege));
+    }
+    hart.x[A7] = call[0];
+    hart.x[A6] = call[1];
+    hart.x[A0] = call[2];
+    hart.x[A1] = call[3];
+    ecall(&mut hart)
+}
+
+// An ECALL from S-mode lands in OpenSBI once it is resident; before that,
+// or from U-mode, the trap handler it reaches deals with it
+fn ecall(hart: &mut Hart) -> Result<(), String> {
+    let from = hart.privilege;
+    hart.ecall()?;
+    if from == Privilege::Supervisor && hart.privilege == Privilege::Machine && FIRMWARE.lock().unwrap().stage == Stage::Kernel {
+        return service_sbi(hart);
+    }
+    Ok(())
+}
+
+// Traps, trap returns and CSR access
+fn process_trap_command(command: &str, args: &[&str]) {
+    let mut hart = HART.lock().unwrap();
+    let joined = args.join(" ");
+    let ops: Vec<&str> = joined.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+    let result = match (command, &ops[..]) {
+        ("ECALL", []) => ecall(&mut hart),
+        ("MRET", []) => hart.mret(),
+        ("SRET", []) => hart.sret(),
+        ("CSRR", [rd, csr]) => match parse_reg(rd) {
+            Some(rd) => hart.csrr(csr).map(|value| {
+                if rd != 0 {
+                    hart.x[rd] = value;
+                }
+                println!("{} = {:#x}", csr, value);
+            }),
+            None => Err(format!("Invalid register '{}'", rd)),
+        },
+        ("CSRW", [csr, rs]) => match parse_reg(rs).map(|rs| hart.x[rs]).or_else(|| parse_imm(rs)) {
+            Some(value) => hart.csrw(csr, value),
+            None => Err(format!("Invalid value '{}'", rs)),
+        },
+        _ => Err("Usage: ECALL | MRET | SRET | CSRR rd, <csr> | CSRW <csr>, rs|value".to_string()),
+    };
+    report(result);
+}
+
+// The most one `mem read` dumps
+const MAX_DUMP: u64 = 0x1000;
+
+// Physical memory accesses in the hart's current mode, through PMP. One
+// PMP refuses comes back as an access fault.
+fn process_mem_command(args: &[&str]) -> Result<(), String> {
+    let mut hart = HART.lock().unwrap();
+    match args {
+        ["read", addr, rest @ ..] => match (parse_imm(addr), rest.first().map_or(Some(32), |l| parse_imm(l))) {
+            (_, Some(len)) if len > MAX_DUMP => Err(format!("mem read shows at most {:#x} bytes", MAX_DUMP)),
+            (Some(addr), Some(len)) => hart.load(addr, len as usize).map(|bytes| hexdump(addr as usize, &bytes)),
+            _ => Err("Invalid address or length".to_string()),
+        },
+        ["write", addr, hex] => match (parse_imm(addr), parse_hex(hex)) {
+            (Some(addr), Some(bytes)) => hart.store(addr, &bytes),
+            _ => Err("Invalid address or hex bytes".to_string()),
+        },
+        _ => Err("Usage: mem read <addr> [len] | mem write <addr> <hexbytes>".to_string()),
+    }
+}
+
+// Boot stage commands only run in the firmware that owns them, in M-mode
+fn in_stage(command: &str, stage: Stage) -> Result<(), String> {
+    let current = FIRMWARE.lock().unwrap().stage;
+    if current != stage || HART.lock().unwrap().privilege != Privilege::Machine {
+        return Err(format!("'{}' runs in {:?} in M-mode", command, stage));
+    }
+    Ok(())
+}
+
+// The secure boot flow: the boot ROM authenticates and runs OpenSBI,
+// OpenSBI authenticates the kernel and MRETs into it in S-mode
+fn process_command(command: &str, args: &[&str]) -> CommandResult {
+    if !HART.lock().unwrap().powered {
+        println!("The system is off. Type 'powerup' first.");
+        return CommandResult::Failed;
+    }
+    match command {
+        "regs" => {
+            HART.lock().unwrap().print_registers();
+            CommandResult::Success
+        },
+        "csrs" => {
+            HART.lock().unwrap().print_csrs();
+            CommandResult::Success
+        },
+        "ECALL" | "MRET" | "SRET" | "CSRR" | "CSRW" => {
+            process_trap_command(command, args);
+            CommandResult::Success
+        },
+        "verify_sbi" | "verify_kernel" => {
+            let image = Image::parse(&command["verify_".len()..]).unwrap();
+            let stage = if image == Image::Sbi { Stage::Rom } else { Stage::Sbi };
+            let result = in_stage(command, stage).and_then(|_| FIRMWARE.lock().unwrap().verify(image));
+            report(result.map(|_| println!("Verified {}", image.name())))
+        },
+        "load_sbi" => {
+            if let Err(msg) = in_stage(command, Stage::Rom) {
+                println!("{}", msg);
+                return CommandResult::Failed;
+            }
+            let mut firmware = FIRMWARE.lock().unwrap();
+            if !firmware.is_verified(Image::Sbi) {
+                println!("OpenSBI not verified. Aborting.");
+                return CommandResult::NotVerified;
+            }
+            let mut hart = HART.lock().unwrap();
+            match firmware.load(Image::Sbi, SBI_BASE) {
+                // fw_jump runs in M-mode like the ROM, so the ROM just jumps to it
+                Ok(bytes) => {
+                    hart.write_image(SBI_BASE, &bytes);
+                    hart.pc = SBI_BASE;
+                    println!("Boot ROM: jumping to OpenSBI at {:#x}", SBI_BASE);
+                    hart.install_tvec(Privilege::Machine, SBI_BASE + TVEC_OFFSET, "OpenSBI");
+                    firmware.stage = Stage::Sbi;
+                    CommandResult::Success
+                },
+                Err(msg) => {
+                    println!("{}", msg);
+                    CommandResult::Failed
+                },
+            }
+        },
+        "load_kernel" => {
+            if let Err(msg) = in_stage(command, Stage::Sbi) {
+                println!("{}", msg);
+                return CommandResult::Failed;
+            }
+            let mut firmware = FIRMWARE.lock().unwrap();
+            if !firmware.is_verified(Image::Kernel) {
+                println!("S-mode kernel not verified. Aborting.");
+                return CommandResult::NotVerified;
+            }
+            let mut hart = HART.lock().unwrap();
+            let bytes = match firmware.load(Image::Kernel, KERNEL_BASE) {
+                Ok(bytes) => bytes,
+                Err(msg) => {
+                    println!("{}", msg);
+                    return CommandResult::Failed;
+                },
+            };
+            hart.write_image(KERNEL_BASE, &bytes);
+            // What OpenSBI delegates: misaligned fetches, breakpoints, ECALL
+            // from U-mode and page faults. Access faults stay in M-mode.
+            hart.medeleg = 0xb109;
+            println!("medeleg = {:#x}", hart.medeleg);
+            report(hart.enter_lower(Privilege::Supervisor, KERNEL_BASE));
+            // Without a PMP entry for it, the kernel's first fetch faults back into OpenSBI
+            if hart.privilege == Privilege::Supervisor {
+                firmware.stage = Stage::Kernel;
+            }
+            CommandResult::Success
+        },
+        "flash" => {
+            let mut firmware = FIRMWARE.lock().unwrap();
+            let image = |entry: &str| Image::parse(entry).ok_or_else(|| format!("No flash image '{}'. Use sbi or kernel.", entry));
+            let result = match args {
+                [] | ["list"] => {
+                    firmware.flash.list();
+                    Ok(())
+                },
+                ["tamper", entry] => image(entry).map(|image| firmware.flash.tamper(image)),
+                ["resign", entry] => image(entry).map(|image| firmware.flash.resign(image)),
+                ["restore"] => {
+                    firmware.flash = Flash::build();
+                    println!("Flash restored from the vendor's images.");
+                    Ok(())
+                },
+                _ => Err("Usage: flash [list] | flash tamper <sbi|kernel> | flash resign <sbi|kernel> | flash restore".to_string()),
+            };
+            report(result)
+        },
+        "pmp" => {
+            HART.lock().unwrap().pmp.print();
+            CommandResult::Success
+        },
+        "mem" => report(process_mem_command(args)),
+        "sbi" => report(process_sbi_command(args)),
+        _ => CommandResult::UnknownCommand,
+    }
+}
+
+fn provide_hint(mode: Mode) {
+    if mode == Mode::Off {
+        return println!("Hint: The system is off. Type 'powerup' to start the hart in the boot ROM");
+    }
+    let stage = FIRMWARE.lock().unwrap().stage;
+    match (stage, mode) {
+        (Stage::Rom, Mode::Machine) => println!("Hint: Boot ROM in M-mode. Type 'verify_sbi' to authenticate OpenSBI from flash, then 'load_sbi' to run it"),
+        (Stage::Sbi, Mode::Machine) => println!("Hint: OpenSBI in M-mode. Type 'setup_pmp' to protect the firmware, 'verify_kernel', then 'load_kernel' to MRET into S-mode"),
+        (_, Mode::Machine) => println!("Hint: Type 'MRET' to return from a trap"),
+        (_, Mode::Supervisor) => println!("Hint: Type 'init_kernel' to initialize the kernel and SRET to U-mode, or 'SRET' to return from a trap"),
+        (_, _) => println!("Hint: You can now execute instructions like 'ADD', 'LI', 'LD', etc. 'ECALL' calls the kernel"),
+    }
+    println!("      'csrs' shows the trap state and 'regs' x0-x31, e.g. after 'LI a0, 5'");
+    println!("      'CSRR a0, mstatus' and 'CSRW mtvec, a0' read and write CSRs; 'pmp' shows the PMP entries");
+    println!("      'flash' lists the signed images; 'flash tamper <sbi|kernel>' corrupts one");
+    println!("      'mem read <addr>' reads memory through PMP, e.g. OpenSBI at {:#x} from S-mode", SBI_BASE);
+    println!("      From the kernel, 'sbi version', 'sbi set_timer <t>', 'sbi putchar <c>', 'sbi reboot' and 'sbi shutdown' call OpenSBI");
+}
+
+// The riscv64 edition on the shared engine
+struct Riscv64;
+
+impl Machine for Riscv64 {
+    type State = Mode;
+
+    fn state(&self) -> Mode {
+        let hart = HART.lock().unwrap();
+        match hart.privilege {
+            _ if !hart.powered => Mode::Off,
+            Privilege::Machine => Mode::Machine,
+            Privilege::Supervisor => Mode::Supervisor,
+            Privilege::User => Mode::User,
+        }
+    }
+
+    fn prompt(&self) -> String {
+        match self.state() {
+            Mode::Off => "Off>> ".to_string(),
+            mode => format!("{:?}>> ", mode),
+        }
+    }
+
+    fn hint(&self) {
+        provide_hint(self.state());
+    }
+
+    fn transition(&mut self, _: &Transition<Mode>) -> Result<(), String> {
+        reset_platform(&mut HART.lock().unwrap(), true);
+        println!("Power on: hart 0 starts in the boot ROM at the reset vector {:#x}", ROM_BASE);
+        Ok(())
+    }
+
+    fn process_command(&mut self, command: &str, args: &[&str]) -> CommandResult {
+        process_command(command, args)
+    }
+
+    // Instructions run in their own mode and every one above it; a mode
+    // below raises an illegal instruction exception. System instructions
+    // belong to one stage.
+    fn guard(&mut self, instruction: &str, required: Mode) -> Result<(), String> {
+        let mode = self.state();
+        let privilege = mode.privilege().ok_or_else(|| format!("Cannot access '{}' in {:?} mode", instruction, mode))?;
+        if let Some(level) = system_level(instruction) {
+            if level != mode {
+                return Err(format!("'{}' runs in {:?} mode, not {:?}", instruction, level, mode));
+            }
+        }
+        if privilege < required.privilege().unwrap() {
+            report(HART.lock().unwrap().illegal_instruction(instruction));
+            return Err(format!("'{}' is refused in {:?} mode", instruction, mode));
+        }
+        Ok(())
+    }
+}
+
+fn main() {
+    println!("** riscv64 Edition **");
+    println!("type powerup once to start");
+    println!("type instructions for superset");
+
+    // RV64I Instructions Simplified
+    let instructions = vec![
+        Instruction { name: "ADD", handler: add_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "SUB", handler: sub_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "AND", handler: and_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "OR", handler: or_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "XOR", handler: xor_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "ADDI", handler: addi_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "LI", handler: li_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "MV", handler: mv_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "LD", handler: ld_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "SD", handler: sd_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "NOP", handler: nop_handler as InstructionHandler, state: Mode::User },
+        Instruction { name: "setup_pmp", handler: setup_pmp as InstructionHandler, state: Mode::Machine },
+        Instruction { name: "init_kernel", handler: init_kernel as InstructionHandler, state: Mode::Supervisor },
+        Instruction { name: "start_user_apps", handler: start_user_apps as InstructionHandler, state: Mode::User },
+    ];
+
+    let transitions = vec![Transition { command: "powerup", from: Mode::Off, to: Mode::Machine }];
+
+    Engine::new(Riscv64, transitions, instructions).run();
+}
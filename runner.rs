@@ -11,6 +11,7 @@ fn print_splash() {
     println!("Architecture options:");
     println!("  x8664");
     println!("  arm64");
+    println!("  riscv64");
 }
 
 fn main() {
@@ -36,23 +37,25 @@ fn main() {
                         io::stdout().flush().unwrap();
                         input.push(c);
                     },
-                    KeyCode::Backspace => {
-                        if !input.is_empty() {
-                            input.pop();
-                            print!("\x08 \x08"); // Backspace, clear character, backspace again
-                            io::stdout().flush().unwrap();
-                        }
+                    KeyCode::Backspace if !input.is_empty() => {
+                        input.pop();
+                        print!("\x08 \x08"); // Backspace, clear character, backspace again
+                        io::stdout().flush().unwrap();
                     },
                     KeyCode::Enter => {
                         println!();
                         match input.trim() {
                             "x8664" => {
                                 println!();
-                                Command::new("x8664").spawn().expect("Failed to execute x8664");
+                                Command::new("x8664").status().expect("Failed to execute x8664");
                             },
                             "arm64" => {
                                 println!();
-                                Command::new("arm64").spawn().expect("Failed to execute arm64");
+                                Command::new("arm64").status().expect("Failed to execute arm64");
+                            },
+                            "riscv64" => {
+                                println!();
+                                Command::new("riscv64").status().expect("Failed to execute riscv64");
                             },
                             "exit" => {
                                 println!("Exiting...");
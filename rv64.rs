@@ -0,0 +1,551 @@
+// $t@$h
+use crate::pmp::{Access, Pmp};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    fn from_bits(bits: u64) -> Privilege {
+        match bits & 3 {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+
+    fn letter(self) -> char {
+        match self {
+            Privilege::User => 'U',
+            Privilege::Supervisor => 'S',
+            Privilege::Machine => 'M',
+        }
+    }
+}
+
+// The boot ROM (ZSBL) at the reset vector, OpenSBI at the start of DRAM
+// with the S-mode kernel 2MB above it, as on QEMU's virt board
+pub const ROM_BASE: u64 = 0x0000_1000;
+pub const SBI_BASE: u64 = 0x8000_0000;
+pub const SBI_SIZE: u64 = 0x0008_0000;
+pub const KERNEL_BASE: u64 = 0x8020_0000;
+pub const USER_BASE: u64 = 0x0001_0000;
+pub const USER_STACK: u64 = 0x0002_0000;
+// Each stage's trap handler sits just past its entry point
+pub const TVEC_OFFSET: u64 = 0x100;
+
+// mcause/scause for the synchronous exceptions the hart raises
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Cause {
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    LoadAccessFault = 5,
+    StoreAccessFault = 7,
+    EcallFromU = 8,
+    EcallFromS = 9,
+    EcallFromM = 11,
+}
+
+impl Cause {
+    fn access_fault(access: Access) -> Cause {
+        match access {
+            Access::Fetch => Cause::InstructionAccessFault,
+            Access::Load => Cause::LoadAccessFault,
+            Access::Store => Cause::StoreAccessFault,
+        }
+    }
+}
+
+// mstatus.MPP and mstatus.SPP; sstatus is a view of the same register
+const MSTATUS_MPP_SHIFT: u64 = 11;
+const MSTATUS_SPP: u64 = 1 << 8;
+
+// The ABI names the assembler accepts for x0-x31
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2",
+    "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+pub const A0: usize = 10;
+pub const A1: usize = 11;
+pub const A6: usize = 16;
+pub const A7: usize = 17;
+
+pub fn parse_reg(s: &str) -> Option<usize> {
+    let s = s.to_ascii_lowercase();
+    if s == "fp" {
+        return Some(8);
+    }
+    if let Some(i) = ABI_NAMES.iter().position(|&name| name == s) {
+        return Some(i);
+    }
+    match s.strip_prefix('x')?.parse() {
+        Ok(i) if i < 32 => Some(i),
+        _ => None,
+    }
+}
+
+// "16", "-8", "0x10"
+pub fn parse_imm(s: &str) -> Option<u64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+// "8(sp)" or "(a0)"
+fn parse_address(s: &str) -> Option<(u64, usize)> {
+    let (offset, rest) = s.split_once('(')?;
+    let reg = parse_reg(rest.strip_suffix(')')?)?;
+    let offset = if offset.is_empty() { 0 } else { parse_imm(offset)? };
+    Some((offset, reg))
+}
+
+// Control and status registers reachable with CSRR/CSRW
+enum Csr {
+    Mstatus,
+    Sstatus,
+    Mtvec,
+    Stvec,
+    Mepc,
+    Sepc,
+    Mcause,
+    Scause,
+    Mtval,
+    Stval,
+    Medeleg,
+    Mhartid,
+    PmpCfg(usize),
+    PmpAddr(usize),
+}
+
+impl Csr {
+    // The register, the lowest privilege that may access it, and whether it is read-only
+    fn parse(name: &str) -> Option<(Csr, Privilege, bool)> {
+        let name = name.to_ascii_lowercase();
+        let csr = match name.as_str() {
+            "mstatus" => Csr::Mstatus,
+            "sstatus" => Csr::Sstatus,
+            "mtvec" => Csr::Mtvec,
+            "stvec" => Csr::Stvec,
+            "mepc" => Csr::Mepc,
+            "sepc" => Csr::Sepc,
+            "mcause" => Csr::Mcause,
+            "scause" => Csr::Scause,
+            "mtval" => Csr::Mtval,
+            "stval" => Csr::Stval,
+            "medeleg" => Csr::Medeleg,
+            "mhartid" => Csr::Mhartid,
+            // RV64 only has the even pmpcfg registers
+            "pmpcfg0" => Csr::PmpCfg(0),
+            "pmpcfg2" => Csr::PmpCfg(8),
+            _ => match name.strip_prefix("pmpaddr").and_then(|i| i.parse().ok()) {
+                Some(i) if i < 16 => Csr::PmpAddr(i),
+                _ => return None,
+            },
+        };
+        let privilege = if name.starts_with('s') { Privilege::Supervisor } else { Privilege::Machine };
+        let read_only = matches!(csr, Csr::Mhartid);
+        Some((csr, privilege, read_only))
+    }
+}
+
+// One RV64 hart: its registers, CSRs and PMP, and the memory behind it
+pub struct Hart {
+    pub x: [u64; 32],
+    pub pc: u64,
+    pub privilege: Privilege,
+    mstatus: u64,
+    // Trap vectors, and whose handler each one is
+    tvec: [Option<(u64, &'static str)>; 2],
+    mepc: u64,
+    sepc: u64,
+    mcause: u64,
+    scause: u64,
+    mtval: u64,
+    stval: u64,
+    pub medeleg: u64,
+    pub pmp: Pmp,
+    memory: HashMap<u64, u8>,
+    // Whether the SoC has power at all
+    pub powered: bool,
+}
+
+impl Hart {
+    // Power-on reset: M-mode at the reset vector, PMP off. The boot ROM's
+    // trap handler is in ROM, so it is there from the start.
+    pub fn new() -> Self {
+        Hart {
+            x: [0; 32],
+            pc: ROM_BASE,
+            privilege: Privilege::Machine,
+            mstatus: 0,
+            tvec: [Some((ROM_BASE + TVEC_OFFSET, "boot ROM")), None],
+            mepc: 0,
+            sepc: 0,
+            mcause: 0,
+            scause: 0,
+            mtval: 0,
+            stval: 0,
+            medeleg: 0,
+            pmp: Pmp::new(),
+            memory: HashMap::new(),
+            powered: false,
+        }
+    }
+
+    // A reset, as opposed to power coming back, leaves DRAM as it was
+    pub fn reset(&mut self, powered: bool) {
+        let memory = if powered { std::mem::take(&mut self.memory) } else { HashMap::new() };
+        *self = Hart::new();
+        self.memory = memory;
+        self.powered = powered;
+    }
+
+    pub fn step(&mut self) {
+        self.pc = self.pc.wrapping_add(4);
+    }
+
+    fn mpp(&self) -> Privilege {
+        Privilege::from_bits(self.mstatus >> MSTATUS_MPP_SHIFT)
+    }
+
+    fn set_mpp(&mut self, privilege: Privilege) {
+        self.mstatus = (self.mstatus & !(3 << MSTATUS_MPP_SHIFT)) | (privilege as u64) << MSTATUS_MPP_SHIFT;
+    }
+
+    fn spp(&self) -> Privilege {
+        if self.mstatus & MSTATUS_SPP != 0 { Privilege::Supervisor } else { Privilege::User }
+    }
+
+    fn set_spp(&mut self, privilege: Privilege) {
+        self.mstatus = (self.mstatus & !MSTATUS_SPP) | if privilege == Privilege::Supervisor { MSTATUS_SPP } else { 0 };
+    }
+
+    // csrw mtvec/stvec at the end of each stage's early setup
+    pub fn install_tvec(&mut self, target: Privilege, base: u64, handler: &'static str) {
+        let (i, name) = if target == Privilege::Machine { (0, "mtvec") } else { (1, "stvec") };
+        self.tvec[i] = Some((base, handler));
+        println!("{} = {:#x} ({} trap handler)", name, base, handler);
+    }
+
+    // Trap entry. Exceptions from S and U go to S-mode when medeleg says
+    // so; M-mode traps never leave M-mode.
+    pub fn trap(&mut self, cause: Cause, tval: u64) -> Result<(), String> {
+        let from = self.privilege;
+        let delegated = from != Privilege::Machine && self.medeleg & (1 << cause as u64) != 0;
+        let target = if delegated { Privilege::Supervisor } else { Privilege::Machine };
+        let (base, handler) = self.tvec[if delegated { 1 } else { 0 }].ok_or_else(|| {
+            format!("No trap vector for {:?}-mode: the hart would jump to address 0 and hang", target)
+        })?;
+        let p = target.letter().to_ascii_lowercase();
+        let epc = self.pc;
+        if delegated {
+            self.sepc = epc;
+            self.scause = cause as u64;
+            self.stval = tval;
+            self.set_spp(from);
+        } else {
+            self.mepc = epc;
+            self.mcause = cause as u64;
+            self.mtval = tval;
+            self.set_mpp(from);
+        }
+        self.privilege = target;
+        self.pc = base;
+        println!("Trap taken from {}-mode to {}-mode ({:?}, {}cause={})", from.letter(), target.letter(), cause, p, cause as u64);
+        println!("  {}epc={:#x}  {}tval={:#x}  {}status.{}PP={}", p, epc, p, tval, p, target.letter(), from.letter());
+        println!("  {}tvec = {:#x}: [{}] trap handler", p, base, handler);
+        // The handler's first job on an ECALL: step the saved pc past it
+        if matches!(cause, Cause::EcallFromU | Cause::EcallFromS | Cause::EcallFromM) {
+            if delegated {
+                self.sepc = self.sepc.wrapping_add(4);
+            } else {
+                self.mepc = self.mepc.wrapping_add(4);
+            }
+            println!("  [{}] {}epc += 4, past the ECALL", handler, p);
+        }
+        Ok(())
+    }
+
+    pub fn illegal_instruction(&mut self, what: &str) -> Result<(), String> {
+        println!("{} is illegal in {}-mode", what, self.privilege.letter());
+        self.trap(Cause::IllegalInstruction, 0)
+    }
+
+    // An access PMP refuses traps with the address in xtval
+    fn access_fault(&mut self, addr: u64, access: Access, msg: String) -> Result<(), String> {
+        println!("{}", msg);
+        self.trap(Cause::access_fault(access), addr)
+    }
+
+    // An access whose last byte would sit past 0xffff_ffff_ffff_ffff faults
+    // like any other refused access
+    fn check(&self, addr: u64, len: usize, access: Access) -> Result<(), String> {
+        if (len as u64).checked_sub(1).is_some_and(|last| addr.checked_add(last).is_none()) {
+            return Err(format!("{:?} of {:#x} bytes at {:#x} wraps past the top of the address space", access, len, addr));
+        }
+        self.pmp.check(addr, len, access, self.privilege == Privilege::Machine)
+    }
+
+    pub fn load(&mut self, addr: u64, len: usize) -> Result<Vec<u8>, String> {
+        if let Err(msg) = self.check(addr, len, Access::Load) {
+            return self.access_fault(addr, Access::Load, msg).and(Err("Load faulted".to_string()));
+        }
+        Ok((0..len as u64).map(|i| *self.memory.get(&(addr + i)).unwrap_or(&0)).collect())
+    }
+
+    pub fn store(&mut self, addr: u64, data: &[u8]) -> Result<(), String> {
+        if let Err(msg) = self.check(addr, data.len(), Access::Store) {
+            return self.access_fault(addr, Access::Store, msg).and(Err("Store faulted".to_string()));
+        }
+        for (i, &byte) in data.iter().enumerate() {
+            self.memory.insert(addr + i as u64, byte);
+        }
+        Ok(())
+    }
+
+    // Where firmware puts images it copies out of flash; PMP does not
+    // apply to M-mode, so this never faults
+    pub fn write_image(&mut self, addr: u64, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            self.memory.insert(addr.wrapping_add(i as u64), byte);
+        }
+    }
+
+    // The first fetch at the pc an xRET or jump landed on
+    fn fetch(&mut self) -> Result<(), String> {
+        match self.check(self.pc, 4, Access::Fetch) {
+            Ok(()) => Ok(()),
+            Err(msg) => self.access_fault(self.pc, Access::Fetch, msg),
+        }
+    }
+
+    pub fn ecall(&mut self) -> Result<(), String> {
+        let cause = match self.privilege {
+            Privilege::User => Cause::EcallFromU,
+            Privilege::Supervisor => Cause::EcallFromS,
+            Privilege::Machine => Cause::EcallFromM,
+        };
+        self.trap(cause, 0)
+    }
+
+    // MRET: back to mstatus.MPP at mepc. MPP drops to U so a second MRET
+    // cannot climb back.
+    pub fn mret(&mut self) -> Result<(), String> {
+        if self.privilege != Privilege::Machine {
+            return self.illegal_instruction("MRET");
+        }
+        let target = self.mpp();
+        self.set_mpp(Privilege::User);
+        self.privilege = target;
+        self.pc = self.mepc;
+        println!("MRET from M-mode to {}-mode: pc=mepc={:#x}", target.letter(), self.pc);
+        self.fetch()
+    }
+
+    pub fn sret(&mut self) -> Result<(), String> {
+        if self.privilege == Privilege::User {
+            return self.illegal_instruction("SRET");
+        }
+        let (from, target) = (self.privilege, self.spp());
+        self.set_spp(Privilege::User);
+        self.privilege = target;
+        self.pc = self.sepc;
+        println!("SRET from {}-mode to {}-mode: pc=sepc={:#x}", from.letter(), target.letter(), self.pc);
+        self.fetch()
+    }
+
+    // How each boot stage hands over to the next: point xPP/xepc at the
+    // next stage, then xRET
+    pub fn enter_lower(&mut self, target: Privilege, entry: u64) -> Result<(), String> {
+        if self.privilege == Privilege::Machine {
+            self.set_mpp(target);
+            self.mepc = entry;
+            self.mret()
+        } else {
+            self.set_spp(target);
+            self.sepc = entry;
+            self.sret()
+        }
+    }
+
+    // CSRR/CSRW: a CSR above the current privilege, or a write to a
+    // read-only one, is an illegal instruction
+    fn access(&mut self, name: &str, write: bool) -> Result<Csr, String> {
+        let (csr, privilege, read_only) = Csr::parse(name).ok_or_else(|| format!("Unknown CSR '{}'", name))?;
+        let current = self.privilege;
+        if current < privilege || (write && read_only) {
+            self.illegal_instruction(&format!("{} of {}", if write { "CSRW" } else { "CSRR" }, name))?;
+            return Err(format!("{} is not {} in {}-mode", name, if write { "writable" } else { "readable" }, current.letter()));
+        }
+        Ok(csr)
+    }
+
+    pub fn csrr(&mut self, name: &str) -> Result<u64, String> {
+        let value = match self.access(name, false)? {
+            Csr::Mstatus => self.mstatus,
+            Csr::Sstatus => self.mstatus & MSTATUS_SPP,
+            Csr::Mtvec => self.tvec[0].map_or(0, |(base, _)| base),
+            Csr::Stvec => self.tvec[1].map_or(0, |(base, _)| base),
+            Csr::Mepc => self.mepc,
+            Csr::Sepc => self.sepc,
+            Csr::Mcause => self.mcause,
+            Csr::Scause => self.scause,
+            Csr::Mtval => self.mtval,
+            Csr::Stval => self.stval,
+            Csr::Medeleg => self.medeleg,
+            Csr::Mhartid => 0,
+            Csr::PmpCfg(first) => self.pmp.read_cfg(first),
+            Csr::PmpAddr(i) => self.pmp.addr[i],
+        };
+        self.step();
+        Ok(value)
+    }
+
+    pub fn csrw(&mut self, name: &str, value: u64) -> Result<(), String> {
+        match self.access(name, true)? {
+            Csr::Mstatus => self.mstatus = value & ((3 << MSTATUS_MPP_SHIFT) | MSTATUS_SPP),
+            Csr::Sstatus => self.mstatus = (self.mstatus & !MSTATUS_SPP) | (value & MSTATUS_SPP),
+            Csr::Mtvec => self.tvec[0] = Some((value & !3, self.tvec[0].map_or("", |(_, handler)| handler))),
+            Csr::Stvec => self.tvec[1] = Some((value & !3, self.tvec[1].map_or("", |(_, handler)| handler))),
+            Csr::Mepc => self.mepc = value & !3,
+            Csr::Sepc => self.sepc = value & !3,
+            Csr::Mcause => self.mcause = value,
+            Csr::Scause => self.scause = value,
+            Csr::Mtval => self.mtval = value,
+            Csr::Stval => self.stval = value,
+            // ECALL from M-mode cannot be delegated
+            Csr::Medeleg => self.medeleg = value & !(1 << Cause::EcallFromM as u64),
+            Csr::Mhartid => {},
+            Csr::PmpCfg(first) => self.pmp.write_cfg(first, value),
+            Csr::PmpAddr(i) => self.pmp.write_addr(i, value),
+        }
+        self.step();
+        Ok(())
+    }
+
+    fn read_reg(&self, i: usize) -> u64 {
+        self.x[i]
+    }
+
+    // x0 is hardwired to zero
+    fn write_reg(&mut self, i: usize, value: u64) {
+        if i != 0 {
+            self.x[i] = value;
+        }
+    }
+
+    // Run one RV64I instruction in the current privilege mode. The pc
+    // moves on when it succeeds; a fault leaves it for the trap.
+    pub fn execute(&mut self, mnemonic: &str, args: &[&str]) -> Result<String, String> {
+        if let Err(msg) = self.check(self.pc, 4, Access::Fetch) {
+            return self.access_fault(self.pc, Access::Fetch, msg).and(Err("Fetch faulted".to_string()));
+        }
+        let joined = args.join(" ");
+        let ops: Vec<&str> = joined.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+        let result = match mnemonic {
+            "ADD" | "SUB" | "AND" | "OR" | "XOR" => self.exec_alu(mnemonic, &ops),
+            "ADDI" | "LI" | "MV" => self.exec_immediate(mnemonic, &ops),
+            "LD" | "SD" => self.exec_load_store(mnemonic, &ops),
+            "NOP" => Ok("no operation".to_string()),
+            _ => Err(format!("No semantics for '{}'", mnemonic)),
+        };
+        if result.is_ok() {
+            self.step();
+        }
+        result
+    }
+
+    fn regs(&self, ops: &[&str]) -> Result<Vec<usize>, String> {
+        ops.iter().map(|op| parse_reg(op).ok_or_else(|| format!("Invalid register '{}'", op))).collect()
+    }
+
+    fn exec_alu(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let [d, a, b] = self.regs(ops)?[..] else {
+            return Err(format!("Usage: {} rd, rs1, rs2", mnemonic));
+        };
+        let (a, b) = (self.read_reg(a), self.read_reg(b));
+        let value = match mnemonic {
+            "ADD" => a.wrapping_add(b),
+            "SUB" => a.wrapping_sub(b),
+            "AND" => a & b,
+            "OR" => a | b,
+            _ => a ^ b,
+        };
+        self.write_reg(d, value);
+        Ok(format!("{}={:#x}", ABI_NAMES[d], self.read_reg(d)))
+    }
+
+    fn exec_immediate(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let value = match (mnemonic, ops) {
+            ("ADDI", [_, s, imm]) => match (parse_reg(s), parse_imm(imm)) {
+                (Some(s), Some(imm)) if (imm as i64) >= -2048 && (imm as i64) < 2048 => self.read_reg(s).wrapping_add(imm),
+                _ => return Err("ADDI takes a register and a 12-bit signed immediate".to_string()),
+            },
+            ("LI", [_, imm]) => parse_imm(imm).ok_or_else(|| format!("Invalid immediate '{}'", imm))?,
+            ("MV", [_, s]) => self.read_reg(parse_reg(s).ok_or_else(|| format!("Invalid register '{}'", s))?),
+            _ => return Err("Usage: ADDI rd, rs1, imm | LI rd, imm | MV rd, rs".to_string()),
+        };
+        let d = parse_reg(ops[0]).ok_or_else(|| format!("Invalid register '{}'", ops[0]))?;
+        self.write_reg(d, value);
+        Ok(format!("{}={:#x}", ABI_NAMES[d], self.read_reg(d)))
+    }
+
+    fn exec_load_store(&mut self, mnemonic: &str, ops: &[&str]) -> Result<String, String> {
+        let (reg, (offset, base)) = match ops {
+            [reg, address] => match (parse_reg(reg), parse_address(address)) {
+                (Some(reg), Some(address)) => (reg, address),
+                _ => return Err(format!("Usage: {} reg, offset(base)", mnemonic)),
+            },
+            _ => return Err(format!("Usage: {} reg, offset(base)", mnemonic)),
+        };
+        let addr = self.read_reg(base).wrapping_add(offset);
+        if mnemonic == "LD" {
+            let bytes = self.load(addr, 8)?;
+            self.write_reg(reg, u64::from_le_bytes(bytes.try_into().unwrap()));
+            Ok(format!("{}={:#x} from {:#x}", ABI_NAMES[reg], self.read_reg(reg), addr))
+        } else {
+            let value = self.read_reg(reg);
+            self.store(addr, &value.to_le_bytes())?;
+            Ok(format!("[{:#x}]={:#x}", addr, value))
+        }
+    }
+
+    pub fn print_registers(&self) {
+        for row in 0..8 {
+            let line: Vec<String> = (0..4).map(|col| row * 4 + col).map(|i| format!("{:>4}={:016x}", ABI_NAMES[i], self.x[i])).collect();
+            println!(" {}", line.join(" "));
+        }
+        println!(" pc={:016x}  {}-mode", self.pc, self.privilege.letter());
+    }
+
+    pub fn print_csrs(&self) {
+        println!(
+            " pc={:#x}  {}-mode  mstatus.MPP={} sstatus.SPP={}  medeleg={:#x}",
+            self.pc,
+            self.privilege.letter(),
+            self.mpp().letter(),
+            self.spp().letter(),
+            self.medeleg
+        );
+        let tvec = |i: usize| self.tvec[i].map_or("unset".to_string(), |(base, handler)| format!("{:#x} ({})", base, handler));
+        println!(" M: mepc={:#012x} mcause={:<2} mtval={:#012x} mtvec={}", self.mepc, self.mcause, self.mtval, tvec(0));
+        println!(" S: sepc={:#012x} scause={:<2} stval={:#012x} stvec={}", self.sepc, self.scause, self.stval, tvec(1));
+    }
+}
+
+lazy_static! {
+    pub static ref HART: Mutex<Hart> = Mutex::new(Hart::new());
+}
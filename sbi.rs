@@ -0,0 +1,223 @@
+// $t@$h
+use crate::crypto::{ct_eq, sha256, to_hex};
+use crate::keys::{SigningKey, RISCV_KERNEL_KEY, RISCV_ROM_KEY, THIRD_PARTY_KEY};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// SBI extension IDs, in a7, and the errors that come back in a0
+pub const EXT_BASE: u64 = 0x10;
+pub const EXT_TIME: u64 = 0x5449_4d45;
+pub const EXT_SRST: u64 = 0x5352_5354;
+pub const EXT_DBCN: u64 = 0x4442_434e;
+pub const SBI_SPEC_VERSION: u64 = 0x0200_0000;
+// OpenSBI's implementation ID, and its version 1.3
+pub const SBI_IMPL_ID: u64 = 1;
+pub const SBI_IMPL_VERSION: u64 = 0x1_0003;
+
+pub fn extension_name(eid: u64) -> Option<&'static str> {
+    match eid {
+        EXT_BASE => Some("BASE"),
+        EXT_TIME => Some("TIME"),
+        EXT_SRST => Some("SRST"),
+        EXT_DBCN => Some("DBCN"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SbiError {
+    NotSupported = -2,
+    InvalidParam = -3,
+}
+
+// The images the boot ROM and OpenSBI load out of SPI flash
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Image {
+    Sbi,
+    Kernel,
+}
+
+impl Image {
+    pub fn parse(s: &str) -> Option<Image> {
+        match s {
+            "sbi" | "opensbi" => Some(Image::Sbi),
+            "kernel" => Some(Image::Kernel),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Image::Sbi => "OpenSBI",
+            Image::Kernel => "S-mode kernel",
+        }
+    }
+}
+
+// What a signature block carries in place of the signer's public key.
+// Keys here are symmetric, so it is a commitment only the holder can make.
+fn public_key(key: &SigningKey) -> [u8; 32] {
+    key.sign(b"RISC-V public key")
+}
+
+// An image in flash with the signature block appended to it
+struct SignedImage {
+    image: Image,
+    bytes: Vec<u8>,
+    signer: &'static SigningKey,
+    signer_pk: [u8; 32],
+    signature: [u8; 32],
+}
+
+impl SignedImage {
+    fn sign(image: Image, bytes: Vec<u8>, signer: &'static SigningKey) -> Self {
+        SignedImage {
+            image,
+            signature: signer.sign(&bytes),
+            bytes,
+            signer,
+            signer_pk: public_key(signer),
+        }
+    }
+}
+
+// SPI flash as the vendor ships it. OpenSBI is built with the kernel
+// signing key's public half at its end, so trusting OpenSBI is what makes
+// the kernel key trusted.
+pub struct Flash {
+    images: Vec<SignedImage>,
+}
+
+impl Flash {
+    pub fn build() -> Self {
+        let mut sbi = b"OpenSBI v1.3 fw_jump".to_vec();
+        sbi.extend_from_slice(&public_key(&RISCV_KERNEL_KEY));
+        Flash {
+            images: vec![
+                SignedImage::sign(Image::Sbi, sbi, &RISCV_ROM_KEY),
+                SignedImage::sign(Image::Kernel, b"Linux 6.6 riscv64 Image".to_vec(), &RISCV_KERNEL_KEY),
+            ],
+        }
+    }
+
+    fn get(&self, image: Image) -> &SignedImage {
+        self.images.iter().find(|i| i.image == image).unwrap()
+    }
+
+    fn get_mut(&mut self, image: Image) -> &mut SignedImage {
+        self.images.iter_mut().find(|i| i.image == image).unwrap()
+    }
+
+    pub fn list(&self) {
+        println!("Flash contents:");
+        for i in &self.images {
+            println!(
+                " {:<14} {} bytes, sha256 {}, signed by {}",
+                i.image.name(),
+                i.bytes.len(),
+                &to_hex(&sha256(&i.bytes))[..16],
+                i.signer.id
+            );
+        }
+    }
+
+    pub fn tamper(&mut self, image: Image) {
+        self.get_mut(image).bytes[0] ^= 0x01;
+        println!("Patched one byte of {} in flash.", image.name());
+    }
+
+    // An attacker who rewrote an image signs it again, but only has their own key to do it with
+    pub fn resign(&mut self, image: Image) {
+        let i = self.get_mut(image);
+        *i = SignedImage::sign(image, std::mem::take(&mut i.bytes), &THIRD_PARTY_KEY);
+        println!("Re-signed {} in flash with '{}'.", image.name(), THIRD_PARTY_KEY.id);
+    }
+}
+
+// Where the boot flow has got to: which firmware owns the hart
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Stage {
+    Rom,
+    Sbi,
+    Kernel,
+}
+
+pub struct Firmware {
+    pub flash: Flash,
+    // Hash of the ROM key's public half, in OTP fuses since manufacture
+    rom_pk_hash: [u8; 32],
+    // The kernel key OpenSBI was built with, once OpenSBI is loaded
+    kernel_pk: Option<[u8; 32]>,
+    pub stage: Stage,
+    verified: HashMap<Image, [u8; 32]>,
+    // The S-mode timer deadline set through the TIME extension
+    pub timer: Option<u64>,
+}
+
+impl Firmware {
+    fn new() -> Self {
+        Firmware {
+            flash: Flash::build(),
+            rom_pk_hash: sha256(&public_key(&RISCV_ROM_KEY)),
+            kernel_pk: None,
+            stage: Stage::Rom,
+            verified: HashMap::new(),
+            timer: None,
+        }
+    }
+
+    // Back to the boot ROM. Flash and the fuses survive; what was verified does not.
+    pub fn reset(&mut self) {
+        self.kernel_pk = None;
+        self.stage = Stage::Rom;
+        self.verified.clear();
+        self.timer = None;
+    }
+
+    // The boot ROM checks OpenSBI's signer against the fuses; OpenSBI
+    // checks the kernel's signer against the key built into it
+    pub fn verify(&mut self, image: Image) -> Result<(), String> {
+        self.verified.remove(&image);
+        println!("Authenticating {}:", image.name());
+        let signed = self.flash.get(image);
+        let trusted = match image {
+            Image::Sbi => ct_eq(&sha256(&signed.signer_pk), &self.rom_pk_hash),
+            Image::Kernel => self.kernel_pk.is_some_and(|pk| ct_eq(&pk, &signed.signer_pk)),
+        };
+        if !trusted {
+            let anchor = if image == Image::Sbi { "the ROM key hash in OTP" } else { "the kernel key built into OpenSBI" };
+            return Err(format!("{}: signed by '{}', which is not {}", image.name(), signed.signer.id, anchor));
+        }
+        println!("  signer '{}' matches the trusted key", signed.signer.id);
+        if !signed.signer.verify(&signed.bytes, &signed.signature) {
+            return Err(format!("{}: signature check failed", image.name()));
+        }
+        let hash = sha256(&signed.bytes);
+        println!("  signature ok over sha256 {}", &to_hex(&hash)[..16]);
+        self.verified.insert(image, hash);
+        Ok(())
+    }
+
+    pub fn is_verified(&self, image: Image) -> bool {
+        self.verified.contains_key(&image)
+    }
+
+    // Copy the image out of flash into DRAM. Flash can change between
+    // verify and load, so the copy is hashed again before it runs.
+    pub fn load(&mut self, image: Image, base: u64) -> Result<Vec<u8>, String> {
+        let bytes = self.flash.get(image).bytes.clone();
+        if !ct_eq(&sha256(&bytes), &self.verified[&image]) {
+            return Err(format!("{} changed in flash since it was verified. Aborting.", image.name()));
+        }
+        if image == Image::Sbi {
+            self.kernel_pk = Some(bytes[bytes.len() - 32..].try_into().unwrap());
+        }
+        println!("Loaded {} ({} bytes) at {:#x}", image.name(), bytes.len(), base);
+        Ok(bytes)
+    }
+}
+
+lazy_static! {
+    pub static ref FIRMWARE: Mutex<Firmware> = Mutex::new(Firmware::new());
+}
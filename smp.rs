@@ -0,0 +1,160 @@
+// $t@$h
+use crate::cpu::Registers;
+use crate::crypto::{ct_eq, sha256, to_hex};
+use crate::memory::{GuestMemory, PAGE_SIZE};
+use crate::{Mode, State};
+
+pub const NUM_CPUS: usize = 4;
+// Where the hypervisor puts its AP startup code: a page below 1 MiB, so a
+// SIPI vector can name it
+pub const TRAMPOLINE_VECTOR: u8 = 0x01;
+const TRAMPOLINE_CODE: &[u8] = b"AP trampoline: real mode -> long mode, then jmp to the hypervisor's AP entry";
+
+// One logical processor with its own mode and registers
+pub struct Core {
+    pub state: State,
+    pub regs: Registers,
+    // Where a SIPI started it, if what it found there was not the verified trampoline
+    pub unverified: Option<usize>,
+}
+
+impl Core {
+    fn new() -> Self {
+        Core {
+            state: State::new(),
+            regs: Registers::new(),
+            unverified: None,
+        }
+    }
+}
+
+// The BSP (APIC ID 0) runs the boot chain. The APs sit in wait-for-SIPI
+// until a running CPU sends them INIT-SIPI-SIPI.
+pub struct Smp {
+    pub cores: Vec<Core>,
+    // The CPU the console drives
+    pub current: usize,
+    // Hash of the trampoline the verified hypervisor installed this boot
+    trampoline: Option<[u8; 32]>,
+}
+
+impl Smp {
+    pub fn new() -> Self {
+        Smp {
+            cores: (0..NUM_CPUS).map(|_| Core::new()).collect(),
+            current: 0,
+            trampoline: None,
+        }
+    }
+
+    pub fn current(&self) -> &Core {
+        &self.cores[self.current]
+    }
+
+    pub fn current_mut(&mut self) -> &mut Core {
+        &mut self.cores[self.current]
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.current().state.current_mode()
+    }
+
+    // A platform reset takes every CPU with it and hands the console back to the BSP
+    pub fn reset(&mut self, next: Mode) {
+        *self = Smp::new();
+        self.cores[0].state.change_mode(next);
+    }
+
+    pub fn install_trampoline(&mut self, dram: &mut GuestMemory) -> Result<(), String> {
+        let addr = TRAMPOLINE_VECTOR as usize * PAGE_SIZE;
+        dram.guest_write(addr, TRAMPOLINE_CODE)?;
+        self.trampoline = Some(sha256(TRAMPOLINE_CODE));
+        println!("AP trampoline installed at {:#x} (SIPI vector {:#04x})", addr, TRAMPOLINE_VECTOR);
+        Ok(())
+    }
+
+    fn ap(apic_id: usize) -> Result<usize, String> {
+        match apic_id {
+            0 => Err("APIC ID 0 is the BSP. It restarts with the platform, not on an IPI.".to_string()),
+            id if id < NUM_CPUS => Ok(id),
+            _ => Err(format!("No APIC ID {}; the platform has CPUs 0-{}", apic_id, NUM_CPUS - 1)),
+        }
+    }
+
+    // INIT: the AP drops whatever it was running and waits for a SIPI
+    pub fn init(&mut self, apic_id: usize) -> Result<(), String> {
+        let id = Smp::ap(apic_id)?;
+        self.cores[id] = Core::new();
+        if self.current == id {
+            self.current = 0;
+        }
+        println!("INIT -> cpu{}: reset, waiting for SIPI", id);
+        Ok(())
+    }
+
+    // SIPI: a waiting AP starts in real mode at vector << 12 and runs what
+    // that page holds. Nothing on the AP checks it. Only the page matching
+    // the trampoline the verified hypervisor wrote makes it part of the
+    // verified platform; anything else runs with the privilege real mode
+    // has, that of pre-boot firmware.
+    pub fn sipi(&mut self, apic_id: usize, vector: u8, dram: &GuestMemory) -> Result<(), String> {
+        let id = Smp::ap(apic_id)?;
+        if self.cores[id].state.current_mode() != Mode::Off {
+            println!("SIPI -> cpu{}: ignored, the CPU is not waiting for SIPI", id);
+            return Ok(());
+        }
+        let addr = vector as usize * PAGE_SIZE;
+        let hash = sha256(&dram.guest_read(addr, TRAMPOLINE_CODE.len())?);
+        let core = &mut self.cores[id];
+        core.regs = Registers::new();
+        core.regs.rip = addr as u64;
+        println!("SIPI -> cpu{}: started in real mode at {:#x} (sha256 {})", id, addr, &to_hex(&hash)[..16]);
+        match self.trampoline {
+            Some(expected) if ct_eq(&hash, &expected) => {
+                core.state.change_mode(Mode::Hypervisor);
+                println!("cpu{}: ran the verified trampoline and joined the hypervisor", id);
+            },
+            expected => {
+                core.state.change_mode(Mode::UEFI);
+                core.unverified = Some(addr);
+                println!(
+                    "WARNING: cpu{} is running unverified code at {:#x}: {}",
+                    id,
+                    addr,
+                    if expected.is_none() { "no verified trampoline has been installed this boot" } else { "it is not the trampoline the hypervisor installed" }
+                );
+            },
+        }
+        Ok(())
+    }
+
+    pub fn switch(&mut self, id: usize) -> Result<(), String> {
+        if id >= NUM_CPUS {
+            return Err(format!("No CPU {}; the platform has CPUs 0-{}", id, NUM_CPUS - 1));
+        }
+        if id != 0 && self.cores[id].state.current_mode() == Mode::Off {
+            return Err(format!("cpu{} is not running. Start it with 'sipi {}'.", id, id));
+        }
+        self.current = id;
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        let powered = self.cores[0].state.current_mode() != Mode::Off;
+        for (id, core) in self.cores.iter().enumerate() {
+            let status = match core.state.current_mode() {
+                Mode::Off if id != 0 && powered => "waiting for SIPI".to_string(),
+                Mode::Off => "off".to_string(),
+                mode => format!("{:?}  RIP={:#x}", mode, core.regs.rip),
+            };
+            println!(
+                " cpu{} {}  {}{}{}",
+                id,
+                if id == 0 { "BSP" } else { "AP " },
+                status,
+                core.unverified.map_or(String::new(), |addr| format!("  UNVERIFIED code from {:#x}", addr)),
+                if id == self.current { "  <- console" } else { "" }
+            );
+        }
+    }
+}
@@ -0,0 +1,313 @@
+// $t@$h
+use crate::crypto::{ct_eq, sha256, to_hex};
+use crate::keys::{
+    SigningKey, NON_TRUSTED_WORLD_KEY, NT_FW_CONTENT_KEY, ROT_KEY, SOC_FW_CONTENT_KEY, THIRD_PARTY_KEY, TOS_FW_CONTENT_KEY,
+    TRUSTED_WORLD_KEY,
+};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// The images BL1 and BL2 load out of the FIP
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Image {
+    Bl2,
+    Bl31,
+    Bl32,
+    Bl33,
+}
+
+impl Image {
+    pub fn parse(s: &str) -> Option<Image> {
+        match s {
+            "bl2" | "tb-fw" => Some(Image::Bl2),
+            "bl31" | "soc-fw" => Some(Image::Bl31),
+            "bl32" | "tos-fw" => Some(Image::Bl32),
+            "bl33" | "nt-fw" => Some(Image::Bl33),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Image::Bl2 => "BL2",
+            Image::Bl31 => "BL31",
+            Image::Bl32 => "BL32",
+            Image::Bl33 => "BL33",
+        }
+    }
+
+    // FIP entry name, as fiptool prints it
+    fn entry(self) -> &'static str {
+        match self {
+            Image::Bl2 => "tb-fw",
+            Image::Bl31 => "soc-fw",
+            Image::Bl32 => "tos-fw",
+            Image::Bl33 => "nt-fw",
+        }
+    }
+
+    fn content_cert(self) -> &'static str {
+        match self {
+            Image::Bl2 => "tb-fw-cert",
+            Image::Bl31 => "soc-fw-cert",
+            Image::Bl32 => "tos-fw-cert",
+            Image::Bl33 => "nt-fw-cert",
+        }
+    }
+
+    fn hash_extension(self) -> &'static str {
+        match self {
+            Image::Bl2 => "tb-fw-hash",
+            Image::Bl31 => "soc-fw-hash",
+            Image::Bl32 => "tos-fw-hash",
+            Image::Bl33 => "nt-fw-hash",
+        }
+    }
+}
+
+// What a certificate carries in place of the signer's public key. Keys here
+// are symmetric, so the "public key" is a commitment only its holder can make.
+fn public_key(key: &SigningKey) -> [u8; 32] {
+    key.sign(b"TBBR public key")
+}
+
+// An X.509 certificate reduced to what the chain of trust checks: who signed
+// it, and the key or image hashes it vouches for
+pub struct Certificate {
+    pub name: &'static str,
+    signer: &'static SigningKey,
+    signer_pk: [u8; 32],
+    extensions: Vec<(&'static str, [u8; 32])>,
+    signature: [u8; 32],
+}
+
+impl Certificate {
+    fn issue(name: &'static str, signer: &'static SigningKey, extensions: Vec<(&'static str, [u8; 32])>) -> Self {
+        let mut cert = Certificate {
+            name,
+            signer,
+            signer_pk: public_key(signer),
+            extensions,
+            signature: [0; 32],
+        };
+        cert.signature = signer.sign(&cert.tbs());
+        cert
+    }
+
+    // The to-be-signed part: everything but the signature
+    fn tbs(&self) -> Vec<u8> {
+        let mut buf = self.name.as_bytes().to_vec();
+        buf.extend_from_slice(&self.signer_pk);
+        for (oid, value) in &self.extensions {
+            buf.extend_from_slice(oid.as_bytes());
+            buf.extend_from_slice(value);
+        }
+        buf
+    }
+
+    fn extension(&self, oid: &str) -> Option<[u8; 32]> {
+        self.extensions.iter().find(|(o, _)| *o == oid).map(|(_, value)| *value)
+    }
+
+    fn extension_mut(&mut self, oid: &str) -> Option<&mut [u8; 32]> {
+        self.extensions.iter_mut().find(|(o, _)| *o == oid).map(|(_, value)| value)
+    }
+}
+
+// The TBBR chain of trust: each certificate, and the certificate and
+// extension that vouch for the key it is signed with. None means the
+// ROT key, whose hash is fused into the SoC.
+const CHAIN_OF_TRUST: [(&str, Option<(&str, &str)>); 8] = [
+    ("tb-fw-cert", None),
+    ("trusted-key-cert", None),
+    ("soc-fw-key-cert", Some(("trusted-key-cert", "trusted-world-pk"))),
+    ("soc-fw-cert", Some(("soc-fw-key-cert", "soc-fw-content-pk"))),
+    ("tos-fw-key-cert", Some(("trusted-key-cert", "trusted-world-pk"))),
+    ("tos-fw-cert", Some(("tos-fw-key-cert", "tos-fw-content-pk"))),
+    ("nt-fw-key-cert", Some(("trusted-key-cert", "non-trusted-world-pk"))),
+    ("nt-fw-cert", Some(("nt-fw-key-cert", "nt-fw-content-pk"))),
+];
+
+// Firmware Image Package: the flash blob holding every image after BL1 and
+// the certificates that authenticate them
+pub struct Fip {
+    images: Vec<(Image, Vec<u8>)>,
+    certs: Vec<Certificate>,
+}
+
+impl Fip {
+    // What the vendor's build produces with cert_create and fiptool
+    pub fn build() -> Self {
+        let images = vec![
+            (Image::Bl2, b"BL2 trusted boot firmware v2.9".to_vec()),
+            (Image::Bl31, b"BL31 EL3 runtime firmware v2.9".to_vec()),
+            (Image::Bl32, b"BL32 trusted OS v3.20".to_vec()),
+            (Image::Bl33, b"BL33 non-trusted firmware (hypervisor) v1.4".to_vec()),
+        ];
+        let hash = |image: Image| sha256(&images.iter().find(|(i, _)| *i == image).unwrap().1);
+        let certs = vec![
+            Certificate::issue("tb-fw-cert", &ROT_KEY, vec![("tb-fw-hash", hash(Image::Bl2))]),
+            Certificate::issue(
+                "trusted-key-cert",
+                &ROT_KEY,
+                vec![
+                    ("trusted-world-pk", public_key(&TRUSTED_WORLD_KEY)),
+                    ("non-trusted-world-pk", public_key(&NON_TRUSTED_WORLD_KEY)),
+                ],
+            ),
+            Certificate::issue("soc-fw-key-cert", &TRUSTED_WORLD_KEY, vec![("soc-fw-content-pk", public_key(&SOC_FW_CONTENT_KEY))]),
+            Certificate::issue("soc-fw-cert", &SOC_FW_CONTENT_KEY, vec![("soc-fw-hash", hash(Image::Bl31))]),
+            Certificate::issue("tos-fw-key-cert", &TRUSTED_WORLD_KEY, vec![("tos-fw-content-pk", public_key(&TOS_FW_CONTENT_KEY))]),
+            Certificate::issue("tos-fw-cert", &TOS_FW_CONTENT_KEY, vec![("tos-fw-hash", hash(Image::Bl32))]),
+            Certificate::issue("nt-fw-key-cert", &NON_TRUSTED_WORLD_KEY, vec![("nt-fw-content-pk", public_key(&NT_FW_CONTENT_KEY))]),
+            Certificate::issue("nt-fw-cert", &NT_FW_CONTENT_KEY, vec![("nt-fw-hash", hash(Image::Bl33))]),
+        ];
+        Fip { images, certs }
+    }
+
+    fn image(&self, image: Image) -> &[u8] {
+        &self.images.iter().find(|(i, _)| *i == image).unwrap().1
+    }
+
+    fn cert(&self, name: &str) -> Result<&Certificate, String> {
+        self.certs.iter().find(|c| c.name == name).ok_or_else(|| format!("{} missing from the FIP", name))
+    }
+
+    pub fn list(&self) {
+        println!("FIP contents:");
+        for (image, bytes) in &self.images {
+            println!(" {:<18} {}, {} bytes, sha256 {}", image.entry(), image.name(), bytes.len(), &to_hex(&sha256(bytes))[..16]);
+        }
+        for cert in &self.certs {
+            println!(" {:<18} signed by {}", cert.name, cert.signer.id);
+        }
+    }
+
+    // Flip a byte in an image, or in the first extension of a certificate
+    pub fn tamper(&mut self, entry: &str) -> Result<(), String> {
+        if let Some(image) = Image::parse(entry) {
+            let bytes = &mut self.images.iter_mut().find(|(i, _)| *i == image).unwrap().1;
+            bytes[0] ^= 0x01;
+            println!("Patched one byte of {} ({}) in flash.", image.entry(), entry);
+            return Ok(());
+        }
+        let cert = self.certs.iter_mut().find(|c| c.name == entry).ok_or_else(|| format!("No FIP entry '{}'", entry))?;
+        cert.extensions[0].1[0] ^= 0x01;
+        println!("Patched the {} extension of {} in flash.", cert.extensions[0].0, cert.name);
+        Ok(())
+    }
+
+    // An attacker who rewrote an image fixes up its content certificate and
+    // signs it again, but only has their own key to do it with
+    pub fn resign(&mut self, entry: &str) -> Result<(), String> {
+        let image = Image::parse(entry).ok_or_else(|| format!("No FIP image '{}'", entry))?;
+        let hash = sha256(self.image(image));
+        let cert = self.certs.iter_mut().find(|c| c.name == image.content_cert()).unwrap();
+        *cert.extension_mut(image.hash_extension()).unwrap() = hash;
+        *cert = Certificate::issue(cert.name, &THIRD_PARTY_KEY, std::mem::take(&mut cert.extensions));
+        println!("Re-issued {} for the {} in flash, signed with '{}'.", cert.name, entry, THIRD_PARTY_KEY.id);
+        Ok(())
+    }
+
+    // Check a certificate's signature, then whoever vouches for the key it
+    // was signed with, up to the ROTPK hash in fuses
+    fn authenticate_cert(&self, name: &str, rotpk_hash: &[u8; 32]) -> Result<&Certificate, String> {
+        let cert = self.cert(name)?;
+        let (_, parent) = CHAIN_OF_TRUST.iter().find(|(n, _)| *n == name).unwrap();
+        match parent {
+            None => {
+                if !ct_eq(&sha256(&cert.signer_pk), rotpk_hash) {
+                    return Err(format!("{}: signing key does not match the ROTPK hash in fuses", name));
+                }
+            },
+            Some((parent, oid)) => {
+                let trusted_pk = self.authenticate_cert(parent, rotpk_hash)?.extension(oid);
+                if trusted_pk.is_none_or(|pk| !ct_eq(&pk, &cert.signer_pk)) {
+                    return Err(format!("{}: signing key is not the {} in {}", name, oid, parent));
+                }
+            },
+        }
+        if !cert.signer.verify(&cert.tbs(), &cert.signature) {
+            return Err(format!("{}: signature check failed", name));
+        }
+        println!("  {} ok (signed by {})", name, cert.signer.id);
+        Ok(cert)
+    }
+
+    // Authenticate the image's certificate chain and check the image against
+    // the hash it carries. Returns the hash.
+    fn authenticate(&self, image: Image, rotpk_hash: &[u8; 32]) -> Result<[u8; 32], String> {
+        let cert = self.authenticate_cert(image.content_cert(), rotpk_hash)?;
+        let hash = sha256(self.image(image));
+        match cert.extension(image.hash_extension()) {
+            Some(expected) if ct_eq(&expected, &hash) => {
+                println!("  {} hash matches {} ({})", image.entry(), image.hash_extension(), &to_hex(&hash)[..16]);
+                Ok(hash)
+            },
+            _ => Err(format!("{}: image hash does not match {} in {}", image.entry(), image.hash_extension(), cert.name)),
+        }
+    }
+}
+
+// Where the boot flow has got to: which BL stage owns the core
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Stage {
+    Bl1,
+    Bl2,
+    Bl31,
+    Bl33,
+}
+
+pub struct TrustedBoot {
+    pub fip: Fip,
+    // Hash of the ROT public key, burnt into OTP fuses at manufacture
+    rotpk_hash: [u8; 32],
+    pub stage: Stage,
+    // Images whose chain of trust checked out, and the hash that was checked
+    verified: HashMap<Image, [u8; 32]>,
+}
+
+impl TrustedBoot {
+    fn new() -> Self {
+        TrustedBoot {
+            fip: Fip::build(),
+            rotpk_hash: sha256(&public_key(&ROT_KEY)),
+            stage: Stage::Bl1,
+            verified: HashMap::new(),
+        }
+    }
+
+    // Back to the boot ROM. Flash and the fuses survive; what was verified does not.
+    pub fn reset(&mut self) {
+        self.stage = Stage::Bl1;
+        self.verified.clear();
+    }
+
+    pub fn verify(&mut self, image: Image) -> Result<(), String> {
+        self.verified.remove(&image);
+        println!("Authenticating {} ({}):", image.name(), image.entry());
+        let hash = self.fip.authenticate(image, &self.rotpk_hash)?;
+        self.verified.insert(image, hash);
+        Ok(())
+    }
+
+    pub fn is_verified(&self, image: Image) -> bool {
+        self.verified.contains_key(&image)
+    }
+
+    // Copy the image out of flash into trusted RAM. Flash can change between
+    // verify and load, so the copy is hashed again before it runs.
+    pub fn load(&self, image: Image, base: u64) -> Result<(), String> {
+        let bytes = self.fip.image(image);
+        if !ct_eq(&sha256(bytes), &self.verified[&image]) {
+            return Err(format!("{} changed in flash since it was verified. Aborting.", image.name()));
+        }
+        println!("Loaded {} ({} bytes) at {:#x}", image.name(), bytes.len(), base);
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref TRUSTED_BOOT: Mutex<TrustedBoot> = Mutex::new(TrustedBoot::new());
+}
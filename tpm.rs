@@ -0,0 +1,492 @@
+// $t@$h
+use crate::crypto::{ct_eq, hmac_sha256, random_bytes, sha256, to_hex, xor_into};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+pub const PCR_COUNT: usize = 24;
+
+// PCR indexes used by the simulated boot chain
+pub const PCR_FIRMWARE: usize = 0;
+pub const PCR_KERNEL: usize = 4;
+pub const PCR_HYPERVISOR: usize = 5;
+pub const PCR_FILESYSTEM: usize = 9;
+pub const PCR_APPLICATION: usize = 10;
+
+// What each stage of the platform boot chain measures
+pub const FIRMWARE_MEASUREMENT: &[u8] = b"uefi firmware";
+pub const BOOTLOADER_MEASUREMENT: &[u8] = b"bootloader";
+pub const HYPERVISOR_MEASUREMENT: &[u8] = b"hypervisor";
+
+// What a verifier asks each layer to quote
+pub const PLATFORM_QUOTE_PCRS: [usize; 3] = [PCR_FIRMWARE, PCR_KERNEL, PCR_HYPERVISOR];
+pub const GUEST_QUOTE_PCRS: [usize; 3] = [PCR_KERNEL, PCR_FILESYSTEM, PCR_APPLICATION];
+
+#[derive(Clone)]
+pub struct PcrBank {
+    pcrs: [[u8; 32]; PCR_COUNT],
+}
+
+impl PcrBank {
+    pub fn new() -> Self {
+        PcrBank {
+            pcrs: [[0u8; 32]; PCR_COUNT],
+        }
+    }
+
+    // PCR[n] = SHA256(PCR[n] || SHA256(data))
+    pub fn extend(&mut self, index: usize, data: &[u8]) {
+        let mut buf = self.pcrs[index].to_vec();
+        buf.extend_from_slice(&sha256(data));
+        self.pcrs[index] = sha256(&buf);
+    }
+
+    // Extend with a value that is already a digest (e.g. an IMA template hash)
+    pub fn extend_digest(&mut self, index: usize, digest: &[u8; 32]) {
+        let mut buf = self.pcrs[index].to_vec();
+        buf.extend_from_slice(digest);
+        self.pcrs[index] = sha256(&buf);
+    }
+
+    pub fn read(&self, index: usize) -> [u8; 32] {
+        self.pcrs[index]
+    }
+
+    pub fn set(&mut self, index: usize, value: [u8; 32]) {
+        self.pcrs[index] = value;
+    }
+
+    // Digest over the selected PCRs in order, as used by quotes
+    pub fn composite(&self, selection: &[usize]) -> [u8; 32] {
+        let mut buf = Vec::new();
+        for &i in selection {
+            buf.extend_from_slice(&self.pcrs[i]);
+        }
+        sha256(&buf)
+    }
+
+    pub fn print(&self) {
+        let mut any = false;
+        for (i, pcr) in self.pcrs.iter().enumerate() {
+            if pcr.iter().any(|&b| b != 0) {
+                println!(" PCR[{:02}] {}", i, to_hex(pcr));
+                any = true;
+            }
+        }
+        if !any {
+            println!(" All PCRs are zero");
+        }
+    }
+}
+
+// Signatures in the simulator are HMAC-SHA256 under the signer's endorsement
+// secret. The "public" half is a hash of that secret, and the simulated
+// verifier is allowed to check signatures through the signing TPM.
+pub struct EkCertificate {
+    pub subject: [u8; 32],
+    pub issuer: [u8; 32],
+    pub signature: [u8; 32],
+}
+
+#[derive(Clone)]
+pub struct Quote {
+    pub selection: Vec<usize>,
+    pub pcr_digest: [u8; 32],
+    pub nonce: Vec<u8>,
+    pub signer: [u8; 32],
+    pub signature: [u8; 32],
+}
+
+impl Quote {
+    fn signed_bytes(selection: &[usize], pcr_digest: &[u8; 32], nonce: &[u8]) -> Vec<u8> {
+        let mut buf: Vec<u8> = selection.iter().map(|&i| i as u8).collect();
+        buf.extend_from_slice(pcr_digest);
+        buf.extend_from_slice(nonce);
+        buf
+    }
+}
+
+// TPM2 command codes folded into policy digests
+const TPM_CC_POLICY_AUTH_VALUE: u32 = 0x16b;
+const TPM_CC_POLICY_OR: u32 = 0x171;
+const TPM_CC_POLICY_PCR: u32 = 0x17f;
+
+// One assertion of a TPM2 authorization policy
+#[derive(Clone, Debug)]
+pub enum PolicyStep {
+    // PolicyPCR: the selected PCRs must reproduce this composite
+    Pcr(Vec<usize>, [u8; 32]),
+    // PolicyAuthValue: the caller must also present the object's auth value
+    AuthValue,
+    // PolicyOR: any one of the branches, each a sequence of assertions
+    Or(Vec<Vec<PolicyStep>>),
+}
+
+pub fn describe_policy(steps: &[PolicyStep]) -> String {
+    let terms: Vec<String> = steps
+        .iter()
+        .map(|step| match step {
+            PolicyStep::Pcr(selection, digest) => format!("PolicyPCR({:?} = {}..)", selection, &to_hex(digest)[..16]),
+            PolicyStep::AuthValue => "PolicyAuthValue".to_string(),
+            PolicyStep::Or(branches) => {
+                let branches: Vec<String> = branches.iter().map(|b| format!("({})", describe_policy(b))).collect();
+                format!("PolicyOR[{}]", branches.join(" | "))
+            },
+        })
+        .collect();
+    terms.join(" + ")
+}
+
+// A policy session. Every assertion that holds is folded into the session
+// digest, and an object only opens if the final digest equals the policy it
+// was created with. A trial session computes that digest without checking.
+#[derive(Clone)]
+pub struct PolicySession {
+    pub digest: [u8; 32],
+    auth_required: bool,
+}
+
+impl PolicySession {
+    pub fn new() -> Self {
+        PolicySession {
+            digest: [0u8; 32],
+            auth_required: false,
+        }
+    }
+
+    fn update(&mut self, command: u32, data: &[u8]) {
+        let mut buf = self.digest.to_vec();
+        buf.extend_from_slice(&command.to_be_bytes());
+        buf.extend_from_slice(data);
+        self.digest = sha256(&buf);
+    }
+
+    fn update_pcr(&mut self, selection: &[usize], pcr_digest: &[u8; 32]) {
+        let mut data: Vec<u8> = selection.iter().map(|&i| i as u8).collect();
+        data.extend_from_slice(pcr_digest);
+        self.update(TPM_CC_POLICY_PCR, &data);
+    }
+
+    // TPM2_PolicyPCR
+    pub fn policy_pcr(&mut self, tpm: &Tpm, selection: &[usize], pcr_digest: &[u8; 32]) -> Result<(), String> {
+        if !ct_eq(&tpm.pcrs.composite(selection), pcr_digest) {
+            return Err(format!("TPM_RC_VALUE: PCRs {:?} do not hold the values the policy expects", selection));
+        }
+        self.update_pcr(selection, pcr_digest);
+        Ok(())
+    }
+
+    // TPM2_PolicyAuthValue: the auth value is checked when the object is used
+    pub fn policy_auth_value(&mut self) {
+        self.auth_required = true;
+        self.update(TPM_CC_POLICY_AUTH_VALUE, &[]);
+    }
+
+    // TPM2_PolicyOR: the session must already match one of the branches, and
+    // afterwards it no longer says which
+    pub fn policy_or(&mut self, branches: &[[u8; 32]]) -> Result<(), String> {
+        if !branches.iter().any(|b| ct_eq(b, &self.digest)) {
+            return Err("TPM_RC_VALUE: the session matches no branch of the PolicyOR".to_string());
+        }
+        self.digest = [0u8; 32];
+        self.update(TPM_CC_POLICY_OR, &branches.concat());
+        Ok(())
+    }
+
+    pub fn trial(&mut self, steps: &[PolicyStep]) {
+        for step in steps {
+            match step {
+                PolicyStep::Pcr(selection, pcr_digest) => self.update_pcr(selection, pcr_digest),
+                PolicyStep::AuthValue => self.policy_auth_value(),
+                PolicyStep::Or(branches) => {
+                    let digests = self.branch_digests(branches);
+                    self.digest = [0u8; 32];
+                    self.update(TPM_CC_POLICY_OR, &digests.concat());
+                },
+            }
+        }
+    }
+
+    fn branch_digests(&self, branches: &[Vec<PolicyStep>]) -> Vec<[u8; 32]> {
+        branches
+            .iter()
+            .map(|branch| {
+                let mut trial = self.clone();
+                trial.trial(branch);
+                trial.digest
+            })
+            .collect()
+    }
+
+    // Satisfy a policy against a TPM, taking the first branch of each
+    // PolicyOR whose assertions all hold. A branch that holds without an
+    // auth value wins over one that asks for it, so a PCR branch still
+    // opens the object when the caller has no auth value to give.
+    pub fn satisfy(&mut self, tpm: &Tpm, steps: &[PolicyStep]) -> Result<(), String> {
+        for step in steps {
+            match step {
+                PolicyStep::Pcr(selection, pcr_digest) => self.policy_pcr(tpm, selection, pcr_digest)?,
+                PolicyStep::AuthValue => self.policy_auth_value(),
+                PolicyStep::Or(branches) => {
+                    let digests = self.branch_digests(branches);
+                    let mut failures = Vec::new();
+                    let mut satisfied: Option<PolicySession> = None;
+                    for branch in branches {
+                        let mut attempt = self.clone();
+                        match attempt.satisfy(tpm, branch) {
+                            Ok(()) => {
+                                let without_auth = !attempt.auth_required;
+                                if satisfied.as_ref().is_none_or(|s| s.auth_required) {
+                                    satisfied = Some(attempt);
+                                }
+                                if without_auth {
+                                    break;
+                                }
+                            },
+                            Err(msg) => failures.push(msg),
+                        }
+                    }
+                    *self = satisfied.ok_or_else(|| format!("No branch of the PolicyOR holds: {}", failures.join("; ")))?;
+                    self.policy_or(&digests)?;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+// A secret bound to an authorization policy. Only the TPM that sealed it can
+// open the blob, and only through a session that satisfies the policy.
+pub struct SealedBlob {
+    // Kept beside the object so a caller knows how to satisfy it
+    pub policy: Vec<PolicyStep>,
+    pub auth_policy: [u8; 32],
+    seed: [u8; 32],
+    // Sensitive area: digest of the auth value, then the secret
+    ciphertext: Vec<u8>,
+    mac: [u8; 32],
+}
+
+pub struct Tpm {
+    ek_secret: [u8; 32],
+    pub pcrs: PcrBank,
+    pub ek_cert: Option<EkCertificate>,
+}
+
+impl Tpm {
+    pub fn new() -> Self {
+        Tpm {
+            ek_secret: random_bytes(),
+            pcrs: PcrBank::new(),
+            ek_cert: None,
+        }
+    }
+
+    // TPM2_Startup(CLEAR) after a platform reset: PCRs start over, the
+    // endorsement identity (and anything else in NV) is kept
+    pub fn startup_clear(&mut self) {
+        self.pcrs = PcrBank::new();
+    }
+
+    pub fn ek_public(&self) -> [u8; 32] {
+        let mut buf = b"EK".to_vec();
+        buf.extend_from_slice(&self.ek_secret);
+        sha256(&buf)
+    }
+
+    // Issue a certificate binding another (virtual) TPM's EK to this one
+    pub fn certify(&self, subject: [u8; 32]) -> EkCertificate {
+        EkCertificate {
+            subject,
+            issuer: self.ek_public(),
+            signature: hmac_sha256(&self.ek_secret, &subject),
+        }
+    }
+
+    pub fn verify_certificate(&self, cert: &EkCertificate) -> bool {
+        ct_eq(&cert.issuer, &self.ek_public())
+            && ct_eq(&cert.signature, &hmac_sha256(&self.ek_secret, &cert.subject))
+    }
+
+    pub fn quote(&self, selection: &[usize], nonce: &[u8]) -> Quote {
+        let pcr_digest = self.pcrs.composite(selection);
+        Quote {
+            selection: selection.to_vec(),
+            pcr_digest,
+            nonce: nonce.to_vec(),
+            signer: self.ek_public(),
+            signature: hmac_sha256(&self.ek_secret, &Quote::signed_bytes(selection, &pcr_digest, nonce)),
+        }
+    }
+
+    pub fn verify_quote(&self, quote: &Quote) -> bool {
+        let expected = hmac_sha256(&self.ek_secret, &Quote::signed_bytes(&quote.selection, &quote.pcr_digest, &quote.nonce));
+        ct_eq(&quote.signer, &self.ek_public()) && ct_eq(&quote.signature, &expected)
+    }
+
+    // Symmetric key of the storage hierarchy, which never leaves the TPM
+    fn storage_key(&self) -> [u8; 32] {
+        hmac_sha256(&self.ek_secret, b"storage root key")
+    }
+
+    fn keystream_xor(key: &[u8; 32], seed: &[u8; 32], data: &mut [u8]) {
+        for (i, chunk) in data.chunks_mut(32).enumerate() {
+            let mut input = seed.to_vec();
+            input.extend_from_slice(&(i as u32).to_le_bytes());
+            xor_into(chunk, &hmac_sha256(key, &input));
+        }
+    }
+
+    fn blob_mac(key: &[u8; 32], auth_policy: &[u8; 32], seed: &[u8; 32], ciphertext: &[u8]) -> [u8; 32] {
+        let mut buf = auth_policy.to_vec();
+        buf.extend_from_slice(seed);
+        buf.extend_from_slice(ciphertext);
+        hmac_sha256(key, &buf)
+    }
+
+    // TPM2_Create with a PolicyPCR policy: `pcr_digest` is the composite the
+    // selected PCRs must reproduce, which need not be their current value
+    pub fn seal(&self, secret: &[u8], selection: &[usize], pcr_digest: [u8; 32]) -> SealedBlob {
+        self.seal_with_policy(secret, vec![PolicyStep::Pcr(selection.to_vec(), pcr_digest)], b"")
+    }
+
+    // TPM2_Create of a sealed data object with an arbitrary policy and auth value
+    pub fn seal_with_policy(&self, secret: &[u8], policy: Vec<PolicyStep>, auth: &[u8]) -> SealedBlob {
+        let key = self.storage_key();
+        let seed = random_bytes();
+        let mut trial = PolicySession::new();
+        trial.trial(&policy);
+        let mut ciphertext = sha256(auth).to_vec();
+        ciphertext.extend_from_slice(secret);
+        Tpm::keystream_xor(&key, &seed, &mut ciphertext);
+        SealedBlob {
+            policy,
+            auth_policy: trial.digest,
+            seed,
+            mac: Tpm::blob_mac(&key, &trial.digest, &seed, &ciphertext),
+            ciphertext,
+        }
+    }
+
+    // TPM2_Unseal through a policy session
+    pub fn unseal_with_session(&self, blob: &SealedBlob, session: &PolicySession, auth: &[u8]) -> Result<Vec<u8>, String> {
+        let key = self.storage_key();
+        if !ct_eq(&blob.mac, &Tpm::blob_mac(&key, &blob.auth_policy, &blob.seed, &blob.ciphertext)) {
+            return Err("TPM_RC_INTEGRITY: blob was not sealed by this TPM or has been modified".to_string());
+        }
+        if !ct_eq(&session.digest, &blob.auth_policy) {
+            return Err("TPM_RC_POLICY_FAIL: the session digest does not match the object's policy".to_string());
+        }
+        let mut sensitive = blob.ciphertext.clone();
+        Tpm::keystream_xor(&key, &blob.seed, &mut sensitive);
+        if session.auth_required && !ct_eq(&sensitive[..32], &sha256(auth)) {
+            return Err("TPM_RC_AUTH_FAIL: wrong auth value".to_string());
+        }
+        Ok(sensitive.split_off(32))
+    }
+
+    // Start a policy session, satisfy the blob's policy and unseal
+    pub fn unseal(&self, blob: &SealedBlob, auth: &[u8]) -> Result<Vec<u8>, String> {
+        let mut session = PolicySession::new();
+        session.satisfy(self, &blob.policy)?;
+        self.unseal_with_session(blob, &session, auth)
+    }
+}
+
+lazy_static! {
+    pub static ref PLATFORM_TPM: Mutex<Tpm> = Mutex::new(Tpm::new());
+}
+
+pub fn print_quote(label: &str, quote: &Quote) {
+    println!("{} quote:", label);
+    println!("  signer   {}", to_hex(&quote.signer));
+    println!("  pcrs     {:?}", quote.selection);
+    println!("  digest   {}", to_hex(&quote.pcr_digest));
+    println!("  nonce    {}", to_hex(&quote.nonce));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcr_step(tpm: &Tpm, selection: &[usize]) -> PolicyStep {
+        PolicyStep::Pcr(selection.to_vec(), tpm.pcrs.composite(selection))
+    }
+
+    #[test]
+    fn pcr_policy_follows_pcr_values() {
+        let mut tpm = Tpm::new();
+        tpm.pcrs.extend(PCR_KERNEL, BOOTLOADER_MEASUREMENT);
+        let blob = tpm.seal(b"secret", &[PCR_KERNEL], tpm.pcrs.composite(&[PCR_KERNEL]));
+        assert_eq!(tpm.unseal(&blob, b"").unwrap(), b"secret");
+        tpm.pcrs.extend(PCR_KERNEL, b"rootkit");
+        assert!(tpm.unseal(&blob, b"").unwrap_err().starts_with("TPM_RC_VALUE"));
+    }
+
+    #[test]
+    fn policy_or_opens_through_any_branch() {
+        let mut tpm = Tpm::new();
+        tpm.pcrs.extend(PCR_KERNEL, BOOTLOADER_MEASUREMENT);
+        let policy = vec![PolicyStep::Or(vec![vec![pcr_step(&tpm, &[PCR_KERNEL])], vec![PolicyStep::AuthValue]])];
+        let blob = tpm.seal_with_policy(b"secret", policy, b"recovery");
+        // PCR branch: no auth value asked for
+        assert_eq!(tpm.unseal(&blob, b"").unwrap(), b"secret");
+        // PCRs moved on: only the auth branch is left
+        tpm.pcrs.extend(PCR_KERNEL, b"update");
+        assert_eq!(tpm.unseal(&blob, b"recovery").unwrap(), b"secret");
+        assert!(tpm.unseal(&blob, b"guess").unwrap_err().starts_with("TPM_RC_AUTH_FAIL"));
+    }
+
+    #[test]
+    fn policy_or_prefers_a_branch_without_auth_value() {
+        let mut tpm = Tpm::new();
+        tpm.pcrs.extend(PCR_KERNEL, BOOTLOADER_MEASUREMENT);
+        // Auth branch listed first: it holds too, but would demand the pin
+        let policy = vec![PolicyStep::Or(vec![vec![PolicyStep::AuthValue], vec![pcr_step(&tpm, &[PCR_KERNEL])]])];
+        let blob = tpm.seal_with_policy(b"secret", policy, b"pin");
+        assert_eq!(tpm.unseal(&blob, b"").unwrap(), b"secret");
+        assert_eq!(tpm.unseal(&blob, b"pin").unwrap(), b"secret");
+        tpm.pcrs.extend(PCR_KERNEL, b"update");
+        assert!(tpm.unseal(&blob, b"").unwrap_err().starts_with("TPM_RC_AUTH_FAIL"));
+        assert_eq!(tpm.unseal(&blob, b"pin").unwrap(), b"secret");
+    }
+
+    #[test]
+    fn policy_or_fails_when_no_branch_holds() {
+        let mut tpm = Tpm::new();
+        let policy = vec![PolicyStep::Or(vec![vec![pcr_step(&tpm, &[PCR_KERNEL])], vec![pcr_step(&tpm, &[PCR_HYPERVISOR])]])];
+        let blob = tpm.seal_with_policy(b"secret", policy, b"");
+        tpm.pcrs.extend(PCR_KERNEL, b"a");
+        tpm.pcrs.extend(PCR_HYPERVISOR, b"b");
+        assert!(tpm.unseal(&blob, b"").unwrap_err().starts_with("No branch of the PolicyOR holds"));
+    }
+
+    #[test]
+    fn policy_or_digest_does_not_depend_on_branch_taken() {
+        let tpm = Tpm::new();
+        let branches = vec![vec![pcr_step(&tpm, &[PCR_KERNEL])], vec![PolicyStep::AuthValue]];
+        let mut trial = PolicySession::new();
+        trial.trial(&[PolicyStep::Or(branches.clone())]);
+        for branch in &branches {
+            let mut session = PolicySession::new();
+            session.satisfy(&tpm, branch).unwrap();
+            session.policy_or(&PolicySession::new().branch_digests(&branches)).unwrap();
+            assert_eq!(session.digest, trial.digest);
+        }
+    }
+
+    #[test]
+    fn session_for_another_policy_is_refused() {
+        let tpm = Tpm::new();
+        let blob = tpm.seal_with_policy(b"secret", vec![pcr_step(&tpm, &[PCR_KERNEL]), PolicyStep::AuthValue], b"pin");
+        let mut session = PolicySession::new();
+        session.satisfy(&tpm, &[pcr_step(&tpm, &[PCR_KERNEL])]).unwrap();
+        assert!(tpm.unseal_with_session(&blob, &session, b"pin").unwrap_err().starts_with("TPM_RC_POLICY_FAIL"));
+    }
+
+    #[test]
+    fn blob_only_opens_on_the_tpm_that_sealed_it() {
+        let tpm = Tpm::new();
+        let blob = tpm.seal(b"secret", &[PCR_KERNEL], tpm.pcrs.composite(&[PCR_KERNEL]));
+        assert!(Tpm::new().unseal(&blob, b"").unwrap_err().starts_with("TPM_RC_INTEGRITY"));
+    }
+}
@@ -0,0 +1,127 @@
+// $t@$h
+use crate::mitigations::TAG_GRANULE;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+// A TZC-400 region: a physical range and whether Non-secure masters may use it
+struct Region {
+    base: u64,
+    size: u64,
+    name: &'static str,
+    secure_only: bool,
+}
+
+// Physical memory as the interconnect sees it. Pages appear on first
+// write; until BL31 programs the TZASC, every access goes through. MTE
+// allocation tags live alongside, one per 16-byte granule, 0 until set.
+pub struct PhysMemory {
+    pages: HashMap<u64, Vec<u8>>,
+    tags: HashMap<u64, u8>,
+    regions: Vec<Region>,
+    pub enabled: bool,
+}
+
+impl PhysMemory {
+    fn new() -> Self {
+        PhysMemory {
+            pages: HashMap::new(),
+            tags: HashMap::new(),
+            regions: vec![
+                Region {
+                    base: 0x0400_0000,
+                    size: 0x0020_0000,
+                    name: "trusted SRAM (BL31, BL32)",
+                    secure_only: true,
+                },
+                Region {
+                    base: 0x4000_0000,
+                    size: 0x8000_0000,
+                    name: "DRAM",
+                    secure_only: false,
+                },
+            ],
+            enabled: false,
+        }
+    }
+
+    // The access fails with an error response, which the core takes as a
+    // synchronous external abort
+    fn check(&self, addr: u64, len: usize, secure: bool, what: &str) -> Result<(), String> {
+        let end = addr
+            .checked_add(len as u64)
+            .ok_or_else(|| format!("TZASC: {} of {:#x} runs past the top of the address space", what, addr))?;
+        if !self.enabled || secure {
+            return Ok(());
+        }
+        match self.regions.iter().find(|r| r.secure_only && addr < r.base + r.size && end > r.base) {
+            Some(region) => Err(format!(
+                "TZASC: Non-secure {} of {:#x} hits {} [{:#x}-{:#x}], which is Secure only",
+                what,
+                addr,
+                region.name,
+                region.base,
+                region.base + region.size - 1
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn read(&self, addr: u64, len: usize, secure: bool) -> Result<Vec<u8>, String> {
+        self.check(addr, len, secure, "read")?;
+        Ok((addr..addr + len as u64)
+            .map(|a| self.pages.get(&(a / PAGE_SIZE)).map_or(0, |page| page[(a % PAGE_SIZE) as usize]))
+            .collect())
+    }
+
+    pub fn write(&mut self, addr: u64, data: &[u8], secure: bool) -> Result<(), String> {
+        self.check(addr, data.len(), secure, "write")?;
+        for (i, byte) in data.iter().enumerate() {
+            let a = addr + i as u64;
+            let page = self.pages.entry(a / PAGE_SIZE).or_insert_with(|| vec![0; PAGE_SIZE as usize]);
+            page[(a % PAGE_SIZE) as usize] = *byte;
+        }
+        Ok(())
+    }
+
+    // A reset leaves the TZASC unprogrammed until BL31 runs again; memory
+    // keeps its contents unless the power went
+    pub fn reset(&mut self, power_off: bool) {
+        self.enabled = false;
+        if power_off {
+            self.pages.clear();
+            self.tags.clear();
+        }
+    }
+
+    pub fn allocation_tag(&self, addr: u64, secure: bool) -> Result<u8, String> {
+        self.check(addr, TAG_GRANULE as usize, secure, "tag read")?;
+        Ok(*self.tags.get(&(addr / TAG_GRANULE)).unwrap_or(&0))
+    }
+
+    pub fn set_allocation_tag(&mut self, addr: u64, tag: u8, secure: bool) -> Result<(), String> {
+        self.check(addr, TAG_GRANULE as usize, secure, "tag write")?;
+        self.tags.insert(addr / TAG_GRANULE, tag);
+        Ok(())
+    }
+
+    pub fn print_regions(&self) {
+        println!("TZASC {}:", if self.enabled { "enabled" } else { "not programmed" });
+        for (i, r) in self.regions.iter().enumerate() {
+            println!(
+                " region {}  {:#012x}-{:#012x}  {:<26} {}",
+                i + 1,
+                r.base,
+                r.base + r.size - 1,
+                r.name,
+                if r.secure_only { "Secure only" } else { "Secure and Non-secure" }
+            );
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref PHYS_MEMORY: Mutex<PhysMemory> = Mutex::new(PhysMemory::new());
+}
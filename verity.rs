@@ -0,0 +1,237 @@
+// $t@$h
+use crate::crypto::{ct_eq, sha256, to_hex};
+use crate::keys::RELEASE_KEY;
+use crate::memory::parse_hex;
+
+pub const BLOCK_SIZE: usize = 512;
+pub const DATA_BLOCKS: usize = 64;
+const HASH_SIZE: usize = 32;
+const HASHES_PER_BLOCK: usize = BLOCK_SIZE / HASH_SIZE;
+const SALT: &[u8] = b"secboot-verity-salt";
+
+pub type Block = [u8; BLOCK_SIZE];
+
+// What the kernel does when a block fails verification
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CorruptionMode {
+    Eio,
+    Restart,
+    Panic,
+}
+
+impl CorruptionMode {
+    pub fn parse(s: &str) -> Option<CorruptionMode> {
+        match s {
+            "eio" => Some(CorruptionMode::Eio),
+            "restart" => Some(CorruptionMode::Restart),
+            "panic" => Some(CorruptionMode::Panic),
+            _ => None,
+        }
+    }
+}
+
+fn hash_block(block: &Block) -> [u8; 32] {
+    let mut buf = SALT.to_vec();
+    buf.extend_from_slice(block);
+    sha256(&buf)
+}
+
+// Pack a list of hashes into hash blocks, zero padded
+fn pack(hashes: &[[u8; 32]]) -> Vec<Block> {
+    hashes
+        .chunks(HASHES_PER_BLOCK)
+        .map(|chunk| {
+            let mut block = [0u8; BLOCK_SIZE];
+            for (i, h) in chunk.iter().enumerate() {
+                block[i * HASH_SIZE..(i + 1) * HASH_SIZE].copy_from_slice(h);
+            }
+            block
+        })
+        .collect()
+}
+
+fn hash_tree(data: &[Block]) -> Vec<Vec<Block>> {
+    let mut tree = Vec::new();
+    let mut hashes: Vec<[u8; 32]> = data.iter().map(hash_block).collect();
+    loop {
+        let level = pack(&hashes);
+        hashes = level.iter().map(hash_block).collect();
+        let done = level.len() == 1;
+        tree.push(level);
+        if done {
+            return tree;
+        }
+    }
+}
+
+// Data blocks followed by the hash tree, as written by `veritysetup format`.
+// Level 0 holds the hashes of data blocks; the last level is a single block
+// whose hash is the root hash.
+pub struct BlockDevice {
+    pub data: Vec<Block>,
+    pub tree: Vec<Vec<Block>>,
+}
+
+impl BlockDevice {
+    // The vendor's root filesystem image
+    pub fn format() -> Self {
+        let mut data = vec![[0u8; BLOCK_SIZE]; DATA_BLOCKS];
+        let files: [&[u8]; 3] = [
+            b"SIMFS superblock: rootfs for secboot guests",
+            b"#!/bin/app\necho 'hello from a verified application'\n",
+            b"# /etc/config\nallow_debug=0\nlog_level=info\n",
+        ];
+        for (i, block) in data.iter_mut().enumerate() {
+            match files.get(i) {
+                Some(content) => block[..content.len()].copy_from_slice(content),
+                None => block.iter_mut().enumerate().for_each(|(j, b)| *b = (i * 31 + j) as u8),
+            }
+        }
+
+        let tree = hash_tree(&data);
+        BlockDevice { data, tree }
+    }
+
+    pub fn root_hash(&self) -> [u8; 32] {
+        hash_block(&self.tree[self.tree.len() - 1][0])
+    }
+
+    // Kernel command line a signed root hash travels on
+    pub fn cmdline(&self) -> String {
+        let root = self.root_hash();
+        format!(
+            "root=/dev/dm-0 dm-verity.roothash={} dm-verity.roothashsig={}",
+            to_hex(&root),
+            to_hex(&RELEASE_KEY.sign(&root))
+        )
+    }
+}
+
+// An active dm-verity mapping. Nothing is checked up front: every read
+// verifies the block and the path of hash blocks up to the trusted root.
+pub struct VerityTarget {
+    root: [u8; 32],
+    pub mode: CorruptionMode,
+    pub verified_reads: u64,
+}
+
+impl VerityTarget {
+    // Parse the root hash from the kernel command line and check its signature
+    pub fn from_cmdline(cmdline: &str, mode: CorruptionMode) -> Result<Self, String> {
+        let param = |key: &str| {
+            cmdline
+                .split_whitespace()
+                .find_map(|p| p.strip_prefix(key))
+                .and_then(parse_hex)
+                .ok_or_else(|| format!("Kernel command line has no valid {}", key.trim_end_matches('=')))
+        };
+        let root = param("dm-verity.roothash=")?;
+        let signature = param("dm-verity.roothashsig=")?;
+        if root.len() != 32 || !RELEASE_KEY.verify(&root, &signature) {
+            return Err(format!("Root hash signature does not verify against the {}", RELEASE_KEY.id));
+        }
+        let mut trusted = [0u8; 32];
+        trusted.copy_from_slice(&root);
+        Ok(VerityTarget {
+            root: trusted,
+            mode,
+            verified_reads: 0,
+        })
+    }
+
+    pub fn read(&mut self, dev: &BlockDevice, index: usize) -> Result<Block, String> {
+        let block = dev.data[index];
+        let mut hash = hash_block(&block);
+        let mut position = index;
+        for (depth, level) in dev.tree.iter().enumerate() {
+            let hash_block_index = position / HASHES_PER_BLOCK;
+            let slot = position % HASHES_PER_BLOCK;
+            let stored = &level[hash_block_index][slot * HASH_SIZE..(slot + 1) * HASH_SIZE];
+            if !ct_eq(stored, &hash) {
+                return Err(match depth {
+                    0 => format!("data block {} is corrupted", index),
+                    _ => format!("hash block {} at level {} is corrupted", position, depth - 1),
+                });
+            }
+            hash = hash_block(&level[hash_block_index]);
+            position = hash_block_index;
+        }
+        if !ct_eq(&hash, &self.root) {
+            return Err("top-level hash block does not match the root hash".to_string());
+        }
+        self.verified_reads += 1;
+        Ok(block)
+    }
+
+    pub fn print_status(&self) {
+        println!("dm-verity target:");
+        println!(" root hash        {}", to_hex(&self.root));
+        println!(" on corruption    {:?}", self.mode);
+        println!(" verified reads   {}", self.verified_reads);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::THIRD_PARTY_KEY;
+
+    fn target(dev: &BlockDevice) -> VerityTarget {
+        VerityTarget::from_cmdline(&dev.cmdline(), CorruptionMode::Eio).unwrap()
+    }
+
+    #[test]
+    fn reads_every_block_of_a_good_image() {
+        let dev = BlockDevice::format();
+        let mut verity = target(&dev);
+        for i in 0..DATA_BLOCKS {
+            assert_eq!(verity.read(&dev, i).unwrap(), dev.data[i]);
+        }
+        assert_eq!(verity.verified_reads, DATA_BLOCKS as u64);
+    }
+
+    #[test]
+    fn detects_a_modified_data_block() {
+        let mut dev = BlockDevice::format();
+        let mut verity = target(&dev);
+        dev.data[5][0] ^= 1;
+        assert_eq!(verity.read(&dev, 5).unwrap_err(), "data block 5 is corrupted");
+        assert!(verity.read(&dev, 6).is_ok());
+    }
+
+    #[test]
+    fn detects_a_modified_hash_block() {
+        let mut dev = BlockDevice::format();
+        let mut verity = target(&dev);
+        dev.tree[0][1][0] ^= 1;
+        assert!(verity.read(&dev, 0).is_ok());
+        assert_eq!(verity.read(&dev, 17).unwrap_err(), "hash block 1 at level 0 is corrupted");
+    }
+
+    #[test]
+    fn detects_a_rebuilt_tree_by_its_root() {
+        // An attacker with the disk rewrites the data and a consistent tree
+        let mut dev = BlockDevice::format();
+        let mut verity = target(&dev);
+        dev.data[2][..8].copy_from_slice(b"evil=1\n\n");
+        dev.tree = hash_tree(&dev.data);
+        assert_eq!(verity.read(&dev, 2).unwrap_err(), "top-level hash block does not match the root hash");
+        assert!(verity.read(&dev, 40).is_err());
+    }
+
+    #[test]
+    fn rejects_a_root_hash_it_cannot_trust() {
+        let good = BlockDevice::format();
+        let mut dev = BlockDevice::format();
+        dev.data[2][0] ^= 1;
+        dev.tree = hash_tree(&dev.data);
+        let root = dev.root_hash();
+        // Swapped in root hash, still carrying the release key's signature of the good one
+        let swapped = good.cmdline().replace(&to_hex(&good.root_hash()), &to_hex(&root));
+        assert!(VerityTarget::from_cmdline(&swapped, CorruptionMode::Eio).is_err());
+        // Properly signed, but not by the release key
+        let resigned = format!("dm-verity.roothash={} dm-verity.roothashsig={}", to_hex(&root), to_hex(&THIRD_PARTY_KEY.sign(&root)));
+        assert!(VerityTarget::from_cmdline(&resigned, CorruptionMode::Eio).is_err());
+        assert!(VerityTarget::from_cmdline("root=/dev/sda1", CorruptionMode::Eio).is_err());
+    }
+}
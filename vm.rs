@@ -0,0 +1,510 @@
+// $t@$h
+use crate::cc::{launch_digest, CcTech, SECURITY_PROCESSOR};
+use crate::cpu::Registers;
+use crate::crypto::{ct_eq, sha256, to_hex};
+use crate::hypercall::{default_policy, HYPERCALLS};
+use crate::elf::{self, ElfImage};
+use crate::ima::{Appraise, Ima};
+use crate::keys::RELEASE_KEY;
+use crate::kmod::{keyring_verifies, KernelIntegrity, LockdownReason};
+use crate::memory::GuestMemory;
+use crate::nested::{Nested, L1_HYPERVISOR_IMAGE};
+use crate::process::Scheduler;
+use crate::tpm::{print_quote, PcrBank, Tpm, GUEST_QUOTE_PCRS, PCR_APPLICATION, PCR_FILESYSTEM, PCR_HYPERVISOR, PCR_KERNEL, PLATFORM_QUOTE_PCRS, PLATFORM_TPM};
+use crate::verity::{Block, BlockDevice, CorruptionMode, VerityTarget};
+use crate::{Mode, State};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+pub const DEFAULT_APPLICATION: &str = "/bin/app";
+
+// The vendor-signed kernel every guest boots unless someone tampers with it
+pub const KERNEL_IMAGE: &[u8] = b"SIMKERNEL vmlinuz-6.1-secboot, signed by the QVLX release key";
+
+// A kernel in the guest's /boot that kexec can switch to
+pub struct BootImage {
+    pub name: &'static str,
+    pub image: &'static [u8],
+    pub signature: Option<(&'static str, [u8; 32])>,
+}
+
+pub fn kexec_catalog() -> Vec<BootImage> {
+    let signed = |image: &[u8]| Some((RELEASE_KEY.id, RELEASE_KEY.sign(image)));
+    vec![
+        BootImage { name: "vmlinuz-6.1-secboot", image: KERNEL_IMAGE, signature: signed(KERNEL_IMAGE) },
+        BootImage { name: "vmlinuz-6.6-secboot", image: b"SIMKERNEL vmlinuz-6.6-secboot, signed by the QVLX release key", signature: signed(b"SIMKERNEL vmlinuz-6.6-secboot, signed by the QVLX release key") },
+        BootImage { name: "vmlinuz-custom", image: b"SIMKERNEL vmlinuz-custom, built on someone's laptop", signature: None },
+    ]
+}
+
+// A guest hosted by the hypervisor. Each guest walks its own boot chain
+// (Hypervisor -> Kernel -> User) independently of the others.
+pub struct Guest {
+    pub name: String,
+    pub state: State,
+    pub is_verified_os: bool,
+    pub is_verified_fs: bool,
+    pub is_verified_ap: bool,
+    pub vtpm: Tpm,
+    pub kernel_image: Vec<u8>,
+    // What is running right now, which kexec can make differ from what booted
+    pub running_kernel: &'static str,
+    pub memory: GuestMemory,
+    // Set for confidential guests, whose memory and launch are out of the hypervisor's hands
+    pub cc: Option<CcTech>,
+    pub launch_measurement: Option<[u8; 32]>,
+    pub regs: Registers,
+    // Hypercall numbers this guest may issue
+    pub hypercall_policy: Vec<u64>,
+    pub balloon_pages: usize,
+    // The L1 hypervisor in the guest's /boot, checked before the kernel runs it
+    pub l1_hypervisor_image: Vec<u8>,
+    pub is_verified_l1: bool,
+    // Present once the guest kernel runs its own (L1) hypervisor
+    pub nested: Option<Nested>,
+    pub cmdline: String,
+    pub disk: BlockDevice,
+    pub verity: Option<VerityTarget>,
+    pub verity_mode: CorruptionMode,
+    pub kmod: KernelIntegrity,
+    pub ima: Ima,
+    pub sched: Scheduler,
+}
+
+impl Guest {
+    fn new(name: &str, cc: Option<CcTech>) -> Self {
+        let mut state = State::new();
+        state.change_mode(Mode::Hypervisor);
+        // The hypervisor provisions a vTPM and has the platform TPM vouch for its EK
+        let mut vtpm = Tpm::new();
+        vtpm.ek_cert = Some(PLATFORM_TPM.lock().unwrap().certify(vtpm.ek_public()));
+        let disk = BlockDevice::format();
+        Guest {
+            name: name.to_string(),
+            state,
+            is_verified_os: false,
+            is_verified_fs: false,
+            is_verified_ap: false,
+            vtpm,
+            kernel_image: KERNEL_IMAGE.to_vec(),
+            running_kernel: "vmlinuz-6.1-secboot",
+            memory: GuestMemory::new(),
+            cc,
+            launch_measurement: None,
+            regs: Registers::new(),
+            hypercall_policy: default_policy(),
+            balloon_pages: 0,
+            l1_hypervisor_image: L1_HYPERVISOR_IMAGE.to_vec(),
+            is_verified_l1: false,
+            nested: None,
+            cmdline: disk.cmdline(),
+            disk,
+            verity: None,
+            verity_mode: CorruptionMode::Eio,
+            kmod: KernelIntegrity::new(),
+            ima: Ima::new(),
+            sched: Scheduler::new(),
+        }
+    }
+
+    // Guest power-off: back to the hypervisor with nothing verified or measured.
+    // The vTPM keeps its endorsement identity like a physical TPM would.
+    pub fn reset(&mut self) {
+        self.state.change_mode(Mode::Hypervisor);
+        self.is_verified_os = false;
+        self.is_verified_fs = false;
+        self.is_verified_ap = false;
+        self.running_kernel = "vmlinuz-6.1-secboot";
+        self.vtpm.pcrs = PcrBank::new();
+        self.memory = GuestMemory::new();
+        self.launch_measurement = None;
+        self.regs = Registers::new();
+        self.balloon_pages = 0;
+        self.is_verified_l1 = false;
+        self.nested = None;
+        self.verity = None;
+        self.kmod = KernelIntegrity::new();
+        self.ima.reset();
+        self.sched = Scheduler::new();
+    }
+
+    // Reset and boot straight back into the kernel if it still verifies
+    fn reboot(&mut self) {
+        self.reset();
+        self.verify_kernel();
+        if self.cc.is_some() || self.is_verified_os {
+            self.launch_kernel();
+        }
+    }
+
+    // Set up dm-verity from the signed root hash on the kernel command line
+    pub fn verify_filesystem(&mut self) {
+        match VerityTarget::from_cmdline(&self.cmdline, self.verity_mode) {
+            Ok(target) => {
+                println!("Verified filesystem root hash for '{}', dm-verity active", self.name);
+                self.vtpm.pcrs.extend(PCR_FILESYSTEM, &self.disk.root_hash());
+                self.verity = Some(target);
+                self.is_verified_fs = true;
+            },
+            Err(msg) => println!("Filesystem verification failed for '{}': {}", self.name, msg),
+        }
+    }
+
+    // Appraise the default application before user space is allowed to start it
+    pub fn verify_application(&mut self) {
+        match self.ima.appraise(DEFAULT_APPLICATION) {
+            Ok(()) => {
+                println!("Verified Application {} for '{}'", DEFAULT_APPLICATION, self.name);
+                self.is_verified_ap = true;
+            },
+            Err(msg) => println!("Application verification failed for '{}': {}", self.name, msg),
+        }
+    }
+
+    // IMA measures and appraises the file, then the ELF loader checks its
+    // signature and segments. security.ima doubles as the detached signature.
+    fn load_binary(&mut self, path: &str) -> Result<ElfImage, String> {
+        self.ima.exec(path, &mut self.vtpm.pcrs)?;
+        let enforce = self.ima.appraise == Appraise::Enforce;
+        let file = self.ima.file_mut(path)?;
+        elf::load(&file.content, file.ima, enforce)
+    }
+
+    pub fn spawn(&mut self, path: &str, priority: u8) -> Result<u32, String> {
+        let image = self.load_binary(path)?;
+        let pid = self.sched.spawn(path, &image, priority);
+        println!("Spawned pid {} ({}) in guest '{}'", pid, path, self.name);
+        Ok(pid)
+    }
+
+    // Start user space with `path` as the first process
+    pub fn start_application(&mut self, path: &str) -> Result<(), String> {
+        let pid = self.spawn(path, 1)?;
+        self.sched.switch_to(pid, &mut self.regs)?;
+        self.state.change_mode(Mode::User);
+        Ok(())
+    }
+
+    pub fn exec(&mut self, path: &str) -> Result<(), String> {
+        let image = self.load_binary(path)?;
+        self.sched.exec(path, &image, &mut self.regs)
+    }
+
+    pub fn kill(&mut self, pid: u32) -> Result<(), String> {
+        self.sched.kill(pid, &mut self.regs)?;
+        if self.sched.is_empty() && self.state.current_mode() == Mode::User {
+            println!("Last user process exited; guest '{}' is back in the kernel", self.name);
+            self.state.change_mode(Mode::Kernel);
+        }
+        Ok(())
+    }
+
+    pub fn read_block(&mut self, index: usize) -> Result<Block, String> {
+        if index >= self.disk.data.len() {
+            return Err(format!("Block {} is beyond the end of the device", index));
+        }
+        let result = match self.verity.as_mut() {
+            Some(target) => target.read(&self.disk, index),
+            None => {
+                println!("Warning: no dm-verity mapping, block {} is read unverified", index);
+                return Ok(self.disk.data[index]);
+            },
+        };
+        let reason = match result {
+            Ok(block) => return Ok(block),
+            Err(reason) => reason,
+        };
+        println!("device-mapper: verity: {}", reason);
+        match self.verity_mode {
+            CorruptionMode::Eio => Err("I/O error (EIO)".to_string()),
+            CorruptionMode::Restart => {
+                println!("device-mapper: verity: restarting guest '{}'", self.name);
+                self.reboot();
+                Err("Guest restarted".to_string())
+            },
+            CorruptionMode::Panic => {
+                println!("Kernel panic - not syncing: dm-verity device corrupted");
+                self.reset();
+                Err(format!("Guest '{}' halted", self.name))
+            },
+        }
+    }
+
+    // Name plus nesting level, shown in the prompt
+    pub fn console_label(&self) -> String {
+        match &self.nested {
+            Some(n) if n.in_l2 => format!("{} L2", self.name),
+            Some(_) => format!("{} L1", self.name),
+            None => self.name.clone(),
+        }
+    }
+
+    // The guest kernel checks the L1 hypervisor it is about to run
+    pub fn verify_l1_hypervisor(&mut self) {
+        if ct_eq(&sha256(&self.l1_hypervisor_image), &sha256(L1_HYPERVISOR_IMAGE)) {
+            println!("Verified L1 hypervisor for '{}'", self.name);
+            self.is_verified_l1 = true;
+        } else {
+            println!("L1 hypervisor signature check failed for '{}'", self.name);
+        }
+    }
+
+    pub fn load_l1_hypervisor(&mut self) -> Result<(), String> {
+        if self.nested.is_some() {
+            return Err("L1 hypervisor already loaded".to_string());
+        }
+        if !self.is_verified_l1 {
+            return Err("L1 hypervisor not verified. Aborting.".to_string());
+        }
+        self.vtpm.pcrs.extend(PCR_HYPERVISOR, &self.l1_hypervisor_image);
+        self.nested = Some(Nested::new());
+        println!("L1 hypervisor loaded in guest '{}'. Type 'nested enter' to launch its L2 guest.", self.name);
+        Ok(())
+    }
+
+    pub fn print_policy(&self) {
+        println!("Hypercall policy for '{}':", self.name);
+        for (nr, name, _) in HYPERCALLS.iter() {
+            let verdict = if self.hypercall_policy.contains(nr) { "allow" } else { "deny" };
+            println!(" {:>2} {:<16} {}", nr, name, verdict);
+        }
+    }
+
+    // Hypervisor-trusted model: the hypervisor checks the image signature
+    pub fn verify_kernel(&mut self) {
+        if let Some(tech) = self.cc {
+            println!("Guest '{}' is a {:?} guest: the hypervisor is outside its trust boundary.", self.name, tech);
+            println!("Its launch is measured by the {} instead.", tech.processor());
+            return;
+        }
+        if ct_eq(&sha256(&self.kernel_image), &sha256(KERNEL_IMAGE)) {
+            println!("Verified Guest OS kernel for '{}'", self.name);
+            self.is_verified_os = true;
+        } else {
+            println!("Kernel signature check failed for '{}'", self.name);
+        }
+    }
+
+    // Places the kernel image in guest memory and hands the console to it.
+    // Confidential guests get their memory key and launch measurement from
+    // the security processor; everyone else is loaded by the hypervisor.
+    pub fn launch_kernel(&mut self) {
+        match self.cc {
+            Some(tech) => {
+                let (key, measurement) = SECURITY_PROCESSOR.lock().unwrap().launch(&self.kernel_image);
+                self.memory = GuestMemory::new_encrypted(&key);
+                self.memory.guest_write(0, &self.kernel_image).unwrap();
+                self.launch_measurement = Some(measurement);
+                println!("{} measured launch image: {}", tech.processor(), to_hex(&measurement));
+                println!("Guest memory encrypted and integrity protected from the hypervisor.");
+            },
+            None => {
+                self.memory = GuestMemory::new();
+                self.memory.host_write(0, &self.kernel_image).unwrap();
+            },
+        }
+        self.vtpm.pcrs.extend(PCR_KERNEL, &self.kernel_image);
+        self.ima.boot_aggregate(&mut self.vtpm.pcrs);
+        self.state.change_mode(Mode::Kernel);
+    }
+
+    // kexec: the running kernel replaces itself without going back through
+    // firmware. Nothing resets the vTPM, so PCR 4 still names the kernel that
+    // booted first; IMA measures the new image into PCR 10 and its log is
+    // handed over so the PCRs keep replaying. Everything the old kernel
+    // verified or locked down has to be done again by the new one.
+    pub fn kexec(&mut self, name: &str) -> Result<(), String> {
+        let catalog = kexec_catalog();
+        let target = catalog.iter().find(|k| k.name == name).ok_or_else(|| format!("kexec: {}: No such file", name))?;
+        let trusted = keyring_verifies(target.signature, target.image);
+        if !trusted {
+            self.kmod.check(LockdownReason::Kexec)?;
+            println!("kexec: {} is not signed by a trusted key; the chain of trust ends here", name);
+        }
+        self.ima.measure(&format!("kexec:{}", name), target.image, &mut self.vtpm.pcrs);
+        println!("kexec: jumping from {} to {}", self.running_kernel, name);
+
+        // The old kernel's pages are not scrubbed, only overwritten where the new one lands
+        self.memory.guest_write(0, target.image)?;
+        self.running_kernel = target.name;
+        self.is_verified_os = self.is_verified_os && trusted;
+        self.is_verified_fs = false;
+        self.is_verified_ap = false;
+        self.is_verified_l1 = false;
+        self.regs = Registers::new();
+        self.sched = Scheduler::new();
+        self.nested = None;
+        self.verity = None;
+        self.kmod = KernelIntegrity::new();
+        self.ima.appraise = Appraise::Enforce;
+        Ok(())
+    }
+
+    // Guest-requested report, checked the way a remote guest owner would:
+    // signature from a genuine chip and a measurement matching the vendor image
+    pub fn request_report(&self, user_data: &[u8]) -> bool {
+        let (tech, measurement) = match (self.cc, self.launch_measurement) {
+            (Some(tech), Some(m)) => (tech, m),
+            _ => {
+                println!("Guest '{}' is not a confidential guest. Use 'attest' instead.", self.name);
+                return false;
+            },
+        };
+        let sp = SECURITY_PROCESSOR.lock().unwrap();
+        let report = sp.report(tech, measurement, sha256(user_data));
+        report.print();
+        let signature_ok = sp.verify_report(&report);
+        let measurement_ok = ct_eq(&report.measurement, &launch_digest(KERNEL_IMAGE));
+        println!("Report signature: {}", if signature_ok { "valid" } else { "INVALID" });
+        println!("Launch measurement: {}", if measurement_ok { "matches reference" } else { "DOES NOT match reference" });
+        signature_ok && measurement_ok
+    }
+}
+
+pub struct Hypervisor {
+    guests: Vec<Guest>,
+    current: Option<usize>,
+}
+
+impl Hypervisor {
+    fn new() -> Self {
+        Hypervisor {
+            guests: Vec::new(),
+            current: None,
+        }
+    }
+
+    pub fn create(&mut self, name: &str, cc: Option<CcTech>) -> Result<(), String> {
+        if self.guests.iter().any(|g| g.name == name) {
+            return Err(format!("Guest '{}' already exists", name));
+        }
+        self.guests.push(Guest::new(name, cc));
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Result<&mut Guest, String> {
+        let index = self.index_of(name)?;
+        Ok(&mut self.guests[index])
+    }
+
+    pub fn destroy(&mut self, name: &str) -> Result<(), String> {
+        let index = self.index_of(name)?;
+        self.guests.remove(index);
+        self.current = match self.current {
+            Some(c) if c == index => None,
+            Some(c) if c > index => Some(c - 1),
+            other => other,
+        };
+        Ok(())
+    }
+
+    pub fn enter(&mut self, name: &str) -> Result<(), String> {
+        self.current = Some(self.index_of(name)?);
+        Ok(())
+    }
+
+    pub fn leave(&mut self) {
+        self.current = None;
+    }
+
+    pub fn current(&self) -> Option<&Guest> {
+        self.current.map(|i| &self.guests[i])
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut Guest> {
+        self.current.map(move |i| &mut self.guests[i])
+    }
+
+    // The selected guest, provided it is running in one of `modes`
+    pub fn running_guest(&mut self, modes: &[Mode]) -> Option<&mut Guest> {
+        self.current_mut().filter(|g| modes.contains(&g.state.current_mode()))
+    }
+
+    pub fn list(&self) {
+        if self.guests.is_empty() {
+            println!("No guests. Type 'vm create <name>' to add one.");
+            return;
+        }
+        println!("Guests:");
+        for (i, guest) in self.guests.iter().enumerate() {
+            let marker = if self.current == Some(i) { "*" } else { " " };
+            let kind = match guest.cc {
+                Some(tech) => format!("{:?}", tech),
+                None => "standard".to_string(),
+            };
+            println!(
+                "{} {:<12} {:<8} {:<10} os:{} fs:{} app:{} vtpm-ek:{}",
+                marker,
+                guest.name,
+                kind,
+                format!("{:?}", guest.state.current_mode()),
+                guest.is_verified_os,
+                guest.is_verified_fs,
+                guest.is_verified_ap,
+                &to_hex(&guest.vtpm.ek_public())[..16]
+            );
+        }
+    }
+
+    // Platform power-off tears down every guest
+    pub fn reset(&mut self) {
+        *self = Hypervisor::new();
+    }
+
+    fn index_of(&self, name: &str) -> Result<usize, String> {
+        self.guests
+            .iter()
+            .position(|g| g.name == name)
+            .ok_or_else(|| format!("No guest named '{}'", name))
+    }
+}
+
+lazy_static! {
+    pub static ref HYPERVISOR: Mutex<Hypervisor> = Mutex::new(Hypervisor::new());
+}
+
+// Runs `f` against the guest that currently owns the console
+pub fn with_current_guest<F: FnOnce(&mut Guest)>(f: F) {
+    let mut hv = HYPERVISOR.lock().unwrap();
+    match hv.current_mut() {
+        Some(guest) => f(guest),
+        None => println!("No guest selected. Type 'vm enter <name>' first."),
+    }
+}
+
+// Layered attestation: the platform TPM quotes the host boot chain and vouches
+// for the guest's vTPM, whose quote covers the guest boot chain. The guest
+// quote's nonce is bound to the platform quote so the two cannot be mixed.
+pub fn attest_guest(guest: &Guest, nonce: &[u8]) -> bool {
+    let platform = PLATFORM_TPM.lock().unwrap();
+    let platform_quote = platform.quote(&PLATFORM_QUOTE_PCRS, nonce);
+    let mut binding = nonce.to_vec();
+    binding.extend_from_slice(&platform_quote.signature);
+    let guest_quote = guest.vtpm.quote(&GUEST_QUOTE_PCRS, &sha256(&binding));
+
+    print_quote("Platform TPM", &platform_quote);
+    print_quote(&format!("vTPM of '{}'", guest.name), &guest_quote);
+
+    let platform_ok = platform.verify_quote(&platform_quote);
+    let cert_ok = match &guest.vtpm.ek_cert {
+        Some(cert) => platform.verify_certificate(cert) && ct_eq(&cert.subject, &guest_quote.signer),
+        None => false,
+    };
+    let guest_ok = guest.vtpm.verify_quote(&guest_quote);
+
+    println!("Platform quote signature: {}", if platform_ok { "valid" } else { "INVALID" });
+    println!("vTPM EK certified by platform TPM: {}", if cert_ok { "yes" } else { "NO" });
+    println!("Guest quote signature: {}", if guest_ok { "valid" } else { "INVALID" });
+
+    // The IMA list explains PCR 10; it is only useful if it replays to the quoted value
+    let mut replayed = guest.vtpm.pcrs.clone();
+    let ima_ok = match guest.ima.replay() {
+        Ok(pcr10) => {
+            replayed.set(PCR_APPLICATION, pcr10);
+            ct_eq(&replayed.composite(&GUEST_QUOTE_PCRS), &guest_quote.pcr_digest)
+        },
+        Err(_) => false,
+    };
+    println!("IMA measurement list ({} entries) replays to quoted PCR 10: {}", guest.ima.log.len(), if ima_ok { "yes" } else { "NO" });
+    platform_ok && cert_ok && guest_ok && ima_ok
+}